@@ -48,3 +48,7 @@ function! tree#util#confirm(msg, choices, default) abort
 
   return a:default
 endfunction
+
+function! tree#util#print_message(level, msg) abort
+  call v:lua.tree.notify(a:level, a:msg)
+endfunction
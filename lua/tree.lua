@@ -40,6 +40,7 @@ local function default_etc_options()
         direction = '',
         search = '',
         new = false,
+        scope = 'global', -- {"global", "tab", "window"}
         toggle = true,
         wincol = math.modf(vim.o.columns / 4),
         winrow = math.modf(vim.o.lines / 3)
@@ -73,6 +74,27 @@ function M.quit(bufnr)
     end
 end
 
+-- bufnr => {{name, value}, ...}, the window_options of the tree given by the backend
+M.win_options = {}
+-- the defaults live in the backend, asked for once
+local default_win_options = nil
+
+function M.set_win_options(bufnr, options) M.win_options[bufnr] = options end
+
+local function apply_win_options(bufnr, winid)
+    local options = M.win_options[bufnr]
+    if options == nil then
+        if default_win_options == nil then
+            default_win_options = rpcrequest('_tree_default_window_options',
+                                             {}, false)
+        end
+        options = default_win_options
+    end
+    for _, opt in ipairs(options) do
+        a.nvim_win_set_option(winid, opt[1], opt[2])
+    end
+end
+
 --- Resume tree window.
 -- If the window corresponding to bufnrs is available, goto it;
 -- otherwise, create a new window.
@@ -149,15 +171,10 @@ function M.resume(bufnrs)
         if resize_cmd ~= nil then cmd(resize_cmd) end
     end
 
-    cmd "se nonu"
-    cmd "se nornu"
-    cmd "se nolist"
-    cmd "se signcolumn=no"
-    a.nvim_win_set_option(winid, 'wrap', false)
+    apply_win_options(bufnr, a.nvim_get_current_win())
 end
 
 function M.resize(size, bufnr)
-    print(size)
     local resize_cmd
     local etc = M.etc_options[bufnr];
     if etc.split == 'vertical' then
@@ -170,12 +187,25 @@ function M.resize(size, bufnr)
     if resize_cmd ~= nil then cmd(resize_cmd) end
 end
 
+-- `+line` for the edit commands, the column is set once the file is open
+local function position_arg(pos)
+    if pos == nil or pos == vim.NIL then return '' end
+    return string.format('+%d ', pos[1])
+end
+
+local function jump_to(pos)
+    if pos == nil or pos == vim.NIL then return end
+    fn.cursor(pos[1], math.max(pos[2], 1))
+    cmd 'normal! zv'
+end
+
 --- Drop file.
 --- If the window corresponding to file is available, goto it;
 --- otherwise, goto prev window and edit file.
 -- @param file  string: file absolute path
+-- @param pos   {line, col} or nil: where the cursor goes, col 0 keeps the first column
 -- @return nil
-function M.drop(args, file)
+function M.drop(args, file, pos)
     local arg = args[1] or 'edit'
     local bufnr = call('bufnr', {file})
     local winids = call('win_findbuf', {bufnr})
@@ -186,9 +216,42 @@ function M.drop(args, file)
         local prev_winnr = call('winnr', {'#'})
         local prev_winid = call('win_getid', {prev_winnr})
         call('win_gotoid', {prev_winid})
-        local str = string.format("%s %s", arg, file)
+        local str = string.format("%s %s%s", arg, position_arg(pos), file)
         cmd(str)
     end
+    jump_to(pos)
+end
+
+--- Open file with split, vsplit, tabnew or edit.
+--- split and edit happen in the previous window, tabnew in a new tab.
+-- @param mode  string: the command used to open the file
+-- @param file  string: file absolute path
+-- @param pos   {line, col} or nil: where the cursor goes, col 0 keeps the first column
+-- @return nil
+function M.open_file(mode, file, pos)
+    if mode ~= 'tabnew' then
+        local prev_winnr = call('winnr', {'#'})
+        local prev_winid = call('win_getid', {prev_winnr})
+        call('win_gotoid', {prev_winid})
+    end
+    cmd(string.format("%s %s%s", mode, position_arg(pos),
+                      call('fnameescape', {file})))
+    jump_to(pos)
+end
+
+--- Run a command in a terminal split, the split closes when the command exits.
+-- @param argv  list: the command and its arguments
+-- @param cwd   string: the working directory of the command
+-- @return nil
+function M.open_terminal(argv, cwd)
+    cmd 'botright new'
+    local bufnr = a.nvim_get_current_buf()
+    fn.termopen(argv, {cwd = cwd, on_exit = function()
+        if a.nvim_buf_is_valid(bufnr) then
+            a.nvim_buf_delete(bufnr, {force = true})
+        end
+    end})
+    cmd 'startinsert'
 end
 
 --- Used to process files with the same name
@@ -212,7 +275,7 @@ function M.pre_paste(pos, src, dest)
     local msg = msg ..
                     string.format('%s already exists.  Overwrite?', dest.path)
     local choice = call('confirm',
-                        {msg, '&Force\n&No\n&Rename\n&Time\n&Underbar', 0})
+                        {msg, '&Force\n&No\n&Rename\n&Time\n&Underbar\n&Suffix', 0})
     local ret = ''
     if choice == 0 then
         return
@@ -228,6 +291,8 @@ function M.pre_paste(pos, src, dest)
         ret = src.path
     elseif choice == 5 then
         ret = dest.path .. '_'
+    elseif choice == 6 then
+        ret = dest.suffixed
     end
 
     -- TODO: notify ret to server --
@@ -250,12 +315,73 @@ function M.pre_remove(bufnr, rmfiles)
     end
 end
 
+--- Send workspace/willRenameFiles to the language servers and apply the edits.
+-- @param files list of {old=, new=} absolute paths
+-- @return number of applied workspace edits
+function M.lsp_will_rename(files)
+    local params = {files = {}}
+    for _, f in ipairs(files) do
+        table.insert(params.files, {
+            oldUri = vim.uri_from_fname(f.old),
+            newUri = vim.uri_from_fname(f.new)
+        })
+    end
+    local cnt = 0
+    for _, client in pairs(vim.lsp.get_active_clients()) do
+        local ok, resp = pcall(client.request_sync,
+                               'workspace/willRenameFiles', params, 1000)
+        if ok and resp and resp.result then
+            vim.lsp.util.apply_workspace_edit(resp.result)
+            cnt = cnt + 1
+        end
+    end
+    return cnt
+end
+
+--- Send workspace/didRenameFiles to the language servers.
+-- @return number of notified servers
+function M.lsp_did_rename(files)
+    local params = {files = {}}
+    for _, f in ipairs(files) do
+        table.insert(params.files, {
+            oldUri = vim.uri_from_fname(f.old),
+            newUri = vim.uri_from_fname(f.new)
+        })
+    end
+    local cnt = 0
+    for _, client in pairs(vim.lsp.get_active_clients()) do
+        if client.notify('workspace/didRenameFiles', params) then
+            cnt = cnt + 1
+        end
+    end
+    return cnt
+end
+
+M.attached_bufs = {}
 function M.buf_attach(buf)
+    a.nvim_exec(string.format([[
+    augroup tree_cursor_%d
+      autocmd!
+      autocmd CursorHold <buffer=%d> lua tree.cursor_hold(%d)
+      autocmd BufWinEnter,WinEnter <buffer=%d> lua tree.focus_changed(%d, true)
+      autocmd WinLeave <buffer=%d> lua tree.focus_changed(%d, false)
+      autocmd BufWinEnter <buffer=%d> lua tree.shown_changed(%d, true)
+      autocmd BufWinLeave <buffer=%d> lua tree.shown_changed(%d, false)
+      autocmd BufLeave <buffer=%d> lua tree.save_state()
+    augroup END
+    ]], buf, buf, buf, buf, buf, buf, buf, buf, buf, buf, buf, buf), false)
+    -- attached once, a restarted backend sets the buffer up again
+    if M.attached_bufs[buf] then return end
+    M.attached_bufs[buf] = true
     a.nvim_buf_attach(buf, false, {
         on_detach = function()
-            rpcrequest('function', {"on_detach", buf}, true)
+            M.attached_bufs[buf] = nil
+            M.highlight_cursor[buf] = nil
+            rpcrequest('_tree_buf_detach', {buf}, true)
             M.alive_buf_cnt = M.alive_buf_cnt - 1
             M.etc_options[buf] = nil
+            M.win_options[buf] = nil
+            M.breadcrumbs[buf] = nil
         end
     })
 end
@@ -273,31 +399,116 @@ end
 -- `:map <buffer>` to show keymap
 keymap = ''
 M.callback = {}
+
+-- the actions of the server, mapped to call_async_action
+local action_set = {
+    copy = true,
+    paste = true,
+    cancel_copy = true,
+    load_more = true,
+    run_command = true,
+    goto_file = true,
+    selection_list = true,
+    search = true,
+    select_pattern = true,
+    toggle_binary_files = true,
+    move = true,
+    drop = true,
+    open = true,
+    open_tree = true,
+    close_tree = true,
+    open_or_close_tree = true,
+    smart_expand = true,
+    click = true,
+    open_directory = true,
+    cd = true,
+    call = true,
+    new_file = true,
+    rename = true,
+    duplicate = true,
+    toggle_select = true,
+    remove = true,
+    remove_trash = true,
+    mark_delete = true,
+    open_with = true,
+    quick_cd = true,
+    execute_system = true,
+    open_external_manager = true,
+    preview = true,
+    diff_with = true,
+    purge_marked = true,
+    toggle_ignored_files = true,
+    yank_path = true,
+    sort_by_column = true,
+    clear_select_all = true,
+    toggle_select_all = true,
+    redraw = true,
+    resize = true,
+    fit_width = true,
+    collapse_others = true,
+    scroll_left = true,
+    scroll_right = true,
+    show_info = true,
+    update_git_map = true,
+    git_restore = true,
+    filter = true,
+    clear_filter = true,
+    filter_extension = true,
+    git_changed = true,
+    delete_swap = true,
+    change_mode = true
+}
+
+--- The action names of fern and neo-tree, {action, args...} each, so that the
+-- mappings written for them keep working
+M.action_aliases = {
+    ['open.edit'] = {'open', 'edit'},
+    ['open.split'] = {'open', 'split'},
+    ['open.vsplit'] = {'open', 'vsplit'},
+    ['open.tabnew'] = {'open', 'tabnew'},
+    ['open:edit'] = {'open', 'edit'},
+    ['open:split'] = {'open', 'split'},
+    ['open:vsplit'] = {'open', 'vsplit'},
+    ['open:tabedit'] = {'open', 'tabnew'},
+    open_split = {'open', 'split'},
+    open_vsplit = {'open', 'vsplit'},
+    open_tabnew = {'open', 'tabnew'},
+    ['navigate.parent'] = {'cd', '..'},
+    navigate_up = {'cd', '..'},
+    set_root = {'open_directory'},
+    close_node = {'close_tree'},
+    toggle_node = {'open_or_close_tree'},
+    ['hidden:toggle'] = {'toggle_ignored_files'},
+    toggle_hidden = {'toggle_ignored_files'},
+    ['mark:toggle'] = {'toggle_select'},
+    ['mark:clear'] = {'clear_select_all'},
+    ['new-file'] = {'new_file'},
+    ['clipboard-copy'] = {'copy'},
+    ['clipboard-move'] = {'move'},
+    ['clipboard-paste'] = {'paste'},
+    copy_to_clipboard = {'copy'},
+    cut_to_clipboard = {'move'},
+    paste_from_clipboard = {'paste'},
+    copy_absolute_path = {'yank_path', 'absolute'},
+    copy_relative_path = {'yank_path', 'relative'},
+    copy_filename = {'yank_path', 'name'},
+    show_file_details = {'show_info'}
+}
+
+-- the action an alias stands for, its arguments come before the given ones. An action
+-- or an Ex command of that name goes first
+local function resolve_alias(op, args)
+    local alias = M.action_aliases[op]
+    if alias == nil or action_set[op] or fn.exists(':' .. op) == 2 then
+        return op, args
+    end
+    local resolved = table.slice(alias, 2)
+    for _, arg in ipairs(args) do table.insert(resolved, arg) end
+    return alias[1], resolved
+end
+
 function M.keymap(lhs, ...)
     -- TODO: call directly uses lua callback
-    local action_set = {
-        copy = true,
-        paste = true,
-        move = true,
-        drop = true,
-        open_tree = true,
-        close_tree = true,
-        open_or_close_tree = true,
-        open_directory = true,
-        cd = true,
-        call = true,
-        new_file = true,
-        rename = true,
-        toggle_select = true,
-        remove = true,
-        toggle_ignored_files = true,
-        yank_path = true,
-        clear_select_all = true,
-        toggle_select_all = true,
-        redraw = true,
-        resize = true,
-        update_git_map = true
-    }
     local action_list = {...}
     local autocmd = [[augroup tree_keymap
 autocmd!
@@ -317,6 +528,7 @@ func! Tree_set_keymap() abort
             op = action
             args = {}
         end
+        op, args = resolve_alias(op, args)
         for i, arg in ipairs(args) do
             if type(arg) == 'function' then
                 M.callback[lhs] = arg
@@ -386,12 +598,20 @@ function M.call(f)
     f(ctx)
 end
 
-function M.print_error(s)
-    a.nvim_command(string.format(
-                       "echohl Error | echomsg '[tree] %s' | echohl None",
-                       M.string(s)))
+--- Show a message through vim.notify, so notification plugins can pick it up
+-- @param level 'info', 'warn' or 'error'
+function M.notify(level, msg, title)
+    local levels = {
+        info = vim.log.levels.INFO,
+        warn = vim.log.levels.WARN,
+        error = vim.log.levels.ERROR
+    }
+    vim.notify(msg, levels[level] or vim.log.levels.INFO,
+               {title = title or 'tree'})
 end
 
+function M.print_error(s) M.notify('error', M.string(s)) end
+
 local function __re_unquoted_match(match)
     -- Don't match a:match if it is located in-between unescaped single or double quotes
     return match ..
@@ -576,36 +796,350 @@ function M.new_file(args)
     rpcrequest('function', {"new_file", {ret, args.bufnr}}, true)
 end
 
-function M.error(str)
-    local cmd = string.format('echomsg "[tree] %s"', str)
-    a.nvim_command('echohl Error')
-    a.nvim_command(cmd)
-    a.nvim_command('echohl None')
+function M.error(str) M.notify('error', str) end
+function M.warning(str) M.notify('warn', str) end
+function M.print_message(str) M.notify('info', str) end
+
+--- The progress of a background copy, kept out of the message history
+function M.copy_progress(msg) a.nvim_echo({{msg}}, false, {}) end
+
+--- A background copy is over, the tree draws its destination
+function M.copy_done(bufnr, dest, level, msg)
+    M.notify(level, msg)
+    if a.nvim_buf_is_valid(bufnr) then
+        rpcrequest('_tree_copy_done', {bufnr, dest}, true)
+    end
+end
+
+--- The output of a command run by run_command, in a scratch buffer of its own
+function M.show_output(title, lines)
+    local buf = a.nvim_create_buf(false, true)
+    a.nvim_buf_set_lines(buf, 0, -1, false, lines)
+    a.nvim_buf_set_option(buf, 'modifiable', false)
+    pcall(a.nvim_buf_set_name, buf, title)
+    cmd 'botright split'
+    a.nvim_win_set_buf(0, buf)
+end
+
+--- The files goto_file found, the chosen one is revealed in the tree of `bufnr`
+function M.goto_file_found(bufnr, prompt, labels, paths)
+    local choice = 1
+    if #paths > 1 then
+        choice = M.inputlist(prompt, labels)
+        if choice == 0 then return end
+    end
+    local winid = fn.bufwinid(bufnr)
+    if winid == -1 then return end
+    fn.win_gotoid(winid)
+    call_async_action('goto_file', {reveal = paths[choice]})
+end
+
+--- Let the user choose one of the items.
+-- @return 1-based index of the chosen item, 0 when cancelled
+function M.inputlist(prompt, items)
+    local lines = {prompt}
+    for i, item in ipairs(items) do
+        table.insert(lines, string.format('%d. %s', i, item))
+    end
+    local choice = fn.inputlist(lines)
+    if choice < 1 or choice > #items then return 0 end
+    return choice
 end
-function M.warning(str)
-    local cmd = string.format('echomsg "[tree] %s"', str)
-    a.nvim_command('echohl WarningMsg')
-    a.nvim_command(cmd)
-    a.nvim_command('echohl None')
+
+function M.show_info(info)
+    require('tree/float')
+    Tree_display(info)
+end
+
+--- User highlight links, e.g. `tree.highlight_links = {tree_cut_item = 'NonText'}`
+M.highlight_links = M.highlight_links or {}
+function M.apply_highlight_links()
+    for group, target in pairs(M.highlight_links) do
+        a.nvim_command(string.format('hi! link %s %s', group, target))
+    end
 end
-function M.print_message(str)
-    local cmd = string.format('echo "[tree] %s"', str)
-    a.nvim_command(cmd)
+
+function M.colorscheme_changed()
+    if not M.channel_id then return end
+    rpcrequest('_tree_colorscheme_changed', {}, true)
 end
 
 function M.run_commands_batch(args)
     for i = 1, #args do a.nvim_command(args[i]) end
 end
 
-function M.hl_lines(bufnr, icon_ns_id, args)
-    for i = 1, #args, 4 do
-        hl_group = args[i]
-        start_pos = args[i + 1]
-        end_pos = args[i + 2]
-        row = args[i + 3]
-        a.nvim_buf_add_highlight(bufnr, icon_ns_id, hl_group, row, start_pos,
-                                 end_pos)
+-- the buffers whose tree highlights the cursor item, the cursor moves are only sent for
+-- them and for the buffer of the preview float while it's open
+M.highlight_cursor = {}
+
+local function update_cursor_autocmd(buf)
+    local on = M.highlight_cursor[buf] or
+                   (M.preview_bufnr == buf and M.preview_is_open())
+    local autocmd = ''
+    if on then
+        autocmd = string.format(
+                      'autocmd CursorMoved <buffer=%d> lua tree.cursor_moved(%d)',
+                      buf, buf)
     end
+    a.nvim_exec(string.format([[
+    augroup tree_cursor_moved_%d
+      autocmd!
+      %s
+    augroup END
+    ]], buf, autocmd), false)
+end
+
+--- Set by the backend once the config of the tree is known
+function M.set_highlight_cursor(buf, on)
+    M.highlight_cursor[buf] = on
+    update_cursor_autocmd(buf)
+end
+
+function M.cursor_moved(bufnr)
+    rpcrequest('_tree_cursor_moved', {bufnr, fn.line('.')}, true)
+    -- the preview float was closed with :q
+    if not M.highlight_cursor[bufnr] and not M.preview_is_open() then
+        update_cursor_autocmd(bufnr)
+    end
+end
+
+function M.cursor_hold(bufnr)
+    rpcrequest('_tree_cursor_hold', {bufnr, fn.line('.')}, true)
+end
+
+-------------------- backend restart --------------------
+-- the trees as last serialized, replayed into a restarted backend
+M.saved_states = {}
+
+-- the backend pushes the states back through store_states, nothing waits for them
+function M.save_state()
+    rpcrequest('_tree_push_states', {}, true)
+end
+
+-- pushed by the backend whenever a tree is expanded, collapsed or moved
+function M.store_states(states)
+    if type(states) == 'table' then M.saved_states = states end
+end
+
+--- The focused tree is drawn in full color, the others are dimmed
+function M.focus_changed(bufnr, focused)
+    -- the cursorline follows the focus, restored to what the user set on enter
+    if focused then
+        if vim.w.tree_cursorline ~= nil then
+            vim.wo.cursorline = vim.w.tree_cursorline
+        end
+    else
+        vim.w.tree_cursorline = vim.wo.cursorline
+        vim.wo.cursorline = false
+    end
+    rpcrequest('_tree_focus_changed', {bufnr, focused}, true)
+end
+
+-- the backend only polls the trees shown in a window
+function M.shown_changed(bufnr, shown)
+    rpcrequest('_tree_shown_changed', {bufnr, shown}, true)
+end
+
+function M.hl_cursor_item(bufnr, ns_id, row, start_pos, end_pos)
+    a.nvim_buf_clear_namespace(bufnr, ns_id, 0, -1)
+    a.nvim_buf_add_highlight(bufnr, ns_id, 'tree_cursor_item', row, start_pos,
+                             end_pos)
+end
+
+--- The summary_footer, a virtual line below `row` (0-based)
+function M.set_footer(bufnr, ns_id, row, text)
+    a.nvim_buf_set_extmark(bufnr, ns_id, row, 0, {
+        id = 1,
+        virt_lines = {{{text, 'tree_footer'}}}
+    })
+end
+
+--- The "… N more" virtual lines of the directories listed in part
+-- @param marks  {{row, text}, ...}, rows 0-based
+function M.set_more_marks(bufnr, ns_id, marks)
+    a.nvim_buf_clear_namespace(bufnr, ns_id, 0, -1)
+    for _, mark in ipairs(marks) do
+        a.nvim_buf_set_extmark(bufnr, ns_id, mark[1], 0, {
+            virt_lines = {{{mark[2], 'tree_footer'}}}
+        })
+    end
+end
+
+--- Highlight the rows (0-based) of the entries that just appeared for `ms`. The
+-- extmarks follow their lines when the tree is redrawn in the meantime
+function M.flash_rows(bufnr, ns_id, rows, ms)
+    local ids = {}
+    for _, row in ipairs(rows) do
+        table.insert(ids, a.nvim_buf_set_extmark(bufnr, ns_id, row, 0, {
+            end_row = row + 1,
+            hl_group = 'tree_new_item',
+            hl_eol = true
+        }))
+    end
+    vim.defer_fn(function()
+        if not a.nvim_buf_is_valid(bufnr) then return end
+        for _, id in ipairs(ids) do a.nvim_buf_del_extmark(bufnr, ns_id, id) end
+    end, ms)
+end
+
+-------------------- breadcrumbs --------------------
+-- bufnr => {{label, path}, ...} from the file system root down to the tree root
+M.breadcrumbs = {}
+
+local function breadcrumbs_winbar(bufnr)
+    local crumbs = M.breadcrumbs[bufnr]
+    if crumbs == nil then return '' end
+    local segments = {}
+    for i, crumb in ipairs(crumbs) do
+        local label = crumb[1]:gsub('%%', '%%%%')
+        table.insert(segments, string.format('%%%d@v:lua.Tree_breadcrumb_click@%s%%X', i, label))
+    end
+    return table.concat(segments, ' > ')
+end
+
+--- Show the ancestors of the root in the winbar of the tree windows, nil clears it
+function M.set_breadcrumbs(bufnr, crumbs)
+    if crumbs == vim.NIL then crumbs = nil end
+    if crumbs == nil and M.breadcrumbs[bufnr] == nil then return end
+    M.breadcrumbs[bufnr] = crumbs
+    -- the windows showing the tree later get the winbar as they enter it
+    a.nvim_exec(string.format([[
+    augroup tree_breadcrumbs_%d
+      autocmd!
+      autocmd BufWinEnter <buffer=%d> lua tree.apply_breadcrumbs(%d)
+    augroup END
+    ]], bufnr, bufnr, bufnr), false)
+    M.apply_breadcrumbs(bufnr)
+end
+
+function M.apply_breadcrumbs(bufnr)
+    for _, winid in ipairs(fn.win_findbuf(bufnr)) do
+        a.nvim_win_set_option(winid, 'winbar', breadcrumbs_winbar(bufnr))
+    end
+end
+
+--- A breadcrumb was clicked, the tree in that window changes its root to it
+function Tree_breadcrumb_click(idx, _clicks, _button, _mods)
+    local winid = fn.getmousepos().winid
+    if winid == 0 then return end
+    local crumbs = M.breadcrumbs[a.nvim_win_get_buf(winid)]
+    if crumbs == nil or crumbs[idx] == nil then return end
+    fn.win_gotoid(winid)
+    call_async_action('cd', crumbs[idx][2])
+end
+
+-------------------- custom columns --------------------
+M.column_callbacks = {}
+-- the column names without a callback, warned about once
+M.unknown_columns = {}
+
+--- Register the callback of a custom column, `columns` can then name it.
+-- @param name      string: the column name
+-- @param callback  function(path): {text, hl_group}, or just the text
+-- @return nil
+function M.register_column(name, callback) M.column_callbacks[name] = callback end
+
+--- The cells of the custom columns `names` for every path, {text, hl_group} each
+function M.custom_columns(names, paths)
+    local result = {}
+    for _, name in ipairs(names) do
+        local callback = M.column_callbacks[name]
+        if not callback and not M.unknown_columns[name] then
+            M.unknown_columns[name] = true
+            M.notify('warn', string.format(
+                'column %s: no callback registered, see tree.register_column', name))
+        end
+        local cells = {}
+        for i, path in ipairs(paths) do
+            local cell = ''
+            if callback then
+                local ok, ret = pcall(callback, path)
+                if ok then
+                    cell = ret
+                else
+                    M.notify('error', string.format('column %s: %s', name, ret))
+                    callback = nil
+                end
+            end
+            if type(cell) ~= 'table' then cell = {tostring(cell or '')} end
+            cells[i] = {
+                tostring(cell[1] or cell.text or ''),
+                cell[2] or cell.hl_group or ''
+            }
+        end
+        table.insert(result, cells)
+    end
+    return result
+end
+
+-------------------- preview float --------------------
+M.preview_winid = nil
+
+local function preview_set_lines(buf, path, lines)
+    a.nvim_buf_set_option(buf, 'modifiable', true)
+    a.nvim_buf_set_lines(buf, 0, -1, false, lines)
+    a.nvim_buf_set_option(buf, 'modifiable', false)
+    local ft = ''
+    if fn.isdirectory(path) == 0 and vim.filetype and vim.filetype.match then
+        ft = vim.filetype.match({filename = path}) or ''
+    end
+    a.nvim_buf_set_option(buf, 'filetype', ft)
+end
+
+function M.preview_is_open()
+    return M.preview_winid ~= nil and a.nvim_win_is_valid(M.preview_winid)
+end
+
+--- Opened from the tree window, the float goes on its side with the most room
+function M.preview_open(path, lines)
+    local buf = a.nvim_create_buf(false, true)
+    a.nvim_buf_set_option(buf, 'bufhidden', 'wipe')
+    preview_set_lines(buf, path, lines)
+    local tree_win = a.nvim_get_current_win()
+    local pos = a.nvim_win_get_position(tree_win)
+    local tree_width = a.nvim_win_get_width(tree_win)
+    local room_right = vim.o.columns - pos[2] - tree_width
+    local room_left = pos[2]
+    -- 2 columns for the border
+    local width = math.min(math.floor(vim.o.columns / 2),
+                           math.max(room_right, room_left) - 2)
+    local height = math.min(math.floor(vim.o.lines * 0.6),
+                            a.nvim_win_get_height(tree_win))
+    if width < 1 or height < 1 then return end
+    local col
+    if room_right >= room_left then
+        col = pos[2] + tree_width
+    else
+        col = pos[2] - width - 2
+    end
+    M.preview_winid = a.nvim_open_win(buf, false, {
+        relative = 'editor',
+        row = pos[1],
+        col = col,
+        width = width,
+        height = height,
+        style = 'minimal',
+        border = 'single'
+    })
+    -- the float follows the cursor
+    M.preview_bufnr = a.nvim_win_get_buf(tree_win)
+    update_cursor_autocmd(M.preview_bufnr)
+end
+
+--- Called as the cursor moves, a no-op once the float is closed
+function M.preview_update(path, lines)
+    if not M.preview_is_open() then return end
+    preview_set_lines(a.nvim_win_get_buf(M.preview_winid), path, lines)
+    a.nvim_win_set_cursor(M.preview_winid, {1, 0})
+end
+
+function M.preview_close()
+    if M.preview_is_open() then a.nvim_win_close(M.preview_winid, true) end
+    M.preview_winid = nil
+    if M.preview_bufnr and a.nvim_buf_is_valid(M.preview_bufnr) then
+        update_cursor_autocmd(M.preview_bufnr)
+    end
+    M.preview_bufnr = nil
 end
 
 function rpcrequest(method, args, is_async)
@@ -680,31 +1214,25 @@ local function init_channel()
     local servername = vim.v.servername
     local cmd
     -- NOTE: ~ cant expand in {cmd} arg of jobstart
-    if M.linux() then
+    if M.bin then
+        -- another build of the server, the tests run the one cargo built
+        cmd = {M.bin, servername}
+    elseif M.linux() then
         cmd = {project_root .. '/bin/tree', servername}
     elseif M.windows() then
-        local ip = '127.0.0.1'
-        if not M.servername then
-            local port = 6666
-            while not M.servername do
-                try {
-                    function()
-                        vim.fn.serverstart(ip .. ':' .. tostring(port))
-                        M.servername = port
-                    end, catch {function(error)
-                        port = port + 1
-                    end}
-                }
-            end
-        end
-        cmd = {project_root .. '\\bin\\tree.exe', tostring(M.servername)}
+        -- servername is a named pipe like \\.\pipe\nvim-1234-0
+        cmd = {project_root .. '\\bin\\tree.exe', servername}
     elseif M.macos() then
         cmd = {project_root .. '/bin/tree', servername}
     end
     -- print('bin:', bin)
     -- print('servername:', servername)
     -- print(inspect(cmd))
-    fn.jobstart(cmd)
+    fn.jobstart(cmd, {
+        on_exit = function(_, code)
+            vim.schedule(function() M.backend_exited(code) end)
+        end
+    })
     local N = 250
     local i = 0
     while i < N and M.channel_id == nil do
@@ -715,14 +1243,36 @@ local function init_channel()
     return true
 end
 
+-- keep in sync with src/protocol.rs
+M.PROTOCOL_VERSION = 4
+M.CAPABILITIES = {scoped_trees = 2, highlight_links = 8}
+
+--- Exchange the protocol version and capabilities with the server, which
+--- refuses a frontend of another version.
+local function handshake()
+    local caps = 0
+    for _, bit in pairs(M.CAPABILITIES) do caps = caps + bit end
+    local ok, reply = pcall(rpcrequest, '_tree_hello', {
+        {version = M.PROTOCOL_VERSION, capabilities = caps}
+    }, false)
+    if not ok then
+        M.notify('error', tostring(reply))
+        return
+    end
+    M.server_protocol = reply
+end
+
 local function initialize()
     if M.channel_id then return end
 
     init_channel()
+    handshake()
     -- NOTE: Exec VimL snippets in lua.
     a.nvim_exec([[
     augroup tree
       autocmd!
+      autocmd TabEnter * lua tree.tab_enter()
+      autocmd ColorScheme * lua tree.colorscheme_changed()
     augroup END
   ]], false)
 
@@ -730,12 +1280,59 @@ local function initialize()
     M.tree_histories = {}
 end
 
+--- The backend is gone, the trees still shown are handed to a new one
+function M.backend_exited(code)
+    M.channel_id = nil
+    if vim.v.exiting ~= vim.NIL then return end
+    local states = {}
+    for _, state in ipairs(M.saved_states) do
+        if a.nvim_buf_is_valid(state.bufnr) then table.insert(states, state) end
+    end
+    if #states == 0 then return end
+    M.notify('warn', string.format(
+                 'tree backend exited (code %d), restoring %d trees', code,
+                 #states))
+    initialize()
+    rpcrequest('_tree_restore', {states}, false)
+end
+
 -- options = core + etc
 function user_options()
     return vim.tbl_extend('force', {
         auto_cd = false,
         auto_recursive_level = 0,
         columns = 'mark:indent:icon:filename:size',
+        cursor_history_limit = 100,
+        expand_store_limit = 1000,
+        highlight_cursor_item = false,
+        lsp_rename = false,
+        readonly = false,
+        smart_case = false,
+        breadcrumbs = false,
+        auto_fit_width = false,
+        truncate_filename = true,
+        min_width = 20,
+        max_width = 120,
+        initial_expand_level = 0,
+        initial_expand_max_items = 500,
+        dir_chunk_size = 1000,
+        select_recursive = false,
+        preserve_permissions = false,
+        trash = false,
+        fs_retries = 2,
+        flash_new_files = 0,
+        file_manager = '',
+        file_manager_terminal = false,
+        quick_dirs = {},
+        commands = {},
+        icons = {},
+        summary_footer = '',
+        smart_expand_threshold = 3,
+        notify_level = 'info',
+        buffer_options = {},
+        -- the defaults are the backend's
+        window_options = {},
+        keymaps = {},
         ignored_files = '.*',
         listed = false,
         profile = false,
@@ -754,6 +1351,9 @@ local function internal_options()
     cmd 'delmarks >'
     return {
         cursor = fn.line('.'),
+        cursor_col = fn.virtcol('.'),
+        count = vim.v.count,
+        mouse = fn.getmousepos(),
         -- drives={},
         prev_bufnr = fn.bufnr('%'),
         prev_winid = fn.win_getid(),
@@ -792,27 +1392,62 @@ end
 M.alive_buf_cnt = 0
 M.etc_options = {}
 local count = 0
+--- Create a tree buffer and its etc options.
+-- Also used by the server to create the buffer of a tab/window scoped tree.
+-- @return bufnr of the new buffer
+function M.new_buffer(ctx)
+    local buf = a.nvim_create_buf(false, true)
+    local bufname = "Tree-" .. tostring(count)
+    a.nvim_buf_set_name(buf, bufname)
+    count = count + 1
+    M.alive_buf_cnt = M.alive_buf_cnt + 1
+    local etc = default_etc_options()
+    for k, _ in pairs(etc) do if ctx[k] then etc[k] = ctx[k] end end
+    M.etc_options[buf] = etc
+    return buf
+end
+
 function M.start(_paths, user_ctx)
     initialize()
     local ctx = init_context(user_ctx)
     local paths = fn.map(_paths, "fnamemodify(v:val, ':p')")
     if #paths == 0 then paths = {fn.getcwd()} end
-    if M.alive_buf_cnt < 1 or user_ctx.new then
-        local buf = a.nvim_create_buf(false, true)
-        local bufname = "Tree-" .. tostring(count)
-        a.nvim_buf_set_name(buf, bufname)
-        count = count + 1
-        M.alive_buf_cnt = M.alive_buf_cnt + 1
-        local etc = default_etc_options()
-        for k, _ in pairs(etc) do if ctx[k] then etc[k] = ctx[k] end end
-        M.etc_options[buf] = etc
-        ctx.bufnr = buf
-    end
-    rpcrequest('_tree_start', {paths, ctx}, false)
+    ctx.scope = ctx.scope or 'global'
+    ctx.tabpage = a.nvim_get_current_tabpage()
+    ctx.winid = fn.win_getid()
+    -- the buffers of tab/window scoped trees are requested by the server
+    if (M.alive_buf_cnt < 1 and ctx.scope == 'global') or user_ctx.new then
+        ctx.bufnr = M.new_buffer(ctx)
+    end
+    -- {bufnr, root, item_count, took_ms} of the tree created or resumed
+    local reply = rpcrequest('_tree_start', {paths, ctx}, false)
+    if type(reply) == 'table' then
+        M.last_start = reply
+        M.save_state()
+        if fn.exists('#User#TreeStarted') == 1 then
+            vim.cmd('doautocmd <nomodeline> User TreeStarted')
+        end
+    end
     -- TODO: search path
     -- if context['search'] !=# ''
     --   call tree#call_action('search', [context['search']])
     -- endif
+    return reply
+end
+
+function M.tab_enter()
+    if not M.channel_id then return end
+    rpcrequest('_tree_tab_enter', {a.nvim_get_current_tabpage()}, true)
+end
+
+--- Show the tree of the tab entered when no window of the tab has it, the cursor stays.
+-- @param bufnr number: the tree buffer of the tab
+-- @return nil
+function M.tab_resume(bufnr)
+    if not buf_is_loaded(bufnr) or call('bufwinid', {bufnr}) > 0 then return end
+    local winid = a.nvim_get_current_win()
+    M.resume({bufnr})
+    call('win_gotoid', {winid})
 end
 
 function call_async_action(action, ...)
@@ -821,21 +1456,91 @@ function call_async_action(action, ...)
     local context = action_context()
     local args = ...
     if type(args) ~= 'table' then args = {...} end
+    action, args = resolve_alias(action, args)
     rpcrequest('_tree_async_action', {action, args, context}, true)
 end
 
+-- the filter is applied once the typing pauses for that long
+local filter_debounce_ms = 150
+local filter_timer = nil
+
+--- Type the filter of the current tree, applied as it's typed.
+-- Cancelling with <Esc> brings back the previous filter.
+function M.filter_prompt(current)
+    a.nvim_exec([[
+    augroup tree_filter
+      autocmd!
+      autocmd CmdlineChanged @ lua require('tree').filter_changed()
+    augroup END
+    ]], false)
+    local cancelled = '<tree-filter-cancelled>'
+    local pattern = fn.input({
+        prompt = 'Filter: ',
+        default = current,
+        cancelreturn = cancelled
+    })
+    cmd 'autocmd! tree_filter'
+    if filter_timer then filter_timer:stop() end
+    if pattern == cancelled then pattern = current end
+    call_async_action('filter', pattern)
+end
+
+function M.filter_changed()
+    local pattern = fn.getcmdline()
+    if not filter_timer then filter_timer = vim.loop.new_timer() end
+    filter_timer:stop()
+    filter_timer:start(filter_debounce_ms, 0, vim.schedule_wrap(function()
+        call_async_action('filter', pattern)
+    end))
+end
+
 function M.get_candidate()
     if vim.bo.filetype ~= 'tree' then return {} end
 
     local context = internal_options()
     return rpcrequest('_tree_get_candidate', {context}, false)
 end
+--- Path completion for the cd/new_file/rename prompts, see `_tree_complete_path`
+local function complete_path(arglead, show_ignored_files, dirs_only)
+    local context = {
+        cwd = fn.getcwd(),
+        show_ignored_files = show_ignored_files,
+        dirs_only = dirs_only
+    }
+    return rpcrequest('_tree_complete_path', {arglead, context}, false)
+end
+function M.complete_path(arglead, cmdline, cursorpos)
+    return complete_path(arglead, false)
+end
+function M.complete_path_all(arglead, cmdline, cursorpos)
+    return complete_path(arglead, true)
+end
+function M.complete_dir(arglead, cmdline, cursorpos)
+    return complete_path(arglead, false, true)
+end
+function M.complete_dir_all(arglead, cmdline, cursorpos)
+    return complete_path(arglead, true, true)
+end
 function M.is_directory() return
     fn.get(M.get_candidate(), 'is_directory', false) end
 function M.is_opened_tree()
     return fn.get(M.get_candidate(), 'is_opened_tree', false)
 end
 
+--- JSON snapshot of the current (or last used) tree, see `_tree_export`
+function M.export(selection_only)
+    local bufnr = vim.NIL
+    if vim.bo.filetype == 'tree' then bufnr = a.nvim_get_current_buf() end
+    return rpcrequest('_tree_export',
+                      {bufnr, {selection_only = selection_only == true}}, false)
+end
+
+--- Change the log level ('trace' .. 'off') and/or the log file of the backend, nil
+--- keeps the current one, '' for the file stops logging
+function M.set_log(level, file)
+    return rpcrequest('_tree_set_log', {level or vim.NIL, file or vim.NIL}, false)
+end
+
 function M.get_context()
     if vim.bo.filetype ~= 'tree' then return {} end
 
@@ -0,0 +1,105 @@
+//! The modes change_mode takes: an octal mode (`644`, `0755`) or the permission bits added
+//! or removed as in chmod's symbolic form (`+x`, `go-w`, `u+rw`)
+use crate::errors::ArgError;
+use std::io;
+use std::path::Path;
+
+fn invalid(spec: &str) -> ArgError {
+    ArgError::from_string(format!(
+        "invalid mode: {} (octal, or [ugoa][+-][rwx] like +x)",
+        spec
+    ))
+}
+
+/// The mode `spec` turns `mode` into
+pub fn apply_spec(spec: &str, mode: u32) -> Result<u32, ArgError> {
+    let spec = spec.trim();
+    if !spec.is_empty() && spec.chars().all(|c| c.is_digit(8)) {
+        return match u32::from_str_radix(spec, 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(mode),
+            _ => Err(invalid(spec)),
+        };
+    }
+    let op_pos = spec
+        .find(|c: char| c == '+' || c == '-')
+        .ok_or_else(|| invalid(spec))?;
+    let (who, rest) = spec.split_at(op_pos);
+    let (op, perms) = rest.split_at(1);
+    let mut who_mask = 0;
+    for c in who.chars() {
+        who_mask |= match c {
+            'u' => 0o700,
+            'g' => 0o070,
+            'o' => 0o007,
+            'a' => 0o777,
+            _ => return Err(invalid(spec)),
+        };
+    }
+    // no one given is everyone
+    if who_mask == 0 {
+        who_mask = 0o777;
+    }
+    let mut bits = 0;
+    for c in perms.chars() {
+        bits |= match c {
+            'r' => 0o444,
+            'w' => 0o222,
+            'x' => 0o111,
+            _ => return Err(invalid(spec)),
+        };
+    }
+    if bits == 0 {
+        return Err(invalid(spec));
+    }
+    if op == "+" {
+        Ok(mode | (bits & who_mask))
+    } else {
+        Ok(mode & !(bits & who_mask))
+    }
+}
+
+/// The permission bits of `path`, out of unix the read-only attribute is all there is
+pub fn mode(path: &Path) -> io::Result<u32> {
+    let permissions = std::fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    let mode = std::os::unix::fs::PermissionsExt::mode(&permissions) & 0o7777;
+    #[cfg(not(unix))]
+    let mode = if permissions.readonly() { 0o444 } else { 0o666 };
+    Ok(mode)
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, mode);
+    #[cfg(not(unix))]
+    permissions.set_readonly(mode & 0o222 == 0);
+    std::fs::set_permissions(path, permissions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octal() {
+        assert_eq!(apply_spec("644", 0o755).unwrap(), 0o644);
+        assert_eq!(apply_spec("0755", 0).unwrap(), 0o755);
+        assert_eq!(apply_spec(" 4755 ", 0).unwrap(), 0o4755);
+    }
+
+    #[test]
+    fn symbolic() {
+        assert_eq!(apply_spec("+x", 0o644).unwrap(), 0o755);
+        assert_eq!(apply_spec("go-w", 0o666).unwrap(), 0o644);
+        assert_eq!(apply_spec("u+rw", 0o004).unwrap(), 0o604);
+        assert_eq!(apply_spec("a-x", 0o4755).unwrap(), 0o4644);
+    }
+
+    #[test]
+    fn invalid_specs() {
+        for spec in &["", "8", "77777", "+", "x", "u+z", "q+x", "u=rw", "+x-w"] {
+            assert!(apply_spec(spec, 0o644).is_err(), "{:?} accepted", spec);
+        }
+    }
+}
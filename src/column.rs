@@ -1,10 +1,16 @@
+#[cfg(unix)]
+use crate::owners;
 use crate::tree::Tree;
 use chrono::{DateTime, Local};
 use git2::Status;
 use log::*;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::convert::From;
 use std::ffi::OsStr;
 use std::fs::Metadata;
+use std::path::MAIN_SEPARATOR;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 #[derive(Eq, PartialEq, Clone)]
 pub enum Icon {
@@ -94,151 +100,161 @@ pub enum Icon {
     Unknown,
 }
 
+// extension => icon
+static EXTENSIONS: &[(&str, Icon)] = &[
+    ("styl", Icon::Stylus),
+    ("sass", Icon::Sass),
+    ("scss", Icon::Sass),
+    ("htm", Icon::Html),
+    ("html", Icon::Html),
+    ("slim", Icon::Html),
+    ("xml", Icon::Xml),
+    ("xaml", Icon::Xml),
+    ("ejs", Icon::Ejs),
+    ("css", Icon::Css),
+    ("less", Icon::Css),
+    ("md", Icon::Markdown),
+    ("mdx", Icon::Markdown),
+    ("markdown", Icon::Markdown),
+    ("rmd", Icon::Markdown),
+    ("json", Icon::Json),
+    ("js", Icon::Javascript),
+    ("es6", Icon::Javascript),
+    ("jsx", Icon::Javascriptreact),
+    ("rb", Icon::Ruby),
+    ("ru", Icon::Ruby),
+    ("php", Icon::Php),
+    ("py", Icon::Python),
+    ("pyc", Icon::Python),
+    ("pyo", Icon::Python),
+    ("pyd", Icon::Python),
+    ("coffee", Icon::Coffee),
+    ("mustache", Icon::Mustache),
+    ("hbs", Icon::Mustache),
+    ("config", Icon::Conf),
+    ("conf", Icon::Conf),
+    ("ini", Icon::Conf),
+    ("yml", Icon::Conf),
+    ("yaml", Icon::Conf),
+    ("toml", Icon::Conf),
+    ("jpg", Icon::Image),
+    ("jpeg", Icon::Image),
+    ("bmp", Icon::Image),
+    ("png", Icon::Image),
+    ("gif", Icon::Image),
+    ("ico", Icon::Ico),
+    ("twig", Icon::Twig),
+    ("cpp", Icon::C),
+    ("c++", Icon::C),
+    ("cxx", Icon::C),
+    ("cc", Icon::C),
+    ("cp", Icon::C),
+    ("c", Icon::C),
+    ("h", Icon::H),
+    ("hpp", Icon::H),
+    ("hxx", Icon::H),
+    ("hs", Icon::Haskell),
+    ("lhs", Icon::Haskell),
+    ("lua", Icon::Lua),
+    ("java", Icon::Java),
+    ("jar", Icon::Java),
+    ("sh", Icon::Terminal),
+    ("fish", Icon::Terminal),
+    ("bash", Icon::Terminal),
+    ("zsh", Icon::Terminal),
+    ("ksh", Icon::Terminal),
+    ("csh", Icon::Terminal),
+    ("awk", Icon::Terminal),
+    ("ps1", Icon::Terminal),
+    ("bat", Icon::Terminal),
+    ("cmd", Icon::Terminal),
+    ("ml", Icon::Ml),
+    ("mli", Icon::Ml),
+    ("diff", Icon::Diff),
+    ("db", Icon::Sql),
+    ("sql", Icon::Sql),
+    ("dump", Icon::Sql),
+    ("accdb", Icon::Sql),
+    ("clj", Icon::Clojure),
+    ("cljc", Icon::Clojure),
+    ("cljs", Icon::Clojure),
+    ("edn", Icon::Edn),
+    ("scala", Icon::Scala),
+    ("go", Icon::Go),
+    ("dart", Icon::Dart),
+    ("xul", Icon::Firefox),
+    ("sln", Icon::Vs),
+    ("suo", Icon::Vs),
+    ("pl", Icon::Perl),
+    ("pm", Icon::Perl),
+    ("t", Icon::Perl),
+    ("rss", Icon::Rss),
+    ("f#", Icon::Fsharp),
+    ("fsscript", Icon::Fsharp),
+    ("fsx", Icon::Fsharp),
+    ("fs", Icon::Fsharp),
+    ("fsi", Icon::Fsharp),
+    ("rs", Icon::Rust),
+    ("rlib", Icon::Rust),
+    ("d", Icon::Dlang),
+    ("erl", Icon::Erlang),
+    ("hrl", Icon::Erlang),
+    ("ex", Icon::Elixir),
+    ("exs", Icon::Elixir),
+    ("exx", Icon::Elixir),
+    ("leex", Icon::Elixir),
+    ("vim", Icon::Vim),
+    ("ai", Icon::Ai),
+    ("psd", Icon::Psd),
+    ("psb", Icon::Psd),
+    ("ts", Icon::Typescript),
+    ("tsx", Icon::Javascriptreact),
+    ("jl", Icon::Julia),
+    ("pp", Icon::Puppet),
+    ("vue", Icon::Vue),
+    ("swift", Icon::Swift),
+    ("xcplayground", Icon::Swift),
+    ("svg", Icon::Svg),
+    ("otf", Icon::Font),
+    ("ttf", Icon::Font),
+    ("fnt", Icon::Font),
+    ("txt", Icon::Text),
+    ("text", Icon::Text),
+    ("zip", Icon::Archive),
+    ("tar", Icon::Archive),
+    ("gz", Icon::Archive),
+    ("gzip", Icon::Archive),
+    ("rar", Icon::Archive),
+    ("7z", Icon::Archive),
+    ("iso", Icon::Archive),
+    ("doc", Icon::Word),
+    ("docx", Icon::Word),
+    ("docm", Icon::Word),
+    ("csv", Icon::Excel),
+    ("xls", Icon::Excel),
+    ("xlsx", Icon::Excel),
+    ("xlsm", Icon::Excel),
+    ("ppt", Icon::Ppt),
+    ("pptx", Icon::Ppt),
+    ("pptm", Icon::Ppt),
+];
+
+// EXTENSIONS hashed, built once, the lookup is done for every file of every redraw
+static EXTENSION_ICONS: std::sync::OnceLock<HashMap<&'static str, Icon>> =
+    std::sync::OnceLock::new();
+
 impl From<&str> for Icon {
     fn from(s: &str) -> Icon {
-        match s {
-            "styl" => Icon::Stylus,
-            "sass" => Icon::Sass,
-            "scss" => Icon::Sass,
-            "htm" => Icon::Html,
-            "html" => Icon::Html,
-            "slim" => Icon::Html,
-            "xml" => Icon::Xml,
-            "xaml" => Icon::Xml,
-            "ejs" => Icon::Ejs,
-            "css" => Icon::Css,
-            "less" => Icon::Css,
-            "md" => Icon::Markdown,
-            "mdx" => Icon::Markdown,
-            "markdown" => Icon::Markdown,
-            "rmd" => Icon::Markdown,
-            "json" => Icon::Json,
-            "js" => Icon::Javascript,
-            "es6" => Icon::Javascript,
-            "jsx" => Icon::Javascriptreact,
-            "rb" => Icon::Ruby,
-            "ru" => Icon::Ruby,
-            "php" => Icon::Php,
-            "py" => Icon::Python,
-            "pyc" => Icon::Python,
-            "pyo" => Icon::Python,
-            "pyd" => Icon::Python,
-            "coffee" => Icon::Coffee,
-            "mustache" => Icon::Mustache,
-            "hbs" => Icon::Mustache,
-            "config" => Icon::Conf,
-            "conf" => Icon::Conf,
-            "ini" => Icon::Conf,
-            "yml" => Icon::Conf,
-            "yaml" => Icon::Conf,
-            "toml" => Icon::Conf,
-            "jpg" => Icon::Image,
-            "jpeg" => Icon::Image,
-            "bmp" => Icon::Image,
-            "png" => Icon::Image,
-            "gif" => Icon::Image,
-            "ico" => Icon::Ico,
-            "twig" => Icon::Twig,
-            "cpp" => Icon::C,
-            "c++" => Icon::C,
-            "cxx" => Icon::C,
-            "cc" => Icon::C,
-            "cp" => Icon::C,
-            "c" => Icon::C,
-            "h" => Icon::H,
-            "hpp" => Icon::H,
-            "hxx" => Icon::H,
-            "hs" => Icon::Haskell,
-            "lhs" => Icon::Haskell,
-            "lua" => Icon::Lua,
-            "java" => Icon::Java,
-            "jar" => Icon::Java,
-            "sh" => Icon::Terminal,
-            "fish" => Icon::Terminal,
-            "bash" => Icon::Terminal,
-            "zsh" => Icon::Terminal,
-            "ksh" => Icon::Terminal,
-            "csh" => Icon::Terminal,
-            "awk" => Icon::Terminal,
-            "ps1" => Icon::Terminal,
-            "bat" => Icon::Terminal,
-            "cmd" => Icon::Terminal,
-            "ml" => Icon::Ml,
-            "mli" => Icon::Ml,
-            "diff" => Icon::Diff,
-            "db" => Icon::Sql,
-            "sql" => Icon::Sql,
-            "dump" => Icon::Sql,
-            "accdb" => Icon::Sql,
-            "clj" => Icon::Clojure,
-            "cljc" => Icon::Clojure,
-            "cljs" => Icon::Clojure,
-            "edn" => Icon::Edn,
-            "scala" => Icon::Scala,
-            "go" => Icon::Go,
-            "dart" => Icon::Dart,
-            "xul" => Icon::Firefox,
-            "sln" => Icon::Vs,
-            "suo" => Icon::Vs,
-            "pl" => Icon::Perl,
-            "pm" => Icon::Perl,
-            "t" => Icon::Perl,
-            "rss" => Icon::Rss,
-            "f#" => Icon::Fsharp,
-            "fsscript" => Icon::Fsharp,
-            "fsx" => Icon::Fsharp,
-            "fs" => Icon::Fsharp,
-            "fsi" => Icon::Fsharp,
-            "rs" => Icon::Rust,
-            "rlib" => Icon::Rust,
-            "d" => Icon::Dlang,
-            "erl" => Icon::Erlang,
-            "hrl" => Icon::Erlang,
-            "ex" => Icon::Elixir,
-            "exs" => Icon::Elixir,
-            "exx" => Icon::Elixir,
-            "leex" => Icon::Elixir,
-            "vim" => Icon::Vim,
-            "ai" => Icon::Ai,
-            "psd" => Icon::Psd,
-            "psb" => Icon::Psd,
-            "ts" => Icon::Typescript,
-            "tsx" => Icon::Javascriptreact,
-            "jl" => Icon::Julia,
-            "pp" => Icon::Puppet,
-            "vue" => Icon::Vue,
-            "swift" => Icon::Swift,
-            "xcplayground" => Icon::Swift,
-            "svg" => Icon::Svg,
-            "otf" => Icon::Font,
-            "ttf" => Icon::Font,
-            "fnt" => Icon::Font,
-            "txt" => Icon::Text,
-            "text" => Icon::Text,
-            "zip" => Icon::Archive,
-            "tar" => Icon::Archive,
-            "gz" => Icon::Archive,
-            "gzip" => Icon::Archive,
-            "rar" => Icon::Archive,
-            "7z" => Icon::Archive,
-            "iso" => Icon::Archive,
-            "doc" => Icon::Word,
-            "docx" => Icon::Word,
-            "docm" => Icon::Word,
-            "csv" => Icon::Excel,
-            "xls" => Icon::Excel,
-            "xlsx" => Icon::Excel,
-            "xlsm" => Icon::Excel,
-            "ppt" => Icon::Ppt,
-            "pptx" => Icon::Ppt,
-            "pptm" => Icon::Ppt,
-            _ => Icon::Unknown,
-        }
+        EXTENSION_ICONS
+            .get_or_init(|| EXTENSIONS.iter().cloned().collect())
+            .get(s)
+            .cloned()
+            .unwrap_or(Icon::Unknown)
     }
 }
 
 impl Icon {
-    pub fn hl_group_name(&self) -> &str {
+    pub fn hl_group_name(&self) -> &'static str {
         match *self {
             Icon::FolderClosed => "tree_icon_FolderClosed",
             Icon::FolderOpened => "tree_icon_FolderOpened",
@@ -326,7 +342,7 @@ impl Icon {
             Icon::Unknown => "tree_icon_Unknonwn",
         }
     }
-    pub fn as_glyph_and_color(&self) -> (&str, &str) {
+    pub fn as_glyph_and_color(&self) -> (&'static str, &'static str) {
         match *self {
             Icon::FolderClosed => ("", "#00afaf"),
             Icon::FolderOpened => ("", "#00afaf"),
@@ -522,6 +538,221 @@ pub static ICONS: &[Icon] = &[
     Icon::Unknown,
 ];
 
+/// Blend a `#rrggbb` color halfway to grey, for the trees without focus
+fn dim_color(color: &str) -> String {
+    let rgb = match u32::from_str_radix(color.trim_start_matches('#'), 16) {
+        Ok(v) if color.len() == 7 => v,
+        _ => return color.to_owned(),
+    };
+    let dim = |shift: u32| (((rgb >> shift) & 0xff) + 0x80) / 2;
+    format!("#{:02x}{:02x}{:02x}", dim(16), dim(8), dim(0))
+}
+
+/// The highlight group used in place of `hl_group` while the tree is not focused, the
+/// groups of custom columns have no dimmed variant
+pub fn dim_hl_group(hl_group: &str) -> String {
+    if hl_group.starts_with("tree_") {
+        format!("{}_dim", hl_group)
+    } else {
+        hl_group.to_owned()
+    }
+}
+
+/// A column computed in rust by the code embedding the tree, drawn for the
+/// `ColumnType::CUSTOM` of the same name instead of asking the lua callbacks. See
+/// `Tree::register_column`
+///
+/// ```
+/// use std::sync::Arc;
+/// use tree_nvim::{ColumnRenderer, ColumnType, Config, FileItem, Tree};
+///
+/// struct LineCount;
+///
+/// impl ColumnRenderer for LineCount {
+///     fn render(&self, fileitem: &FileItem) -> Option<(String, Option<&'static str>)> {
+///         let text = std::fs::read_to_string(&fileitem.path).ok()?;
+///         Some((text.lines().count().to_string(), Some("Number")))
+///     }
+/// }
+///
+/// let dir = std::env::temp_dir().join(format!("tree_nvim_doc_column_{}", std::process::id()));
+/// std::fs::create_dir_all(&dir).unwrap();
+/// std::fs::write(dir.join("a.txt"), "1\n2\n3\n").unwrap();
+///
+/// let mut config = Config::default();
+/// config.columns = vec![ColumnType::FILENAME, ColumnType::CUSTOM("lines".to_owned())];
+/// let mut tree = Tree::headless(config);
+/// tree.register_column("lines", Arc::new(LineCount));
+/// tree.load_root(dir.clone()).unwrap();
+/// assert!(tree
+///     .lines()
+///     .iter()
+///     .any(|line| line.contains("a.txt") && line.trim_end().ends_with('3')));
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub trait ColumnRenderer: Send + Sync {
+    /// The text and highlight group of the cell of `fileitem`, None leaves it empty
+    fn render(&self, fileitem: &FileItem) -> Option<(String, Option<&'static str>)>;
+}
+
+/// A glyph, and optionally its color, given in the `icons` option in place of the
+/// built-in one
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconOverride {
+    pub glyph: String,
+    pub color: Option<String>,
+}
+
+impl IconOverride {
+    /// tree_icon_custom_<key>, the key with the characters not allowed in a group name
+    /// replaced
+    pub fn hl_group_name(key: &str) -> String {
+        let key: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("tree_icon_custom_{}", key)
+    }
+}
+
+/// The highlight groups of the overridden icons that have a color
+pub fn icon_override_commands(icons: &HashMap<String, IconOverride>) -> Vec<String> {
+    let mut commands = Vec::new();
+    for (key, icon) in icons {
+        if let Some(color) = &icon.color {
+            let name = IconOverride::hl_group_name(key);
+            commands.push(format!("hi {} guifg={}", name, color));
+            commands.push(format!(
+                "hi {} guifg={}",
+                dim_hl_group(&name),
+                dim_color(color)
+            ));
+        }
+    }
+    commands
+}
+
+/// The `hi` commands of all the groups used by the tree, `:colorscheme` clears them so they
+/// are emitted again on ColorScheme
+pub fn highlight_commands() -> Vec<String> {
+    let mut commands = Vec::new();
+    for icon in ICONS {
+        let name = icon.hl_group_name();
+        let color = icon.as_glyph_and_color().1;
+        commands.push(format!("hi {} guifg={}", name, color));
+        commands.push(format!(
+            "hi {} guifg={}",
+            dim_hl_group(name),
+            dim_color(color)
+        ));
+    }
+
+    for color in GUI_COLORS {
+        commands.push(format!(
+            "hi {} guifg={}",
+            color.hl_group_name(),
+            color.color_val(),
+        ));
+        commands.push(format!(
+            "hi {} guifg={}",
+            dim_hl_group(color.hl_group_name()),
+            dim_color(color.color_val()),
+        ));
+    }
+    commands.push(format!(
+        "hi default link {} WarningMsg",
+        INVALID_NAME_HL_GROUP
+    ));
+    commands.push(format!(
+        "hi default link {} WarningMsg",
+        dim_hl_group(INVALID_NAME_HL_GROUP)
+    ));
+    commands.push(format!("hi default link {} Comment", CUT_HL_GROUP));
+    commands.push(format!(
+        "hi default link {} Comment",
+        dim_hl_group(CUT_HL_GROUP)
+    ));
+    commands.push(format!("hi default link {} Comment", FLAT_DIR_HL_GROUP));
+    commands.push(format!("hi default link {} Comment", FOOTER_HL_GROUP));
+    for (hl_group, color) in &[
+        (PERM_TYPE_HL_GROUP, GuiColor::BLUE),
+        (PERM_READ_HL_GROUP, GuiColor::YELLOW),
+        (PERM_WRITE_HL_GROUP, GuiColor::RED),
+        (PERM_EXEC_HL_GROUP, GuiColor::GREEN),
+        (OWNER_OTHER_HL_GROUP, GuiColor::ORANGE),
+        (BROKEN_LINK_HL_GROUP, GuiColor::RED),
+    ] {
+        commands.push(format!(
+            "hi default link {} {}",
+            hl_group,
+            color.hl_group_name()
+        ));
+        commands.push(format!(
+            "hi default link {} {}",
+            dim_hl_group(hl_group),
+            dim_hl_group(color.hl_group_name())
+        ));
+    }
+    commands.push(format!("hi default link {} Comment", PERM_NONE_HL_GROUP));
+    commands.push(format!(
+        "hi default link {} Comment",
+        dim_hl_group(PERM_NONE_HL_GROUP)
+    ));
+    commands.push(format!("hi default link {} Comment", GIT_IGNORED_HL_GROUP));
+    commands.push(format!(
+        "hi default link {} Comment",
+        dim_hl_group(GIT_IGNORED_HL_GROUP)
+    ));
+    commands.push(format!("hi default link {} Comment", OWNER_HL_GROUP));
+    commands.push(format!("hi default link {} Comment", SYMLINK_HL_GROUP));
+    commands.push(format!(
+        "hi default link {} Comment",
+        dim_hl_group(SYMLINK_HL_GROUP)
+    ));
+    commands.push(format!(
+        "hi default link {} Comment",
+        dim_hl_group(OWNER_HL_GROUP)
+    ));
+    commands.push(format!(
+        "hi default link {} Comment",
+        dim_hl_group(FLAT_DIR_HL_GROUP)
+    ));
+    commands.push(format!(
+        "hi default {} gui=strikethrough cterm=strikethrough guifg={}",
+        MARKED_DELETE_HL_GROUP,
+        GuiColor::RED.color_val()
+    ));
+    commands.push(format!(
+        "hi default {} gui=strikethrough cterm=strikethrough guifg={}",
+        dim_hl_group(MARKED_DELETE_HL_GROUP),
+        dim_color(GuiColor::RED.color_val())
+    ));
+    commands.push(String::from(
+        "hi default tree_cursor_item gui=bold,underline cterm=bold,underline",
+    ));
+    commands.push(format!("hi default link {} DiffAdd", NEW_ITEM_HL_GROUP));
+    commands
+}
+
+/// The status worth showing when several apply, e.g. to a directory whose files were
+/// changed in different ways
+fn most_significant(status: Status) -> Status {
+    let order = [
+        Status::CONFLICTED,
+        Status::WT_DELETED,
+        Status::WT_MODIFIED,
+        Status::INDEX_MODIFIED,
+        Status::WT_RENAMED,
+        Status::WT_NEW,
+        Status::IGNORED,
+    ];
+    order
+        .iter()
+        .find(|s| status.contains(**s))
+        .cloned()
+        .unwrap_or(status)
+}
+
 fn get_git_indicator(status: Status) -> (&'static str, GuiColor) {
     match status {
         Status::WT_NEW => ("✭", GuiColor::WHITE),
@@ -538,8 +769,209 @@ fn get_git_indicator(status: Status) -> (&'static str, GuiColor) {
     }
 }
 
+const SIZE_BAR_WIDTH: usize = 6;
+static SIZE_BAR_EIGHTHS: [char; 8] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉', '█'];
+
+/// A bar of `SIZE_BAR_WIDTH` cells filled up to `ratio`, in eighths of a cell
+fn size_bar(ratio: f64) -> String {
+    let eighths = (ratio.max(0.0).min(1.0) * (SIZE_BAR_WIDTH * 8) as f64).round() as usize;
+    let mut bar = "█".repeat(eighths / 8);
+    if eighths % 8 > 0 {
+        bar.push(SIZE_BAR_EIGHTHS[eighths % 8 - 1]);
+    }
+    let padding = SIZE_BAR_WIDTH - bar.chars().count();
+    bar.push_str(&" ".repeat(padding));
+    bar
+}
+
+/// Stable name of the status, used by `_tree_export`
+pub fn git_status_name(status: Status) -> &'static str {
+    match status {
+        Status::WT_NEW => "new",
+        Status::WT_MODIFIED => "modified",
+        Status::INDEX_MODIFIED => "staged",
+        Status::WT_RENAMED => "renamed",
+        Status::IGNORED => "ignored",
+        Status::CONFLICTED => "conflicted",
+        Status::WT_DELETED => "deleted",
+        _ => "unknown",
+    }
+}
+
+/// 1.5 MB, the size with one decimal in the largest unit below 1024
+pub fn short_size(sz: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB", "PB"];
+    let mut size = sz as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", sz)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// drwxr-xr-x, with the setuid, setgid and sticky bits shown like ls does
+#[cfg(unix)]
+pub fn permissions_string(metadata: &Metadata) -> String {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    let ft = metadata.file_type();
+    let kind = if ft.is_symlink() {
+        'l'
+    } else if ft.is_dir() {
+        'd'
+    } else if ft.is_block_device() {
+        'b'
+    } else if ft.is_char_device() {
+        'c'
+    } else if ft.is_fifo() {
+        'p'
+    } else if ft.is_socket() {
+        's'
+    } else {
+        '-'
+    };
+    let mode = metadata.permissions().mode();
+    let mut text = String::with_capacity(10);
+    text.push(kind);
+    // (shift of the rwx bits, the special bit, its char with and without x)
+    for &(shift, special, set, set_no_exec) in &[
+        (6, 0o4000, 's', 'S'),
+        (3, 0o2000, 's', 'S'),
+        (0, 0o1000, 't', 'T'),
+    ] {
+        let bits = (mode >> shift) & 0o7;
+        text.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        let exec = bits & 0o1 != 0;
+        text.push(match (mode & special != 0, exec) {
+            (true, true) => set,
+            (true, false) => set_no_exec,
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    text
+}
+
+/// RO or RW, the read-only attribute is all there is
+#[cfg(not(unix))]
+pub fn permissions_string(metadata: &Metadata) -> String {
+    if metadata.permissions().readonly() {
+        String::from("RO")
+    } else {
+        String::from("RW")
+    }
+}
+
+/// The highlight of every character of the permissions string, the runs of a group merged
+fn permissions_segments(text: &str) -> Vec<(usize, usize, &'static str)> {
+    if text == "RO" {
+        return vec![(0, text.len(), PERM_READ_HL_GROUP)];
+    } else if text == "RW" {
+        return vec![(0, text.len(), PERM_WRITE_HL_GROUP)];
+    }
+    let mut segments: Vec<(usize, usize, &'static str)> = Vec::new();
+    for (i, c) in text.char_indices() {
+        let hl_group = match c {
+            _ if i == 0 && c != '-' => PERM_TYPE_HL_GROUP,
+            'r' => PERM_READ_HL_GROUP,
+            'w' => PERM_WRITE_HL_GROUP,
+            'x' | 's' | 'S' | 't' | 'T' => PERM_EXEC_HL_GROUP,
+            _ => PERM_NONE_HL_GROUP,
+        };
+        let end = i + c.len_utf8();
+        match segments.last_mut() {
+            Some(last) if last.2 == hl_group => last.1 = end,
+            _ => segments.push((i, end, hl_group)),
+        }
+    }
+    segments
+}
+
+// the owner and group columns are this wide, longer names are cut
+const OWNER_WIDTH: usize = 8;
+
+/// The owner (or group) name of the file, highlighted when someone else owns it
+#[cfg(unix)]
+fn owner_cell(metadata: &Metadata, group: bool) -> (String, &'static str) {
+    use std::os::unix::fs::MetadataExt;
+    let name = if group {
+        owners::group_name(metadata.gid())
+    } else {
+        owners::user_name(metadata.uid())
+    };
+    let hl_group = if owners::is_foreign(metadata.uid()) {
+        OWNER_OTHER_HL_GROUP
+    } else {
+        OWNER_HL_GROUP
+    };
+    let width = UnicodeWidthStr::width(name.as_str());
+    let text = if width > OWNER_WIDTH {
+        let mut cut = String::new();
+        for c in name.chars() {
+            if UnicodeWidthStr::width(cut.as_str()) + c.width().unwrap_or(0) >= OWNER_WIDTH {
+                break;
+            }
+            cut.push(c);
+        }
+        cut + "…"
+    } else {
+        name + &" ".repeat(OWNER_WIDTH - width)
+    };
+    (text, hl_group)
+}
+
+#[cfg(unix)]
+fn is_locked(path: &std::path::Path, metadata: &Metadata) -> bool {
+    owners::is_locked(path, metadata)
+}
+
+#[cfg(not(unix))]
+fn is_locked(_path: &std::path::Path, _metadata: &Metadata) -> bool {
+    false
+}
+
+/// No owners to show
+#[cfg(not(unix))]
+fn owner_cell(_metadata: &Metadata, _group: bool) -> (String, &'static str) {
+    (" ".repeat(OWNER_WIDTH), OWNER_HL_GROUP)
+}
+
 static READ_ONLY_ICON: &'static str = "✗";
+// a directory the user can't enter, a file it can't read
+static LOCKED_ICON: &'static str = "";
 static SELECTED_ICON: &'static str = "✓";
+static PARTIAL_SELECTED_ICON: &'static str = "◐";
+// a swap file exists for the file, red while its vim is running
+static SWAP_ICON: &'static str = "✎";
+pub static CUT_HL_GROUP: &'static str = "tree_cut_item";
+// names that aren't valid UTF-8
+pub static INVALID_NAME_HL_GROUP: &'static str = "tree_invalid_name";
+pub static MARKED_DELETE_HL_GROUP: &'static str = "tree_marked_delete";
+// the directory part of the root-relative paths of the flat view
+pub static FLAT_DIR_HL_GROUP: &'static str = "tree_flat_dir";
+// the counts shown under the root or at the end with summary_footer
+pub static FOOTER_HL_GROUP: &'static str = "tree_footer";
+// the characters of the permissions column, colored like the long listings of exa and lsd
+pub static PERM_TYPE_HL_GROUP: &'static str = "tree_perm_type";
+pub static PERM_READ_HL_GROUP: &'static str = "tree_perm_read";
+pub static PERM_WRITE_HL_GROUP: &'static str = "tree_perm_write";
+pub static PERM_EXEC_HL_GROUP: &'static str = "tree_perm_exec";
+pub static PERM_NONE_HL_GROUP: &'static str = "tree_perm_none";
+// the file names git ignores, shown by show_ignored_files
+pub static GIT_IGNORED_HL_GROUP: &'static str = "tree_git_ignored";
+// the owner and group columns, of the files owned by someone else
+pub static OWNER_HL_GROUP: &'static str = "tree_owner";
+pub static OWNER_OTHER_HL_GROUP: &'static str = "tree_owner_other";
+// the ` -> target` of the symlinks, and the links pointing to nothing
+pub static SYMLINK_HL_GROUP: &'static str = "tree_symlink";
+pub static BROKEN_LINK_HL_GROUP: &'static str = "tree_broken_link";
+// the rows of the entries that just appeared, for flash_new_files seconds
+pub static NEW_ITEM_HL_GROUP: &'static str = "tree_new_item";
 
 #[derive(PartialEq, Eq, Clone, Hash, Debug)]
 pub enum ColumnType {
@@ -550,7 +982,14 @@ pub enum ColumnType {
     FILENAME,
     SIZE,
     TIME,
+    SIZE_BAR,
+    SWAP,
+    PERMISSIONS,
+    OWNER,
+    GROUP,
     SPACE,
+    // any other name, the cells come from the lua callback registered under it
+    CUSTOM(String),
 }
 
 impl From<&str> for ColumnType {
@@ -563,8 +1002,13 @@ impl From<&str> for ColumnType {
             "filename" => ColumnType::FILENAME,
             "size" => ColumnType::SIZE,
             "time" => ColumnType::TIME,
+            "size_bar" => ColumnType::SIZE_BAR,
+            "swap" => ColumnType::SWAP,
+            "permissions" => ColumnType::PERMISSIONS,
+            "owner" => ColumnType::OWNER,
+            "group" => ColumnType::GROUP,
             "space" => ColumnType::SPACE,
-            _ => panic!("Error! unknown column type: {}", s),
+            _ => ColumnType::CUSTOM(s.to_owned()),
         }
     }
 }
@@ -610,7 +1054,7 @@ impl GuiColor {
         }
     }
 
-    pub fn hl_group_name(&self) -> &str {
+    pub fn hl_group_name(&self) -> &'static str {
         match *self {
             GuiColor::BROWN => "tree_color_brow",
             GuiColor::AQUA => "tree_color_aqua",
@@ -640,12 +1084,24 @@ pub struct FileItem {
     pub parent: Option<FileItemPtr>, // the index of the parent in the Tree::fileitems
     pub last: bool,
     pub id: usize,
+    // can't be entered or read by the current user, checked as the item is listed
+    pub locked: bool,
+    // what the item links to when it's a symlink, and whether that doesn't exist
+    pub link_target: Option<std::path::PathBuf>,
+    pub broken_link: bool,
     // pub git_map: HashMap<String, GitStatus>,
 }
 pub type FileItemPtr = std::sync::Arc<FileItem>;
 
 impl FileItem {
     pub fn new(path: std::path::PathBuf, metadata: Metadata, id: usize) -> Self {
+        let locked = is_locked(&path, &metadata);
+        let link_target = if metadata.file_type().is_symlink() {
+            std::fs::read_link(&path).ok()
+        } else {
+            None
+        };
+        let broken_link = link_target.is_some() && std::fs::metadata(&path).is_err();
         Self {
             path,
             metadata,
@@ -653,6 +1109,9 @@ impl FileItem {
             parent: None,
             last: false,
             id,
+            locked,
+            link_target,
+            broken_link,
         }
     }
 
@@ -661,6 +1120,15 @@ impl FileItem {
     }
 }
 
+/// Selection of an item, directories are partially selected when only some of their
+/// descendants are (see `select_recursive`)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SelectionState {
+    None,
+    Partial,
+    Full,
+}
+
 #[derive(Debug)]
 pub struct ColumnCell {
     pub col_start: usize,
@@ -668,24 +1136,41 @@ pub struct ColumnCell {
     pub byte_start: usize,
     pub byte_end: usize,
     pub text: String,
-    pub hl_group: Option<String>,
+    // the untruncated text, used for everything but the display
+    pub full_text: String,
+    // borrowed for the built-in groups, owned only for the icon overrides and the custom columns
+    pub hl_group: Option<Cow<'static, str>>,
+    // (start, end, hl_group) byte ranges of the text, highlighted instead of hl_group
+    pub hl_segments: Vec<(usize, usize, &'static str)>,
 }
 
 impl ColumnCell {
     pub fn new(tree: &Tree, fileitem: &FileItem, ty: ColumnType, is_root_cell: bool) -> Self {
         let mut text;
-        let mut hl_group = None;
-        let path_str = fileitem.path.to_str().unwrap();
+        let mut hl_group: Option<Cow<'static, str>> = None;
+        let mut hl_segments = Vec::new();
+        // only for display and the git map, the actions use the path itself
+        let path_str = fileitem.path.to_string_lossy();
         match ty {
             ColumnType::MARK => {
-                if fileitem.metadata.permissions().readonly() {
+                if fileitem.locked {
+                    text = String::from(LOCKED_ICON);
+                    hl_group = Some(GuiColor::RED.hl_group_name().into())
+                } else if fileitem.metadata.permissions().readonly() {
                     text = String::from(READ_ONLY_ICON);
-                    hl_group = Some(String::from(GuiColor::BROWN.hl_group_name()))
-                } else if tree.is_item_selected(fileitem.id) {
-                    text = String::from(SELECTED_ICON);
-                    hl_group = Some(String::from(GuiColor::GREEN.hl_group_name()))
+                    hl_group = Some(GuiColor::BROWN.hl_group_name().into())
                 } else {
-                    text = String::from(" ");
+                    match tree.selection_state(fileitem.id) {
+                        SelectionState::Full => {
+                            text = String::from(SELECTED_ICON);
+                            hl_group = Some(GuiColor::GREEN.hl_group_name().into())
+                        }
+                        SelectionState::Partial => {
+                            text = String::from(PARTIAL_SELECTED_ICON);
+                            hl_group = Some(GuiColor::YELLOW.hl_group_name().into())
+                        }
+                        SelectionState::None => text = String::from(" "),
+                    }
                 }
             }
             ColumnType::INDENT => {
@@ -733,28 +1218,33 @@ impl ColumnCell {
                 }
             }
             ColumnType::GIT => {
-                if let Some(status) = tree.git_map.get(path_str) {
-                    let (icon, color) = get_git_indicator(*status);
+                if let Some(status) = tree.git_status(&path_str) {
+                    let (icon, color) = get_git_indicator(most_significant(status));
                     text = String::from(icon);
-                    hl_group = Some(color.hl_group_name().to_owned());
+                    hl_group = Some(color.hl_group_name().into());
                 } else {
                     text = String::from(" ");
                 }
             }
             ColumnType::ICON => {
+                // the key of the icon in the `icons` option
+                let key;
                 if fileitem.metadata.is_dir() {
                     text = String::new();
-                    let dir_opened = tree.is_item_opened(path_str);
+                    let dir_opened = tree.is_item_opened(&fileitem.path);
+                    let icon;
+                    if dir_opened {
+                        icon = Icon::FolderOpened;
+                        key = Some("folder_opened");
+                    } else if fileitem.metadata.file_type().is_symlink() {
+                        icon = Icon::FolderSymlink;
+                        key = Some("folder_symlink");
+                    } else {
+                        icon = Icon::FolderClosed;
+                        key = Some("folder_closed");
+                    }
                     if !is_root_cell {
-                        let icon;
-                        if dir_opened {
-                            icon = Icon::FolderOpened;
-                        } else if fileitem.metadata.file_type().is_symlink() {
-                            icon = Icon::FolderSymlink;
-                        } else {
-                            icon = Icon::FolderClosed;
-                        }
-                        hl_group = Some(icon.hl_group_name().to_owned());
+                        hl_group = Some(icon.hl_group_name().into());
                         text.push_str(icon.as_glyph_and_color().0);
                     }
                 } else {
@@ -762,20 +1252,92 @@ impl ColumnCell {
                         Some(extension) => Icon::from(extension),
                         None => Icon::Unknown,
                     };
-                    hl_group = Some(extension_icon.hl_group_name().to_owned());
+                    key = match fileitem.extension() {
+                        Some(extension) if tree.config.icons.contains_key(extension) => {
+                            Some(extension)
+                        }
+                        // the files without an icon of their own
+                        _ if extension_icon == Icon::Unknown => Some("default"),
+                        _ => None,
+                    };
+                    hl_group = Some(extension_icon.hl_group_name().into());
                     text = extension_icon.as_glyph_and_color().0.to_owned();
                 }
+                let icon_override = key.and_then(|k| tree.config.icons.get(k).map(|o| (k, o)));
+                if let (Some((key, icon)), false) = (icon_override, is_root_cell) {
+                    text = icon.glyph.clone();
+                    if icon.color.is_some() {
+                        hl_group = Some(IconOverride::hl_group_name(key).into());
+                    }
+                }
             }
             ColumnType::FILENAME => {
-                hl_group = Some(GuiColor::WHITE.hl_group_name().to_owned());
+                hl_group = Some(GuiColor::WHITE.hl_group_name().into());
                 if is_root_cell {
                     text = tree.config.root_marker.clone();
-                    text.push_str(path_str);
+                    text.push_str(&path_str);
+                    // show the active ordering unless it's the default one
+                    if !tree.config.sort.is_empty() && tree.config.sort != "filename" {
+                        text.push_str(&format!(" [sort: {}]", tree.config.sort));
+                    }
+                    for filter in tree.filter_summary() {
+                        text.push_str(&format!(" [{}]", filter));
+                    }
+                    if tree.network_fs {
+                        text.push_str(" [network]");
+                    }
                 } else {
-                    text = String::from(fileitem.path.file_name().and_then(OsStr::to_str).unwrap());
+                    let file_name = fileitem.path.file_name().unwrap_or_default();
+                    // shown with replacement characters, and highlighted as such
+                    let valid_name = file_name.to_str().is_some();
+                    let name = file_name.to_string_lossy();
+                    // the flat view shows where the file is, the name stands out
+                    let dir_part = match tree
+                        .flat_root()
+                        .and_then(|root| fileitem.path.strip_prefix(root).ok())
+                        .and_then(|rel| rel.parent())
+                        .map(|dir| dir.to_string_lossy())
+                    {
+                        Some(dir) if !dir.is_empty() => format!("{}{}", dir, MAIN_SEPARATOR),
+                        _ => String::new(),
+                    };
+                    text = format!("{}{}", dir_part, name);
                     if fileitem.metadata.is_dir() {
-                        text.push('/');
-                        hl_group = Some(String::from(GuiColor::BLUE.hl_group_name()));
+                        text.push(MAIN_SEPARATOR);
+                        hl_group = Some(GuiColor::BLUE.hl_group_name().into());
+                    }
+                    if tree.config.show_ignored_files && tree.is_git_ignored(&fileitem.path) {
+                        hl_group = Some(GIT_IGNORED_HL_GROUP.into());
+                    }
+                    if tree.is_item_marked_for_deletion(&fileitem.path) {
+                        hl_group = Some(MARKED_DELETE_HL_GROUP.into());
+                    } else if tree.is_item_cut(&fileitem.path) {
+                        hl_group = Some(CUT_HL_GROUP.into());
+                    } else if !valid_name {
+                        hl_group = Some(INVALID_NAME_HL_GROUP.into());
+                    } else if fileitem.broken_link {
+                        hl_group = Some(BROKEN_LINK_HL_GROUP.into());
+                    }
+                    let name_end = text.len();
+                    if let Some(target) = &fileitem.link_target {
+                        text.push_str(" -> ");
+                        text.push_str(&target.to_string_lossy());
+                    }
+                    if !dir_part.is_empty() || name_end < text.len() {
+                        if !dir_part.is_empty() {
+                            hl_segments.push((0, dir_part.len(), FLAT_DIR_HL_GROUP));
+                        }
+                        if let Some(hl) = hl_group {
+                            hl_segments.push((dir_part.len(), name_end, hl));
+                        }
+                        if name_end < text.len() {
+                            let target_hl = if fileitem.broken_link {
+                                BROKEN_LINK_HL_GROUP
+                            } else {
+                                SYMLINK_HL_GROUP
+                            };
+                            hl_segments.push((name_end, text.len(), target_hl));
+                        }
                     }
                 }
             }
@@ -802,21 +1364,100 @@ impl ColumnCell {
                 }
             }
             ColumnType::TIME => {
-                hl_group = Some(GuiColor::BLUE.hl_group_name().to_owned());
+                hl_group = Some(GuiColor::BLUE.hl_group_name().into());
                 let modified_dt: DateTime<Local> = fileitem.metadata.modified().unwrap().into();
                 text = format!("{}", modified_dt.format("%Y-%m-%d"));
             }
+            ColumnType::SIZE_BAR => {
+                if is_root_cell {
+                    text = " ".repeat(SIZE_BAR_WIDTH);
+                } else {
+                    text = size_bar(tree.size_ratio(fileitem));
+                    hl_group = Some(GuiColor::DARKBLUE.hl_group_name().into());
+                }
+            }
+            ColumnType::SWAP => match tree.swap_state(fileitem) {
+                Some(in_use) => {
+                    text = String::from(SWAP_ICON);
+                    hl_group = Some(
+                        if in_use {
+                            GuiColor::RED.hl_group_name()
+                        } else {
+                            GuiColor::BROWN.hl_group_name()
+                        }
+                        .into(),
+                    );
+                }
+                None => text = String::from(" "),
+            },
+            ColumnType::PERMISSIONS => {
+                text = permissions_string(&fileitem.metadata);
+                if is_root_cell {
+                    text = " ".repeat(text.len());
+                } else {
+                    hl_segments = permissions_segments(&text);
+                }
+            }
+            ColumnType::OWNER | ColumnType::GROUP => {
+                let (cell_text, cell_hl) = owner_cell(&fileitem.metadata, ty == ColumnType::GROUP);
+                if is_root_cell {
+                    text = " ".repeat(OWNER_WIDTH);
+                } else {
+                    text = cell_text;
+                    hl_group = Some(cell_hl.into());
+                }
+            }
             ColumnType::SPACE => {
                 text = String::from(" ");
             }
+            ColumnType::CUSTOM(name) => {
+                // padded to the widest cell so that the next columns line up
+                let width = tree.custom_column_width(&name);
+                match tree.custom_cell(&name, &fileitem.path) {
+                    Some((cell_text, cell_hl)) if !is_root_cell => {
+                        text = cell_text.clone();
+                        hl_group = cell_hl.clone();
+                    }
+                    _ => text = String::new(),
+                }
+                let pad = width.saturating_sub(UnicodeWidthStr::width(text.as_str()));
+                text.push_str(&" ".repeat(pad));
+            }
         };
         Self {
             col_start: 0,
             col_end: 0,
             byte_start: 0,
             byte_end: 0,
+            full_text: text.clone(),
             text,
             hl_group,
+            hl_segments,
+        }
+    }
+
+    /// Shorten the text to at most `width` display columns, ending with '…'
+    pub fn truncate(&mut self, width: usize) {
+        if UnicodeWidthStr::width(self.text.as_str()) <= width || width == 0 {
+            return;
+        }
+        let mut text = String::new();
+        let mut text_width = 0;
+        for c in self.text.chars() {
+            let w = UnicodeWidthChar::width(c).unwrap_or(0);
+            if text_width + w + 1 > width {
+                break;
+            }
+            text.push(c);
+            text_width += w;
+        }
+        let kept = text.len();
+        text.push('…');
+        self.text = text;
+        for seg in self.hl_segments.iter_mut() {
+            seg.0 = seg.0.min(kept);
+            seg.1 = seg.1.min(kept);
         }
+        self.hl_segments.retain(|seg| seg.0 < seg.1);
     }
 }
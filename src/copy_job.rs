@@ -0,0 +1,81 @@
+//! Directory copies run on a blocking thread with their progress streamed back, a large
+//! paste doesn't hold the tree up
+use fs_extra::dir::{TransitProcess, TransitProcessResult};
+use futures::channel::mpsc::UnboundedSender;
+use log::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// the cancellation flags of the running copies
+static RUNNING: Mutex<Vec<Arc<AtomicBool>>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub struct Progress {
+    pub copied: u64,
+    pub total: u64,
+    // the file being copied
+    pub file: String,
+}
+
+impl Progress {
+    pub fn percent(&self) -> u64 {
+        if self.total == 0 {
+            100
+        } else {
+            self.copied * 100 / self.total
+        }
+    }
+}
+
+/// Copy the contents of the directory `from` into `to`, created if needed, replacing the
+/// files there. The progress goes to `progress` whenever the percentage changes
+pub fn copy_dir(
+    from: &Path,
+    to: &Path,
+    progress: UnboundedSender<Progress>,
+) -> Result<u64, fs_extra::error::Error> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    RUNNING.lock().unwrap().push(cancelled.clone());
+    let mut options = fs_extra::dir::CopyOptions::new();
+    options.content_only = true;
+    options.overwrite = true;
+    let mut last_percent = None;
+    let result = std::fs::create_dir_all(to)
+        .map_err(fs_extra::error::Error::from)
+        .and_then(|_| {
+            fs_extra::dir::copy_with_progress(from, to, &options, |p: TransitProcess| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return TransitProcessResult::Abort;
+                }
+                let update = Progress {
+                    copied: p.copied_bytes,
+                    total: p.total_bytes,
+                    file: p.file_name,
+                };
+                if last_percent != Some(update.percent()) {
+                    last_percent = Some(update.percent());
+                    // the receiver is gone when the tree was destroyed, copy on anyway
+                    let _ = progress.unbounded_send(update);
+                }
+                TransitProcessResult::ContinueOrAbort
+            })
+        });
+    RUNNING
+        .lock()
+        .unwrap()
+        .retain(|flag| !Arc::ptr_eq(flag, &cancelled));
+    if cancelled.load(Ordering::Relaxed) {
+        info!("copy of {:?} cancelled", from);
+    }
+    result
+}
+
+/// Stop the running copies, returns how many there were
+pub fn cancel_all() -> usize {
+    let running = RUNNING.lock().unwrap();
+    for flag in running.iter() {
+        flag.store(true, Ordering::Relaxed);
+    }
+    running.len()
+}
@@ -1,5 +1,7 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 
 macro_rules! define_error {
     ($($err:ident)*) => { $(
@@ -37,4 +39,123 @@ impl Error for $err {
 )*}
 }
 
-define_error!(ArgError);
+define_error!(ArgError NvimCallError);
+
+/// The failures of the actions, Tree::action surfaces each kind its own way
+#[derive(Debug)]
+pub enum TreeError {
+    // a file operation, with the path it failed on when known
+    Io(Option<PathBuf>, io::Error),
+    Git(git2::Error),
+    // neovim didn't answer, or answered with an error
+    Rpc(NvimCallError),
+    InvalidArg(ArgError),
+    // the destination of a rename, copy or new file exists
+    Conflict(PathBuf),
+    Other(Box<dyn Error>),
+}
+
+/// For `map_err`, `std::fs::remove_file(path).map_err(path_error(path))?`
+pub fn path_error(path: &Path) -> impl FnOnce(io::Error) -> TreeError + '_ {
+    move |source| TreeError::Io(Some(path.to_path_buf()), source)
+}
+
+/// For `map_err` on a copy, the source when it can't be read and the destination
+/// otherwise, as the write side failed
+pub fn copy_error<'a>(from: &'a Path, to: &'a Path) -> impl FnOnce(io::Error) -> TreeError + 'a {
+    move |source| {
+        let path = if std::fs::File::open(from).is_ok() {
+            to
+        } else {
+            from
+        };
+        TreeError::Io(Some(path.to_path_buf()), source)
+    }
+}
+
+/// The io error without its "(os error N)", the log has it
+fn io_reason(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::NotFound => String::from("no such file or directory"),
+        io::ErrorKind::PermissionDenied => String::from("permission denied"),
+        io::ErrorKind::AlreadyExists => String::from("already exists"),
+        _ => err.to_string(),
+    }
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeError::Io(Some(path), e) => write!(f, "{}: {}", path.display(), io_reason(e)),
+            TreeError::Io(None, e) => write!(f, "{}", io_reason(e)),
+            TreeError::Git(e) => write!(f, "git: {}", e.message()),
+            TreeError::Rpc(e) => write!(f, "neovim: {}", e),
+            TreeError::InvalidArg(e) => write!(f, "{}", e),
+            TreeError::Conflict(path) => write!(f, "{} already exists", path.display()),
+            TreeError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for TreeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TreeError::Io(_, e) => Some(e),
+            TreeError::Git(e) => Some(e),
+            TreeError::Rpc(e) => Some(e),
+            TreeError::InvalidArg(e) => Some(e),
+            TreeError::Conflict(_) => None,
+            TreeError::Other(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<io::Error> for TreeError {
+    fn from(e: io::Error) -> Self {
+        TreeError::Io(None, e)
+    }
+}
+
+impl From<git2::Error> for TreeError {
+    fn from(e: git2::Error) -> Self {
+        TreeError::Git(e)
+    }
+}
+
+impl From<NvimCallError> for TreeError {
+    fn from(e: NvimCallError) -> Self {
+        TreeError::Rpc(e)
+    }
+}
+
+impl From<ArgError> for TreeError {
+    fn from(e: ArgError) -> Self {
+        TreeError::InvalidArg(e)
+    }
+}
+
+/// The errors boxed on the way up, sorted back into their kind
+impl From<Box<dyn Error>> for TreeError {
+    fn from(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<TreeError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<io::Error>() {
+            Ok(e) => return TreeError::Io(None, *e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<git2::Error>() {
+            Ok(e) => return TreeError::Git(*e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<NvimCallError>() {
+            Ok(e) => return TreeError::Rpc(*e),
+            Err(e) => e,
+        };
+        match e.downcast::<ArgError>() {
+            Ok(e) => TreeError::InvalidArg(*e),
+            Err(e) => TreeError::Other(e),
+        }
+    }
+}
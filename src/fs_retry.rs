@@ -0,0 +1,126 @@
+//! Retries of the file system calls failing transiently, as they do now and then over
+//! network mounts (EIO, ETIMEDOUT, stale handles). The number of retries is the
+//! fs_retries option of the tree making the call
+use log::*;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+// doubled on every retry
+const FIRST_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The errors worth another try, the others (not found, permission denied...) won't
+/// go away by themselves
+fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => {
+            return true
+        }
+        _ => {}
+    }
+    let code = match err.raw_os_error() {
+        Some(code) => code,
+        None => return false,
+    };
+    // EIO, ESTALE
+    #[cfg(target_os = "linux")]
+    let transient: &[i32] = &[5, 116];
+    #[cfg(all(unix, not(target_os = "linux")))]
+    let transient: &[i32] = &[5, 70];
+    // ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED, ERROR_SEM_TIMEOUT
+    #[cfg(windows)]
+    let transient: &[i32] = &[59, 64, 121];
+    transient.contains(&code)
+}
+
+/// What to do after a failed attempt
+enum Next {
+    Retry,
+    Fail(io::Error),
+}
+
+/// Record the failure `err` of the attempt, the error after the last attempt lists the
+/// errors of all of them
+fn after_failure(
+    what: &str,
+    path: &Path,
+    retries: usize,
+    err: io::Error,
+    errors: &mut Vec<String>,
+) -> Next {
+    if !is_transient(&err) {
+        if errors.is_empty() {
+            return Next::Fail(err);
+        }
+        errors.push(err.to_string());
+        return Next::Fail(combined(what, path, err.kind(), errors.split_off(0)));
+    }
+    errors.push(err.to_string());
+    if errors.len() > retries {
+        return Next::Fail(combined(what, path, err.kind(), errors.split_off(0)));
+    }
+    warn!(
+        "{} {:?} failed (attempt {}), retrying: {}",
+        what,
+        path,
+        errors.len(),
+        err
+    );
+    Next::Retry
+}
+
+/// Run `op` on `path` again after a transient failure, waiting longer every time. For the
+/// callers on a blocking thread, the executor has `retry_async`
+pub fn retry<T>(
+    what: &str,
+    path: &Path,
+    retries: usize,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut errors = Vec::new();
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        match op() {
+            Ok(v) => return Ok(v),
+            Err(e) => match after_failure(what, path, retries, e, &mut errors) {
+                Next::Retry => {}
+                Next::Fail(e) => return Err(e),
+            },
+        }
+        std::thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+/// Run `op` on `path` again after a transient failure, waiting longer every time
+pub async fn retry_async<T>(
+    what: &str,
+    path: &Path,
+    retries: usize,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut errors = Vec::new();
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        match op() {
+            Ok(v) => return Ok(v),
+            Err(e) => match after_failure(what, path, retries, e, &mut errors) {
+                Next::Retry => {}
+                Next::Fail(e) => return Err(e),
+            },
+        }
+        async_std::task::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+fn combined(what: &str, path: &Path, kind: io::ErrorKind, errors: Vec<String>) -> io::Error {
+    let msg = format!(
+        "{} failed after {} attempts: {}",
+        what,
+        errors.len(),
+        errors.join("; ")
+    );
+    error!("{:?}: {}", path, msg);
+    io::Error::new(kind, msg)
+}
@@ -0,0 +1,118 @@
+//! `tree --cmd <action> <path>`, the model layer without a neovim connection, for
+//! debugging and shell pipelines
+use crate::errors::ArgError;
+use crate::session;
+use crate::tree::{absolute_path, dir_size, Config, Tree};
+use log::*;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+pub static USAGE: &str = "headless commands:\n  \
+     tree --cmd render <path>   print the tree as it would be drawn\n  \
+     tree --cmd export <path>   print the tree as JSON\n  \
+     tree --cmd du <path>       print the total size in bytes\n  \
+     tree --cmd session <file> [buffer_name]\n                             \
+     print the tree a session file brings back";
+
+fn headless_tree() -> Tree {
+    let mut config = Config::default();
+    // the pipelines want every entry
+    config.dir_chunk_size = 0;
+    Tree::headless(config)
+}
+
+fn load(path: &Path) -> Result<Tree, Box<dyn Error>> {
+    if !path.is_dir() {
+        return Err(Box::new(ArgError::from_string(format!(
+            "{} is not a directory",
+            path.display()
+        ))));
+    }
+    let mut tree = headless_tree();
+    tree.load_root(absolute_path(path)?)?;
+    Ok(tree)
+}
+
+/// Shown on stderr as well, there's no neovim to notify
+fn warn_user(msg: &str) {
+    warn!("{}", msg);
+    eprintln!("warning: {}", msg);
+}
+
+/// The tree the session file brings back for `name`, at the current directory when the
+/// file can't be read, has no state for `name` or its root is gone
+fn load_session(file: &Path, name: &str) -> Result<Tree, Box<dyn Error>> {
+    let cwd = std::env::current_dir()?;
+    let session = match session::load(file, name) {
+        Ok(Some(s)) => Some(s),
+        Ok(None) => {
+            warn_user(&format!(
+                "no session for {} in {}, starting at {}",
+                name,
+                file.display(),
+                cwd.display()
+            ));
+            None
+        }
+        Err(e) => {
+            warn_user(&format!(
+                "can't read the session {}: {}, starting at {}",
+                file.display(),
+                e,
+                cwd.display()
+            ));
+            None
+        }
+    };
+    let root = match &session {
+        Some(s) if Path::new(&s.root).is_dir() => PathBuf::from(&s.root),
+        Some(s) => {
+            warn_user(&format!(
+                "the root {:?} of the session is gone, starting at {}",
+                s.root,
+                cwd.display()
+            ));
+            cwd
+        }
+        None => cwd,
+    };
+    let mut tree = headless_tree();
+    if let Some(session) = &session {
+        tree.apply_session(session);
+    }
+    tree.load_root(absolute_path(&root)?)?;
+    Ok(tree)
+}
+
+pub fn run(action: &str, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(args.get(0).map(String::as_str).unwrap_or("."));
+    match action {
+        "render" => {
+            for line in load(path)?.lines() {
+                println!("{}", line.trim_end());
+            }
+        }
+        "export" => println!("{}", load(path)?.export_json(false)),
+        "session" => {
+            let name = args.get(1).map(String::as_str).unwrap_or("default");
+            for line in load_session(path, name)?.lines() {
+                println!("{}", line.trim_end());
+            }
+        }
+        "du" => {
+            let size = if path.is_dir() {
+                dir_size(path, usize::MAX)
+            } else {
+                std::fs::metadata(path)?.len()
+            };
+            println!("{}\t{}", size, path.display());
+        }
+        _ => {
+            return Err(Box::new(ArgError::from_string(format!(
+                "unknown command: {}\n{}",
+                action, USAGE
+            ))))
+        }
+    }
+    Ok(())
+}
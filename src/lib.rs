@@ -0,0 +1,55 @@
+//! The tree model of tree-nvim-rs and its neovim handler, for the rust plugins embedding a
+//! file tree or drawing it with columns of their own.
+//!
+//! - `Tree`: the listed items, their cells and the actions, built without neovim by
+//!   `Tree::headless`
+//! - `Config` and the types of its fields, `ColumnRenderer` for the custom columns
+//! - `TreeHandler`: the requests and notifications of `lua/tree.lua`
+//!
+//! ```
+//! use tree_nvim::{ColumnType, Config, Tree};
+//!
+//! let dir = std::env::temp_dir().join(format!("tree_nvim_doc_lib_{}", std::process::id()));
+//! std::fs::create_dir_all(dir.join("src")).unwrap();
+//! std::fs::write(dir.join("Cargo.toml"), "").unwrap();
+//!
+//! let mut config = Config::default();
+//! config.columns = vec![ColumnType::INDENT, ColumnType::FILENAME];
+//! let mut tree = Tree::headless(config);
+//! tree.load_root(dir.clone()).unwrap();
+//! let lines = tree.lines();
+//! // the root, then the directories first
+//! assert_eq!(lines.len(), 3);
+//! assert!(lines[1].contains("src"));
+//! assert!(lines[2].contains("Cargo.toml"));
+//! std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+mod chmod;
+mod column;
+mod copy_job;
+mod errors;
+mod fs_retry;
+pub mod headless;
+pub mod logging;
+mod matcher;
+mod mounts;
+mod nvim_call;
+mod open_with;
+#[cfg(unix)]
+mod owners;
+mod protocol;
+mod run_command;
+mod session;
+mod swap;
+mod tasks;
+pub mod trace;
+mod trash;
+mod tree;
+mod tree_handler;
+
+// headless, logging and trace are public for the tree-nvim-rs binary
+pub use column::{ColumnRenderer, ColumnType, FileItem, IconOverride};
+pub use errors::{ArgError, TreeError};
+pub use run_command::ShellCommand;
+pub use tree::{Config, NotifyLevel, SortKey, Tree};
+pub use tree_handler::TreeHandler;
@@ -0,0 +1,150 @@
+//! The `log` records of the backend. LOG_LEVEL and LOG_FILE set them up at startup,
+//! `_tree_set_log` changes both at runtime to capture one action without a restart
+use crate::errors::ArgError;
+use log::*;
+use simplelog::{ConfigBuilder, WriteLogger};
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+struct Target {
+    level: LevelFilter,
+    path: Option<String>,
+    file: Option<File>,
+}
+
+static TARGET: Mutex<Target> = Mutex::new(Target {
+    level: LevelFilter::Info,
+    path: None,
+    file: None,
+});
+
+/// The writer of the logger, the records go to the current log file if any
+struct TargetWriter;
+
+impl Write for TargetWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match TARGET.lock() {
+            Ok(mut target) => match target.file.as_mut() {
+                Some(file) => file.write(buf),
+                None => Ok(buf.len()),
+            },
+            Err(_) => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match TARGET.lock() {
+            Ok(mut target) => match target.file.as_mut() {
+                Some(file) => file.flush(),
+                None => Ok(()),
+            },
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level.to_lowercase().as_ref() {
+        "debug" => Some(LevelFilter::Debug),
+        "error" => Some(LevelFilter::Error),
+        "info" => Some(LevelFilter::Info),
+        "off" => Some(LevelFilter::Off),
+        "trace" => Some(LevelFilter::Trace),
+        "warn" => Some(LevelFilter::Warn),
+        _ => None,
+    }
+}
+
+fn open_log_file(path: &str) -> io::Result<File> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+/// Nothing is formatted while there is no file to write to
+fn apply_max_level(target: &Target) {
+    if target.file.is_some() {
+        log::set_max_level(target.level);
+    } else {
+        log::set_max_level(LevelFilter::Off);
+    }
+}
+
+pub fn init() -> Result<(), Box<dyn Error>> {
+    use std::env::VarError;
+
+    let level = parse_level(&env::var("LOG_LEVEL").unwrap_or(String::from("info")))
+        .unwrap_or(LevelFilter::Off);
+    let file = match env::var("LOG_FILE") {
+        Err(VarError::NotPresent) => None,
+        Err(e @ VarError::NotUnicode(_)) => return Err(Box::new(e)),
+        Ok(path) => Some((open_log_file(&path)?, path)),
+    };
+
+    let config = ConfigBuilder::new()
+        .set_max_level(LevelFilter::Info)
+        .build();
+    // the logger lets everything through, the global max level does the filtering
+    WriteLogger::init(LevelFilter::Trace, config, TargetWriter)?;
+
+    let mut target = TARGET
+        .lock()
+        .map_err(|_| ArgError::new("log target poisoned"))?;
+    target.level = level;
+    if let Some((file, path)) = file {
+        target.file = Some(file);
+        target.path = Some(path);
+    }
+    apply_max_level(&target);
+    Ok(())
+}
+
+/// Change the level and/or the file, an empty file stops logging. Returns the
+/// resulting (level, file)
+pub fn set(level: Option<&str>, path: Option<&str>) -> Result<(String, Option<String>), ArgError> {
+    let level = match level {
+        Some(level) => Some(
+            parse_level(level)
+                .ok_or_else(|| ArgError::from_string(format!("unknown log level: {}", level)))?,
+        ),
+        None => None,
+    };
+    let file = match path {
+        Some("") => Some(None),
+        Some(path) => Some(Some((
+            open_log_file(path)
+                .map_err(|e| ArgError::from_string(format!("can't open {}: {}", path, e)))?,
+            path.to_owned(),
+        ))),
+        None => None,
+    };
+
+    let mut target = TARGET
+        .lock()
+        .map_err(|_| ArgError::new("log target poisoned"))?;
+    if let Some(level) = level {
+        target.level = level;
+    }
+    if let Some(file) = file {
+        if let Some(old) = target.file.as_mut() {
+            let _ = old.flush();
+        }
+        match file {
+            Some((file, path)) => {
+                target.file = Some(file);
+                target.path = Some(path);
+            }
+            None => {
+                target.file = None;
+                target.path = None;
+            }
+        }
+    }
+    apply_max_level(&target);
+    Ok((target.level.to_string().to_lowercase(), target.path.clone()))
+}
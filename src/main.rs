@@ -4,59 +4,17 @@ use futures::io::AsyncWrite;
 use futures::io::WriteHalf;
 use log::*;
 
+use async_std::net::TcpStream;
 #[cfg(unix)]
 use async_std::os::unix::net::UnixStream;
-use nvim_rs::{create::async_std as create, Neovim, Value};
-use simplelog::{ConfigBuilder, LevelFilter, WriteLogger};
+use async_std::task::JoinHandle;
+use nvim_rs::{create::async_std as create, error::LoopError, Neovim, Value};
 use std::env;
 use std::error::Error;
-mod column;
-mod errors;
-mod tree;
-mod tree_handler;
-use tree_handler::TreeHandler;
+use tree_nvim::{headless, logging, trace, ArgError, TreeHandler};
 
-fn init_logging() -> Result<(), Box<dyn Error>> {
-    use std::env::VarError;
-
-    let log_level_filter = match env::var("LOG_LEVEL")
-        .unwrap_or(String::from("info"))
-        .to_lowercase()
-        .as_ref()
-    {
-        "debug" => LevelFilter::Debug,
-        "error" => LevelFilter::Error,
-        "info" => LevelFilter::Info,
-        "off" => LevelFilter::Off,
-        "trace" => LevelFilter::Trace,
-        "warn" => LevelFilter::Warn,
-        _ => LevelFilter::Off,
-    };
-
-    let config = ConfigBuilder::new()
-        .set_max_level(LevelFilter::Info)
-        .build();
-
-    let filepath = match env::var("LOG_FILE") {
-        Err(err) => match err {
-            VarError::NotPresent => return Ok(()),
-            e @ VarError::NotUnicode(_) => {
-                return Err(Box::new(e));
-            }
-        },
-        Ok(path) => path.to_owned(),
-    };
-
-    let log_file = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(filepath)?;
-
-    WriteLogger::init(log_level_filter, config, log_file)?;
-
-    Ok(())
-}
+#[cfg(windows)]
+mod named_pipe;
 
 fn panic_hook() {
     use std::panic;
@@ -68,7 +26,7 @@ fn panic_hook() {
 }
 
 /// Initialize the neovim channel
-/// It sets up the channel_id and and highlight groups
+/// It sets up the channel_id, the highlight groups are registered with the first tree
 async fn init_channel<T>(nvim: &Neovim<T>)
 where
     T: Sync + Send + Unpin + AsyncWrite,
@@ -79,32 +37,124 @@ where
         .await
         .unwrap();
     info!("Set chan to {} done!", chan);
+}
 
-    let mut commands = Vec::new();
-    for icon in column::ICONS {
-        let name = icon.hl_group_name();
-        let color = icon.as_glyph_and_color().1;
-        let cmd = format!("hi {} guifg={}", name, color);
-        commands.push(Value::from(cmd));
+/// Connect to the `\\.\pipe\nvim-...` named pipe neovim listens on by default on windows
+#[cfg(windows)]
+async fn connect_named_pipe(
+    server: &str,
+    handler: TreeHandler<named_pipe::PipeWriter>,
+) -> std::io::Result<(
+    Neovim<named_pipe::PipeWriter>,
+    JoinHandle<Result<(), Box<LoopError>>>,
+)> {
+    let (reader, writer) = named_pipe::connect(server).await?;
+    let (nvim, io) = Neovim::<named_pipe::PipeWriter>::new(reader, writer, handler);
+    Ok((nvim, async_std::task::spawn(io)))
+}
+
+/// `host:port` or `[v6 addr]:port`, what `nvim --listen 127.0.0.1:6666` listens on
+fn is_tcp_address(server: &str) -> bool {
+    match server.rfind(':') {
+        Some(idx) => {
+            let host = &server[..idx];
+            !host.is_empty()
+                && !host.contains('/')
+                && !host.contains('\\')
+                && server[idx + 1..].parse::<u16>().is_ok()
+        }
+        None => false,
     }
+}
 
-    for color in column::GUI_COLORS {
-        let cmd = format!("hi {} guifg={}", color.hl_group_name(), color.color_val(),);
-        commands.push(Value::from(cmd));
+/// The address to connect to: the first argument, falling back to `$NVIM` and
+/// `$NVIM_LISTEN_ADDRESS` so wrapper scripts can spawn the binary without arguments
+fn server_address(args: &[String]) -> Result<String, ArgError> {
+    if let Some(server) = args.get(1) {
+        return Ok(server.clone());
+    }
+    for var in &["NVIM", "NVIM_LISTEN_ADDRESS"] {
+        match env::var(var) {
+            Ok(server) if !server.is_empty() => {
+                info!("server address from ${}: {}", var, server);
+                return Ok(server);
+            }
+            _ => {}
+        }
     }
-    nvim.execute_lua("require('tree').run_commands_batch(...)", vec![Value::from(commands)]).await.unwrap();
+    Err(ArgError::new(
+        "no neovim address given, accepted forms:\n  \
+         tree <unix socket path>\n  \
+         tree <host>:<port>\n  \
+         tree \\\\.\\pipe\\<name> (windows)\n  \
+         NVIM=<address> tree\n  \
+         NVIM_LISTEN_ADDRESS=<address> tree",
+    ))
 }
 
 async fn run(args: Vec<String>) {
     debug!("args: {:?}", args);
-    let server = args[1].clone();
-    // create the neovim session with TreeHandler
-    let (nvim, io_handler) = create::new_unix_socket(
-        server,
-        TreeHandler::<WriteHalf<UnixStream>>::default(),
-    )
-    .await
-    .unwrap();
+    let server = match server_address(&args) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if is_tcp_address(&server) {
+        let handler = TreeHandler::<WriteHalf<TcpStream>>::default();
+        match create::new_tcp(&server, handler.clone()).await {
+            Ok((nvim, io_handler)) => serve(nvim, io_handler, handler).await,
+            Err(e) => {
+                let msg = format!("can't connect to {}: {}", server, e);
+                error!("{}", msg);
+                eprintln!("{}", msg);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    #[cfg(windows)]
+    {
+        if named_pipe::is_named_pipe(&server) {
+            let handler = TreeHandler::<named_pipe::PipeWriter>::default();
+            match connect_named_pipe(&server, handler.clone()).await {
+                Ok((nvim, io_handler)) => serve(nvim, io_handler, handler).await,
+                Err(e) => {
+                    let msg = format!(
+                        "can't connect to {}: {}, nvim --listen <host>:<port> gives a TCP \
+                         address to connect to instead",
+                        server, e
+                    );
+                    error!("{}", msg);
+                    eprintln!("{}", msg);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    }
+    #[cfg(unix)]
+    {
+        // create the neovim session with TreeHandler
+        let handler = TreeHandler::<WriteHalf<UnixStream>>::default();
+        let (nvim, io_handler) = create::new_unix_socket(server, handler.clone())
+            .await
+            .unwrap();
+        serve(nvim, io_handler, handler).await;
+    }
+    #[cfg(not(unix))]
+    error!("Unsupported server address: {}", server);
+}
+
+async fn serve<W>(
+    nvim: Neovim<W>,
+    io_handler: JoinHandle<Result<(), Box<LoopError>>>,
+    handler: TreeHandler<W>,
+) where
+    W: Sync + Send + Unpin + AsyncWrite + 'static,
+{
     // set tree#_channel_id
     init_channel(&nvim).await;
 
@@ -132,14 +182,76 @@ async fn run(args: Vec<String>) {
         }
         Ok(()) => {}
     }
+    // the work left for the trees has nowhere to go once neovim is gone
+    handler.shutdown().await;
+}
+
+/// The command line without the flags, `--cwd <dir>` and `--cmd <action>`
+struct Flags {
+    positional: Vec<String>,
+    cwd: Option<String>,
+    cmd: Option<String>,
+}
+
+fn parse_flags(args: Vec<String>) -> Result<Flags, ArgError> {
+    let mut flags = Flags {
+        positional: Vec::new(),
+        cwd: None,
+        cmd: None,
+    };
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--cwd" | "--cmd" => {
+                let value = match iter.next() {
+                    Some(v) => v,
+                    None => return Err(ArgError::from_string(format!("{} needs a value", arg))),
+                };
+                if arg == "--cwd" {
+                    flags.cwd = Some(value);
+                } else {
+                    flags.cmd = Some(value);
+                }
+            }
+            _ => flags.positional.push(arg),
+        }
+    }
+    Ok(flags)
 }
 
 #[async_std::main]
 async fn main() {
-    let _ = init_logging();
+    let _ = logging::init();
+    // flushes the chrome trace when main returns
+    let _trace_guard = match trace::init() {
+        Ok(guard) => guard,
+        Err(e) => {
+            error!("Fail to set up tracing: {}", e);
+            None
+        }
+    };
     panic_hook();
-    let args: Vec<String> = env::args().collect();
+    let flags = match parse_flags(env::args().collect()) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{}\n{}", e, headless::USAGE);
+            std::process::exit(1);
+        }
+    };
+    if let Some(cwd) = &flags.cwd {
+        if let Err(e) = env::set_current_dir(cwd) {
+            eprintln!("Can't change directory to {}: {}", cwd, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(action) = &flags.cmd {
+        if let Err(e) = headless::run(action, &flags.positional[1..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     debug!("No fork");
-    run(args).await;
+    run(flags.positional).await;
     debug!("Done!");
 }
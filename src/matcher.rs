@@ -0,0 +1,63 @@
+//! Matching of the file names against what the user types, shared by filter, search and
+//! select_pattern. Case is ignored with unicode folding, unless smart_case is on and the
+//! pattern has an uppercase letter
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    needle: String,
+    case_sensitive: bool,
+}
+
+impl Matcher {
+    pub fn new(pattern: &str, smart_case: bool) -> Self {
+        let case_sensitive = smart_case && pattern.chars().any(char::is_uppercase);
+        let needle = if case_sensitive {
+            pattern.to_owned()
+        } else {
+            fold(pattern)
+        };
+        Self {
+            needle,
+            case_sensitive,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.needle.is_empty()
+    }
+
+    /// Whether `name` contains the pattern
+    pub fn matches(&self, name: &str) -> bool {
+        if self.case_sensitive {
+            name.contains(&self.needle)
+        } else {
+            fold(name).contains(&self.needle)
+        }
+    }
+
+    /// Whether every name self matches is matched by `wider` too
+    pub fn narrows(&self, wider: &Matcher) -> bool {
+        self.case_sensitive == wider.case_sensitive && self.needle.contains(&wider.needle)
+    }
+
+    /// Whether the file name of `path` contains the pattern
+    pub fn matches_path(&self, path: &Path) -> bool {
+        path.file_name()
+            .map_or(false, |name| self.matches(&name.to_string_lossy()))
+    }
+}
+
+/// Lowercase, plus the foldings lowercasing misses: ß and ss, ς and σ compare equal
+pub fn fold(s: &str) -> String {
+    let mut folded = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            'ß' | 'ẞ' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            'ſ' => folded.push('s'),
+            _ => folded.extend(c.to_lowercase()),
+        }
+    }
+    folded
+}
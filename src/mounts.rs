@@ -0,0 +1,93 @@
+use log::*;
+use std::path::Path;
+
+// file systems for which every stat is a round trip to the server
+static NETWORK_FS_TYPES: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smbfs",
+    "smb3",
+    "fuse.sshfs",
+    "sshfs",
+    "9p",
+    "afs",
+    "ceph",
+    "glusterfs",
+    "webdav",
+    "davfs",
+];
+
+fn is_network_fs_type(fs_type: &str) -> bool {
+    NETWORK_FS_TYPES.contains(&fs_type)
+}
+
+/// The file system type of the mount point containing `path`, given the (mount point,
+/// type) pairs of the mount table
+fn fs_type_of<'a>(path: &Path, mounts: &'a [(String, String)]) -> Option<&'a str> {
+    mounts
+        .iter()
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.len())
+        .map(|(_, fs_type)| fs_type.as_str())
+}
+
+#[cfg(target_os = "linux")]
+fn mount_table() -> Vec<(String, String)> {
+    // <device> <mount point> <type> <options> <dump> <pass>
+    let content = match std::fs::read_to_string("/proc/self/mounts") {
+        Ok(c) => c,
+        Err(e) => {
+            info!("can't read the mount table: {:?}", e);
+            return Vec::new();
+        }
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            // spaces in mount points are escaped as \040
+            let mount_point = fields.next()?.replace("\\040", " ");
+            let fs_type = fields.next()?.to_owned();
+            Some((mount_point, fs_type))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+fn mount_table() -> Vec<(String, String)> {
+    // <device> on <mount point> (<type>, <options>...)
+    let output = match std::process::Command::new("mount").output() {
+        Ok(o) => o,
+        Err(e) => {
+            info!("can't run mount: {:?}", e);
+            return Vec::new();
+        }
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let rest = &line[line.find(" on ")? + 4..];
+            let paren = rest.rfind(" (")?;
+            let fs_type = rest[paren + 2..].split(|c| c == ',' || c == ')').next()?;
+            Some((rest[..paren].to_owned(), fs_type.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn mount_table() -> Vec<(String, String)> {
+    Vec::new()
+}
+
+/// Whether `path` lives on a network file system (NFS, SMB, SSHFS...)
+pub fn is_network_fs(path: &Path) -> bool {
+    let mounts = mount_table();
+    match fs_type_of(path, &mounts) {
+        Some(fs_type) => {
+            debug!("{:?} is on a {} file system", path, fs_type);
+            is_network_fs_type(fs_type)
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,191 @@
+//! The `\\.\pipe\nvim-...` named pipe neovim listens on by default on windows. The pipe is
+//! opened for overlapped I/O, reads and writes of a synchronous handle would wait on each
+//! other, and each direction is pumped by a blocking task so the executor never blocks
+use futures::channel::mpsc;
+use futures::io::AsyncWrite;
+use futures::stream::{IntoAsyncRead, TryStreamExt};
+use log::*;
+use std::fs::File;
+use std::io;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
+use winapi::shared::winerror::{ERROR_BROKEN_PIPE, ERROR_IO_PENDING};
+use winapi::um::fileapi::{ReadFile, WriteFile};
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::ioapiset::GetOverlappedResult;
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::synchapi::CreateEventW;
+use winapi::um::winbase::FILE_FLAG_OVERLAPPED;
+use winapi::um::winnt::HANDLE;
+
+pub fn is_named_pipe(server: &str) -> bool {
+    server.starts_with(r"\\.\pipe\") || server.starts_with(r"//./pipe/")
+}
+
+/// The event an overlapped operation signals, one per pumping task
+struct Event(HANDLE);
+
+// only used by the task that created it
+unsafe impl Send for Event {}
+
+impl Event {
+    fn new() -> io::Result<Self> {
+        let handle = unsafe { CreateEventW(std::ptr::null_mut(), TRUE, FALSE, std::ptr::null()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(handle))
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Start a read or a write with `op` and wait for its completion, the bytes transferred
+fn overlapped(
+    pipe: &File,
+    event: &Event,
+    op: impl FnOnce(HANDLE, *mut OVERLAPPED) -> i32,
+) -> io::Result<usize> {
+    let handle = pipe.as_raw_handle() as HANDLE;
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    overlapped.hEvent = event.0;
+    if op(handle, &mut overlapped) == 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+            return Err(err);
+        }
+    }
+    let mut transferred: DWORD = 0;
+    if unsafe { GetOverlappedResult(handle, &mut overlapped, &mut transferred, TRUE) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(transferred as usize)
+}
+
+fn read_loop(pipe: Arc<File>, tx: mpsc::UnboundedSender<io::Result<Vec<u8>>>) {
+    let event = match Event::new() {
+        Ok(e) => e,
+        Err(e) => {
+            let _ = tx.unbounded_send(Err(e));
+            return;
+        }
+    };
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = overlapped(&pipe, &event, |handle, overlapped| unsafe {
+            ReadFile(
+                handle,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as DWORD,
+                std::ptr::null_mut(),
+                overlapped,
+            )
+        });
+        match read {
+            // neovim closed its end
+            Ok(0) => break,
+            Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => break,
+            Ok(n) => {
+                if tx.unbounded_send(Ok(buf[..n].to_vec())).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                let _ = tx.unbounded_send(Err(e));
+                break;
+            }
+        }
+    }
+    debug!("named pipe reader done");
+}
+
+fn write_loop(pipe: Arc<File>, rx: mpsc::UnboundedReceiver<Vec<u8>>) {
+    let event = match Event::new() {
+        Ok(e) => e,
+        Err(e) => {
+            error!("named pipe writer: {}", e);
+            return;
+        }
+    };
+    for chunk in futures::executor::block_on_stream(rx) {
+        let mut written = 0;
+        while written < chunk.len() {
+            let rest = &chunk[written..];
+            let ret = overlapped(&pipe, &event, |handle, overlapped| unsafe {
+                WriteFile(
+                    handle,
+                    rest.as_ptr() as *const _,
+                    rest.len() as DWORD,
+                    std::ptr::null_mut(),
+                    overlapped,
+                )
+            });
+            match ret {
+                Ok(n) => written += n,
+                Err(e) => {
+                    error!("named pipe writer: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+    debug!("named pipe writer done");
+}
+
+/// The write half, the bytes are queued for the writing task
+pub struct PipeWriter {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(match self.tx.unbounded_send(buf.to_vec()) {
+            Ok(_) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "named pipe closed",
+            )),
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        self.tx.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub type PipeReader = IntoAsyncRead<mpsc::UnboundedReceiver<io::Result<Vec<u8>>>>;
+
+/// Open the pipe `server` and start the tasks pumping it
+pub async fn connect(server: &str) -> io::Result<(PipeReader, PipeWriter)> {
+    let server = server.to_owned();
+    let pipe = async_std::task::spawn_blocking(move || {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(server)
+    })
+    .await?;
+    let pipe = Arc::new(pipe);
+    let (read_tx, read_rx) = mpsc::unbounded();
+    let (write_tx, write_rx) = mpsc::unbounded();
+    {
+        let pipe = pipe.clone();
+        async_std::task::spawn_blocking(move || read_loop(pipe, read_tx));
+    }
+    async_std::task::spawn_blocking(move || write_loop(pipe, write_rx));
+    Ok((read_rx.into_async_read(), PipeWriter { tx: write_tx }))
+}
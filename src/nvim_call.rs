@@ -0,0 +1,139 @@
+//! Wrappers around the neovim calls: the errors tell which call failed, and the
+//! read-only calls (the query_* ones) are retried after a transient failure. The others
+//! may have done part of their work before failing and are sent once
+use crate::errors::NvimCallError;
+use futures::io::AsyncWrite;
+use log::*;
+use nvim_rs::{error::CallError, Neovim, Value};
+use std::future::Future;
+use std::time::Duration;
+use tracing::instrument;
+
+const MAX_ATTEMPTS: u64 = 3;
+
+/// Errors neovim raises while it is busy (e.g. textlock while a completion menu or a
+/// prompt is up), the call usually succeeds a moment later
+fn is_transient(err: &CallError) -> bool {
+    match err {
+        CallError::NeovimError(_, msg) => {
+            msg.contains("E523") || msg.contains("E565") || msg.contains("textlock")
+        }
+        _ => false,
+    }
+}
+
+// `what` names the span, these are the RPC phase of the traces
+#[instrument(level = "debug", skip(call))]
+async fn with_retry<T, F, Fut>(what: &str, mut call: F) -> Result<T, NvimCallError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<CallError>>>,
+{
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                warn!("{} failed (attempt {}), retrying: {}", what, attempt, e);
+                async_std::task::sleep(Duration::from_millis(20 * attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(NvimCallError::from_string(format!("{}: {}", what, e))),
+        }
+    }
+}
+
+#[instrument(level = "debug", skip(call))]
+async fn once<T, Fut>(what: &str, call: Fut) -> Result<T, NvimCallError>
+where
+    Fut: Future<Output = Result<T, Box<CallError>>>,
+{
+    call.await
+        .map_err(|e| NvimCallError::from_string(format!("{}: {}", what, e)))
+}
+
+pub async fn call_function<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    nvim: &Neovim<W>,
+    fname: &str,
+    args: Vec<Value>,
+) -> Result<Value, NvimCallError> {
+    once(
+        &format!("call_function({})", fname),
+        nvim.call_function(fname, args),
+    )
+    .await
+}
+
+/// call_function for the functions without side effects (getcwd, line...)
+pub async fn query_function<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    nvim: &Neovim<W>,
+    fname: &str,
+    args: Vec<Value>,
+) -> Result<Value, NvimCallError> {
+    with_retry(&format!("call_function({})", fname), || {
+        nvim.call_function(fname, args.clone())
+    })
+    .await
+}
+
+pub async fn execute_lua<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    nvim: &Neovim<W>,
+    code: &str,
+    args: Vec<Value>,
+) -> Result<Value, NvimCallError> {
+    once(
+        &format!("execute_lua({})", code),
+        nvim.execute_lua(code, args),
+    )
+    .await
+}
+
+/// execute_lua for the code that only reads the editor state
+pub async fn query_lua<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    nvim: &Neovim<W>,
+    code: &str,
+    args: Vec<Value>,
+) -> Result<Value, NvimCallError> {
+    with_retry(&format!("execute_lua({})", code), || {
+        nvim.execute_lua(code, args.clone())
+    })
+    .await
+}
+
+pub async fn command<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    nvim: &Neovim<W>,
+    cmd: &str,
+) -> Result<(), NvimCallError> {
+    once(&format!("command({})", cmd), nvim.command(cmd)).await
+}
+
+/// One `[method, args]` entry of call_atomic
+pub fn atomic_call(method: &str, args: Vec<Value>) -> Value {
+    Value::Array(vec![Value::from(method), Value::Array(args)])
+}
+
+/// Run the calls in a single request, neovim stops at the first one failing and reports
+/// it in the result rather than as an error
+pub async fn call_atomic<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    nvim: &Neovim<W>,
+    calls: Vec<Value>,
+) -> Result<(), NvimCallError> {
+    let ret = once("call_atomic", nvim.call_atomic(calls.clone())).await?;
+    match ret.get(1) {
+        // [index of the failed call, error type, message]
+        Some(Value::Array(err)) => {
+            let method = err
+                .get(0)
+                .and_then(Value::as_u64)
+                .and_then(|i| calls.get(i as usize))
+                .and_then(|call| call.as_array()?.get(0)?.as_str())
+                .unwrap_or("?");
+            let msg = err.get(2).and_then(Value::as_str).unwrap_or_default();
+            Err(NvimCallError::from_string(format!(
+                "call_atomic({}): {}",
+                method, msg
+            )))
+        }
+        _ => Ok(()),
+    }
+}
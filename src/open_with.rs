@@ -0,0 +1,241 @@
+use log::*;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// An application that is able to open a file
+#[derive(Debug, Clone)]
+pub struct Application {
+    pub name: String,
+    // desktop file id on linux, bundle path on macos
+    pub id: String,
+}
+
+#[cfg(target_os = "macos")]
+pub fn applications_for(_path: &Path) -> Vec<Application> {
+    // LaunchServices has no command line interface to query the handlers of a type, we list
+    // the installed bundles and let `open -a` sort it out
+    let mut apps = Vec::new();
+    let mut dirs = vec![PathBuf::from("/Applications")];
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(Path::new(&home).join("Applications"));
+    }
+    for dir in dirs {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|x| x.ok()) {
+            let path = entry.path();
+            if path.extension().and_then(|x| x.to_str()) != Some("app") {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|x| x.to_str()) {
+                apps.push(Application {
+                    name: name.to_owned(),
+                    id: path.to_string_lossy().into_owned(),
+                });
+            }
+        }
+    }
+    apps.sort_by(|l, r| l.name.cmp(&r.name));
+    apps
+}
+
+#[cfg(target_os = "macos")]
+pub fn launch(app: &Application, path: &Path) -> std::io::Result<()> {
+    Command::new("open")
+        .arg("-a")
+        .arg(&app.id)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn mime_type(path: &Path) -> Option<String> {
+    let output = Command::new("xdg-mime")
+        .args(&["query", "filetype"])
+        .arg(path)
+        .output()
+        .ok()?;
+    let mime = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    if mime.is_empty() {
+        None
+    } else {
+        Some(mime)
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn application_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    match env::var("XDG_DATA_HOME") {
+        Ok(d) => dirs.push(PathBuf::from(d)),
+        Err(_) => {
+            if let Ok(home) = env::var("HOME") {
+                dirs.push(Path::new(&home).join(".local/share"));
+            }
+        }
+    }
+    let data_dirs =
+        env::var("XDG_DATA_DIRS").unwrap_or_else(|_| String::from("/usr/local/share:/usr/share"));
+    dirs.extend(data_dirs.split(':').map(PathBuf::from));
+    dirs.into_iter().map(|d| d.join("applications")).collect()
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn applications_for(path: &Path) -> Vec<Application> {
+    let mime = mime_type(path);
+    info!("mime type of {:?}: {:?}", path, mime);
+    let mut apps: Vec<Application> = Vec::new();
+    for dir in application_dirs() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|x| x.ok()) {
+            let entry_path = entry.path();
+            if entry_path.extension().and_then(|x| x.to_str()) != Some("desktop") {
+                continue;
+            }
+            let id = entry.file_name().to_str().unwrap_or_default().to_owned();
+            // entries in the user's directory shadow the system ones
+            if apps.iter().any(|a| a.id == id) {
+                continue;
+            }
+            let content = match std::fs::read_to_string(&entry_path) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            let mut name = None;
+            let mut mime_types = "";
+            let mut hidden = false;
+            let mut in_main_section = false;
+            for line in content.lines() {
+                let line = line.trim();
+                if line.starts_with('[') {
+                    in_main_section = line == "[Desktop Entry]";
+                    continue;
+                }
+                if !in_main_section {
+                    continue;
+                }
+                if let Some(v) = line.strip_prefix("Name=") {
+                    name = Some(v.to_owned());
+                } else if let Some(v) = line.strip_prefix("MimeType=") {
+                    mime_types = v;
+                } else if line == "NoDisplay=true" || line == "Hidden=true" {
+                    hidden = true;
+                }
+            }
+            if hidden {
+                continue;
+            }
+            let handles = match &mime {
+                Some(m) => mime_types.split(';').any(|x| x == m),
+                None => !mime_types.is_empty(),
+            };
+            if let (true, Some(name)) = (handles, name) {
+                apps.push(Application { name, id });
+            }
+        }
+    }
+    apps.sort_by(|l, r| l.name.cmp(&r.name));
+    apps
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn launch(app: &Application, path: &Path) -> std::io::Result<()> {
+    let desktop_id = app.id.trim_end_matches(".desktop");
+    // setsid detaches the application from the server, so it survives nvim quitting
+    Command::new("setsid")
+        .arg("gtk-launch")
+        .arg(desktop_id)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn applications_for(_path: &Path) -> Vec<Application> {
+    Vec::new()
+}
+
+#[cfg(not(unix))]
+pub fn launch(_app: &Application, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "open_with is not supported on this platform",
+    ))
+}
+
+/// The file manager used when `file_manager` isn't set
+pub fn default_file_manager() -> &'static str {
+    if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(windows) {
+        "explorer"
+    } else {
+        "xdg-open"
+    }
+}
+
+/// `file_manager` split in words, with the directory as last argument
+pub fn file_manager_command(file_manager: &str, dir: &Path) -> Vec<String> {
+    let mut words: Vec<String> = file_manager.split_whitespace().map(str::to_owned).collect();
+    if words.is_empty() {
+        words.push(default_file_manager().to_owned());
+    }
+    words.push(dir.to_string_lossy().into_owned());
+    words
+}
+
+/// Start the file manager detached from the server, so it survives nvim quitting
+pub fn launch_file_manager(words: &[String]) -> std::io::Result<()> {
+    let mut command = if cfg!(all(unix, not(target_os = "macos"))) {
+        let mut command = Command::new("setsid");
+        command.args(words);
+        command
+    } else {
+        let mut command = Command::new(&words[0]);
+        command.args(&words[1..]);
+        command
+    };
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+/// Open `path` with the default application of the system, like a double click in the
+/// file manager
+pub fn open_default(path: &Path) -> std::io::Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(windows) {
+        // the first quoted argument of start is the window title
+        let mut command = Command::new("cmd");
+        command.args(&["/C", "start", ""]);
+        command
+    } else {
+        let mut command = Command::new("setsid");
+        command.arg("xdg-open");
+        command
+    };
+    command
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! The user and group names of the owner and group columns, asked from getpwuid_r(3) and
+//! getgrgid_r(3) so directory services are covered, and cached per id. The ids without a
+//! name are shown as numbers
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct Names {
+    users: HashMap<u32, Option<String>>,
+    groups: HashMap<u32, Option<String>>,
+}
+
+static NAMES: Mutex<Option<Names>> = Mutex::new(None);
+
+/// Call the reentrant lookup `get` with a buffer growing on ERANGE, the name of the
+/// entry it fills
+fn lookup<T>(
+    get: impl Fn(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+    name: impl Fn(&T) -> *const libc::c_char,
+) -> Option<String> {
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut entry: T = unsafe { std::mem::zeroed() };
+        let mut result: *mut T = std::ptr::null_mut();
+        let ret = get(&mut entry, buf.as_mut_ptr(), buf.len(), &mut result);
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(name(&entry)) };
+        return Some(name.to_string_lossy().into_owned());
+    }
+}
+
+fn lookup_user(uid: u32) -> Option<String> {
+    lookup(
+        |pwd, buf, len, result| unsafe { libc::getpwuid_r(uid, pwd, buf, len, result) },
+        |pwd: &libc::passwd| pwd.pw_name,
+    )
+}
+
+fn lookup_group(gid: u32) -> Option<String> {
+    lookup(
+        |grp, buf, len, result| unsafe { libc::getgrgid_r(gid, grp, buf, len, result) },
+        |grp: &libc::group| grp.gr_name,
+    )
+}
+
+fn cached(
+    id: u32,
+    pick: impl Fn(&mut Names) -> &mut HashMap<u32, Option<String>>,
+    resolve: impl Fn(u32) -> Option<String>,
+) -> Option<String> {
+    let mut guard = NAMES.lock().ok()?;
+    let names = pick(guard.get_or_insert_with(Names::default));
+    names.entry(id).or_insert_with(|| resolve(id)).clone()
+}
+
+pub fn user_name(uid: u32) -> String {
+    cached(uid, |names| &mut names.users, lookup_user).unwrap_or_else(|| uid.to_string())
+}
+
+pub fn group_name(gid: u32) -> String {
+    cached(gid, |names| &mut names.groups, lookup_group).unwrap_or_else(|| gid.to_string())
+}
+
+/// Whether `uid` is someone else than the effective user the tree runs as
+pub fn is_foreign(uid: u32) -> bool {
+    unsafe { libc::geteuid() != uid }
+}
+
+/// Whether the current user can't enter the directory, or can't read the file, as
+/// access(2) tells with the ACLs and the capabilities taken into account. The links are
+/// left to their target
+pub fn is_locked(path: &Path, metadata: &Metadata) -> bool {
+    if metadata.file_type().is_symlink() {
+        return false;
+    }
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    let mode = if metadata.is_dir() {
+        libc::X_OK
+    } else {
+        libc::R_OK
+    };
+    unsafe { libc::access(path.as_ptr(), mode) != 0 }
+}
@@ -0,0 +1,73 @@
+use crate::errors::ArgError;
+use nvim_rs::Value;
+
+/// Version of the lua <-> rust protocol, bumped whenever requests or notifications change
+pub const PROTOCOL_VERSION: u64 = 4;
+
+// capability bits, exchanged by `_tree_hello`, 1 << 0 and 1 << 2 are retired
+pub const CAP_SCOPED_TREES: u64 = 1 << 1;
+pub const CAP_HIGHLIGHT_LINKS: u64 = 1 << 3;
+
+pub const SERVER_CAPABILITIES: u64 = CAP_SCOPED_TREES | CAP_HIGHLIGHT_LINKS;
+
+/// What the lua frontend speaks
+#[derive(Debug, Clone, Copy)]
+pub struct ClientProtocol {
+    pub version: u64,
+    pub capabilities: u64,
+}
+
+impl Default for ClientProtocol {
+    // frontends older than the handshake
+    fn default() -> Self {
+        Self {
+            version: 1,
+            capabilities: 0,
+        }
+    }
+}
+
+impl ClientProtocol {
+    pub fn from_value(val: &Value) -> Result<Self, ArgError> {
+        let map = match val {
+            Value::Map(m) => m,
+            _ => return Err(ArgError::new("Protocol: map expected")),
+        };
+        let get = |name: &str| {
+            map.iter()
+                .find(|(k, _)| k.as_str() == Some(name))
+                .and_then(|(_, v)| v.as_u64())
+        };
+        let version = match get("version") {
+            Some(v) => v,
+            None => return Err(ArgError::new("Protocol: version is missing")),
+        };
+        // the requests and notifications of another version can't be told apart
+        if version != PROTOCOL_VERSION {
+            return Err(ArgError::from_string(format!(
+                "Protocol: the frontend speaks version {} and the backend version {}, \
+                 update them together",
+                version, PROTOCOL_VERSION
+            )));
+        }
+        Ok(Self {
+            version,
+            capabilities: get("capabilities").unwrap_or(0),
+        })
+    }
+
+    pub fn supports(&self, capability: u64) -> bool {
+        self.capabilities & capability != 0
+    }
+
+    /// The reply of `_tree_hello`, capabilities are the ones both sides support
+    pub fn to_reply(&self) -> Value {
+        Value::Map(vec![
+            (Value::from("version"), Value::from(PROTOCOL_VERSION)),
+            (
+                Value::from("capabilities"),
+                Value::from(self.capabilities & SERVER_CAPABILITIES),
+            ),
+        ])
+    }
+}
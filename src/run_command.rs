@@ -0,0 +1,53 @@
+//! The named shell commands of the `commands` option, run by `run_command` in the
+//! directory of the cursor item
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellCommand {
+    pub name: String,
+    pub cmd: String,
+    // the output goes to a scratch buffer once the command is over
+    pub capture: bool,
+}
+
+/// Run `cmd` through the shell in `dir`, until it exits. The output is kept only with
+/// `capture`, otherwise it goes straight to the stdout and stderr of the backend
+pub fn run(cmd: &str, dir: &Path, capture: bool) -> std::io::Result<Output> {
+    let mut command = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    command.arg(cmd).current_dir(dir).stdin(Stdio::null());
+    if capture {
+        return command.output();
+    }
+    let status = command
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()?;
+    Ok(Output {
+        status,
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+    })
+}
+
+/// The lines of stdout then stderr, for the scratch buffer
+pub fn output_lines(output: &Output) -> Vec<String> {
+    let mut lines: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    lines.extend(
+        String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .map(str::to_owned),
+    );
+    lines
+}
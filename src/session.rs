@@ -0,0 +1,218 @@
+//! The state of the trees saved to `session_file`, msgpack encoded, so that a restarted
+//! neovim comes back with the same directories expanded. The file maps the `buffer_name`
+//! of each tree to its state, the frontend keeps the states as well, to replay them into a
+//! restarted backend
+use crate::errors::ArgError;
+use log::*;
+use nvim_rs::Value;
+use std::error::Error;
+use std::path::Path;
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub root: String,
+    // the expanded directories, least recently used first
+    pub expanded: Vec<String>,
+    // (root, cursor line), least recently used first
+    pub cursor_history: Vec<(String, u64)>,
+}
+
+fn strings(val: Option<&Value>) -> Vec<String> {
+    val.and_then(|v| v.as_array())
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|x| x.as_str().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl Session {
+    pub fn to_value(&self) -> Value {
+        Value::Map(vec![
+            (Value::from("root"), Value::from(self.root.as_str())),
+            (
+                Value::from("expanded"),
+                Value::Array(
+                    self.expanded
+                        .iter()
+                        .map(|x| Value::from(x.as_str()))
+                        .collect(),
+                ),
+            ),
+            (
+                Value::from("cursor_history"),
+                Value::Array(
+                    self.cursor_history
+                        .iter()
+                        .map(|(path, line)| {
+                            Value::Array(vec![Value::from(path.as_str()), Value::from(*line)])
+                        })
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // writing to a Vec can't fail
+        rmpv::encode::write_value(&mut bytes, &self.to_value()).unwrap();
+        bytes
+    }
+
+    pub fn from_value(val: &Value) -> Result<Self, Box<dyn Error>> {
+        let map = match val {
+            Value::Map(m) => m,
+            _ => return Err(Box::new(ArgError::new("session: map expected"))),
+        };
+        let get = |key: &str| {
+            map.iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, v)| v)
+        };
+        let cursor_history = get("cursor_history")
+            .and_then(|v| v.as_array())
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|entry| {
+                        let entry = entry.as_array()?;
+                        Some((entry.get(0)?.as_str()?.to_owned(), entry.get(1)?.as_u64()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Self {
+            root: get("root")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_owned(),
+            expanded: strings(get("expanded")),
+            cursor_history,
+        })
+    }
+}
+
+/// The states in the file, by buffer name. A file written before the states were keyed
+/// holds the one of the default tree
+fn decode_all(mut bytes: &[u8]) -> Result<Vec<(String, Session)>, Box<dyn Error>> {
+    let val = rmpv::decode::read_value(&mut bytes)?;
+    let map = match &val {
+        Value::Map(m) => m,
+        _ => return Err(Box::new(ArgError::new("session: map expected"))),
+    };
+    if map
+        .iter()
+        .any(|(k, v)| k.as_str() == Some("root") && v.is_str())
+    {
+        return Ok(vec![("default".to_owned(), Session::from_value(&val)?)]);
+    }
+    let mut sessions = Vec::new();
+    for (k, v) in map {
+        if let Some(name) = k.as_str() {
+            sessions.push((name.to_owned(), Session::from_value(v)?));
+        }
+    }
+    Ok(sessions)
+}
+
+fn encode_all(sessions: &[(String, Session)]) -> Vec<u8> {
+    let val = Value::Map(
+        sessions
+            .iter()
+            .map(|(name, session)| (Value::from(name.as_str()), session.to_value()))
+            .collect(),
+    );
+    let mut bytes = Vec::new();
+    // writing to a Vec can't fail
+    rmpv::encode::write_value(&mut bytes, &val).unwrap();
+    bytes
+}
+
+/// The state saved for the tree `name`, None when the file has none for it
+pub fn load(path: &Path, name: &str) -> Result<Option<Session>, Box<dyn Error>> {
+    Ok(decode_all(&std::fs::read(path)?)?
+        .into_iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, session)| session))
+}
+
+/// Replace the state of the tree `name`, the other trees keep theirs. Written to a
+/// temporary file first, a crash never leaves a truncated session behind. A file that
+/// can't be decoded is moved to `<file>.bak` rather than overwritten
+pub fn save(path: &Path, name: &str, session: &Session) -> std::io::Result<()> {
+    let mut sessions = match std::fs::read(path) {
+        Ok(bytes) => match decode_all(&bytes) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                let mut backup = path.as_os_str().to_owned();
+                backup.push(".bak");
+                warn!(
+                    "session {:?} is corrupt ({}), moved to {:?}",
+                    path, e, backup
+                );
+                std::fs::rename(path, &backup)?;
+                Vec::new()
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    match sessions.iter_mut().find(|(k, _)| k == name) {
+        Some((_, saved)) => *saved = session.clone(),
+        None => sessions.push((name.to_owned(), session.clone())),
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, encode_all(&sessions))?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("tree_nvim_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn sessions_by_buffer_name() {
+        let file = temp_file("session");
+        let left = Session {
+            root: String::from("/left"),
+            expanded: vec![String::from("/left/src")],
+            cursor_history: vec![(String::from("/left"), 3)],
+        };
+        let right = Session {
+            root: String::from("/right"),
+            ..Session::default()
+        };
+        save(&file, "left", &left).unwrap();
+        save(&file, "right", &right).unwrap();
+        assert_eq!(load(&file, "left").unwrap(), Some(left));
+        assert_eq!(load(&file, "right").unwrap(), Some(right));
+        assert_eq!(load(&file, "default").unwrap(), None);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn corrupt_file_backed_up() {
+        let file = temp_file("corrupt_session");
+        let mut backup = file.as_os_str().to_owned();
+        backup.push(".bak");
+        std::fs::write(&file, b"\xc1 not msgpack").unwrap();
+        let session = Session {
+            root: String::from("/root"),
+            ..Session::default()
+        };
+        save(&file, "default", &session).unwrap();
+        assert_eq!(load(&file, "default").unwrap(), Some(session));
+        assert_eq!(std::fs::read(&backup).unwrap(), b"\xc1 not msgpack");
+        std::fs::remove_file(&file).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+}
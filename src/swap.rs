@@ -0,0 +1,123 @@
+//! Vim swap files, the sign that a file is being edited by another (neo)vim
+use crate::tree::expand_path;
+use log::*;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// swap, swo, swn... vim counts down from swp when the name is taken
+fn is_swap_extension(ext: &str) -> bool {
+    ext.len() == 3 && ext.starts_with("sw") && ext.as_bytes()[2].is_ascii_lowercase()
+}
+
+/// The directories of the 'directory' option that name the swap files after the full
+/// path of the file (the ones ending with `//`)
+pub fn swap_dirs(option: &str) -> Vec<PathBuf> {
+    option
+        .split(',')
+        .filter(|dir| dir.ends_with("//"))
+        .map(|dir| PathBuf::from(expand_path(dir.trim_end_matches('/'))))
+        .collect()
+}
+
+/// file => swap file, for the swap files kept in `dirs`
+pub fn scan(dirs: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    let mut swaps = HashMap::new();
+    for dir in dirs {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|x| x.ok()) {
+            let swap = entry.path();
+            if !swap
+                .extension()
+                .and_then(|x| x.to_str())
+                .map_or(false, is_swap_extension)
+            {
+                continue;
+            }
+            // %home%user%file.txt.swp
+            let name = match swap.file_stem().and_then(|x| x.to_str()) {
+                Some(n) if n.starts_with('%') => n,
+                _ => continue,
+            };
+            swaps.insert(PathBuf::from(name.replace('%', "/")), swap.clone());
+        }
+    }
+    debug!("{} swap files found", swaps.len());
+    swaps
+}
+
+/// file => swap file, for the swap files vim puts next to the files of `dir` by default,
+/// a single listing rather than a stat per file and extension
+pub fn local_swaps(dir: &Path) -> HashMap<PathBuf, PathBuf> {
+    let mut swaps = HashMap::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return swaps,
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let name = entry.file_name();
+        // .file.txt.swp
+        let name = match name.to_str() {
+            Some(n) if n.starts_with('.') => n,
+            _ => continue,
+        };
+        let (stem, ext) = match name[1..].rfind('.') {
+            Some(pos) => (&name[1..pos + 1], &name[pos + 2..]),
+            None => continue,
+        };
+        if stem.is_empty() || !is_swap_extension(ext) {
+            continue;
+        }
+        // swp wins over the swo and swn of older sessions
+        let file = dir.join(stem);
+        let keep = swaps.get(&file).map_or(true, |old: &PathBuf| {
+            old.extension().and_then(|x| x.to_str()) < Some(ext)
+        });
+        if keep {
+            swaps.insert(file, entry.path());
+        }
+    }
+    swaps
+}
+
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|h| h.trim().to_owned())
+}
+
+#[cfg(unix)]
+fn pid_alive(pid: u32) -> bool {
+    // signal 0 only checks the process exists, EPERM means it does but isn't ours
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn pid_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Whether the vim that owns the swap file is still running, a swap file of another host
+/// or one that can't be read is assumed to be in use
+pub fn is_in_use(swap: &Path) -> bool {
+    // block 0: id[2] version[10] page_size[4] mtime[4] ino[4] pid[4] uname[40] hname[40]
+    let mut block0 = [0u8; 108];
+    let read = std::fs::File::open(swap).and_then(|mut f| f.read_exact(&mut block0));
+    if read.is_err() {
+        return true;
+    }
+    let host = String::from_utf8_lossy(&block0[68..108])
+        .trim_end_matches('\0')
+        .to_owned();
+    if let Some(ours) = hostname() {
+        if !host.is_empty() && host != ours {
+            return true;
+        }
+    }
+    let pid = u32::from_le_bytes([block0[24], block0[25], block0[26], block0[27]]);
+    pid != 0 && pid_alive(pid)
+}
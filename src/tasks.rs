@@ -0,0 +1,43 @@
+//! Background tasks owned by a tree (or by the handler), cancelled together when the
+//! owner goes away instead of outliving the buffer they target
+use async_std::task::JoinHandle;
+use log::*;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default, Debug)]
+pub struct TaskSet {
+    next_id: AtomicU64,
+    // the running tasks, a task removes itself when it completes
+    handles: Arc<Mutex<HashMap<u64, JoinHandle<()>>>>,
+}
+
+impl TaskSet {
+    pub fn spawn<F>(&self, fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let handles = self.handles.clone();
+        // held until the handle is stored, a task done right away can't be left behind
+        let mut running = self.handles.lock().unwrap();
+        let handle = async_std::task::spawn(async move {
+            fut.await;
+            handles.lock().unwrap().remove(&id);
+        });
+        running.insert(id, handle);
+    }
+
+    /// Cancel the running tasks, they're dropped at their next await point
+    pub async fn cancel_all(&self) {
+        let handles: Vec<_> = self.handles.lock().unwrap().drain().collect();
+        if !handles.is_empty() {
+            debug!("cancelling {} tasks", handles.len());
+        }
+        for (_, handle) in handles {
+            handle.cancel().await;
+        }
+    }
+}
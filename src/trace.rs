@@ -0,0 +1,48 @@
+//! Timing spans around the scan, render and RPC phases, collected only with
+//! LOG_LEVEL=trace. Their durations go to LOG_FILE as they close, and to a chrome trace
+//! (chrome://tracing, perfetto, inferno) when TRACE_FILE is set
+use std::env;
+use std::sync::Mutex;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+
+/// Install the span collectors, the chrome trace is written when the guard is dropped
+pub fn init() -> Result<Option<FlushGuard>, Box<dyn std::error::Error>> {
+    let enabled = env::var("LOG_LEVEL").map_or(false, |level| level.eq_ignore_ascii_case("trace"));
+    if !enabled {
+        return Ok(None);
+    }
+    let fmt_layer = match env::var("LOG_FILE") {
+        Ok(path) => {
+            let log_file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(Mutex::new(log_file))
+                    .with_span_events(FmtSpan::CLOSE),
+            )
+        }
+        Err(_) => None,
+    };
+    let (chrome_layer, guard) = match env::var("TRACE_FILE") {
+        Ok(path) => {
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .file(path)
+                .include_args(true)
+                .build();
+            (Some(layer), Some(guard))
+        }
+        Err(_) => (None, None),
+    };
+    // not `init()`, the `log` records already go to simplelog
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(chrome_layer),
+    )?;
+    Ok(guard)
+}
@@ -0,0 +1,165 @@
+#[cfg(any(unix, windows))]
+use log::*;
+use std::io;
+use std::path::Path;
+#[cfg(all(unix, not(target_os = "macos")))]
+use std::path::PathBuf;
+
+/// Move `path` to the trash of the desktop, so that it can be restored from the file manager
+#[cfg(target_os = "macos")]
+pub fn move_to_trash(path: &Path) -> io::Result<()> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "non UTF-8 path"))?;
+    let script = format!(
+        "tell application \"Finder\" to delete POSIX file \"{}\"",
+        path.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let output = std::process::Command::new("osascript")
+        .arg("-e")
+        .arg(script)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    info!("trashed {:?}", path);
+    Ok(())
+}
+
+/// $XDG_DATA_HOME/Trash, see the freedesktop.org trash specification
+#[cfg(all(unix, not(target_os = "macos")))]
+fn trash_dir() -> io::Result<PathBuf> {
+    let data_home = match std::env::var("XDG_DATA_HOME") {
+        Ok(d) if !d.is_empty() => PathBuf::from(d),
+        _ => match std::env::var("HOME") {
+            Ok(home) => Path::new(&home).join(".local/share"),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::NotFound, "HOME is not set")),
+        },
+    };
+    Ok(data_home.join("Trash"))
+}
+
+/// Paths in .trashinfo files are percent-encoded like URLs, '/' is kept
+#[cfg(all(unix, not(target_os = "macos")))]
+fn url_encode(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut encoded = String::new();
+    for b in path.as_os_str().as_bytes() {
+        match *b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(*b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn move_to_trash(path: &Path) -> io::Result<()> {
+    use std::io::Write;
+    let trash = trash_dir()?;
+    let files_dir = trash.join("files");
+    let info_dir = trash.join("info");
+    std::fs::create_dir_all(&files_dir)?;
+    std::fs::create_dir_all(&info_dir)?;
+
+    let name = match path.file_name().and_then(|x| x.to_str()) {
+        Some(n) => n.to_owned(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no file name")),
+    };
+    // the info file is created exclusively first, it reserves the name in files/
+    let mut n = 1;
+    let (trash_name, mut info) = loop {
+        let candidate = if n == 1 {
+            name.clone()
+        } else {
+            format!("{}.{}", name, n)
+        };
+        let info_path = info_dir.join(format!("{}.trashinfo", candidate));
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(f) if !files_dir.join(&candidate).exists() => break (candidate, f),
+            Ok(_) => {
+                std::fs::remove_file(&info_path)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        n += 1;
+    };
+    write!(
+        info,
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        url_encode(path),
+        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S")
+    )?;
+
+    let dest = files_dir.join(&trash_name);
+    if let Err(e) = std::fs::rename(path, &dest) {
+        // another file system, copy then delete
+        debug!("rename to trash failed, copying: {:?}", e);
+        let moved = if path.is_dir() {
+            let mut options = fs_extra::dir::CopyOptions::new();
+            options.copy_inside = true;
+            fs_extra::dir::move_dir(path, &dest, &options).map(|_| ())
+        } else {
+            fs_extra::file::move_file(path, &dest, &fs_extra::file::CopyOptions::new()).map(|_| ())
+        };
+        if let Err(e) = moved {
+            let _ = std::fs::remove_file(info_dir.join(format!("{}.trashinfo", trash_name)));
+            return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+        }
+    }
+    info!("trashed {:?} as {:?}", path, dest);
+    Ok(())
+}
+
+/// The Recycle Bin, through the shell so that it can be restored from the Explorer
+#[cfg(windows)]
+pub fn move_to_trash(path: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::um::shellapi::{
+        SHFileOperationW, FOF_ALLOWUNDO, FOF_NOCONFIRMATION, FOF_NOERRORUI, FOF_SILENT, FO_DELETE,
+        SHFILEOPSTRUCTW,
+    };
+    // a list of null terminated paths, ended by another null
+    let mut from: Vec<u16> = path.as_os_str().encode_wide().collect();
+    from.extend_from_slice(&[0, 0]);
+    let mut op = SHFILEOPSTRUCTW {
+        hwnd: std::ptr::null_mut(),
+        wFunc: FO_DELETE as u32,
+        pFrom: from.as_ptr(),
+        pTo: std::ptr::null(),
+        fFlags: FOF_ALLOWUNDO | FOF_NOCONFIRMATION | FOF_NOERRORUI | FOF_SILENT,
+        fAnyOperationsAborted: 0,
+        hNameMappings: std::ptr::null_mut(),
+        lpszProgressTitle: std::ptr::null(),
+    };
+    let ret = unsafe { SHFileOperationW(&mut op) };
+    if ret != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("SHFileOperationW failed: {:#x}", ret),
+        ));
+    }
+    if op.fAnyOperationsAborted != 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "trash aborted"));
+    }
+    info!("trashed {:?}", path);
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn move_to_trash(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "trash is not supported on this platform",
+    ))
+}
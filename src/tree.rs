@@ -1,6 +1,21 @@
+use crate::chmod;
 use crate::column::ColumnType;
-use crate::column::{ColumnCell, FileItem, FileItemPtr};
-use crate::errors::ArgError;
+use crate::column::{
+    dim_hl_group, git_status_name, icon_override_commands, short_size, ColumnCell, ColumnRenderer,
+    FileItem, FileItemPtr, IconOverride, SelectionState,
+};
+use crate::copy_job;
+use crate::errors::{copy_error, path_error, ArgError, NvimCallError, TreeError};
+use crate::fs_retry;
+use crate::matcher::Matcher;
+use crate::mounts;
+use crate::nvim_call;
+use crate::open_with;
+use crate::run_command::{self, ShellCommand};
+use crate::session::{self, Session};
+use crate::swap;
+use crate::tasks::TaskSet;
+use crate::trash;
 use async_std::sync::{Arc, Mutex, RwLock};
 use fs_extra;
 use futures::io::AsyncWrite;
@@ -11,27 +26,360 @@ use nvim_rs::{
     Neovim, Value,
 };
 use path_clean::PathClean;
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::convert::From;
+use std::convert::TryFrom;
 use std::env;
 use std::fmt;
 use std::fmt::Debug;
+use std::future::Future;
 use std::io;
-use std::path::{Path, PathBuf};
-use unicode_width::UnicodeWidthStr;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::pin::Pin;
+use tracing::instrument;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 pub fn absolute_path<P>(path: P) -> io::Result<PathBuf>
 where
     P: AsRef<Path>,
 {
     let path = path.as_ref();
-    if path.is_absolute() {
-        Ok(path.to_path_buf().clean())
+    let path = if path.is_absolute() {
+        path.to_path_buf().clean()
     } else {
-        Ok(env::current_dir()?.join(path).clean())
+        env::current_dir()?.join(path).clean()
+    };
+    Ok(normalize_path(path))
+}
+
+/// `\\?\C:\dir` => `C:\dir` and `\\?\UNC\server\share` => `\\server\share`, the
+/// verbatim paths canonicalize and some shells hand out. None when `path` isn't one
+#[cfg_attr(not(windows), allow(dead_code))]
+fn strip_verbatim(path: &str) -> Option<String> {
+    let rest = path.strip_prefix(r"\\?\")?;
+    match rest.get(..4) {
+        Some(unc) if unc.eq_ignore_ascii_case(r"UNC\") => Some(format!(r"\\{}", &rest[4..])),
+        _ if rest.as_bytes().get(1) == Some(&b':') => Some(rest.to_owned()),
+        // the verbatim paths of devices and volumes have no other form
+        _ => None,
+    }
+}
+
+/// A single spelling of the paths on windows, they are the keys of the expansion state
+/// and the caches: no verbatim prefix, backslashes, the drive letter upper case
+#[cfg(windows)]
+fn normalize_path(path: PathBuf) -> PathBuf {
+    let mut s = match path.to_str() {
+        Some(s) => s.to_owned(),
+        None => return path,
+    };
+    if let Some(stripped) = strip_verbatim(&s) {
+        s = stripped;
+    }
+    s = s.replace('/', "\\");
+    if s.as_bytes().get(1) == Some(&b':') {
+        s[..1].make_ascii_uppercase();
+    }
+    PathBuf::from(s)
+}
+
+#[cfg(not(windows))]
+fn normalize_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// The directory `cd ..` goes to, None at the root of a drive or of a UNC share
+fn parent_dir(path: &Path) -> Option<&Path> {
+    path.parent().filter(|p| !p.as_os_str().is_empty())
+}
+
+fn is_hidden_name(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Shell style wildcard match of a whole file name, `*` and `?`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // the last `*` seen and the name position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            // let the `*` take one more character
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// `*.pyc,node_modules,target` => the patterns
+pub fn parse_ignored(spec: &str) -> Vec<String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Expand a leading `~` and `$VAR`/`${VAR}` references, unknown variables are left as is
+pub fn expand_path(path: &str) -> String {
+    let path = match (path.starts_with('~'), env::var("HOME")) {
+        (true, Ok(home)) if path == "~" || path.starts_with("~/") => path.replacen('~', &home, 1),
+        _ => path.to_owned(),
+    };
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path.as_str();
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let (name, consumed) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) => (&rest[1..end], end + 1),
+                None => ("", 0),
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or_else(|| rest.len());
+            (&rest[..end], end)
+        };
+        match env::var(name) {
+            Ok(v) if !name.is_empty() => expanded.push_str(&v),
+            _ => {
+                expanded.push('$');
+                expanded.push_str(&rest[..consumed]);
+            }
+        }
+        rest = &rest[consumed..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Completion candidates for `partial`, relative paths are resolved against `cwd`.
+/// Directories come first and carry a trailing '/'
+pub fn complete_path(
+    partial: &str,
+    cwd: &str,
+    show_ignored_files: bool,
+    dirs_only: bool,
+) -> Vec<String> {
+    // split at the last separator, "foo/ba" => ("foo/", "ba")
+    let (dir_part, prefix) = match partial.rfind('/') {
+        Some(pos) => partial.split_at(pos + 1),
+        None => ("", partial),
+    };
+    let dir = Path::new(cwd).join(expand_path(dir_part));
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("complete_path: can't read {:?}: {:?}", dir, e);
+            return Vec::new();
+        }
+    };
+    let mut candidates: Vec<(bool, String)> = entries
+        .filter_map(|x| x.ok())
+        .filter_map(|x| {
+            let name = x.file_name().to_str()?.to_owned();
+            if !name.starts_with(prefix) {
+                return None;
+            }
+            // hidden entries are only offered when asked for explicitly
+            if is_hidden_name(&name) && !show_ignored_files && !is_hidden_name(prefix) {
+                return None;
+            }
+            let is_dir = x.path().is_dir();
+            if dirs_only && !is_dir {
+                return None;
+            }
+            Some((is_dir, name))
+        })
+        .collect();
+    candidates.sort_by(|l, r| match (l.0, r.0) {
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        _ => l.1.cmp(&r.1),
+    });
+    candidates
+        .into_iter()
+        .map(|(is_dir, name)| {
+            let mut candidate = format!("{}{}", dir_part, name);
+            if is_dir {
+                candidate.push('/');
+            }
+            candidate
+        })
+        .collect()
+}
+
+/// Level of the messages sent to `vim.notify`, the variants are ordered by severity
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum NotifyLevel {
+    INFO,
+    WARN,
+    ERROR,
+}
+
+impl NotifyLevel {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "info" => Some(NotifyLevel::INFO),
+            "warn" => Some(NotifyLevel::WARN),
+            "error" => Some(NotifyLevel::ERROR),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            NotifyLevel::INFO => "info",
+            NotifyLevel::WARN => "warn",
+            NotifyLevel::ERROR => "error",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum SortKey {
+    FILENAME,
+    EXTENSION,
+    SIZE,
+    TIME,
+}
+
+impl SortKey {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "filename" => Some(SortKey::FILENAME),
+            "extension" => Some(SortKey::EXTENSION),
+            "size" => Some(SortKey::SIZE),
+            "time" => Some(SortKey::TIME),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SortKey::FILENAME => "filename",
+            SortKey::EXTENSION => "extension",
+            SortKey::SIZE => "size",
+            SortKey::TIME => "time",
+        }
+    }
+
+    /// The key a column is sorted by, none for the columns without a value to compare
+    fn for_column(col: &ColumnType) -> Option<Self> {
+        match col {
+            ColumnType::FILENAME => Some(SortKey::FILENAME),
+            ColumnType::ICON => Some(SortKey::EXTENSION),
+            ColumnType::SIZE | ColumnType::SIZE_BAR => Some(SortKey::SIZE),
+            ColumnType::TIME => Some(SortKey::TIME),
+            _ => None,
+        }
+    }
+
+    fn compare(
+        &self,
+        l: &(PathBuf, std::fs::Metadata),
+        r: &(PathBuf, std::fs::Metadata),
+    ) -> Ordering {
+        match self {
+            SortKey::FILENAME => l.0.file_name().cmp(&r.0.file_name()),
+            SortKey::EXTENSION => l.0.extension().cmp(&r.0.extension()),
+            SortKey::SIZE => l.1.len().cmp(&r.1.len()),
+            SortKey::TIME => l.1.modified().ok().cmp(&r.1.modified().ok()),
+        }
+    }
+}
+
+/// Parse a compound sort spec like `-time,filename`, a leading '-' reverses the key
+pub fn parse_sort(spec: &str) -> Result<Vec<(SortKey, bool)>, ArgError> {
+    let mut keys = Vec::new();
+    for key in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let (name, reverse) = match key.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (key, false),
+        };
+        match SortKey::from_str(name) {
+            Some(k) => keys.push((k, reverse)),
+            None => return Err(ArgError::from_string(format!("Unknown sort key: {}", name))),
+        }
+    }
+    Ok(keys)
+}
+
+/// The spec parse_sort reads back
+pub fn sort_spec(keys: &[(SortKey, bool)]) -> String {
+    keys.iter()
+        .map(|(key, reverse)| format!("{}{}", if *reverse { "-" } else { "" }, key.name()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The actions rejected by a readonly tree, move included since the paste takes the files
+/// away from it. run_command is too, nothing tells what the command does to the files,
+/// while execute_system only opens them
+const MUTATING_ACTIONS: &[&str] = &[
+    "new_file",
+    "rename",
+    "duplicate",
+    "remove",
+    "remove_trash",
+    "move",
+    "paste",
+    "purge_marked",
+    "delete_swap",
+    "change_mode",
+    "git_restore",
+    "run_command",
+];
+
+/// Take the `+line` or `+line:col` argument of drop and open out of `args`, the position
+/// is sent to lua as [line, col] (col 0 when missing) or nil
+fn split_position(args: &Value) -> Result<(Vec<Value>, Value), ArgError> {
+    let args = match args {
+        Value::Array(args) => args.clone(),
+        Value::Nil => Vec::new(),
+        v => vec![v.clone()],
+    };
+    let mut rest = Vec::new();
+    let mut pos = Value::Nil;
+    for arg in args {
+        let spec = match arg.as_str().and_then(|x| x.strip_prefix('+')) {
+            Some(spec) => spec.to_owned(),
+            None => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        let mut parts = spec.splitn(2, ':');
+        let line = parts.next().unwrap_or_default().parse::<u64>();
+        let col = parts.next().map_or(Ok(0), |x| x.parse::<u64>());
+        match (line, col) {
+            (Ok(line), Ok(col)) => pos = Value::Array(vec![Value::from(line), Value::from(col)]),
+            _ => {
+                return Err(ArgError::from_string(format!(
+                    "invalid position +{}, expected +line or +line:col",
+                    spec
+                )))
+            }
+        }
     }
+    Ok((rest, pos))
 }
 
 #[derive(Default, Debug, Clone)]
@@ -41,9 +389,81 @@ pub struct Context {
     pub visual_start: u64,
     pub visual_end: u64,
     pub prev_bufnr: Option<Value>,
+    // the tree buffer the action targets, when it's not the current one
+    pub bufnr: Option<Value>,
+    pub count: u64,
+    // (line, column) of the last mouse click, 1-based
+    pub mouse: Option<(u64, u64)>,
+    // display column of the cursor, 1-based
+    pub cursor_col: u64,
+}
+
+/// Context of the actions operating on the item under the cursor
+#[derive(Debug, Clone)]
+pub struct CursorContext {
+    // zero-based index of the cursor item
+    pub idx: usize,
+    pub count: usize,
+}
+
+impl TryFrom<&Context> for CursorContext {
+    type Error = ArgError;
+
+    fn try_from(ctx: &Context) -> Result<Self, Self::Error> {
+        if ctx.cursor == 0 {
+            return Err(ArgError::new("Context: cursor is missing"));
+        }
+        Ok(Self {
+            idx: ctx.cursor as usize - 1,
+            count: std::cmp::max(ctx.count, 1) as usize,
+        })
+    }
+}
+
+/// Context of the actions triggered by the mouse
+#[derive(Debug, Clone)]
+pub struct MouseContext {
+    // zero-based index of the clicked item
+    pub idx: usize,
+    pub column: usize,
+}
+
+impl TryFrom<&Context> for MouseContext {
+    type Error = ArgError;
+
+    fn try_from(ctx: &Context) -> Result<Self, Self::Error> {
+        match ctx.mouse {
+            Some((line, column)) if line > 0 => Ok(Self {
+                idx: line as usize - 1,
+                column: column as usize,
+            }),
+            _ => Err(ArgError::new("Context: mouse position is missing")),
+        }
+    }
 }
 
 impl Context {
+    /// Build the context from the map sent by the lua side
+    pub fn from_map(map: Vec<(Value, Value)>) -> Result<Self, ArgError> {
+        let mut ctx = Context::default();
+        for (k, v) in map {
+            let key = match k {
+                Value::String(v) => match v.into_str() {
+                    Some(vv) => vv,
+                    None => return Err(ArgError::new("Context: key is not valid utf8")),
+                },
+                _ => {
+                    return Err(ArgError::from_string(format!(
+                        "Context: key should be of type string, but {:?} found",
+                        k
+                    )))
+                }
+            };
+            ctx.update(&key, v);
+        }
+        Ok(ctx)
+    }
+
     pub fn update(&mut self, key: &str, val: Value) {
         match key {
             "cursor" => match val {
@@ -85,6 +505,43 @@ impl Context {
                     error!("Unknown value: {}", val);
                 }
             },
+            "count" => match val.as_u64() {
+                Some(v) => self.count = v,
+                None => error!("Can't convert value {} to u64", val),
+            },
+            "cursor_col" => match val.as_u64() {
+                Some(v) => self.cursor_col = v,
+                None => error!("Can't convert value {} to u64", val),
+            },
+            "mouse" => match val {
+                Value::Map(m) => {
+                    let get = |name: &str| {
+                        m.iter()
+                            .find(|(k, _)| k.as_str() == Some(name))
+                            .and_then(|(_, v)| v.as_u64())
+                    };
+                    if let (Some(line), Some(column)) = (get("line"), get("column")) {
+                        self.mouse = Some((line, column));
+                    }
+                }
+                _ => {
+                    error!("Unknown value: {:?}", val);
+                }
+            },
+            "prev_bufnr" => match val {
+                Value::Integer(_) | Value::Ext(_, _) => self.prev_bufnr = Some(val),
+                _ => {
+                    error!("Unknown value: {}", val);
+                }
+            },
+            "bufnr" => match val {
+                Value::Integer(_) | Value::Ext(_, _) => self.bufnr = Some(val),
+                _ => {
+                    error!("Unknown value: {}", val);
+                }
+            },
+            // only used by the lua side
+            "prev_winid" => {}
             _ => {
                 warn!("Context: Unsupported member: {}", key);
             }
@@ -100,22 +557,96 @@ pub enum ClipboardMode {
 static CLIPBOARD_MODE: RwLock<ClipboardMode> = RwLock::new(ClipboardMode::COPY);
 static CLIPBOARD: RwLock<Vec<std::path::PathBuf>> = RwLock::new(Vec::new());
 
+/// The items that are going to be moved by the next paste, empty in copy mode
+pub async fn clipboard_cut_items() -> HashSet<PathBuf> {
+    match *CLIPBOARD_MODE.read().await {
+        ClipboardMode::MOVE => CLIPBOARD.read().await.iter().cloned().collect(),
+        ClipboardMode::COPY => HashSet::new(),
+    }
+}
+
 // State parameters for Tree
 #[derive(Debug)]
 pub struct Config {
     pub auto_cd: bool,
     pub auto_recursive_level: u16,
     pub columns: Vec<ColumnType>,
+    // comma separated wildcard patterns of the names hidden unless show_ignored_files
     pub ignored_files: String,
+    pub ignored_patterns: Vec<String>,
     pub show_ignored_files: bool,
     pub profile: bool,
     pub root_marker: String,
 
+    // the filter, only the matching entries and their ancestors are listed
     pub search: String,
+    // filter, search and select_pattern turn case sensitive for the patterns with an
+    // uppercase letter
+    pub smart_case: bool,
     pub session_file: String,
+    // the state of the tree is kept under this name in session_file
+    pub buffer_name: String,
     pub sort: String,
+    pub sort_keys: Vec<(SortKey, bool)>,
 
     pub listed: bool,
+    pub highlight_cursor_item: bool,
+    pub lsp_rename: bool,
+    // the actions changing the file system are rejected, run_command included, for trees
+    // embedded as browsers
+    pub readonly: bool,
+    pub auto_fit_width: bool,
+    pub truncate_filename: bool,
+    pub min_width: u16,
+    pub max_width: u16,
+    // directories expanded automatically when the tree is created, as long as the
+    // number of displayed items stays below initial_expand_max_items
+    pub initial_expand_level: u16,
+    pub initial_expand_max_items: usize,
+    // directories list this many entries at first, load_more lists the next chunk, 0 lists
+    // everything
+    pub dir_chunk_size: usize,
+    // selecting a directory implicitly selects its contents
+    pub select_recursive: bool,
+    // an overwritten file keeps its permissions instead of taking the source's
+    pub preserve_permissions: bool,
+    // remove moves to the trash, `remove true` still deletes for good
+    pub trash: bool,
+    // file system calls failing transiently are tried again this many times
+    pub fs_retries: usize,
+    // the displayed directories are polled, the entries appearing in them are listed and
+    // highlighted for this many seconds, 0 turns the polling off
+    pub flash_new_files: u16,
+    // open_external_manager runs this command with the directory appended, the
+    // platform's file manager when empty, in a terminal split for the terminal ones
+    pub file_manager: String,
+    pub file_manager_terminal: bool,
+    // extension (or folder_closed, folder_opened, folder_symlink, default) => the glyph
+    // used in place of the built-in one
+    pub icons: HashMap<String, IconOverride>,
+    // the directories quick_cd offers, `~` and `$VAR` are expanded when jumping
+    pub quick_dirs: Vec<String>,
+    // the named shell commands run_command offers
+    pub commands: Vec<ShellCommand>,
+    // the directory, file and selection counts as a virtual line under the root ("root")
+    // or after the last item ("bottom"), none when empty
+    pub summary_footer: String,
+    // the ancestors of the root as clickable segments in the winbar of the tree windows
+    pub breadcrumbs: bool,
+    // smart_expand keeps descending into directories with at most this many entries
+    pub smart_expand_threshold: usize,
+    // messages below this level are not shown
+    pub notify_level: NotifyLevel,
+    // {name = value}, set on the tree buffers and on the windows showing them
+    pub buffer_options: Vec<(String, Value)>,
+    pub window_options: Vec<(String, Value)>,
+    // lhs => action and its arguments, the normal mode mappings of the tree buffers, the
+    // ones of `tree.keymap` win over them
+    pub keymaps: Vec<(String, String, Vec<Value>)>,
+
+    // upper bounds of the per-tree caches, the least recently used entries are evicted first
+    pub cursor_history_limit: usize,
+    pub expand_store_limit: usize,
 }
 
 impl Default for Config {
@@ -133,14 +664,55 @@ impl Default for Config {
                 ColumnType::TIME,
             ],
             ignored_files: String::new(),
+            ignored_patterns: Vec::new(),
             show_ignored_files: false,
             profile: false,
             root_marker: "[in]: ".to_owned(),
             search: String::new(),
+            smart_case: false,
             session_file: String::new(),
+            buffer_name: "default".to_owned(),
             sort: String::new(),
+            sort_keys: Vec::new(),
 
             listed: false,
+            highlight_cursor_item: false,
+            lsp_rename: false,
+            readonly: false,
+            auto_fit_width: false,
+            truncate_filename: true,
+            min_width: 20,
+            max_width: 120,
+            initial_expand_level: 0,
+            initial_expand_max_items: 500,
+            dir_chunk_size: 1000,
+            select_recursive: false,
+            preserve_permissions: false,
+            trash: false,
+            fs_retries: 2,
+            flash_new_files: 0,
+            file_manager: String::new(),
+            file_manager_terminal: false,
+            quick_dirs: Vec::new(),
+            commands: Vec::new(),
+            icons: HashMap::new(),
+            summary_footer: String::new(),
+            breadcrumbs: false,
+            smart_expand_threshold: 3,
+            notify_level: NotifyLevel::INFO,
+            buffer_options: Vec::new(),
+            window_options: vec![
+                ("list".to_owned(), Value::from(false)),
+                ("number".to_owned(), Value::from(false)),
+                ("relativenumber".to_owned(), Value::from(false)),
+                ("signcolumn".to_owned(), Value::from("no")),
+                ("winfixwidth".to_owned(), Value::from(true)),
+                ("wrap".to_owned(), Value::from(false)),
+            ],
+            keymaps: Vec::new(),
+
+            cursor_history_limit: 100,
+            expand_store_limit: 1000,
         }
     }
 }
@@ -158,6 +730,19 @@ fn val_to_u16(v: &Value) -> Result<u16, Box<dyn std::error::Error>> {
     }
 }
 
+fn val_to_usize(v: &Value) -> Result<usize, Box<dyn std::error::Error>> {
+    if let Some(v_str) = v.as_str() {
+        Ok(v_str.parse::<usize>()?)
+    } else {
+        match v.as_u64() {
+            Some(v) => Ok(v as usize),
+            None => Err(Box::new(crate::errors::ArgError::new(
+                "Type mismatch: str u64",
+            ))),
+        }
+    }
+}
+
 fn val_to_string(v: &Value) -> Result<String, Box<dyn std::error::Error>> {
     if let Some(v_str) = v.as_str() {
         Ok(v_str.to_owned())
@@ -168,6 +753,182 @@ fn val_to_string(v: &Value) -> Result<String, Box<dyn std::error::Error>> {
     }
 }
 
+/// A list of strings, or a single comma separated string
+fn val_to_strings(v: &Value) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    match v {
+        Value::Array(arr) => arr.iter().map(val_to_string).collect(),
+        // an empty lua table
+        Value::Map(m) if m.is_empty() => Ok(Vec::new()),
+        _ => Ok(val_to_string(v)?
+            .split(',')
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty())
+            .collect()),
+    }
+}
+
+/// {key = {glyph = ..., color = ...}} or {key = glyph}
+fn val_to_icons(v: &Value) -> Result<HashMap<String, IconOverride>, Box<dyn std::error::Error>> {
+    let map = match v {
+        Value::Map(m) => m,
+        // an empty lua table
+        Value::Array(arr) if arr.is_empty() => return Ok(HashMap::new()),
+        _ => return Err(Box::new(ArgError::new("icons: table expected"))),
+    };
+    let mut icons = HashMap::new();
+    for (k, v) in map {
+        let key = val_to_string(k)?;
+        let icon = match v {
+            Value::String(_) => IconOverride {
+                glyph: val_to_string(v)?,
+                color: None,
+            },
+            Value::Map(fields) => {
+                let get = |name: &str| {
+                    fields
+                        .iter()
+                        .find(|(k, _)| k.as_str() == Some(name))
+                        .and_then(|(_, v)| v.as_str())
+                        .map(str::to_owned)
+                };
+                IconOverride {
+                    glyph: get("glyph").ok_or_else(|| {
+                        ArgError::from_string(format!("icons: no glyph for {}", key))
+                    })?,
+                    color: get("color"),
+                }
+            }
+            _ => {
+                return Err(Box::new(ArgError::from_string(format!(
+                    "icons: {} should be a glyph or {{glyph, color}}",
+                    key
+                ))))
+            }
+        };
+        icons.insert(key, icon);
+    }
+    Ok(icons)
+}
+
+/// {name = cmd} or {name = {cmd = ..., capture = ...}}, listed by name, or a list of
+/// {name = ..., cmd = ..., capture = ...} listed in order
+fn val_to_commands(v: &Value) -> Result<Vec<ShellCommand>, Box<dyn std::error::Error>> {
+    let field = |fields: &Vec<(Value, Value)>, name: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(name))
+            .map(|(_, v)| v.clone())
+    };
+    let command = |name: Option<String>, v: &Value| -> Result<_, Box<dyn std::error::Error>> {
+        let (name, cmd, capture) = match v {
+            Value::String(_) => (name, Some(val_to_string(v)?), false),
+            Value::Map(fields) => (
+                name.or(field(fields, "name").and_then(|n| n.as_str().map(str::to_owned))),
+                field(fields, "cmd").and_then(|c| c.as_str().map(str::to_owned)),
+                match field(fields, "capture") {
+                    Some(c) => val_to_bool(&c)?,
+                    None => false,
+                },
+            ),
+            _ => (name, None, false),
+        };
+        match (name, cmd) {
+            (Some(name), Some(cmd)) => Ok(ShellCommand { name, cmd, capture }),
+            _ => Err(Box::new(ArgError::from_string(format!(
+                "commands: {:?} needs a name and a cmd",
+                v
+            )))),
+        }
+    };
+    match v {
+        Value::Array(arr) => arr.iter().map(|c| command(None, c)).collect(),
+        Value::Map(m) => {
+            let mut commands = m
+                .iter()
+                .map(|(k, c)| command(Some(val_to_string(k)?), c))
+                .collect::<Result<Vec<_>, _>>()?;
+            commands.sort_by(|l, r| l.name.cmp(&r.name));
+            Ok(commands)
+        }
+        _ => Err(Box::new(ArgError::new("commands: table expected"))),
+    }
+}
+
+/// {{name, value}, ...}, the options as the frontend applies them
+pub fn options_value(options: &[(String, Value)]) -> Value {
+    Value::Array(
+        options
+            .iter()
+            .map(|(name, value)| Value::Array(vec![Value::from(name.as_str()), value.clone()]))
+            .collect(),
+    )
+}
+
+/// {name = value}, in the name order
+fn val_to_options(v: &Value) -> Result<Vec<(String, Value)>, Box<dyn std::error::Error>> {
+    let map = match v {
+        Value::Map(m) => m,
+        // an empty lua table
+        Value::Array(arr) if arr.is_empty() => return Ok(Vec::new()),
+        _ => return Err(Box::new(ArgError::new("options: table expected"))),
+    };
+    let mut options = map
+        .iter()
+        .map(|(k, v)| Ok((val_to_string(k)?, v.clone())))
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    options.sort_by(|l, r| l.0.cmp(&r.0));
+    Ok(options)
+}
+
+/// {lhs = action} or {lhs = {action, args...}}, the arguments being strings, numbers or
+/// booleans
+fn val_to_keymaps(
+    v: &Value,
+) -> Result<Vec<(String, String, Vec<Value>)>, Box<dyn std::error::Error>> {
+    let mut keymaps = Vec::new();
+    for (lhs, action) in val_to_options(v)? {
+        let (action, args) = match &action {
+            Value::String(_) => (val_to_string(&action)?, Vec::new()),
+            Value::Array(arr) if !arr.is_empty() => (val_to_string(&arr[0])?, arr[1..].to_vec()),
+            _ => {
+                return Err(Box::new(ArgError::from_string(format!(
+                    "keymaps: {} should be an action or {{action, args...}}",
+                    lhs
+                ))))
+            }
+        };
+        if args
+            .iter()
+            .any(|a| !(a.is_str() || a.is_number() || a.is_bool()))
+        {
+            return Err(Box::new(ArgError::from_string(format!(
+                "keymaps: the arguments of {} should be strings, numbers or booleans",
+                lhs
+            ))));
+        }
+        keymaps.push((lhs, action, args));
+    }
+    Ok(keymaps)
+}
+
+/// The right hand side of a `keymaps` mapping, running the action like the mappings of
+/// `tree.keymap` do
+fn keymap_rhs(action: &str, args: &[Value]) -> String {
+    let args: Vec<String> = args
+        .iter()
+        .map(|a| match a.as_str() {
+            Some(s) => json_string(s),
+            None => a.to_string(),
+        })
+        .collect();
+    // `<` would start a key name
+    format!(
+        "<Cmd>lua call_async_action({}, {{{}}})<CR>",
+        json_string(action).replace('<', "<lt>"),
+        args.join(", ").replace('<', "<lt>")
+    )
+}
+
 fn val_to_bool(v: &Value) -> Result<bool, Box<dyn std::error::Error>> {
     if let Some(v_str) = v.as_str() {
         Ok(v_str.parse::<bool>()?)
@@ -205,6 +966,75 @@ impl Config {
                         ArgError::from_string(format!("Config: auto_cd need boolean type: {:?}", e))
                     })?
                 }
+                "highlight_cursor_item" => {
+                    self.highlight_cursor_item = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!(
+                            "highlight_cursor_item need boolean type: {:?}",
+                            e
+                        ))
+                    })?
+                }
+                "auto_fit_width" => {
+                    self.auto_fit_width = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!("auto_fit_width need boolean type: {:?}", e))
+                    })?
+                }
+                "truncate_filename" => {
+                    self.truncate_filename = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!(
+                            "truncate_filename need boolean type: {:?}",
+                            e
+                        ))
+                    })?
+                }
+                "min_width" => self.min_width = val_to_u16(v)?,
+                "max_width" => self.max_width = val_to_u16(v)?,
+                "initial_expand_level" => self.initial_expand_level = val_to_u16(v)?,
+                "initial_expand_max_items" => self.initial_expand_max_items = val_to_usize(v)?,
+                "dir_chunk_size" => self.dir_chunk_size = val_to_usize(v)?,
+                "fs_retries" => self.fs_retries = val_to_usize(v)?,
+                "flash_new_files" => self.flash_new_files = val_to_u16(v)?,
+                "select_recursive" => {
+                    self.select_recursive = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!(
+                            "select_recursive need boolean type: {:?}",
+                            e
+                        ))
+                    })?
+                }
+                "preserve_permissions" => {
+                    self.preserve_permissions = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!(
+                            "preserve_permissions need boolean type: {:?}",
+                            e
+                        ))
+                    })?
+                }
+                "trash" => {
+                    self.trash = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!("trash need boolean type: {:?}", e))
+                    })?
+                }
+                "lsp_rename" => {
+                    self.lsp_rename = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!("lsp_rename need boolean type: {:?}", e))
+                    })?
+                }
+                "smart_case" => {
+                    self.smart_case = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!("smart_case need boolean type: {:?}", e))
+                    })?
+                }
+                "breadcrumbs" => {
+                    self.breadcrumbs = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!("breadcrumbs need boolean type: {:?}", e))
+                    })?
+                }
+                "readonly" => {
+                    self.readonly = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!("readonly need boolean type: {:?}", e))
+                    })?
+                }
                 "profile" => {
                     self.profile = val_to_bool(v).map_err(|e| {
                         ArgError::from_string(format!("profile need boolean type: {:?}", e))
@@ -218,11 +1048,55 @@ impl Config {
                         ))
                     })?
                 }
+                "smart_expand_threshold" => self.smart_expand_threshold = val_to_usize(v)?,
+                "cursor_history_limit" => self.cursor_history_limit = val_to_usize(v)?,
+                "expand_store_limit" => self.expand_store_limit = val_to_usize(v)?,
                 "root_marker" => self.root_marker = val_to_string(v)?,
-                "ignored_files" => self.ignored_files = val_to_string(v)?,
+                "ignored_files" => {
+                    self.ignored_files = val_to_string(v)?;
+                    self.ignored_patterns = parse_ignored(&self.ignored_files);
+                }
                 "search" => self.search = val_to_string(v)?,
                 "session_file" => self.session_file = val_to_string(v)?,
-                "sort" => self.sort = val_to_string(v)?,
+                "buffer_name" => self.buffer_name = val_to_string(v)?,
+                "file_manager" => self.file_manager = val_to_string(v)?,
+                "quick_dirs" => self.quick_dirs = val_to_strings(v)?,
+                "commands" => self.commands = val_to_commands(v)?,
+                "buffer_options" => self.buffer_options = val_to_options(v)?,
+                "window_options" => self.window_options = val_to_options(v)?,
+                "keymaps" => self.keymaps = val_to_keymaps(v)?,
+                "icons" => self.icons = val_to_icons(v)?,
+                "summary_footer" => {
+                    let position = val_to_string(v)?;
+                    match position.as_str() {
+                        "" | "root" | "bottom" => self.summary_footer = position,
+                        _ => {
+                            return Err(Box::new(ArgError::from_string(format!(
+                                "Unknown summary_footer: {}",
+                                position
+                            ))))
+                        }
+                    }
+                }
+                "file_manager_terminal" => {
+                    self.file_manager_terminal = val_to_bool(v).map_err(|e| {
+                        ArgError::from_string(format!(
+                            "file_manager_terminal need boolean type: {:?}",
+                            e
+                        ))
+                    })?
+                }
+                "notify_level" => {
+                    let level = val_to_string(v)?;
+                    self.notify_level = NotifyLevel::from_str(&level).ok_or_else(|| {
+                        ArgError::from_string(format!("Unknown notify_level: {}", level))
+                    })?;
+                }
+                "sort" => {
+                    let sort = val_to_string(v)?;
+                    self.sort_keys = parse_sort(&sort)?;
+                    self.sort = sort;
+                }
                 "columns" => {
                     self.columns.clear();
                     for col in match v.as_str() {
@@ -244,323 +1118,3431 @@ impl Config {
 
 const KSTOP: usize = 60;
 
-pub struct Tree {
-    pub bufnr: Value, // use bufnr to avoid tedious generic code
-    pub icon_ns_id: i64,
-    pub config: Config,
-    selected_items: HashSet<usize>,
-    file_items: Vec<FileItemPtr>,
-    expand_store: HashMap<String, bool>,
-    col_map: HashMap<ColumnType, Vec<ColumnCell>>,
-    targets: Vec<usize>,
-    cursor_history: HashMap<String, u64>,
-    git_repo: Option<Mutex<Repository>>,
-    pub git_map: HashMap<String, Status>,
+/// Unfiltered entries of a directory, valid as long as its mtime is unchanged
+struct CachedListing {
+    mtime: std::time::SystemTime,
+    // the names and whether they're directories only, the metadata of the entries changes
+    // without touching the mtime of the directory and is read again on every hit. An entry
+    // changes type only by being removed and created again, which touches the directory
+    entries: Vec<(PathBuf, bool)>,
+    last_used: std::time::Instant,
 }
 
-impl Debug for Tree {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Tree {{ bufnr: {:?}, icon_ns_id: {:?}, config: {:?} }}",
-            self.bufnr, self.icon_ns_id, self.config
-        )
+/// Directory sizes for the size_bar column, summed up in the background
+#[derive(Default)]
+struct DirSizes {
+    sizes: HashMap<PathBuf, u64>,
+    pending: HashSet<PathBuf>,
+    // the pending directories in order, summed up one at a time by a single worker
+    queue: std::collections::VecDeque<PathBuf>,
+    // the worker is running
+    summing: bool,
+    // some size came in since the last draw
+    computed: bool,
+}
+
+/// The swap files next to the files, by directory, and whether their vim is running
+#[derive(Default)]
+struct SwapCache {
+    local: HashMap<PathBuf, HashMap<PathBuf, PathBuf>>,
+    in_use: HashMap<PathBuf, bool>,
+}
+
+const LISTING_CACHE_LIMIT: usize = 512;
+
+// shared by all the trees, a second tree of the same project doesn't hit the disk
+static LISTING_CACHE: std::sync::Mutex<Option<HashMap<PathBuf, CachedListing>>> =
+    std::sync::Mutex::new(None);
+
+/// The names of the cached listing of `path` and whether they're directories, if the
+/// directory hasn't changed since
+fn cached_names(path: &Path) -> Option<Vec<(PathBuf, bool)>> {
+    let mtime = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let mut guard = LISTING_CACHE.lock().ok()?;
+    let cache = guard.as_mut()?;
+    match cache.get_mut(path) {
+        Some(cached) if cached.mtime == mtime => {
+            debug!("listing cache hit: {:?}", path);
+            cached.last_used = std::time::Instant::now();
+            Some(cached.entries.clone())
+        }
+        Some(_) => {
+            cache.remove(path);
+            None
+        }
+        None => None,
     }
 }
 
-impl Tree {
-    pub async fn new<W: AsyncWrite + Send + Sync + Unpin + 'static>(
-        bufnr: Value,
-        buf: &Buffer<W>,
-        nvim: &Neovim<W>,
-        icon_ns_id: i64,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        buf.set_option("ft", Value::from("tree")).await?;
-        buf.set_option("modifiable", Value::from(false)).await?;
-        nvim.command("lua tree = require('tree')").await?;
-        nvim.execute_lua("tree.buf_attach(...)", vec![buf.get_value().clone()])
-            .await?;
-        Ok(Self {
-            bufnr,
-            icon_ns_id,
-            config: Default::default(),
+/// The cached listing of `path`, if the directory hasn't changed since
+async fn cached_listing(path: &Path, retries: usize) -> Option<Vec<(PathBuf, std::fs::Metadata)>> {
+    let names = cached_names(path)?;
+    let mut entries = Vec::with_capacity(names.len());
+    for (p, _) in names {
+        // not following the links, like the listing; an entry gone since is dropped
+        if let Ok(meta) =
+            fs_retry::retry_async("stat", &p, retries, || std::fs::symlink_metadata(&p)).await
+        {
+            entries.push((p, meta));
+        }
+    }
+    Some(entries)
+}
+
+fn store_listing(path: PathBuf, mtime: std::time::SystemTime, entries: Vec<(PathBuf, bool)>) {
+    let mut guard = match LISTING_CACHE.lock() {
+        Ok(g) => g,
+        Err(_) => return,
+    };
+    let cache = guard.get_or_insert_with(HashMap::new);
+    if cache.len() >= LISTING_CACHE_LIMIT && !cache.contains_key(&path) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, cached)| cached.last_used)
+            .map(|(p, _)| p.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    let last_used = std::time::Instant::now();
+    cache.insert(
+        path,
+        CachedListing {
+            mtime,
+            entries,
+            last_used,
+        },
+    );
+}
+
+// the directories being read and the tasks waiting for them, a second reader on the
+// executor awaits `scan_finished` and takes the listing from the cache instead of
+// scanning the directory again
+static SCANS_IN_FLIGHT: std::sync::Mutex<
+    Option<HashMap<PathBuf, Vec<futures::channel::oneshot::Sender<()>>>>,
+> = std::sync::Mutex::new(None);
+
+/// Takes `path` off SCANS_IN_FLIGHT when the scan is over, even a failed one, which wakes
+/// the waiters up by dropping their senders
+struct ScanGuard<'a>(&'a Path);
+
+impl Drop for ScanGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = SCANS_IN_FLIGHT.lock() {
+            if let Some(scans) = guard.as_mut() {
+                scans.remove(self.0);
+            }
+        }
+    }
+}
+
+/// Wait without blocking the executor for the scan of `path` going on in the background,
+/// if any
+async fn scan_finished(path: &Path) {
+    let done = {
+        let mut guard = match SCANS_IN_FLIGHT.lock() {
+            Ok(g) => g,
+            Err(_) => return,
+        };
+        match guard.as_mut().and_then(|scans| scans.get_mut(path)) {
+            Some(waiters) => {
+                let (tx, rx) = futures::channel::oneshot::channel();
+                waiters.push(tx);
+                rx
+            }
+            None => return,
+        }
+    };
+    debug!("waiting for the scan of {:?}", path);
+    let _ = done.await;
+}
+
+/// Read the directory, through the shared cache. A directory being scanned by someone
+/// else is read again rather than waited for, see `scan_finished`
+async fn read_listing_cached(
+    path: &Path,
+    retries: usize,
+) -> io::Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    if let Some(entries) = cached_listing(path, retries).await {
+        return Ok(entries);
+    }
+    let first = SCANS_IN_FLIGHT.lock().map_or(false, |mut guard| {
+        let scans = guard.get_or_insert_with(HashMap::new);
+        if scans.contains_key(path) {
+            false
+        } else {
+            scans.insert(path.to_path_buf(), Vec::new());
+            true
+        }
+    });
+    if !first {
+        return read_listing_async(path, retries).await;
+    }
+    let _scan = ScanGuard(path);
+    let mtime = fs_retry::retry_async("stat", path, retries, || std::fs::metadata(path))
+        .await?
+        .modified()?;
+    let entries = read_listing_async(path, retries).await?;
+    let names = entries
+        .iter()
+        .map(|(p, metadata)| (p.clone(), metadata.is_dir()))
+        .collect();
+    store_listing(path.to_path_buf(), mtime, names);
+    Ok(entries)
+}
+
+/// Drop the cached listings of `path` and the directories below, after the tree changed
+/// them (the mtime has a coarse resolution on some file systems)
+fn invalidate_listings(path: &Path) {
+    if let Ok(mut guard) = LISTING_CACHE.lock() {
+        if let Some(cache) = guard.as_mut() {
+            cache.retain(|p, _| !p.starts_with(path));
+        }
+    }
+}
+
+/// `std::fs::rename`, blaming the destination when its directory is missing
+fn rename_file(from: &Path, to: &Path) -> Result<(), TreeError> {
+    std::fs::rename(from, to).map_err(|e| {
+        let target_missing = e.kind() == io::ErrorKind::NotFound
+            && from.symlink_metadata().is_ok()
+            && !to.parent().map_or(true, Path::exists);
+        if target_missing {
+            TreeError::Io(Some(to.to_path_buf()), e)
+        } else {
+            TreeError::Io(Some(from.to_path_buf()), e)
+        }
+    })
+}
+
+/// 99000 => "99,000"
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Read the directory on a blocking thread
+#[instrument(level = "debug", skip_all, fields(path = ?path))]
+fn read_listing(path: &Path, retries: usize) -> io::Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    Ok(
+        fs_retry::retry("readdir", path, retries, || std::fs::read_dir(path))?
+            .filter_map(|x| x.ok())
+            .filter_map(|x| {
+                let entry_path = x.path();
+                // an entry failing fails alone
+                let meta = fs_retry::retry("stat", &entry_path, retries, || x.metadata()).ok()?;
+                Some((entry_path, meta))
+            })
+            .collect(),
+    )
+}
+
+/// Read the directory on the executor, the retries wait without holding it up
+#[instrument(level = "debug", skip_all, fields(path = ?path))]
+async fn read_listing_async(
+    path: &Path,
+    retries: usize,
+) -> io::Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    let dir = fs_retry::retry_async("readdir", path, retries, || std::fs::read_dir(path)).await?;
+    let mut entries = Vec::new();
+    for entry in dir.filter_map(|x| x.ok()) {
+        let entry_path = entry.path();
+        // an entry failing fails alone
+        match fs_retry::retry_async("stat", &entry_path, retries, || entry.metadata()).await {
+            Ok(meta) => entries.push((entry_path, meta)),
+            Err(e) => debug!("can't stat {:?}: {}", entry_path, e),
+        }
+    }
+    Ok(entries)
+}
+
+/// The path as sent to neovim, a string that isn't valid UTF-8 goes as raw bytes, which
+/// neovim takes as a string all the same
+pub fn path_value(path: &Path) -> Value {
+    match path.to_str() {
+        Some(p) => Value::from(p),
+        #[cfg(unix)]
+        None => {
+            use std::os::unix::ffi::OsStrExt;
+            Value::Binary(path.as_os_str().as_bytes().to_vec())
+        }
+        #[cfg(not(unix))]
+        None => Value::from(path.to_string_lossy().as_ref()),
+    }
+}
+
+/// A path sent back by neovim, as raw bytes when it isn't valid UTF-8
+pub fn value_to_path(v: &Value) -> Option<PathBuf> {
+    let bytes = match v {
+        Value::String(s) => s.as_bytes(),
+        Value::Binary(b) => b.as_slice(),
+        _ => return None,
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+    }
+    #[cfg(not(unix))]
+    {
+        Some(PathBuf::from(String::from_utf8_lossy(bytes).into_owned()))
+    }
+}
+
+/// `file://` URI of the absolute path, percent-encoded except for the unreserved
+/// characters and '/'
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    let mut path = path.to_string_lossy().into_owned();
+    if cfg!(windows) {
+        path = path.replace('\\', "/");
+    }
+    // C:/x => file:///C:/x
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for b in path.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' | b':' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+// the paths listed when confirming the removal of a selection
+const REMOVE_CONFIRM_MAX_PATHS: usize = 10;
+
+// upper bound of the entries looked at to find a filter match below a directory
+const FILTER_MAX_ENTRIES: usize = 10000;
+
+/// A filter of the listed entries, the active ones are applied in turn
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryFilter {
+    // dot files, unless show_ignored_files
+    HIDDEN,
+    // the names matching one of the ignored_files patterns, unless show_ignored_files
+    IGNORED(Vec<String>),
+    // files with one of the extensions, directories are kept
+    EXTENSIONS(Vec<String>),
+    // the files that aren't binary, by toggle_binary_files
+    TEXT,
+}
+
+impl EntryFilter {
+    fn keeps(&self, path: &Path, metadata: &std::fs::Metadata) -> bool {
+        match self {
+            EntryFilter::HIDDEN | EntryFilter::IGNORED(_) => self.keeps_dir(path),
+            EntryFilter::EXTENSIONS(exts) => {
+                metadata.is_dir()
+                    || path
+                        .extension()
+                        .and_then(|x| x.to_str())
+                        .map_or(false, |ext| {
+                            exts.iter().any(|e| e.eq_ignore_ascii_case(ext))
+                        })
+            }
+            EntryFilter::TEXT => !metadata.is_file() || !is_binary(path, metadata),
+        }
+    }
+
+    /// `keeps` for a directory, only its name counts
+    fn keeps_dir(&self, path: &Path) -> bool {
+        match self {
+            EntryFilter::HIDDEN => !path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .map_or(false, is_hidden_name),
+            EntryFilter::IGNORED(patterns) => {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                !patterns.iter().any(|p| glob_match(p, &name))
+            }
+            EntryFilter::EXTENSIONS(_) | EntryFilter::TEXT => true,
+        }
+    }
+}
+
+// the head of a file looked at for a null byte, as much as git does
+const BINARY_SNIFF_BYTES: u64 = 8000;
+const BINARY_CACHE_LIMIT: usize = 100000;
+
+// path => (mtime, binary), shared by all the trees
+static BINARY_CACHE: std::sync::Mutex<Option<HashMap<PathBuf, (std::time::SystemTime, bool)>>> =
+    std::sync::Mutex::new(None);
+
+/// Whether the first block of the file has a null byte, cached until it's modified
+fn is_binary(path: &Path, metadata: &std::fs::Metadata) -> bool {
+    use std::io::Read;
+    let mtime = match metadata.modified() {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    if let Ok(guard) = BINARY_CACHE.lock() {
+        match guard.as_ref().and_then(|cache| cache.get(path)) {
+            Some((cached_mtime, binary)) if *cached_mtime == mtime => return *binary,
+            _ => {}
+        }
+    }
+    let mut head = Vec::new();
+    let binary = match std::fs::File::open(path) {
+        Ok(f) => f.take(BINARY_SNIFF_BYTES).read_to_end(&mut head).is_ok() && head.contains(&0),
+        // unreadable files stay listed
+        Err(_) => false,
+    };
+    if let Ok(mut guard) = BINARY_CACHE.lock() {
+        let cache = guard.get_or_insert_with(HashMap::new);
+        if cache.len() >= BINARY_CACHE_LIMIT {
+            cache.clear();
+        }
+        cache.insert(path.to_path_buf(), (mtime, binary));
+    }
+    binary
+}
+
+fn passes(filters: &[EntryFilter], path: &Path, metadata: &std::fs::Metadata) -> bool {
+    filters.iter().all(|f| f.keeps(path, metadata))
+}
+
+/// Whether an entry below `dir` matches the filter, a bounded breadth first walk. A
+/// directory too large to walk is kept. The cached listings are walked by name, only the
+/// files matching are looked at
+async fn dir_contains_match(
+    dir: &Path,
+    matcher: &Matcher,
+    filters: &[EntryFilter],
+    retries: usize,
+) -> bool {
+    let mut queue = std::collections::VecDeque::new();
+    queue.push_back(dir.to_path_buf());
+    let mut seen = 0;
+    while let Some(dir) = queue.pop_front() {
+        let listing = match cached_names(&dir) {
+            Some(names) => names,
+            None => match read_listing_cached(&dir, retries).await {
+                Ok(l) => l
+                    .into_iter()
+                    .map(|(path, metadata)| (path, metadata.is_dir()))
+                    .collect(),
+                Err(_) => continue,
+            },
+        };
+        for (path, is_dir) in listing {
+            seen += 1;
+            // too large to tell, better shown than hiding a match
+            if seen > FILTER_MAX_ENTRIES {
+                return true;
+            }
+            if is_dir {
+                if !filters.iter().all(|f| f.keeps_dir(&path)) {
+                    continue;
+                }
+                if matcher.matches_path(&path) {
+                    return true;
+                }
+                queue.push_back(path);
+            } else if matcher.matches_path(&path) {
+                // not following the links, like the listing
+                let metadata = fs_retry::retry_async("stat", &path, retries, || {
+                    std::fs::symlink_metadata(&path)
+                })
+                .await;
+                if metadata.map_or(false, |m| passes(filters, &path, &m)) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+// upper bounds of a goto_file walk, the entries looked at and the files offered
+const GOTO_FILE_MAX_ENTRIES: usize = 200_000;
+const GOTO_FILE_MAX_MATCHES: usize = 50;
+
+/// The entries below `root` whose name matches, breadth first so the shallowest come
+/// first. The filtered out entries and the git-ignored directories aren't walked
+fn find_files(
+    root: &Path,
+    matcher: &Matcher,
+    filters: &[EntryFilter],
+    ignored_dirs: &HashSet<PathBuf>,
+    retries: usize,
+) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut queue = std::collections::VecDeque::new();
+    queue.push_back(root.to_path_buf());
+    let mut seen = 0;
+    while let Some(dir) = queue.pop_front() {
+        let listing = match read_listing(&dir, retries) {
+            Ok(l) => l,
+            Err(_) => continue,
+        };
+        for (path, metadata) in listing {
+            seen += 1;
+            if seen > GOTO_FILE_MAX_ENTRIES {
+                return found;
+            }
+            if !passes(filters, &path, &metadata) {
+                continue;
+            }
+            if matcher.matches_path(&path) {
+                found.push(path.clone());
+                if found.len() >= GOTO_FILE_MAX_MATCHES {
+                    return found;
+                }
+            }
+            if metadata.is_dir() && !ignored_dirs.contains(&path) {
+                queue.push_back(path);
+            }
+        }
+    }
+    found
+}
+
+// upper bound of the directories a single smart_expand opens
+const SMART_EXPAND_MAX_DIRS: usize = 64;
+
+const PREVIEW_MAX_LINES: usize = 200;
+const PREVIEW_MAX_BYTES: u64 = 64 * 1024;
+const PREVIEW_CACHE_LIMIT: usize = 32;
+// cursor moves closer together than this only preview the last item
+const PREVIEW_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(80);
+
+struct CachedPreview {
+    mtime: std::time::SystemTime,
+    lines: Vec<String>,
+    last_used: std::time::Instant,
+}
+
+static PREVIEW_CACHE: std::sync::Mutex<Option<HashMap<PathBuf, CachedPreview>>> =
+    std::sync::Mutex::new(None);
+
+/// The head of a file, or the entries of a directory
+fn read_preview(path: &Path, retries: usize) -> io::Result<Vec<String>> {
+    use std::io::Read;
+    if path.is_dir() {
+        let mut names: Vec<String> = read_listing(path, retries)?
+            .into_iter()
+            .filter_map(|(p, meta)| {
+                let name = p.file_name()?.to_string_lossy().into_owned();
+                Some(if meta.is_dir() { name + "/" } else { name })
+            })
+            .collect();
+        names.sort();
+        names.truncate(PREVIEW_MAX_LINES);
+        return Ok(names);
+    }
+    let mut buf = Vec::new();
+    std::fs::File::open(path)?
+        .take(PREVIEW_MAX_BYTES)
+        .read_to_end(&mut buf)?;
+    if buf.contains(&0) {
+        return Ok(vec!["(binary file)".to_owned()]);
+    }
+    Ok(String::from_utf8_lossy(&buf)
+        .lines()
+        .take(PREVIEW_MAX_LINES)
+        .map(str::to_owned)
+        .collect())
+}
+
+/// The preview of `path`, through the cache of recently previewed files
+fn preview_lines(path: &Path, retries: usize) -> io::Result<Vec<String>> {
+    let mtime = std::fs::metadata(path)?.modified()?;
+    let mut guard = PREVIEW_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let cache = guard.get_or_insert_with(HashMap::new);
+    if let Some(cached) = cache.get_mut(path) {
+        if cached.mtime == mtime {
+            cached.last_used = std::time::Instant::now();
+            return Ok(cached.lines.clone());
+        }
+    }
+    let lines = read_preview(path, retries)?;
+    if cache.len() >= PREVIEW_CACHE_LIMIT && !cache.contains_key(path) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, cached)| cached.last_used)
+            .map(|(p, _)| p.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(
+        path.to_path_buf(),
+        CachedPreview {
+            mtime,
+            lines: lines.clone(),
+            last_used: std::time::Instant::now(),
+        },
+    );
+    Ok(lines)
+}
+
+fn preview_args(path: &Path, retries: usize) -> Vec<Value> {
+    let lines = match preview_lines(path, retries) {
+        Ok(lines) => lines,
+        Err(e) => vec![format!("can't read {}: {}", path.display(), e)],
+    };
+    vec![
+        path_value(path),
+        Value::Array(lines.into_iter().map(Value::from).collect()),
+    ]
+}
+
+// directories with more entries are only partially accounted for in size_bar
+const DIR_SIZE_MAX_ENTRIES: usize = 10000;
+// the directories waiting for their size
+const DIR_SIZE_QUEUE_LIMIT: usize = 1024;
+
+/// The worker of `Tree::item_size`, sums up the queued directories until there's none
+fn sum_dir_sizes(sizes: Arc<std::sync::Mutex<DirSizes>>) {
+    loop {
+        let path = match sizes.lock() {
+            Ok(mut cache) => match cache.queue.pop_front() {
+                Some(path) => path,
+                None => {
+                    cache.summing = false;
+                    return;
+                }
+            },
+            Err(_) => return,
+        };
+        let size = dir_size(&path, DIR_SIZE_MAX_ENTRIES);
+        if let Ok(mut cache) = sizes.lock() {
+            cache.pending.remove(&path);
+            cache.sizes.insert(path, size);
+            cache.computed = true;
+        }
+    }
+}
+
+/// Total size of the files below `path`, the walk stops after `max_entries` entries
+pub fn dir_size(path: &Path, max_entries: usize) -> u64 {
+    let mut size = 0;
+    let mut seen = 0;
+    let mut stack = vec![path.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|x| x.ok()) {
+            seen += 1;
+            if seen > max_entries {
+                return size;
+            }
+            // symlinks aren't followed, they could loop
+            match entry.metadata() {
+                Ok(m) if m.is_dir() => stack.push(entry.path()),
+                Ok(m) => size += m.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    size
+}
+
+/// Where the display column `offset` starts in `line`: the byte index, and the padding
+/// replacing a wide character cut in half
+fn hscroll_split(line: &str, offset: usize) -> (usize, usize) {
+    let mut width = 0;
+    for (i, c) in line.char_indices() {
+        if width >= offset {
+            return (i, width - offset);
+        }
+        width += UnicodeWidthChar::width(c).unwrap_or(0);
+    }
+    (line.len(), width.saturating_sub(offset))
+}
+
+/// Propagate the statuses to the directories up to the work dir, so that a collapsed
+/// directory shows what happened inside
+fn aggregate_git_dirs(
+    git_map: &HashMap<String, Status>,
+    work_dir: &Path,
+) -> HashMap<String, Status> {
+    let mut dir_map: HashMap<String, Status> = HashMap::new();
+    for (path, status) in git_map {
+        if status.contains(Status::IGNORED) {
+            continue;
+        }
+        for ancestor in Path::new(path).ancestors().skip(1) {
+            if !ancestor.starts_with(work_dir) {
+                break;
+            }
+            *dir_map
+                .entry(ancestor.to_string_lossy().into_owned())
+                .or_insert_with(Status::empty) |= *status;
+        }
+    }
+    dir_map
+}
+
+/// Quote `s` as a JSON string
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn hl_hash(highlights: &[(String, i64, i64)]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    highlights.hash(&mut hasher);
+    // 0 stands for the lines not highlighted yet
+    hasher.finish().max(1)
+}
+
+/// The nvim_buf_set_extmark calls highlighting the line `row`, for call_atomic
+fn push_hl_calls(
+    calls: &mut Vec<Value>,
+    bufnr: &Value,
+    ns_id: i64,
+    highlights: Vec<(String, i64, i64)>,
+    row: usize,
+) {
+    for (hl_group, start, end) in highlights {
+        let opts = Value::Map(vec![
+            (Value::from("end_col"), Value::from(end)),
+            (Value::from("hl_group"), Value::from(hl_group)),
+        ]);
+        calls.push(nvim_call::atomic_call(
+            "nvim_buf_set_extmark",
+            vec![
+                bufnr.clone(),
+                Value::from(ns_id),
+                Value::from(row),
+                Value::from(start),
+                opts,
+            ],
+        ));
+    }
+}
+
+/// Clearing the highlights of the lines [sl, el), for call_atomic
+fn clear_hl_call(bufnr: &Value, ns_id: i64, sl: usize, el: usize) -> Value {
+    nvim_call::atomic_call(
+        "nvim_buf_clear_namespace",
+        vec![
+            bufnr.clone(),
+            Value::from(ns_id),
+            Value::from(sl),
+            Value::from(el),
+        ],
+    )
+}
+
+/// The first of foo(1).txt, foo(2).txt... that doesn't exist, for the name conflicts
+pub fn available_name(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|x| format!(".{}", x.to_string_lossy()))
+        .unwrap_or_default();
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{}({}){}", stem, n, ext));
+        if !candidate.exists() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Replace `dest` with a copy of `src` without ever leaving a partially written `dest`:
+/// the copy goes to a temporary file next to it, which is then renamed over it
+async fn overwrite_file(
+    src: &Path,
+    dest: &Path,
+    preserve_permissions: bool,
+    retries: usize,
+) -> io::Result<()> {
+    let dest_meta =
+        fs_retry::retry_async("stat", dest, retries, || std::fs::metadata(dest)).await?;
+    let tmp_name = format!(
+        ".{}.tree-{}.tmp",
+        dest.file_name().and_then(|x| x.to_str()).unwrap_or("paste"),
+        std::process::id()
+    );
+    let tmp = dest.with_file_name(tmp_name);
+    let res = fs_retry::retry_async("copy", src, retries, || std::fs::copy(src, &tmp))
+        .await
+        .and_then(|_| {
+            if preserve_permissions {
+                std::fs::set_permissions(&tmp, dest_meta.permissions())?;
+            }
+            std::fs::rename(&tmp, dest)
+        });
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res
+}
+
+// modification times of .git/index, .git/HEAD and of the ignore files, .git/info/exclude
+// and the top .gitignore
+type GitStamp = [Option<std::time::SystemTime>; 4];
+
+fn git_stamp(git_dir: &Path, work_dir: Option<&Path>) -> GitStamp {
+    let mtime = |path: PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    [
+        mtime(git_dir.join("index")),
+        mtime(git_dir.join("HEAD")),
+        mtime(git_dir.join("info").join("exclude")),
+        work_dir.and_then(|dir| mtime(dir.join(".gitignore"))),
+    ]
+}
+
+/// The files the watcher looks at for a tree, stated off the executor and without the
+/// lock of the trees held
+pub struct PollTargets {
+    dirs: Vec<PathBuf>,
+    // the git directory and the work tree
+    git: Option<(PathBuf, Option<PathBuf>)>,
+}
+
+/// The modification times of the `PollTargets`
+pub struct PollStamps {
+    dirs: HashMap<PathBuf, std::time::SystemTime>,
+    git: Option<GitStamp>,
+}
+
+impl PollTargets {
+    pub fn stat(self) -> PollStamps {
+        let dirs = self
+            .dirs
+            .into_iter()
+            .filter_map(|dir| {
+                let mtime = std::fs::metadata(&dir).and_then(|m| m.modified()).ok()?;
+                Some((dir, mtime))
+            })
+            .collect();
+        let git = self
+            .git
+            .map(|(git_dir, work_dir)| git_stamp(&git_dir, work_dir.as_deref()));
+        PollStamps { dirs, git }
+    }
+}
+
+/// The items of a previous root, listed again by change_root as long as the directories
+/// it shows are unchanged
+struct RootSnapshot {
+    items: Vec<FileItemPtr>,
+    // the listed directories and their mtimes when the root was left
+    mtimes: Vec<(PathBuf, Option<std::time::SystemTime>)>,
+    // the options the listings depend on, see listing_key
+    key: String,
+    // the directory the root went down to and whether it was expanded then, becoming the
+    // root expands it
+    child: PathBuf,
+    child_expanded: bool,
+}
+
+/// The model of a tree: the listed items, their cells and the state of the actions. Drawn
+/// in a neovim buffer by TreeHandler, or built with `Tree::headless` without neovim
+pub struct Tree {
+    pub bufnr: Value, // use bufnr to avoid tedious generic code
+    pub icon_ns_id: i64,
+    pub cursor_ns_id: i64,
+    pub footer_ns_id: i64,
+    pub more_ns_id: i64,
+    pub flash_ns_id: i64,
+    pub config: Config,
+    // the options the tree was started and resumed with, for a restarted backend to
+    // replay
+    pub options: HashMap<String, Value>,
+    // unfocused trees are drawn with the dimmed highlight groups
+    focused: bool,
+    // display columns scrolled out on the left by scroll_left/scroll_right
+    hscroll: usize,
+    // the root is on a network file system, the expensive features are turned off
+    pub network_fs: bool,
+    // shown in a window, the watcher leaves the other trees alone
+    pub shown: bool,
+    // the root network_fs was looked up for, the mount table is read once per root
+    network_fs_root: Option<PathBuf>,
+    selected_items: HashSet<usize>,
+    // the selected items no longer displayed, collapsed away or outside the root, selected
+    // again as they are listed
+    selected_offscreen: HashSet<PathBuf>,
+    file_items: Vec<FileItemPtr>,
+    expand_store: HashMap<PathBuf, bool>,
+    expand_store_lru: Vec<PathBuf>, // recently used order
+    col_map: HashMap<ColumnType, Vec<ColumnCell>>,
+    targets: Vec<usize>,
+    cursor_history: HashMap<PathBuf, u64>,
+    cursor_history_lru: Vec<PathBuf>, // recently used order
+    root_history: Vec<PathBuf>,
+    cut_items: HashSet<PathBuf>,
+    marked_for_deletion: HashSet<PathBuf>,
+    // sizes for the size_bar column, directories are expensive to sum up
+    size_cache: Arc<std::sync::Mutex<DirSizes>>,
+    // the largest size among the children of an item, by item id, for the current draw
+    sibling_max_size: std::sync::Mutex<HashMap<usize, u64>>,
+    // the lines as last sent to the buffer and a hash of their highlights (0 when not
+    // highlighted yet), the soft redraws only send the lines that differ
+    drawn: std::sync::Mutex<Vec<(String, u64)>>,
+    git_repo: Option<Mutex<Repository>>,
+    git_stamp: Option<GitStamp>, // the state git_map was computed from
+    git_stamp_pending: Option<GitStamp>, // a change waiting to settle
+    // the mtimes of the displayed directories at the last poll
+    dir_stamps: HashMap<PathBuf, std::time::SystemTime>,
+    pub git_map: HashMap<String, Status>,
+    // the statuses of the descendants of every directory, merged
+    git_dir_map: HashMap<String, Status>,
+    // the root lists the git changed files with their root-relative paths
+    flat_view: bool,
+    // the state of the tree in session_file as last read or written
+    session_saved: Vec<u8>,
+    // only the files with these extensions are listed, for this session only
+    extension_filter: Vec<String>,
+    // the binary files are hidden, for this session only
+    hide_binary: bool,
+    // the swap directories of the 'directory' option, and the files they have swap files of
+    pub swap_dirs: Vec<PathBuf>,
+    swap_files: HashMap<PathBuf, PathBuf>,
+    // what the swap column found since the last refresh
+    swap_cache: std::sync::Mutex<SwapCache>,
+    // the preview float follows the cursor while it's open, cleared as well once the float
+    // turns out to be closed from neovim
+    preview_open: Arc<std::sync::atomic::AtomicBool>,
+    // bumped on every cursor move, a pending preview update is dropped once it's stale
+    preview_generation: Arc<std::sync::atomic::AtomicU64>,
+    // the background work targeting this tree, cancelled when it's destroyed
+    pub tasks: TaskSet,
+    // column => path => (text, hl_group), the cells of the custom columns as returned by
+    // their lua callbacks or renderers
+    custom_cells: HashMap<String, HashMap<PathBuf, (String, Option<Cow<'static, str>>)>>,
+    // the widths of the custom columns, for the current draw
+    custom_widths: std::sync::Mutex<HashMap<String, usize>>,
+    // the custom columns computed in rust, registered by the embedding code
+    renderers: HashMap<String, Arc<dyn ColumnRenderer>>,
+    // the roots above the current one, `cd ..` goes back to them without a rescan
+    root_snapshots: HashMap<PathBuf, RootSnapshot>,
+    // directory => number of entries listed, when load_more went past dir_chunk_size
+    dir_limits: HashMap<PathBuf, usize>,
+    // the pattern of the last search, offered by the next one
+    last_search: String,
+    // directory => number of entries left out of its listing, drawn as a "… N more" line
+    hidden_entries: std::sync::Mutex<HashMap<PathBuf, usize>>,
+    // some "… N more" lines are in the buffer, to be cleared once nothing is hidden
+    more_marks_drawn: std::sync::atomic::AtomicBool,
+}
+
+impl Debug for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tree {{ bufnr: {:?}, icon_ns_id: {:?}, config: {:?} }}",
+            self.bufnr, self.icon_ns_id, self.config
+        )
+    }
+}
+
+impl Tree {
+    pub async fn new<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        bufnr: Value,
+        buf: &Buffer<W>,
+        nvim: &Neovim<W>,
+        icon_ns_id: i64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        nvim_call::command(nvim, "lua tree = require('tree')").await?;
+        nvim_call::execute_lua(nvim, "tree.buf_attach(...)", vec![buf.get_value().clone()]).await?;
+        Ok(Self::with_buffer(bufnr, icon_ns_id))
+    }
+    /// Set the buffer up once the config is known, in a single request: the keymaps first,
+    /// so that the mappings `tree.keymap` makes on FileType win over them, the filetype
+    /// and the buffer options, then the window options lua applies to the tree windows and
+    /// whether the cursor moves are sent
+    pub async fn provision_buffer<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut calls = Vec::new();
+        for (lhs, action, args) in &self.config.keymaps {
+            let opts = ["noremap", "silent", "nowait"]
+                .iter()
+                .map(|opt| (Value::from(*opt), Value::from(true)))
+                .collect();
+            calls.push(nvim_call::atomic_call(
+                "nvim_buf_set_keymap",
+                vec![
+                    self.bufnr.clone(),
+                    Value::from("n"),
+                    Value::from(lhs.as_str()),
+                    Value::from(keymap_rhs(action, args)),
+                    Value::Map(opts),
+                ],
+            ));
+        }
+        let mut options = vec![
+            ("filetype".to_owned(), Value::from("tree")),
+            ("modifiable".to_owned(), Value::from(false)),
+            ("buflisted".to_owned(), Value::from(self.config.listed)),
+        ];
+        options.extend(self.config.buffer_options.iter().cloned());
+        for (name, value) in options {
+            calls.push(nvim_call::atomic_call(
+                "nvim_buf_set_option",
+                vec![self.bufnr.clone(), Value::from(name), value],
+            ));
+        }
+        calls.push(nvim_call::atomic_call(
+            "nvim_execute_lua",
+            vec![
+                Value::from("tree.set_win_options(...)"),
+                Value::Array(vec![
+                    self.bufnr.clone(),
+                    options_value(&self.config.window_options),
+                ]),
+            ],
+        ));
+        // the cursor moves are only sent when they're highlighted
+        calls.push(nvim_call::atomic_call(
+            "nvim_execute_lua",
+            vec![
+                Value::from("tree.set_highlight_cursor(...)"),
+                Value::Array(vec![
+                    self.bufnr.clone(),
+                    Value::from(self.config.highlight_cursor_item),
+                ]),
+            ],
+        ));
+        nvim_call::call_atomic(nvim, calls).await?;
+        Ok(())
+    }
+    /// A tree that isn't attached to any buffer, for the headless commands
+    pub fn headless(config: Config) -> Self {
+        let mut tree = Self::with_buffer(Value::Nil, 0);
+        tree.config = config;
+        tree
+    }
+    fn with_buffer(bufnr: Value, icon_ns_id: i64) -> Self {
+        Self {
+            bufnr,
+            icon_ns_id,
+            cursor_ns_id: 0,
+            footer_ns_id: 0,
+            more_ns_id: 0,
+            flash_ns_id: 0,
+            config: Default::default(),
+            options: HashMap::new(),
+            focused: true,
+            hscroll: 0,
+            network_fs: false,
+            network_fs_root: None,
+            shown: true,
             file_items: Default::default(),
             expand_store: Default::default(),
+            expand_store_lru: Default::default(),
             col_map: Default::default(),
             targets: Default::default(),
             cursor_history: Default::default(),
+            cursor_history_lru: Default::default(),
+            root_history: Default::default(),
+            cut_items: Default::default(),
+            marked_for_deletion: Default::default(),
+            size_cache: Default::default(),
+            sibling_max_size: Default::default(),
+            drawn: Default::default(),
             selected_items: Default::default(),
+            selected_offscreen: Default::default(),
             git_repo: None,
+            git_stamp: None,
+            git_stamp_pending: None,
+            dir_stamps: Default::default(),
             git_map: Default::default(),
-        })
+            git_dir_map: Default::default(),
+            flat_view: false,
+            session_saved: Vec::new(),
+            extension_filter: Vec::new(),
+            hide_binary: false,
+            swap_dirs: Vec::new(),
+            swap_files: Default::default(),
+            swap_cache: Default::default(),
+            preview_open: Default::default(),
+            preview_generation: Default::default(),
+            tasks: TaskSet::default(),
+            custom_cells: HashMap::new(),
+            custom_widths: Default::default(),
+            renderers: HashMap::new(),
+            root_snapshots: HashMap::new(),
+            dir_limits: HashMap::new(),
+            last_search: String::new(),
+            hidden_entries: Default::default(),
+            more_marks_drawn: Default::default(),
+        }
+    }
+    /// The displayed items as a JSON document, for scripts and test harnesses
+    pub fn export_json(&self, selection_only: bool) -> String {
+        let items: Vec<String> = self
+            .file_items
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !selection_only || self.is_item_selected(*i))
+            .map(|(i, fi)| {
+                let path = fi.path.to_string_lossy();
+                let git = match self.git_map.get(path.as_ref()) {
+                    Some(status) => json_string(git_status_name(*status)),
+                    None => String::from("null"),
+                };
+                format!(
+                    "{{\"path\":{},\"level\":{},\"is_directory\":{},\"expanded\":{},\"selected\":{},\"git\":{}}}",
+                    json_string(&path),
+                    fi.level,
+                    fi.metadata.is_dir(),
+                    self.is_item_opened(&fi.path),
+                    self.is_item_selected(i),
+                    git
+                )
+            })
+            .collect();
+        let root = self
+            .file_items
+            .get(0)
+            .map(|fi| fi.path.to_string_lossy())
+            .unwrap_or_default();
+        format!(
+            "{{\"root\":{},\"items\":[{}]}}",
+            json_string(&root),
+            items.join(",")
+        )
+    }
+    /// Summary of the tree, the reply of `_tree_start`
+    pub fn start_reply(&self, took_ms: u64) -> Value {
+        let root = self
+            .file_items
+            .get(0)
+            .map(|fi| path_value(&fi.path))
+            .unwrap_or_else(|| Value::from(""));
+        Value::Map(vec![
+            (Value::from("bufnr"), self.bufnr.clone()),
+            (Value::from("root"), root),
+            (
+                Value::from("item_count"),
+                Value::from(self.file_items.len()),
+            ),
+            (Value::from("took_ms"), Value::from(took_ms)),
+        ])
     }
-    pub fn is_item_opened(&self, path: &str) -> bool {
-        match self.expand_store.get(path) {
+    pub fn is_item_opened<P: AsRef<Path>>(&self, path: P) -> bool {
+        match self.expand_store.get(path.as_ref()) {
             Some(v) => *v,
             None => false,
         }
     }
-    pub fn is_item_selected(&self, idx: usize) -> bool {
-        self.selected_items.contains(&idx)
+    pub fn is_item_selected(&self, idx: usize) -> bool {
+        self.selected_items.contains(&idx)
+            || (self.config.select_recursive && self.selected_ancestor(idx).is_some())
+    }
+    /// Full, partial (some descendants only) or no selection, for the MARK column
+    pub fn selection_state(&self, idx: usize) -> SelectionState {
+        if self.is_item_selected(idx) {
+            return SelectionState::Full;
+        }
+        if self.config.select_recursive
+            && self
+                .descendant_range(idx)
+                .any(|i| self.selected_items.contains(&i))
+        {
+            return SelectionState::Partial;
+        }
+        SelectionState::None
+    }
+    fn selected_ancestor(&self, idx: usize) -> Option<usize> {
+        let mut parent = self.file_items.get(idx)?.parent.clone();
+        while let Some(p) = parent {
+            if self.selected_items.contains(&p.id) {
+                return Some(p.id);
+            }
+            parent = p.parent.clone();
+        }
+        None
+    }
+    /// The displayed descendants of the item, they directly follow it in `file_items`
+    fn descendant_range(&self, idx: usize) -> std::ops::Range<usize> {
+        let level = match self.file_items.get(idx) {
+            Some(fi) => fi.level,
+            None => return idx..idx,
+        };
+        let end = self.file_items[idx + 1..]
+            .iter()
+            .position(|fi| fi.level <= level)
+            .map_or(self.file_items.len(), |pos| idx + 1 + pos);
+        idx + 1..end
+    }
+    /// The size of the item, 0 for a directory until its size is summed up in the
+    /// background, the tree is redrawn once it is
+    fn item_size(&self, fileitem: &FileItem) -> u64 {
+        if !fileitem.metadata.is_dir() {
+            return fileitem.metadata.len();
+        }
+        if self.network_fs {
+            return 0;
+        }
+        let mut cache = match self.size_cache.lock() {
+            Ok(c) => c,
+            Err(_) => return 0,
+        };
+        if let Some(size) = cache.sizes.get(&fileitem.path) {
+            return *size;
+        }
+        // past the bound, asked again by a later draw
+        if cache.queue.len() >= DIR_SIZE_QUEUE_LIMIT || !cache.pending.insert(fileitem.path.clone())
+        {
+            return 0;
+        }
+        cache.queue.push_back(fileitem.path.clone());
+        if !cache.summing {
+            cache.summing = true;
+            let sizes = self.size_cache.clone();
+            async_std::task::spawn_blocking(move || sum_dir_sizes(sizes));
+        }
+        0
+    }
+
+    /// Whether directory sizes came in since the last call, for the watcher to redraw
+    pub fn take_computed_sizes(&self) -> bool {
+        self.size_cache
+            .lock()
+            .map_or(false, |mut c| std::mem::replace(&mut c.computed, false))
+    }
+
+    /// The swap file of the item, if there's one
+    fn swap_file(&self, fileitem: &FileItem) -> Option<PathBuf> {
+        if fileitem.metadata.is_dir() {
+            return None;
+        }
+        self.swap_of_path(&fileitem.path)
+    }
+
+    /// The swap file of `path`, which doesn't need to be displayed
+    fn swap_of_path(&self, path: &Path) -> Option<PathBuf> {
+        if let Some(swap) = self.swap_files.get(path) {
+            return Some(swap.clone());
+        }
+        let dir = path.parent()?;
+        let mut cache = self.swap_cache.lock().ok()?;
+        cache
+            .local
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| swap::local_swaps(dir))
+            .get(path)
+            .cloned()
+    }
+
+    /// For the swap column: None without a swap file, whether its vim is running otherwise
+    pub fn swap_state(&self, fileitem: &FileItem) -> Option<bool> {
+        let swap = self.swap_file(fileitem)?;
+        let mut cache = match self.swap_cache.lock() {
+            Ok(c) => c,
+            Err(_) => return Some(swap::is_in_use(&swap)),
+        };
+        if let Some(in_use) = cache.in_use.get(&swap) {
+            return Some(*in_use);
+        }
+        let in_use = swap::is_in_use(&swap);
+        cache.in_use.insert(swap, in_use);
+        Some(in_use)
+    }
+
+    /// Rescan the swap directories, only when the swap column is shown
+    fn refresh_swap_files(&mut self) {
+        if let Ok(mut cache) = self.swap_cache.lock() {
+            *cache = SwapCache::default();
+        }
+        if self.config.columns.contains(&ColumnType::SWAP) {
+            self.swap_files = swap::scan(&self.swap_dirs);
+        }
+    }
+
+    /// Size of the item relative to the largest of its siblings, in [0, 1]
+    pub fn size_ratio(&self, fileitem: &FileItem) -> f64 {
+        let parent = match fileitem.parent.as_ref() {
+            Some(p) => p,
+            None => return 0.0,
+        };
+        let cached = self
+            .sibling_max_size
+            .lock()
+            .ok()
+            .and_then(|m| m.get(&parent.id).cloned());
+        let max = match cached {
+            Some(max) => max,
+            None => {
+                let max = self
+                    .descendant_range(parent.id)
+                    .map(|i| &self.file_items[i])
+                    .filter(|fi| fi.level == fileitem.level)
+                    .map(|fi| self.item_size(fi))
+                    .max()
+                    .unwrap_or(0);
+                if let Ok(mut m) = self.sibling_max_size.lock() {
+                    m.insert(parent.id, max);
+                }
+                max
+            }
+        };
+        if max == 0 {
+            0.0
+        } else {
+            self.item_size(fileitem) as f64 / max as f64
+        }
+    }
+    /// The items operations act on, with `select_recursive` the descendants of a selected
+    /// directory are dropped since they are included by the directory
+    fn selected_targets(&self) -> Vec<usize> {
+        let mut targets: Vec<usize> = self.selected_items.iter().cloned().collect();
+        if self.config.select_recursive {
+            targets.retain(|idx| self.selected_ancestor(*idx).is_none());
+        }
+        targets.sort();
+        targets
+    }
+    fn has_selection(&self) -> bool {
+        !self.selected_items.is_empty() || !self.selected_offscreen.is_empty()
+    }
+    fn clear_selection(&mut self) {
+        self.selected_items.clear();
+        self.selected_offscreen.clear();
+    }
+    /// The paths of the selected targets, displayed or not, in the display order then the
+    /// path order
+    fn selected_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .selected_targets()
+            .iter()
+            .map(|idx| self.file_items[*idx].path.clone())
+            .collect();
+        let mut offscreen: Vec<&PathBuf> = self
+            .selected_offscreen
+            .iter()
+            // inside a selected directory, with `select_recursive` it's covered already
+            .filter(|p| {
+                !self.config.select_recursive
+                    || !paths
+                        .iter()
+                        .chain(self.selected_offscreen.iter())
+                        .any(|dir| p.starts_with(dir) && p != &dir)
+            })
+            .collect();
+        offscreen.sort();
+        paths.extend(offscreen.into_iter().cloned());
+        paths
+    }
+    /// Keep the selection by path while the items are listed again
+    fn stash_selection(&mut self) {
+        for idx in self.selected_items.drain() {
+            if let Some(fi) = self.file_items.get(idx) {
+                self.selected_offscreen.insert(fi.path.clone());
+            }
+        }
+    }
+    /// Toggle the item, with `select_recursive` a directory takes its displayed descendants
+    /// along, and unselecting an implicitly selected item turns its ancestor partial
+    fn toggle_item_selection(&mut self, idx: usize) {
+        if !self.config.select_recursive {
+            if !self.selected_items.remove(&idx) {
+                self.selected_items.insert(idx);
+            }
+            return;
+        }
+        if let Some(ancestor) = self.selected_ancestor(idx) {
+            // spell out the ancestor's selection, minus the item
+            self.selected_items.remove(&ancestor);
+            let excluded = self.descendant_range(idx);
+            for i in self.descendant_range(ancestor) {
+                if i != idx && !excluded.contains(&i) {
+                    self.selected_items.insert(i);
+                }
+            }
+            // the intermediate directories are partially selected
+            let mut parent = self.file_items[idx].parent.clone();
+            while let Some(p) = parent {
+                if p.id == ancestor {
+                    break;
+                }
+                self.selected_items.remove(&p.id);
+                parent = p.parent.clone();
+            }
+            return;
+        }
+        let selected = !self.selected_items.remove(&idx);
+        // the descendants are covered by the directory either way
+        for i in self.descendant_range(idx) {
+            self.selected_items.remove(&i);
+        }
+        if selected {
+            self.selected_items.insert(idx);
+        }
+    }
+    pub fn is_item_cut(&self, path: &Path) -> bool {
+        self.cut_items.contains(path)
+    }
+    pub fn is_item_marked_for_deletion(&self, path: &Path) -> bool {
+        self.marked_for_deletion.contains(path)
+    }
+    /// Called for every tree when the clipboard changes, so that the items to be moved
+    /// are dimmed everywhere
+    pub async fn update_cut_items<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        cut_items: HashSet<PathBuf>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.cut_items.is_empty() && cut_items.is_empty() {
+            return Ok(());
+        }
+        self.cut_items = cut_items;
+        if !self.file_items.is_empty() {
+            self.redraw_subtree(nvim, 0, false).await?;
+        }
+        Ok(())
+    }
+    pub fn init_git_repo<P: AsRef<Path>>(&mut self, path: P) {
+        match Repository::discover(path) {
+            Ok(repo) => self.git_repo = Some(Mutex::new(repo)),
+            Err(e) => {
+                info!("Not a git repo: {:?}", e);
+            }
+        }
+    }
+    fn git_stamp_dirs(&self) -> Option<(PathBuf, Option<PathBuf>)> {
+        let repo = self.git_repo.as_ref()?.try_lock()?;
+        Some((
+            repo.path().to_path_buf(),
+            repo.workdir().map(Path::to_path_buf),
+        ))
+    }
+    fn read_git_stamp(&self) -> Option<GitStamp> {
+        let (git_dir, work_dir) = self.git_stamp_dirs()?;
+        Some(git_stamp(&git_dir, work_dir.as_deref()))
+    }
+    /// The git statuses are needed by the git column, and to dim the git-ignored files
+    /// shown by show_ignored_files
+    fn git_statuses_shown(&self) -> bool {
+        self.config.columns.contains(&ColumnType::GIT) || self.config.show_ignored_files
+    }
+    fn polls_git(&self) -> bool {
+        self.git_statuses_shown() && self.git_stamp.is_some() && !self.network_fs
+    }
+
+    fn polls_dirs(&self) -> bool {
+        self.config.flash_new_files != 0 && !self.network_fs && !self.flat_view
+    }
+
+    /// Whether the watcher has something to look at in a tree shown in a window: the git
+    /// statuses shown, the polled directories or directory sizes being summed up
+    pub fn needs_polling(&self) -> bool {
+        self.shown
+            && (self.polls_git()
+                || self.polls_dirs()
+                || self
+                    .size_cache
+                    .lock()
+                    .map_or(false, |c| c.computed || !c.pending.is_empty()))
+    }
+
+    /// What `poll_dir_stamps` and `poll_git_stamp` compare, to be stated by the caller
+    pub fn poll_targets(&self) -> PollTargets {
+        let dirs = if self.polls_dirs() {
+            self.file_items
+                .iter()
+                .enumerate()
+                .filter(|(idx, fi)| {
+                    fi.metadata.is_dir() && (*idx == 0 || self.is_item_opened(&fi.path))
+                })
+                .map(|(_, fi)| fi.path.clone())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let git = if self.polls_git() {
+            self.git_stamp_dirs()
+        } else {
+            None
+        };
+        PollTargets { dirs, git }
+    }
+
+    /// Whether the git statuses are outdated because `.git/index`, `.git/HEAD` or an
+    /// ignore file changed. A change is reported once it stayed the same for a whole poll
+    /// interval, since git writes these files several times during a single commit
+    pub fn poll_git_stamp(&mut self, stamps: &PollStamps) -> bool {
+        if !self.polls_git() {
+            return false;
+        }
+        let current = stamps.git;
+        if current == self.git_stamp {
+            self.git_stamp_pending = None;
+            false
+        } else if current == self.git_stamp_pending {
+            self.git_stamp_pending = None;
+            true
+        } else {
+            self.git_stamp_pending = current;
+            false
+        }
+    }
+    /// The displayed directories whose mtime changed since the last poll, entries were
+    /// added or removed outside the tree. A directory is only recorded by its first poll
+    pub fn poll_dir_stamps(&mut self, stamps: &PollStamps) -> Vec<PathBuf> {
+        if !self.polls_dirs() {
+            self.dir_stamps.clear();
+            return Vec::new();
+        }
+        // the directories displayed still, the tree may have changed since the stat
+        let mut current = HashMap::new();
+        let mut changed = Vec::new();
+        for (idx, fi) in self.file_items.iter().enumerate() {
+            if !fi.metadata.is_dir() || (idx != 0 && !self.is_item_opened(&fi.path)) {
+                continue;
+            }
+            let mtime = match stamps.dirs.get(&fi.path) {
+                Some(mtime) => *mtime,
+                None => continue,
+            };
+            if self
+                .dir_stamps
+                .get(&fi.path)
+                .map_or(false, |old| *old != mtime)
+            {
+                changed.push(fi.path.clone());
+            }
+            current.insert(fi.path.clone(), mtime);
+        }
+        self.dir_stamps = current;
+        changed
+    }
+    /// List the changed directories again and flash the entries that appeared in them,
+    /// build outputs or downloads landing in view
+    pub async fn refresh_changed_dirs<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        dirs: &[PathBuf],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut appeared = HashSet::new();
+        for dir in dirs {
+            // listed again along with its changed ancestor
+            if dirs.iter().any(|d| d != dir && dir.starts_with(d)) {
+                continue;
+            }
+            let idx = match self.file_items.iter().position(|fi| &fi.path == dir) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let before: HashSet<PathBuf> = self
+                .descendant_range(idx)
+                .map(|i| self.file_items[i].path.clone())
+                .collect();
+            self.redraw_subtree(nvim, idx, true).await?;
+            for i in self.descendant_range(idx) {
+                if !before.contains(&self.file_items[i].path) {
+                    appeared.insert(self.file_items[i].path.clone());
+                }
+            }
+        }
+        // the rows once every directory is redrawn
+        let rows: Vec<Value> = self
+            .file_items
+            .iter()
+            .enumerate()
+            .filter(|(_, fi)| appeared.contains(&fi.path))
+            .map(|(idx, _)| Value::from(idx))
+            .collect();
+        if rows.is_empty() {
+            return Ok(());
+        }
+        info!("{} new entries in {:?}", rows.len(), dirs);
+        let args = vec![
+            self.bufnr.clone(),
+            Value::from(self.flash_ns_id),
+            Value::Array(rows),
+            Value::from(self.config.flash_new_files as u64 * 1000),
+        ];
+        nvim_call::execute_lua(nvim, "tree.flash_rows(...)", args).await?;
+        Ok(())
+    }
+    /// The status of the file, or the merged statuses below the directory
+    pub fn git_status(&self, path: &str) -> Option<Status> {
+        self.git_map
+            .get(path)
+            .or_else(|| self.git_dir_map.get(path))
+            .cloned()
+    }
+    /// Whether git ignores the file, or one of the directories it's in. git reports the
+    /// ignored directories, with a trailing slash, without what's inside
+    pub fn is_git_ignored(&self, path: &Path) -> bool {
+        let ignored = |key: &str| {
+            self.git_map
+                .get(key)
+                .map_or(false, |status| status.contains(Status::IGNORED))
+        };
+        !self.git_map.is_empty()
+            && path.ancestors().any(|p| {
+                let key = p.to_string_lossy();
+                ignored(&key) || ignored(&format!("{}/", key))
+            })
+    }
+    pub fn update_git_map(&mut self) {
+        // a status scan stats every file of the repository
+        if self.network_fs {
+            self.git_map.clear();
+            self.git_dir_map.clear();
+            return;
+        }
+        if self.git_repo.is_none() {
+            self.init_git_repo(&self.file_items[0].path.clone())
+        }
+        self.git_stamp = self.read_git_stamp();
+        if let Some(ref mutex) = self.git_repo {
+            if let Some(ref repo) = mutex.try_lock() {
+                self.git_map.clear();
+                match repo.statuses(None) {
+                    Ok(statuses) => {
+                        let work_dir = repo.workdir().unwrap();
+                        for status in statuses.iter() {
+                            // keyed like the cells look them up, lossily
+                            let path = String::from_utf8_lossy(status.path_bytes());
+                            self.git_map.insert(
+                                work_dir.join(path.as_ref()).to_string_lossy().into_owned(),
+                                status.status(),
+                            );
+                        }
+                        self.git_dir_map = aggregate_git_dirs(&self.git_map, work_dir);
+                        info!("git_map: {:?}", self.git_map);
+                    }
+                    Err(e) => error!("Fail to get status: {:?}", e),
+                }
+            } else {
+                info!("We failed the race!");
+            }
+        } else {
+            info!("Git not enabled");
+        }
+    }
+    pub async fn action<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        action: &str,
+        args: Value,
+        ctx: Context,
+    ) {
+        info!(
+            "Action: {:?}, \n args: {:?}, \n ctx: {:?}",
+            action, args, ctx
+        );
+        if self.config.readonly && MUTATING_ACTIONS.contains(&action) {
+            let msg = format!("{}: the tree is read-only", action);
+            if let Err(e) = self.notify(nvim, NotifyLevel::WARN, &msg).await {
+                error!("can't report the error: {}", e);
+            }
+            return;
+        }
+        // the items the action works on, a NotFound on one of them is no news to the user
+        let mut sources = self.selected_paths();
+        if let Some(item) = (ctx.cursor as usize)
+            .checked_sub(1)
+            .and_then(|idx| self.file_items.get(idx))
+        {
+            sources.push(item.path.clone());
+        }
+        match match action {
+            "drop" => self.action_drop(nvim, args, ctx).await,
+            "open" => self.action_open(nvim, args, ctx).await,
+            "open_tree" => self.action_open_tree(nvim, args, ctx).await,
+            "smart_expand" => self.action_smart_expand(nvim, args, ctx).await,
+            "close_tree" => self.action_close_tree(nvim, args, ctx).await,
+            "open_or_close_tree" => self.action_open_or_close_tree(nvim, args, ctx).await,
+            "click" => self.action_click(nvim, args, ctx).await,
+            "open_directory" => self.action_open_directory(nvim, args, ctx).await,
+            "cd" => self.action_cd(nvim, args, ctx).await,
+            "call" => self.action_call(nvim, args, ctx).await,
+            "new_file" => self.action_new_file(nvim, args, ctx).await,
+            "rename" => self.action_rename(nvim, args, ctx).await,
+            "duplicate" => self.action_duplicate(nvim, args, ctx).await,
+            "toggle_select" => self.action_toggle_select(nvim, args, ctx).await,
+            "remove" => self.action_remove(nvim, args, ctx).await,
+            "remove_trash" => self.action_remove_trash(nvim, args, ctx).await,
+            "open_with" => self.action_open_with(nvim, args, ctx).await,
+            "quick_cd" => self.action_quick_cd(nvim, args, ctx).await,
+            "execute_system" => self.action_execute_system(nvim, args, ctx).await,
+            "open_external_manager" => self.action_open_external_manager(nvim, args, ctx).await,
+            "preview" => self.action_preview(nvim, args, ctx).await,
+            "diff_with" => self.action_diff_with(nvim, args, ctx).await,
+            "mark_delete" => self.action_mark_delete(nvim, args, ctx).await,
+            "purge_marked" => self.action_purge_marked(nvim, args, ctx).await,
+            "toggle_ignored_files" => self.action_show_ignored(nvim, args, ctx).await,
+            "yank_path" => self.action_yank_path(nvim, args, ctx).await,
+            "sort_by_column" => self.action_sort_by_column(nvim, args, ctx).await,
+            "clear_select_all" => self.action_clear_select_all(nvim, args, ctx).await,
+            "toggle_select_all" => self.action_toggle_select_all(nvim, args, ctx).await,
+            "redraw" => self.action_redraw(nvim, args, ctx).await,
+            "resize" => self.action_resize(nvim, args, ctx).await,
+            "fit_width" => self.action_fit_width(nvim, args, ctx).await,
+            "collapse_others" => self.action_collapse_others(nvim, args, ctx).await,
+            "scroll_left" => self.action_scroll(nvim, args, ctx, false).await,
+            "scroll_right" => self.action_scroll(nvim, args, ctx, true).await,
+            "show_info" => self.action_show_info(nvim, args, ctx).await,
+            "update_git_map" => self.action_update_git_map(nvim, args, ctx).await,
+            "filter" => self.action_filter(nvim, args, ctx).await,
+            "search" => self.action_search(nvim, args, ctx).await,
+            "select_pattern" => self.action_select_pattern(nvim, args, ctx).await,
+            "clear_filter" => self.action_clear_filter(nvim, args, ctx).await,
+            "filter_extension" => self.action_filter_extension(nvim, args, ctx).await,
+            "toggle_binary_files" => self.action_toggle_binary_files(nvim, args, ctx).await,
+            "git_changed" => self.action_git_changed(nvim, args, ctx).await,
+            "delete_swap" => self.action_delete_swap(nvim, args, ctx).await,
+            "change_mode" => self.action_change_mode(nvim, args, ctx).await,
+            "git_restore" => self.action_git_restore(nvim, args, ctx).await,
+            "copy" => self.action_copy(nvim, args, ctx).await,
+            "move" => self.action_move(nvim, args, ctx).await,
+            "paste" => self.action_paste(nvim, args, ctx).await,
+            "cancel_copy" => self.action_cancel_copy(nvim, args, ctx).await,
+            "load_more" => self.action_load_more(nvim, args, ctx).await,
+            "run_command" => self.action_run_command(nvim, args, ctx).await,
+            "goto_file" => self.action_goto_file(nvim, args, ctx).await,
+            "selection_list" => self.action_selection_list(nvim, args, ctx).await,
+            _ => {
+                error!("Unknown action: {}", action);
+                return;
+            }
+        } {
+            Ok(_) => {}
+            Err(e) => {
+                error!("action {}: {:?}", action, e);
+                self.report_error(nvim, action, &sources, TreeError::from(e))
+                    .await;
+            }
+        }
+        if let Err(e) = self.refresh_custom_columns(nvim).await {
+            error!("custom columns: {:?}", e);
+        }
+        self.save_session();
+    }
+
+    /// Surface the failure of an action through notify, a file gone in the
+    /// meantime also refreshes the tree and goes unreported when it's one of `sources`
+    async fn report_error<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        action: &str,
+        sources: &[PathBuf],
+        err: TreeError,
+    ) {
+        if let TreeError::Io(Some(path), e) = &err {
+            if e.kind() == io::ErrorKind::NotFound {
+                if let Err(e) = self.redraw_subtree(nvim, 0, true).await {
+                    error!("refresh after {}: {:?}", action, e);
+                }
+                if sources.contains(path) {
+                    return;
+                }
+            }
+        }
+        let level = match &err {
+            TreeError::Conflict(_) | TreeError::InvalidArg(_) => NotifyLevel::WARN,
+            _ => NotifyLevel::ERROR,
+        };
+        let msg = format!("{}: {}", action, err);
+        if let Err(e) = self.notify(nvim, level, &msg).await {
+            error!("can't report the error: {}", e);
+        }
+    }
+
+    /// Define the highlight groups of the icons overridden with a color
+    pub async fn register_icon_highlights<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), NvimCallError> {
+        let commands = icon_override_commands(&self.config.icons);
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let commands = commands.into_iter().map(Value::from).collect();
+        nvim_call::execute_lua(
+            nvim,
+            "tree.run_commands_batch(...)",
+            vec![Value::Array(commands)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// 3 directories, 12 files, 2 selected, 1.5 MB, the size is the one of the listed files
+    pub fn summary(&self) -> String {
+        let (mut dirs, mut files, mut size) = (0, 0, 0);
+        // the root isn't counted
+        for fi in self.file_items.iter().skip(1) {
+            if fi.metadata.is_dir() {
+                dirs += 1;
+            } else {
+                files += 1;
+                size += fi.metadata.len();
+            }
+        }
+        let plural =
+            |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+        format!(
+            "{}, {}, {} selected, {}",
+            plural(dirs, "directory", "directories"),
+            plural(files, "file", "files"),
+            self.selected_items.len() + self.selected_offscreen.len(),
+            short_size(size)
+        )
+    }
+
+    /// Draw the summary_footer with the current counts, after every change of the lines
+    pub async fn update_footer<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), NvimCallError> {
+        let row = match self.config.summary_footer.as_str() {
+            "" => return Ok(()),
+            "root" => 0,
+            _ => self.file_items.len().saturating_sub(1),
+        };
+        nvim_call::execute_lua(
+            nvim,
+            "tree.set_footer(...)",
+            vec![
+                self.bufnr.clone(),
+                Value::from(self.footer_ns_id),
+                Value::from(row),
+                Value::from(self.summary()),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Draw a "… N more" virtual line after the listed entries of the directories that
+    /// were cut at dir_chunk_size
+    pub async fn update_more_marks<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), NvimCallError> {
+        let hidden = match self.hidden_entries.lock() {
+            Ok(hidden) => hidden.clone(),
+            Err(_) => return Ok(()),
+        };
+        let mut marks = Vec::new();
+        if !hidden.is_empty() {
+            for (idx, item) in self.file_items.iter().enumerate() {
+                let count = match hidden.get(&item.path) {
+                    Some(count) => *count,
+                    None => continue,
+                };
+                if idx != 0 && !self.is_item_opened(&item.path) {
+                    continue;
+                }
+                // below the last line of the subtree, lined up with the file names
+                let end = self.file_items[idx + 1..]
+                    .iter()
+                    .position(|fi| fi.level <= item.level)
+                    .map_or(self.file_items.len(), |n| idx + 1 + n);
+                let last_child = (idx + 1..end)
+                    .rev()
+                    .find(|i| self.file_items[*i].level == item.level + 1)
+                    .unwrap_or(end - 1);
+                let indent = self
+                    .col_map
+                    .get(&ColumnType::FILENAME)
+                    .and_then(|cells| cells.get(last_child))
+                    .map_or(0, |cell| cell.col_start.saturating_sub(self.hscroll));
+                let text = format!("{}… {} more", " ".repeat(indent), thousands(count));
+                marks.push(Value::Array(vec![Value::from(end - 1), Value::from(text)]));
+            }
+        }
+        let drawn = self
+            .more_marks_drawn
+            .swap(!marks.is_empty(), std::sync::atomic::Ordering::Relaxed);
+        if marks.is_empty() && !drawn {
+            return Ok(());
+        }
+        nvim_call::execute_lua(
+            nvim,
+            "tree.set_more_marks(...)",
+            vec![
+                self.bufnr.clone(),
+                Value::from(self.more_ns_id),
+                Value::Array(marks),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Put the ancestors of the root in the winbar, clicking one changes the root to it.
+    /// Cleared when the breadcrumbs are disabled
+    pub async fn update_breadcrumbs<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), NvimCallError> {
+        let crumbs = if self.config.breadcrumbs {
+            let root = &self.file_items[0].path;
+            let mut crumbs: Vec<Value> = root
+                .ancestors()
+                .map(|p| {
+                    let label = match p.file_name() {
+                        Some(name) => name.to_string_lossy().into_owned(),
+                        None => p.to_string_lossy().into_owned(),
+                    };
+                    Value::Array(vec![Value::from(label), path_value(p)])
+                })
+                .collect();
+            crumbs.reverse();
+            Value::Array(crumbs)
+        } else {
+            Value::Nil
+        };
+        nvim_call::execute_lua(
+            nvim,
+            "tree.set_breadcrumbs(...)",
+            vec![self.bufnr.clone(), crumbs],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub fn custom_cell(
+        &self,
+        column: &str,
+        path: &Path,
+    ) -> Option<&(String, Option<Cow<'static, str>>)> {
+        self.custom_cells.get(column)?.get(path)
+    }
+
+    /// The width of the widest cell of the custom column, measured once per draw
+    pub fn custom_column_width(&self, column: &str) -> usize {
+        if let Some(width) = self
+            .custom_widths
+            .lock()
+            .ok()
+            .and_then(|w| w.get(column).cloned())
+        {
+            return width;
+        }
+        let width = self.measure_custom_column(column);
+        if let Ok(mut widths) = self.custom_widths.lock() {
+            widths.insert(column.to_owned(), width);
+        }
+        width
+    }
+
+    fn measure_custom_column(&self, column: &str) -> usize {
+        self.custom_cells.get(column).map_or(0, |cells| {
+            cells
+                .values()
+                .map(|(text, _)| UnicodeWidthStr::width(text.as_str()))
+                .max()
+                .unwrap_or(0)
+        })
+    }
+
+    /// Draw the custom column `name` with `renderer` rather than its lua callback, the
+    /// listed items get their cells right away
+    pub fn register_column(&mut self, name: &str, renderer: Arc<dyn ColumnRenderer>) {
+        self.renderers.insert(name.to_owned(), renderer);
+        self.custom_cells.remove(name);
+        let items = self.file_items.clone();
+        self.render_columns(&items);
+        let len = self.file_items.len();
+        self.update_cells(0, len);
+    }
+
+    /// Compute the cells of `items` in the columns with a renderer. Whether one of the
+    /// columns got wider, the cells drawn before have to be made again to line up
+    fn render_columns(&mut self, items: &[FileItemPtr]) -> bool {
+        let mut widened = false;
+        for (name, renderer) in &self.renderers {
+            if !self
+                .config
+                .columns
+                .contains(&ColumnType::CUSTOM(name.clone()))
+            {
+                continue;
+            }
+            let width = self.measure_custom_column(name);
+            let mut new_width = width;
+            let store = self
+                .custom_cells
+                .entry(name.clone())
+                .or_insert_with(HashMap::new);
+            for item in items {
+                match renderer.render(item) {
+                    Some((text, hl_group)) => {
+                        let text = text.replace('\n', " ");
+                        new_width = new_width.max(UnicodeWidthStr::width(text.as_str()));
+                        store.insert(item.path.clone(), (text, hl_group.map(Cow::Borrowed)));
+                    }
+                    None => {
+                        store.remove(&item.path);
+                    }
+                }
+            }
+            widened |= new_width > width;
+        }
+        widened
+    }
+
+    /// Ask the lua callbacks for the cells of the custom columns not known yet, in a single
+    /// call, then draw the tree again with them
+    #[instrument(skip_all)]
+    async fn refresh_custom_columns<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let names: Vec<String> = self
+            .config
+            .columns
+            .iter()
+            .filter_map(|col| match col {
+                ColumnType::CUSTOM(name) if !self.renderers.contains_key(name) => {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        if names.is_empty() {
+            return Ok(());
+        }
+        // the root has no custom cell
+        let missing: Vec<PathBuf> = self
+            .file_items
+            .iter()
+            .skip(1)
+            .map(|fi| fi.path.clone())
+            .filter(|path| {
+                names.iter().any(|name| {
+                    self.custom_cells
+                        .get(name)
+                        .map_or(true, |cells| !cells.contains_key(path))
+                })
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let ret = nvim_call::execute_lua(
+            nvim,
+            "return tree.custom_columns(...)",
+            vec![
+                Value::Array(names.iter().map(|x| Value::from(x.as_str())).collect()),
+                Value::Array(missing.iter().map(|x| path_value(x)).collect()),
+            ],
+        )
+        .await?;
+        let columns = match ret {
+            Value::Array(columns) if columns.len() == names.len() => columns,
+            _ => return Err(Box::new(ArgError::new("custom_columns: wrong return type"))),
+        };
+        let widths: Vec<usize> = names
+            .iter()
+            .map(|name| self.measure_custom_column(name))
+            .collect();
+        for (name, cells) in names.iter().cloned().zip(columns) {
+            let cells = cells.as_array().cloned().unwrap_or_default();
+            let store = self.custom_cells.entry(name).or_insert_with(HashMap::new);
+            for (path, cell) in missing.iter().zip(cells) {
+                // [text, hl_group]
+                let cell = cell.as_array().cloned().unwrap_or_default();
+                let text = cell
+                    .get(0)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .replace('\n', " ");
+                let hl_group = cell
+                    .get(1)
+                    .and_then(|v| v.as_str())
+                    .filter(|x| !x.is_empty())
+                    .map(|x| Cow::Owned(x.to_owned()));
+                store.insert(path.clone(), (text, hl_group));
+            }
+        }
+        let len = self.file_items.len();
+        let widened = names
+            .iter()
+            .zip(widths)
+            .any(|(name, width)| self.measure_custom_column(name) != width);
+        // the rows of the new cells only, the others keep theirs unless a column widened
+        let (sl, el) = if widened {
+            (0, len)
+        } else {
+            let missing: HashSet<&PathBuf> = missing.iter().collect();
+            let rows: Vec<usize> = (0..len)
+                .filter(|&i| missing.contains(&self.file_items[i].path))
+                .collect();
+            match (rows.first(), rows.last()) {
+                (Some(&first), Some(&last)) => (first, last + 1),
+                _ => return Ok(()),
+            }
+        };
+        self.update_cells(sl, el);
+        self.redraw_lines_diffed(nvim, sl, el).await?;
+        self.auto_fit_width(nvim).await
+    }
+
+    /// Bring back the expanded directories and the cursor history saved for the tree in
+    /// session_file, returns the saved root when it's `start_path` or a directory below
+    pub fn restore_session(&mut self, start_path: &str) -> Option<String> {
+        if self.config.session_file.is_empty() {
+            return None;
+        }
+        let file = PathBuf::from(expand_path(&self.config.session_file));
+        let session = match session::load(&file, &self.config.buffer_name) {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                info!("no session for {} in {:?}", self.config.buffer_name, file);
+                return None;
+            }
+            // no file yet is the first start, anything else is worth a warning
+            Err(e) if !file.exists() => {
+                info!("no session restored from {:?}: {}", file, e);
+                return None;
+            }
+            Err(e) => {
+                warn!(
+                    "can't read the session {:?}: {}, starting at {}",
+                    file, e, start_path
+                );
+                return None;
+            }
+        };
+        self.apply_session(&session);
+        self.session_saved = session.to_bytes();
+        let root = Path::new(&session.root);
+        if !root.is_dir() {
+            warn!(
+                "the root {:?} of the session is gone, starting at {}",
+                session.root, start_path
+            );
+            return None;
+        }
+        if root.starts_with(start_path) {
+            Some(session.root)
+        } else {
+            None
+        }
+    }
+
+    /// Expand the directories of `session` and take its cursor history, the root is left
+    /// to the caller
+    pub fn apply_session(&mut self, session: &Session) {
+        for path in &session.expanded {
+            if Path::new(path).is_dir() {
+                self.set_expanded(path);
+            }
+        }
+        for (path, line) in &session.cursor_history {
+            let path = PathBuf::from(path);
+            self.cursor_history.insert(path.clone(), *line);
+            self.cursor_history_lru.retain(|v| v != &path);
+            self.cursor_history_lru.push(path);
+        }
+        self.prune_cursor_history();
+    }
+
+    /// The root, expanded directories and cursor history
+    pub fn session(&self) -> Session {
+        Session {
+            root: self.file_items[0]
+                .path
+                .to_str()
+                .unwrap_or_default()
+                .to_owned(),
+            // the session file keeps UTF-8 paths only
+            expanded: self
+                .expand_store_lru
+                .iter()
+                .filter(|path| self.expand_store.get(*path) == Some(&true))
+                .filter_map(|path| Some(path.to_str()?.to_owned()))
+                .collect(),
+            cursor_history: self
+                .cursor_history_lru
+                .iter()
+                .filter_map(|path| {
+                    Some((path.to_str()?.to_owned(), *self.cursor_history.get(path)?))
+                })
+                .collect(),
+        }
+    }
+
+    /// Write the state to session_file, if it changed since the last write
+    fn save_session(&mut self) {
+        if self.config.session_file.is_empty() || self.file_items.is_empty() {
+            return;
+        }
+        let session = self.session();
+        let bytes = session.to_bytes();
+        if bytes == self.session_saved {
+            return;
+        }
+        let file = PathBuf::from(expand_path(&self.config.session_file));
+        match session::save(&file, &self.config.buffer_name, &session) {
+            Ok(_) => self.session_saved = bytes,
+            Err(e) => error!("can't save the session to {:?}: {:?}", file, e),
+        }
+    }
+
+    /// Completion spec for path prompts, served by `_tree_complete_path`
+    fn path_completion(&self) -> &'static str {
+        if self.config.show_ignored_files {
+            "customlist,v:lua.tree.complete_path_all"
+        } else {
+            "customlist,v:lua.tree.complete_path"
+        }
+    }
+
+    fn dir_completion(&self) -> &'static str {
+        if self.config.show_ignored_files {
+            "customlist,v:lua.tree.complete_dir_all"
+        } else {
+            "customlist,v:lua.tree.complete_dir"
+        }
+    }
+
+    /// Remember the current root before jumping away from it, bounded like the cursor history
+    fn push_root_history(&mut self) {
+        let root = match self.file_items.get(0) {
+            Some(item) => item.path.clone(),
+            None => return,
+        };
+        if self.root_history.last() == Some(&root) {
+            return;
+        }
+        self.root_history.push(root);
+        let limit = self.config.cursor_history_limit;
+        if self.root_history.len() > limit {
+            let evict_count = self.root_history.len() - limit;
+            self.root_history.drain(..evict_count);
+        }
+    }
+
+    pub fn save_cursor(&mut self, ctx: &Context) {
+        let path = match self.file_items.get(0) {
+            Some(item) => item.path.clone(),
+            None => return,
+        };
+        self.cursor_history.insert(path.clone(), ctx.cursor);
+        self.cursor_history_lru.retain(|v| v != &path);
+        self.cursor_history_lru.push(path);
+        self.prune_cursor_history();
+    }
+
+    /// Mark the directory as expanded and evict the least recently used entries
+    /// once `expand_store_limit` is exceeded
+    fn set_expanded<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref();
+        self.expand_store.insert(path.to_path_buf(), true);
+        self.expand_store_lru.retain(|v| v != path);
+        self.expand_store_lru.push(path.to_path_buf());
+        self.prune_expand_store();
+    }
+
+    /// Whether `root` is on a network file system, for `network_fs`
+    fn detect_network_fs(&mut self, root: &Path) {
+        if self.network_fs_root.as_deref() == Some(root) {
+            return;
+        }
+        self.network_fs = mounts::is_network_fs(root);
+        self.network_fs_root = Some(root.to_path_buf());
+        if self.network_fs {
+            info!("{:?} is on a network file system, degraded mode", root);
+        }
+    }
+
+    /// Expand the directories `initial_expand_level` levels below `root` breadth first,
+    /// a level is only expanded as a whole, so the listing doesn't end half way. Nothing
+    /// is expanded on a network file system
+    pub async fn expand_initial_levels(&mut self, root: &str) {
+        let max_level = self.config.initial_expand_level;
+        let max_items = self.config.initial_expand_max_items;
+        let mut level_dirs = match absolute_path(root) {
+            Ok(p) => vec![p],
+            Err(_) => return,
+        };
+        // every level would be a round trip to the server per directory
+        self.detect_network_fs(&level_dirs[0]);
+        if self.network_fs {
+            return;
+        }
+        let filters = self.entry_filters();
+        let retries = self.config.fs_retries;
+        let mut item_count = 0;
+        // level 0 is the root itself, which is always expanded
+        for level in 0..=max_level {
+            let mut next_dirs = Vec::new();
+            let mut level_count = 0;
+            for dir in &level_dirs {
+                let listing = match read_listing_async(dir, retries).await {
+                    Ok(l) => l,
+                    Err(e) => {
+                        debug!("initial expand: can't read {:?}: {:?}", dir, e);
+                        continue;
+                    }
+                };
+                for (path, metadata) in listing {
+                    if !passes(&filters, &path, &metadata) {
+                        continue;
+                    }
+                    level_count += 1;
+                    if metadata.is_dir() {
+                        next_dirs.push(path);
+                    }
+                }
+            }
+            item_count += level_count;
+            // the root's own listing is always displayed
+            if level > 0 && item_count > max_items {
+                info!(
+                    "initial expand stopped at level {}: {} items",
+                    level, item_count
+                );
+                return;
+            }
+            for dir in &level_dirs {
+                self.set_expanded(dir);
+            }
+            level_dirs = next_dirs;
+        }
+    }
+
+    fn unset_expanded<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref();
+        self.expand_store.remove(path);
+        self.expand_store_lru.retain(|v| v != path);
+    }
+
+    fn prune_cursor_history(&mut self) {
+        let limit = self.config.cursor_history_limit;
+        if self.cursor_history_lru.len() <= limit {
+            return;
+        }
+        let evict_count = self.cursor_history_lru.len() - limit;
+        for path in self.cursor_history_lru.drain(..evict_count) {
+            self.cursor_history.remove(&path);
+        }
+        debug!("cursor_history pruned {} entries", evict_count);
+    }
+
+    fn prune_expand_store(&mut self) {
+        let limit = self.config.expand_store_limit;
+        if self.expand_store_lru.len() <= limit {
+            return;
+        }
+        // directories that are currently displayed as expanded must survive the eviction,
+        // otherwise a redraw would collapse them
+        let displayed: HashSet<&Path> = self
+            .file_items
+            .iter()
+            .filter(|fi| fi.metadata.is_dir())
+            .map(|fi| fi.path.as_path())
+            .collect();
+        let mut evict_count = self.expand_store_lru.len() - limit;
+        let mut kept = Vec::with_capacity(self.expand_store_lru.len());
+        for path in self.expand_store_lru.drain(..) {
+            if evict_count > 0 && !displayed.contains(path.as_path()) {
+                self.expand_store.remove(&path);
+                evict_count -= 1;
+            } else {
+                kept.push(path);
+            }
+        }
+        self.expand_store_lru = kept;
+    }
+
+    pub async fn cwd_input<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        nvim: &Neovim<W>,
+        cwd: &Path,
+        prompt: &str,
+        text: &str,
+        completion: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let save_cwd = nvim_call::query_function(nvim, "getcwd", vec![]).await?;
+        info!("cwd: {:?}", save_cwd);
+        nvim_call::call_function(nvim, "tree#util#cd", vec![path_value(cwd)]).await?;
+
+        let filename = if let Value::String(v) = nvim_call::call_function(
+            nvim,
+            "tree#util#input",
+            vec![
+                Value::from(prompt),
+                Value::from(text),
+                Value::from(completion),
+            ],
+        )
+        .await?
+        {
+            String::from_utf8_lossy(v.as_bytes()).into_owned()
+        } else {
+            return Err(Box::new(ArgError::new("Wrong return type")));
+        };
+
+        nvim_call::call_function(nvim, "tree#util#cd", vec![save_cwd]).await?;
+
+        Ok(filename)
+    }
+
+    pub async fn confirm<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        nvim: &Neovim<W>,
+        question: String,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if let Value::Integer(v) = nvim_call::call_function(
+            nvim,
+            "tree#util#confirm",
+            vec![
+                Value::from(question),
+                Value::from("&Yes\n&No\n&Cancel"),
+                Value::from(2),
+            ],
+        )
+        .await?
+        {
+            Ok(v.as_i64().unwrap() == 1)
+        } else {
+            Err(Box::new(ArgError::new("Invalid return type")))
+        }
+    }
+
+    /// The path to create in place of `path` when it exists: the next free foo(1).txt, or
+    /// `path` itself when the user confirms overwriting it, None when cancelled
+    async fn resolve_name_conflict<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        nvim: &Neovim<W>,
+        path: &Path,
+        can_overwrite: bool,
+    ) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(Some(path.to_owned()));
+        }
+        let suffixed = available_name(path);
+        let question = format!(
+            "{} already exists, create {} instead?",
+            path.display(),
+            suffixed.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let choices = if can_overwrite {
+            "&Suffix\n&Overwrite\n&Cancel"
+        } else {
+            "&Suffix\n&Cancel"
+        };
+        let choice = nvim_call::call_function(
+            nvim,
+            "tree#util#confirm",
+            vec![Value::from(question), Value::from(choices), Value::from(1)],
+        )
+        .await?
+        .as_i64();
+        Ok(match choice {
+            Some(1) => Some(suffixed),
+            Some(2) if can_overwrite => Some(path.to_owned()),
+            _ => None,
+        })
+    }
+
+    /// Show `msg` through `vim.notify`, unless it's below the configured notify_level
+    pub async fn notify<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+        level: NotifyLevel,
+        msg: &str,
+    ) -> Result<(), NvimCallError> {
+        if level < self.config.notify_level {
+            return Ok(());
+        }
+        nvim_call::execute_lua(
+            nvim,
+            "tree.notify(...)",
+            vec![Value::from(level.as_str()), Value::from(msg)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Rename on disk, surrounded by the `workspace/willRenameFiles` and
+    /// `workspace/didRenameFiles` LSP notifications when `lsp_rename` is enabled
+    pub async fn rename_path<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+        from: &Path,
+        to: &Path,
+    ) -> Result<(), TreeError> {
+        if !self.config.lsp_rename {
+            return rename_file(from, to);
+        }
+        let files = Value::Array(vec![Value::Map(vec![
+            (Value::from("old"), path_value(&from)),
+            (Value::from("new"), path_value(&to)),
+        ])]);
+        // servers get the chance to update the imports before the file disappears
+        let edited = nvim_call::execute_lua(
+            nvim,
+            "return tree.lsp_will_rename(...)",
+            vec![files.clone()],
+        )
+        .await?;
+        rename_file(from, to)?;
+        let notified =
+            nvim_call::execute_lua(nvim, "return tree.lsp_did_rename(...)", vec![files]).await?;
+        let msg = format!(
+            "{} -> {}: {} edit(s) applied, {} server(s) notified",
+            from.display(),
+            to.display(),
+            edited.as_u64().unwrap_or(0),
+            notified.as_u64().unwrap_or(0)
+        );
+        info!("{}", msg);
+        self.notify(nvim, NotifyLevel::INFO, &msg).await?;
+        Ok(())
+    }
+
+    /// Carry the state kept by path (expansion, cursor history, marks, clipboard) of `from`
+    /// and everything below it over to `to`, returns the selected paths with the rename
+    /// applied, the selection being kept by index
+    async fn rebase_path_state(&mut self, from: &Path, to: &Path) -> Vec<PathBuf> {
+        let rebase = |path: &Path| -> Option<PathBuf> {
+            path.strip_prefix(from).ok().map(|rest| {
+                if rest.as_os_str().is_empty() {
+                    to.to_path_buf()
+                } else {
+                    to.join(rest)
+                }
+            })
+        };
+        let rebase_owned =
+            |path: &PathBuf| -> PathBuf { rebase(path).unwrap_or_else(|| path.clone()) };
+        self.expand_store = self
+            .expand_store
+            .drain()
+            .map(|(k, v)| (rebase_owned(&k), v))
+            .collect();
+        self.expand_store_lru = self.expand_store_lru.iter().map(rebase_owned).collect();
+        self.cursor_history = self
+            .cursor_history
+            .drain()
+            .map(|(k, v)| (rebase_owned(&k), v))
+            .collect();
+        self.cursor_history_lru = self.cursor_history_lru.iter().map(rebase_owned).collect();
+        self.root_history = self.root_history.iter().map(rebase_owned).collect();
+        self.marked_for_deletion = self
+            .marked_for_deletion
+            .drain()
+            .map(|p| rebase(&p).unwrap_or(p))
+            .collect();
+        self.cut_items = self
+            .cut_items
+            .drain()
+            .map(|p| rebase(&p).unwrap_or(p))
+            .collect();
+        self.selected_offscreen = self
+            .selected_offscreen
+            .drain()
+            .map(|p| rebase(&p).unwrap_or(p))
+            .collect();
+        {
+            let mut clipboard = CLIPBOARD.write().await;
+            for p in clipboard.iter_mut() {
+                if let Some(new) = rebase(p) {
+                    *p = new;
+                }
+            }
+        }
+        self.selected_items
+            .iter()
+            .filter_map(|idx| self.file_items.get(*idx))
+            .map(|fi| rebase(&fi.path).unwrap_or_else(|| fi.path.clone()))
+            .collect()
+    }
+
+    /// Select the items at `paths`, after a redraw moved them around
+    async fn reselect<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        paths: &[PathBuf],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        self.selected_items = self
+            .file_items
+            .iter()
+            .enumerate()
+            .filter(|(_, fi)| paths.contains(&fi.path))
+            .map(|(idx, _)| idx)
+            .collect();
+        self.redraw_subtree(nvim, 0, false).await
+    }
+
+    /// Point the buffers of `from` (or of files under it) to `to`, like `:file`
+    pub async fn rename_buffers<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        nvim: &Neovim<W>,
+        from: &Path,
+        to: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for buf in nvim.list_bufs().await? {
+            let name = buf.get_name().await?;
+            if name.is_empty() {
+                continue;
+            }
+            if let Ok(rest) = Path::new(&name).strip_prefix(from) {
+                let new_name = to.join(rest);
+                info!("rename buffer {} -> {:?}", name, new_name);
+                buf.set_name(&new_name.to_string_lossy()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Offer to close the buffers of the removed paths
+    pub async fn close_removed_buffers<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        nvim: &Neovim<W>,
+        removed: &[PathBuf],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for buf in nvim.list_bufs().await? {
+            let name = buf.get_name().await?;
+            if name.is_empty() || Path::new(&name).exists() {
+                continue;
+            }
+            if removed.iter().any(|p| Path::new(&name).starts_with(p)) {
+                let question = format!("{} has been deleted, close its buffer?", name);
+                if Self::confirm(nvim, question).await? {
+                    let bufnr = buf.get_number().await?;
+                    nvim_call::command(nvim, &format!("silent! bdelete! {}", bufnr)).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    pub async fn redraw_subtree<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        parent_idx: usize,
+        force: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cur = match self.file_items.get(parent_idx) {
+            Some(c) => c,
+            None => return Err(Box::new(ArgError::new("Invalid index"))),
+        }
+        .clone();
+
+        let idx = cur.id;
+        let base_level = cur.level;
+        let start = cur.id + 1;
+        let mut end = start;
+        for fi in &self.file_items[start..] {
+            if fi.level <= base_level {
+                break;
+            }
+            end += 1;
+        }
+
+        info!("remove range [{}, {})", start, end);
+        let new_end;
+        if force {
+            if let Ok(mut cache) = self.size_cache.lock() {
+                cache.sizes.clear();
+            }
+            // the rendered ones are remade as the items are listed
+            let renderers = &self.renderers;
+            self.custom_cells
+                .retain(|name, _| renderers.contains_key(name));
+            invalidate_listings(&cur.path);
+            self.refresh_swap_files();
+            self.remove_items_and_cells(start, end)?;
+            let mut child_items = Vec::new();
+            self.list_children(cur.clone(), &mut child_items, idx + 1)
+                .await?;
+            let child_item_size = child_items.len();
+            self.insert_items_and_cells(start, child_items)?;
+            new_end = start + child_item_size;
+        } else {
+            let cells = self.make_cells(&self.file_items[start..end], start == 0);
+            for (col, cells) in cells {
+                if !self.col_map.contains_key(&col) {
+                    self.col_map.insert(col.clone(), Vec::new());
+                }
+                self.col_map
+                    .get_mut(&col)
+                    .unwrap()
+                    .splice(start..end, cells);
+            }
+            new_end = end;
+        }
+        // the new end after adding the new file
+        info!("redraw range [{}, {})", start, new_end);
+        if force {
+            // update lines (zero based)
+            let ret = (start..new_end).map(|i| self.makeline(i)).collect();
+            self.buf_set_lines(nvim, start as i64, end as i64, true, ret)
+                .await?;
+            self.hl_lines(&nvim, start, new_end).await?;
+        } else {
+            self.redraw_lines_diffed(nvim, start, new_end).await?;
+        }
+        self.auto_fit_width(nvim).await?;
+        Ok(())
+    }
+
+    pub async fn action_redraw<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.redraw_subtree(nvim, 0, true).await?;
+        Ok(())
     }
-    pub fn init_git_repo<P: AsRef<Path>>(&mut self, path: P) {
-        match Repository::discover(path) {
-            Ok(repo) => self.git_repo = Some(Mutex::new(repo)),
-            Err(e) => {
-                info!("Not a git repo: {:?}", e);
+
+    pub async fn action_resize<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut args = match arg {
+            Value::Array(v) => v,
+            _ => {
+                Err(ArgError::new("Invalid arg type"))?;
+                return Ok(());
             }
+        };
+        if args.is_empty() {
+            return Ok(());
         }
+        args.push(self.bufnr.clone());
+        info!(" args for resize: {:?}", args);
+        // nvim_call::execute_lua(nvim, "tree.print_message(...)", vec![Value::from("hello".to_owned())]).await?;
+        nvim_call::execute_lua(nvim, "tree.resize(...)", args).await?;
+        Ok(())
     }
-    pub fn update_git_map(&mut self) {
-        if self.git_repo.is_none() {
-            self.init_git_repo(&self.file_items[0].path.clone())
+
+    /// Resize the window to the widest line, clamped to [min_width, max_width]
+    pub async fn fit_width<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let content_width = (0..self.file_items.len())
+            .map(|i| UnicodeWidthStr::width(self.makeline_full(i).trim_end()))
+            .max()
+            .unwrap_or(0);
+        let min_width = self.config.min_width as usize;
+        let max_width = std::cmp::max(self.config.max_width as usize, min_width);
+        // one more column so that the cursor doesn't scroll the window horizontally
+        let width = std::cmp::min(std::cmp::max(content_width + 1, min_width), max_width);
+        info!("fit width: content {}, window {}", content_width, width);
+        nvim_call::execute_lua(
+            nvim,
+            "tree.resize(...)",
+            vec![Value::from(width), self.bufnr.clone()],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn auto_fit_width<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.auto_fit_width {
+            self.fit_width(nvim).await?;
         }
-        if let Some(ref mutex) = self.git_repo {
-            if let Some(ref repo) = mutex.try_lock() {
-                self.git_map.clear();
-                match repo.statuses(None) {
-                    Ok(statuses) => {
-                        let work_dir = repo.workdir().unwrap();
-                        for status in statuses.iter() {
-                            self.git_map.insert(
-                                work_dir
-                                    .join(status.path().unwrap())
-                                    .to_str()
-                                    .unwrap()
-                                    .to_owned(),
-                                status.status(),
-                            );
-                        }
-                        info!("git_map: {:?}", self.git_map);
-                    }
-                    Err(e) => error!("Fail to get status: {:?}", e),
-                }
-            } else {
-                info!("We failed the race!");
+        Ok(())
+    }
+
+    /// Close every expanded directory but the ones leading to the cursor item
+    pub async fn action_collapse_others<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _args: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cur_path = match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            Some(fi) => fi.path.clone(),
+            None => return Err(Box::new(ArgError::new("invalid cursor pos"))),
+        };
+        let root = self.file_items[0].path.clone();
+        let to_close: Vec<PathBuf> = self
+            .expand_store
+            .keys()
+            .filter(|path| {
+                // the expansion of other roots is left alone
+                path.starts_with(&root) && **path != root && !cur_path.starts_with(path)
+            })
+            .cloned()
+            .collect();
+        if to_close.is_empty() {
+            return Ok(());
+        }
+        for path in &to_close {
+            self.unset_expanded(path);
+        }
+        self.redraw_subtree(nvim, 0, true).await?;
+        // the cursor stays on the same item, which moved up
+        if let Some(idx) = self.file_items.iter().position(|fi| fi.path == cur_path) {
+            let win = Window::new(Value::from(0), nvim.clone());
+            if let Err(e) = win.set_cursor((idx as i64 + 1, 0)).await {
+                warn!("Fail to set cursor position {}: {:?}", idx + 1, e);
             }
-        } else {
-            info!("Git not enabled");
         }
+        Ok(())
     }
-    pub async fn action<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+
+    /// Scroll the lines horizontally by the columns given as argument (8 by default)
+    /// times [count]
+    pub async fn action_scroll<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
-        action: &str,
         args: Value,
         ctx: Context,
-    ) {
-        info!(
-            "Action: {:?}, \n args: {:?}, \n ctx: {:?}",
-            action, args, ctx
-        );
-        match match action {
-            "drop" => self.action_drop(nvim, args, ctx).await,
-            "open_tree" => self.action_open_tree(nvim, args, ctx).await,
-            "close_tree" => self.action_close_tree(nvim, args, ctx).await,
-            "open_or_close_tree" => self.action_open_or_close_tree(nvim, args, ctx).await,
-            "open_directory" => self.action_open_directory(nvim, args, ctx).await,
-            "cd" => self.action_cd(nvim, args, ctx).await,
-            "call" => self.action_call(nvim, args, ctx).await,
-            "new_file" => self.action_new_file(nvim, args, ctx).await,
-            "rename" => self.action_rename(nvim, args, ctx).await,
-            "toggle_select" => self.action_toggle_select(nvim, args, ctx).await,
-            "remove" => self.action_remove(nvim, args, ctx).await,
-            "toggle_ignored_files" => self.action_show_ignored(nvim, args, ctx).await,
-            "yank_path" => self.action_yank_path(nvim, args, ctx).await,
-            "clear_select_all" => self.action_clear_select_all(nvim, args, ctx).await,
-            "toggle_select_all" => self.action_toggle_select_all(nvim, args, ctx).await,
-            "redraw" => self.action_redraw(nvim, args, ctx).await,
-            "resize" => self.action_resize(nvim, args, ctx).await,
-            "update_git_map" => self.action_update_git_map(nvim, args, ctx).await,
-            "copy" => self.action_copy(nvim, args, ctx).await,
-            "move" => self.action_move(nvim, args, ctx).await,
-            "paste" => self.action_paste(nvim, args, ctx).await,
-            _ => {
-                error!("Unknown action: {}", action);
-                return;
-            }
-        } {
-            Ok(_) => {}
-            Err(e) => error!("err: {:?}", e),
+        right: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let step = match args.as_array().and_then(|a| a.get(0)) {
+            Some(v) => val_to_usize(v)?,
+            None => 8,
+        };
+        let amount = step * CursorContext::try_from(&ctx)?.count;
+        let hscroll = if right {
+            // keep at least a column of the widest line in view
+            let content_width = (0..self.file_items.len())
+                .map(|i| UnicodeWidthStr::width(self.makeline_full(i).trim_end()))
+                .max()
+                .unwrap_or(0);
+            std::cmp::min(self.hscroll + amount, content_width.saturating_sub(1))
+        } else {
+            self.hscroll.saturating_sub(amount)
+        };
+        if hscroll == self.hscroll {
+            return Ok(());
         }
+        self.hscroll = hscroll;
+        let buf = Buffer::new(self.bufnr.clone(), nvim.clone());
+        buf.clear_namespace(self.icon_ns_id, 0, -1).await?;
+        self.redraw_subtree(nvim, 0, false).await?;
+        // the root line isn't part of the subtree
+        let root_line = vec![self.makeline(0)];
+        self.buf_set_lines(nvim, 0, 1, true, root_line).await?;
+        self.hl_lines(nvim, 0, 1).await
     }
 
-    pub fn save_cursor(&mut self, ctx: &Context) {
-        if let Some(item) = self.file_items.get(0) {
-            if let Some(path) = item.path.to_str() {
-                self.cursor_history.insert(path.to_owned(), ctx.cursor);
+    pub async fn action_fit_width<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.fit_width(nvim).await
+    }
+
+    /// Show the full name of the cursor item in a popup, it may be truncated in the tree
+    pub async fn action_show_info<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let idx = CursorContext::try_from(&ctx)?.idx;
+        let fi = match self.file_items.get(idx) {
+            Some(fi) => fi,
+            None => {
+                return Err(Box::new(ArgError::new(
+                    "show_info: invalid cursor position",
+                )))
+            }
+        };
+        let name = match self
+            .col_map
+            .get(&ColumnType::FILENAME)
+            .and_then(|c| c.get(idx))
+        {
+            Some(cell) => cell.full_text.clone(),
+            None => fi
+                .path
+                .file_name()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        let modified: chrono::DateTime<chrono::Local> = fi.metadata.modified()?.into();
+        let info = Value::Map(vec![
+            (Value::from("name"), Value::from(name)),
+            (Value::from("path"), path_value(&fi.path)),
+            (Value::from("size"), Value::from(fi.metadata.len())),
+            (
+                Value::from("date"),
+                Value::from(format!("{}", modified.format("%Y-%m-%d %H:%M:%S"))),
+            ),
+        ]);
+        nvim_call::execute_lua(nvim, "tree.show_info(...)", vec![info]).await?;
+        Ok(())
+    }
+
+    /// `sort_by_column [column]`, the column under the cursor by default, cycles its sort
+    /// key through ascending, descending and off
+    pub async fn action_sort_by_column<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let name = match &arg {
+            Value::Array(args) => args.get(0).and_then(|v| v.as_str()),
+            _ => arg.as_str(),
+        }
+        .unwrap_or_default();
+        let key = if !name.is_empty() {
+            SortKey::from_str(name).or_else(|| SortKey::for_column(&ColumnType::from(name)))
+        } else {
+            let idx = CursorContext::try_from(&ctx)?.idx;
+            // the cells are laid out before the horizontal scroll
+            let col = (ctx.cursor_col as usize).saturating_sub(1) + self.hscroll;
+            self.config
+                .columns
+                .iter()
+                .find(|ty| {
+                    self.col_map
+                        .get(ty)
+                        .and_then(|cells| cells.get(idx))
+                        .map_or(false, |cell| cell.col_start <= col && col < cell.col_end)
+                })
+                .and_then(SortKey::for_column)
+        };
+        let key = match key {
+            Some(k) => k,
+            None => {
+                return Err(Box::new(ArgError::new(
+                    "no sortable column, expected filename, extension, size or time",
+                )))
+            }
+        };
+        let keys = &mut self.config.sort_keys;
+        match keys.iter().position(|(k, _)| *k == key) {
+            // the newly sorted column comes first
+            None => keys.insert(0, (key, false)),
+            Some(i) if !keys[i].1 => keys[i].1 = true,
+            Some(i) => {
+                keys.remove(i);
             }
         }
+        self.config.sort = sort_spec(&self.config.sort_keys);
+        info!("sort: {}", self.config.sort);
+        // the listings are sorted as they're read
+        self.redraw_subtree(nvim, 0, true).await
     }
 
-    pub async fn cwd_input<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// `yank_path [absolute|relative|name|uri] [register]`, absolute paths to "+" by default
+    pub async fn action_yank_path<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
         nvim: &Neovim<W>,
-        cwd: &str,
-        prompt: &str,
-        text: &str,
-        completion: &str,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let save_cwd = nvim.call_function("getcwd", vec![]).await?;
-        info!("cwd: {:?}", save_cwd);
-        nvim.call_function("tree#util#cd", vec![Value::from(cwd)])
-            .await?;
+        arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let args = match &arg {
+            Value::Array(args) => args.as_slice(),
+            _ => &[],
+        };
+        let format = args.get(0).and_then(|v| v.as_str()).unwrap_or("absolute");
+        let register = args.get(1).and_then(|v| v.as_str()).unwrap_or("+");
+        let targets = if self.has_selection() {
+            self.selected_paths()
+        } else {
+            let idx = CursorContext::try_from(&ctx)?.idx;
+            vec![self.file_items[idx].path.clone()]
+        };
+        let root = self.file_items[0].path.clone();
+        let mut paths = Vec::new();
+        for path in &targets {
+            paths.push(match format {
+                "absolute" => path.to_string_lossy().into_owned(),
+                // the root itself is "."
+                "relative" => match path.strip_prefix(&root) {
+                    Ok(rel) if rel.as_os_str().is_empty() => ".".to_owned(),
+                    Ok(rel) => rel.to_string_lossy().into_owned(),
+                    Err(_) => path.to_string_lossy().into_owned(),
+                },
+                "name" => path
+                    .file_name()
+                    .unwrap_or_else(|| path.as_os_str())
+                    .to_string_lossy()
+                    .into_owned(),
+                "uri" => file_uri(path),
+                _ => {
+                    return Err(Box::new(ArgError::from_string(format!(
+                        "yank_path: unknown format {}, expected absolute, relative, name or uri",
+                        format
+                    ))))
+                }
+            });
+        }
+        let paths_str = paths.join("\n");
+        // without a clipboard provider the register can't be set, the paths are
+        // still printed so they can be copied from the message
+        if let Err(e) = nvim_call::call_function(
+            nvim,
+            "setreg",
+            vec![Value::from(register), Value::from(paths_str.as_str())],
+        )
+        .await
+        {
+            warn!("yank_path: {}", e);
+        }
+        self.notify(nvim, NotifyLevel::INFO, &paths_str).await?;
+        Ok(())
+    }
 
-        let filename = if let Value::String(v) = nvim
-            .call_function(
-                "tree#util#input",
-                vec![
-                    Value::from(prompt),
-                    Value::from(text),
-                    Value::from(completion),
-                ],
-            )
+    /// Toggle the preview float of the item under the cursor
+    pub async fn action_preview<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let is_open = nvim_call::query_lua(nvim, "return tree.preview_is_open()", vec![])
             .await?
+            .as_bool()
+            .unwrap_or(false);
+        if is_open {
+            nvim_call::execute_lua(nvim, "tree.preview_close()", vec![]).await?;
+            self.preview_open
+                .store(false, std::sync::atomic::Ordering::SeqCst);
+            return Ok(());
+        }
+        let path = match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            Some(fi) => fi.path.clone(),
+            None => return Err(Box::new(ArgError::new("preview: invalid cursor position"))),
+        };
+        let retries = self.config.fs_retries;
+        let args = async_std::task::spawn_blocking(move || preview_args(&path, retries)).await;
+        nvim_call::execute_lua(nvim, "tree.preview_open(...)", args).await?;
+        self.preview_open
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Show the item under the cursor in the preview float, once the cursor rests on it
+    pub fn follow_preview<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+        cursor: usize,
+    ) {
+        use std::sync::atomic::Ordering::SeqCst;
+        if !self.preview_open.load(SeqCst) {
+            return;
+        }
+        let path = match cursor
+            .checked_sub(1)
+            .and_then(|idx| self.file_items.get(idx))
         {
-            v.into_str().unwrap()
-        } else {
-            return Err(Box::new(ArgError::new("Wrong return type")));
+            Some(fi) => fi.path.clone(),
+            None => return,
+        };
+        let generation = self.preview_generation.fetch_add(1, SeqCst) + 1;
+        let latest = self.preview_generation.clone();
+        let preview_open = self.preview_open.clone();
+        let retries = self.config.fs_retries;
+        let nvim = nvim.clone();
+        self.tasks.spawn(async move {
+            async_std::task::sleep(PREVIEW_DEBOUNCE).await;
+            if latest.load(SeqCst) != generation {
+                return;
+            }
+            // closed with :q or <C-w>c, no point in reading the file
+            match nvim_call::query_lua(&nvim, "return tree.preview_is_open()", vec![]).await {
+                Ok(Value::Boolean(true)) => {}
+                Ok(_) => {
+                    preview_open.store(false, SeqCst);
+                    return;
+                }
+                Err(e) => {
+                    error!("preview update error: {}", e);
+                    return;
+                }
+            }
+            let args = async_std::task::spawn_blocking(move || preview_args(&path, retries)).await;
+            // the cursor moved on while reading
+            if latest.load(SeqCst) != generation {
+                return;
+            }
+            if let Err(e) = nvim_call::execute_lua(&nvim, "tree.preview_update(...)", args).await {
+                error!("preview update error: {}", e);
+            }
+        });
+    }
+
+    /// Root the tree at one of the quick_dirs, picked from a numbered list
+    pub async fn action_quick_cd<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.quick_dirs.is_empty() {
+            self.notify(
+                nvim,
+                NotifyLevel::WARN,
+                "No quick_dirs configured, set them with the quick_dirs option",
+            )
+            .await?;
+            return Ok(());
+        }
+        let choices = self
+            .config
+            .quick_dirs
+            .iter()
+            .map(|x| Value::from(x.as_str()))
+            .collect();
+        let choice = nvim_call::execute_lua(
+            nvim,
+            "return tree.inputlist(...)",
+            vec![Value::from("Change root to:"), Value::Array(choices)],
+        )
+        .await?;
+        // 1-based, 0 when cancelled
+        let dir = match choice.as_u64() {
+            Some(i) if i > 0 => match self.config.quick_dirs.get(i as usize - 1) {
+                Some(dir) => expand_path(dir),
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
         };
-
-        nvim.call_function("tree#util#cd", vec![save_cwd]).await?;
-
-        Ok(filename)
+        if !Path::new(&dir).is_dir() {
+            return Err(Box::new(ArgError::from_string(format!(
+                "quick_cd: {} is not a directory",
+                dir
+            ))));
+        }
+        info!("quick_cd: {}", dir);
+        self.change_root(&dir, nvim).await
     }
 
-    pub async fn confirm<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// Let the user pick one of the applications able to open the cursor file
+    pub async fn action_open_with<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
         nvim: &Neovim<W>,
-        question: String,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        if let Value::Integer(v) = nvim
-            .call_function(
-                "tree#util#confirm",
-                vec![
-                    Value::from(question),
-                    Value::from("&Yes\n&No\n&Cancel"),
-                    Value::from(2),
-                ],
-            )
-            .await?
-        {
-            Ok(v.as_i64().unwrap() == 1)
-        } else {
-            Err(Box::new(ArgError::new("Invalid return type")))
+        _arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            Some(fi) => fi.path.clone(),
+            None => {
+                return Err(Box::new(ArgError::new(
+                    "open_with: invalid cursor position",
+                )))
+            }
+        };
+        let apps = open_with::applications_for(&path);
+        if apps.is_empty() {
+            self.notify(nvim, NotifyLevel::WARN, "No application found")
+                .await?;
+            return Ok(());
         }
+        let choices = apps.iter().map(|a| Value::from(a.name.as_str())).collect();
+        let choice = nvim_call::execute_lua(
+            nvim,
+            "return tree.inputlist(...)",
+            vec![
+                Value::from(format!("Open {} with:", path.display())),
+                Value::Array(choices),
+            ],
+        )
+        .await?;
+        // 1-based, 0 when cancelled
+        let app = match choice.as_u64() {
+            Some(i) if i > 0 => match apps.get(i as usize - 1) {
+                Some(app) => app,
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        info!("open {:?} with {:?}", path, app);
+        open_with::launch(app, &path)?;
+        Ok(())
     }
 
-    pub async fn redraw_subtree<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// Run one of the `commands`, the one named in the argument or picked from a numbered
+    /// list, in the cursor directory (the parent of the cursor file). It runs in the
+    /// background, its exit status is notified once it's over
+    pub async fn action_run_command<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
-        parent_idx: usize,
-        force: bool,
+        args: Value,
+        ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let cur = match self.file_items.get(parent_idx) {
-            Some(c) => c,
-            None => return Err(Box::new(ArgError::new("Invalid index"))),
+        if self.config.commands.is_empty() {
+            self.notify(
+                nvim,
+                NotifyLevel::WARN,
+                "No commands configured, set them with the commands option",
+            )
+            .await?;
+            return Ok(());
         }
-        .clone();
-
-        let idx = cur.id;
-        let base_level = cur.level;
-        let start = cur.id + 1;
-        let mut end = start;
-        for fi in &self.file_items[start..] {
-            if fi.level <= base_level {
-                break;
+        let cur = match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            Some(cur) => cur,
+            None => {
+                return Err(Box::new(ArgError::new(
+                    "run_command: invalid cursor position",
+                )))
             }
-            end += 1;
-        }
-
-        info!("remove range [{}, {})", start, end);
-        let new_end;
-        if force {
-            self.remove_items_and_cells(start, end)?;
-            let mut child_items = Vec::new();
-            self.entry_info_recursively_sync(cur.clone(), &mut child_items, idx + 1)?;
-            let child_item_size = child_items.len();
-            self.insert_items_and_cells(start, child_items)?;
-            new_end = start + child_item_size;
+        };
+        let dir = if cur.metadata.is_dir() {
+            cur.path.clone()
         } else {
-            let cells = self.make_cells(&self.file_items[start..end], start == 0);
-            for (col, cells) in cells {
-                if !self.col_map.contains_key(&col) {
-                    self.col_map.insert(col.clone(), Vec::new());
+            cur.path.parent().unwrap().to_path_buf()
+        };
+        let name = match &args {
+            Value::Array(v) => v.get(0).and_then(Value::as_str),
+            _ => None,
+        };
+        let command = match name {
+            Some(name) => match self.config.commands.iter().find(|c| c.name == name) {
+                Some(c) => c.clone(),
+                None => {
+                    return Err(Box::new(ArgError::from_string(format!(
+                        "run_command: no command named {}",
+                        name
+                    ))))
+                }
+            },
+            None => {
+                let choices = self
+                    .config
+                    .commands
+                    .iter()
+                    .map(|c| Value::from(format!("{}: {}", c.name, c.cmd)))
+                    .collect();
+                let choice = nvim_call::execute_lua(
+                    nvim,
+                    "return tree.inputlist(...)",
+                    vec![
+                        Value::from(format!("Run in {}:", dir.display())),
+                        Value::Array(choices),
+                    ],
+                )
+                .await?;
+                // 1-based, 0 when cancelled
+                match choice.as_u64() {
+                    Some(i) if i > 0 => match self.config.commands.get(i as usize - 1) {
+                        Some(c) => c.clone(),
+                        None => return Ok(()),
+                    },
+                    _ => return Ok(()),
                 }
-                self.col_map
-                    .get_mut(&col)
-                    .unwrap()
-                    .splice(start..end, cells);
             }
-            new_end = end;
-        }
-        // the new end after adding the new file
-        info!("redraw range [{}, {})", start, new_end);
-        // update lines (zero based)
-        let ret = (start..new_end).map(|i| self.makeline(i)).collect();
-        self.buf_set_lines(nvim, start as i64, end as i64, true, ret)
-            .await?;
-        self.hl_lines(&nvim, start, new_end).await?;
+        };
+        info!("run_command {:?} in {:?}", command, dir);
+        self.notify(
+            nvim,
+            NotifyLevel::INFO,
+            &format!("Running {} in {}", command.name, dir.display()),
+        )
+        .await?;
+        let nvim = nvim.clone();
+        let notify_level = self.config.notify_level;
+        self.tasks.spawn(async move {
+            let output = {
+                let (cmd, dir, capture) = (command.cmd.clone(), dir.clone(), command.capture);
+                async_std::task::spawn_blocking(move || run_command::run(&cmd, &dir, capture)).await
+            };
+            let (level, msg) = match &output {
+                Ok(out) if out.status.success() => {
+                    (NotifyLevel::INFO, format!("{} finished", command.name))
+                }
+                Ok(out) => (
+                    NotifyLevel::ERROR,
+                    match out.status.code() {
+                        Some(code) => format!("{} failed with exit code {}", command.name, code),
+                        None => format!("{} was killed", command.name),
+                    },
+                ),
+                Err(e) => (
+                    NotifyLevel::ERROR,
+                    format!("{} can't be run: {}", command.name, e),
+                ),
+            };
+            info!("{}", msg);
+            if level >= notify_level {
+                let args = vec![Value::from(level.as_str()), Value::from(msg)];
+                if let Err(e) = nvim_call::execute_lua(&nvim, "tree.notify(...)", args).await {
+                    error!("run_command: {}", e);
+                }
+            }
+            if let (true, Ok(out)) = (command.capture, &output) {
+                let lines = run_command::output_lines(out)
+                    .into_iter()
+                    .map(Value::from)
+                    .collect();
+                let args = vec![
+                    Value::from(format!("[{}] {}", command.name, dir.display())),
+                    Value::Array(lines),
+                ];
+                if let Err(e) = nvim_call::execute_lua(&nvim, "tree.show_output(...)", args).await {
+                    error!("run_command output: {}", e);
+                }
+            }
+        });
         Ok(())
     }
 
-    pub async fn action_redraw<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// Open the selected items (or the cursor item) with the default application of the
+    /// system, directories included
+    pub async fn action_execute_system<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
         _arg: Value,
-        _ctx: Context,
+        ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.redraw_subtree(nvim, 0, true).await?;
+        let targets: Vec<PathBuf> = if !self.has_selection() {
+            match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+                Some(fi) => vec![fi.path.clone()],
+                None => {
+                    return Err(Box::new(ArgError::new(
+                        "execute_system: invalid cursor position",
+                    )))
+                }
+            }
+        } else {
+            self.selected_paths()
+        };
+        for path in &targets {
+            info!("execute_system: {:?}", path);
+            if let Err(e) = open_with::open_default(path) {
+                self.notify(
+                    nvim,
+                    NotifyLevel::ERROR,
+                    &format!("Can't open {}: {}", path.display(), e),
+                )
+                .await?;
+            }
+        }
+        if self.has_selection() {
+            self.clear_selection();
+            self.redraw_subtree(nvim, 0, false).await?;
+        }
         Ok(())
     }
 
-    pub async fn action_resize<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// Open the cursor directory (the parent of the cursor file) in `file_manager`, or in
+    /// the command given as argument
+    pub async fn action_open_external_manager<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
-        arg: Value,
-        _ctx: Context,
+        args: Value,
+        ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut args = match arg {
-            Value::Array(v) => v,
-            _ => {
-                Err(ArgError::new("Invalid arg type"))?;
-                return Ok(());
+        let cur = match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            Some(cur) => cur,
+            None => {
+                return Err(Box::new(ArgError::new(
+                    "open_external_manager: invalid cursor position",
+                )))
             }
         };
-        if args.is_empty() {
-            return Ok(());
+        let dir = if cur.metadata.is_dir() {
+            cur.path.clone()
+        } else {
+            cur.path.parent().unwrap().to_path_buf()
+        };
+        let file_manager = match &args {
+            Value::Array(args) if !args.is_empty() => {
+                args[0].as_str().unwrap_or_default().to_owned()
+            }
+            _ => self.config.file_manager.clone(),
+        };
+        let words = open_with::file_manager_command(&file_manager, &dir);
+        info!("open_external_manager: {:?}", words);
+        if self.config.file_manager_terminal {
+            nvim_call::execute_lua(
+                nvim,
+                "tree.open_terminal(...)",
+                vec![
+                    Value::Array(words.into_iter().map(Value::from).collect()),
+                    path_value(&dir),
+                ],
+            )
+            .await?;
+        } else {
+            open_with::launch_file_manager(&words)?;
         }
-        args.push(self.bufnr.clone());
-        info!(" args for resize: {:?}", args);
-        // nvim.execute_lua("tree.print_message(...)", vec![Value::from("hello".to_owned())]).await?;
-        nvim.execute_lua("tree.resize(...)", args).await?;
         Ok(())
     }
 
-    pub async fn action_yank_path<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// Compare the cursor file against the selected one (or the first item of the clipboard)
+    pub async fn action_diff_with<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
         _arg: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let paths_str = if self.selected_items.is_empty() {
-            self.file_items[ctx.cursor as usize - 1]
-                .path
-                .to_str()
-                .unwrap()
-                .to_owned()
+        let other = match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            Some(fi) => fi.path.clone(),
+            None => {
+                return Err(Box::new(ArgError::new(
+                    "diff_with: invalid cursor position",
+                )))
+            }
+        };
+        let base = if self.selected_items.len() == 1 {
+            let idx = *self.selected_items.iter().next().unwrap();
+            Some(self.file_items[idx].path.clone())
         } else {
-            self.selected_items
-                .iter()
-                .map(|x| self.file_items[*x].path.to_str().unwrap().to_owned())
-                .collect::<Vec<String>>()
-                .join("\n")
+            CLIPBOARD.read().await.get(0).cloned()
         };
-        nvim.call_function(
-            "setreg",
-            vec![Value::from("+"), Value::from(paths_str.as_str())],
-        )
-        .await?;
-        nvim.execute_lua("tree.print_message(...)", vec![Value::from(paths_str)])
-            .await?;
+        let base = match base {
+            Some(b) if b != other => b,
+            _ => {
+                self.notify(
+                    nvim,
+                    NotifyLevel::WARN,
+                    "Select a file or copy one to the clipboard to diff with",
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+        if base.is_dir() || other.is_dir() {
+            return Err(Box::new(ArgError::new(
+                "diff_with: directories can't be compared",
+            )));
+        }
+        let base_arg =
+            nvim_call::query_function(nvim, "fnameescape", vec![path_value(&base)]).await?;
+        let other_arg =
+            nvim_call::query_function(nvim, "fnameescape", vec![path_value(&other)]).await?;
+        let cmd = format!(
+            "wincmd p | edit {} | vertical diffsplit {}",
+            base_arg.as_str().unwrap_or_default(),
+            other_arg.as_str().unwrap_or_default()
+        );
+        info!("diff_with: {}", cmd);
+        nvim_call::command(nvim, &cmd).await?;
+        if self.has_selection() {
+            self.clear_selection();
+            self.redraw_subtree(nvim, 0, false).await?;
+        }
         Ok(())
     }
 
@@ -571,6 +4553,10 @@ impl Tree {
         _ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
         self.config.show_ignored_files = !self.config.show_ignored_files;
+        if self.config.show_ignored_files && self.git_stamp.is_none() {
+            // for the git-ignored files to be dimmed
+            self.update_git_map();
+        }
         self.redraw_subtree(nvim, 0, true).await?;
         Ok(())
     }
@@ -593,59 +4579,318 @@ impl Tree {
             Some(Value::String(v)) => v.as_str().unwrap() == "true",
             _ => false,
         };
-        let targets: Vec<&FileItem> = if self.selected_items.is_empty() {
-            vec![&self.file_items[ctx.cursor as usize - 1].as_ref()]
+        // with trash enabled, deleting for good takes the force flag
+        let to_trash = self.config.trash && !force;
+        self.remove_targets(nvim, ctx, force, to_trash).await
+    }
+
+    /// Move the selected items (or the cursor item) to the trash, whatever the config
+    pub async fn action_remove_trash<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.remove_targets(nvim, ctx, false, true).await
+    }
+
+    async fn remove_targets<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        ctx: Context,
+        force: bool,
+        to_trash: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let targets: Vec<PathBuf> = if self.has_selection() {
+            self.selected_paths()
         } else {
-            self.selected_items
-                .iter()
-                .map(|x| self.file_items[*x].as_ref())
-                .collect()
+            vec![self.file_items[CursorContext::try_from(&ctx)?.idx]
+                .path
+                .clone()]
         };
         if !force {
+            let verb = if to_trash {
+                "move to the trash"
+            } else {
+                "delete"
+            };
             let message = if targets.len() == 1 {
                 format!(
-                    "Are you sure you want to delete {}?",
-                    targets[0].path.to_str().unwrap()
+                    "Are you sure you want to {} {}?",
+                    verb,
+                    targets[0].display()
                 )
             } else {
-                format!("Are you sure you want to delete {} files?", targets.len())
+                // the selection may reach outside the view, the user sees what goes
+                let mut lines: Vec<String> = targets
+                    .iter()
+                    .take(REMOVE_CONFIRM_MAX_PATHS)
+                    .map(|p| format!("  {}", p.display()))
+                    .collect();
+                if targets.len() > REMOVE_CONFIRM_MAX_PATHS {
+                    lines.push(format!(
+                        "  ... and {} more",
+                        targets.len() - REMOVE_CONFIRM_MAX_PATHS
+                    ));
+                }
+                format!(
+                    "Are you sure you want to {} {} files?\n{}",
+                    verb,
+                    targets.len(),
+                    lines.join("\n")
+                )
             };
             if !Self::confirm(nvim, message).await? {
                 info!("Remove cancelled");
                 return Ok(());
             }
         }
-        for target in targets {
-            if target.metadata.is_dir() {
-                std::fs::remove_dir_all(&target.path)?;
+        let mut removed = Vec::new();
+        for target in targets {
+            // not following the links, like the listing
+            let is_dir = std::fs::symlink_metadata(&target)
+                .map(|m| m.is_dir())
+                .map_err(path_error(&target))?;
+            if to_trash {
+                trash::move_to_trash(&target).map_err(path_error(&target))?;
+            } else if is_dir {
+                std::fs::remove_dir_all(&target).map_err(path_error(&target))?;
+            } else {
+                std::fs::remove_file(&target).map_err(path_error(&target))?;
+            }
+            removed.push(target);
+        }
+        // redraw the entire tree
+        self.redraw_subtree(nvim, 0, true).await?;
+        for path in &removed {
+            self.selected_offscreen.remove(path);
+        }
+        Self::close_removed_buffers(nvim, &removed).await?;
+
+        Ok(())
+    }
+    /// Toggle the deletion mark of the selected items (or the cursor item), nothing is
+    /// removed until `purge_marked`
+    pub async fn action_mark_delete<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let targets: Vec<PathBuf> = if self.has_selection() {
+            self.selected_paths()
+        } else {
+            match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+                Some(fi) => vec![fi.path.clone()],
+                None => vec![],
+            }
+        };
+        for path in targets {
+            // never mark the root
+            if path == self.file_items[0].path {
+                continue;
+            }
+            if !self.marked_for_deletion.remove(&path) {
+                self.marked_for_deletion.insert(path);
+            }
+        }
+        self.clear_selection();
+        self.redraw_subtree(nvim, 0, false).await?;
+        Ok(())
+    }
+
+    /// Change the mode of the selected items (or the cursor item) to an octal mode, or add
+    /// and remove permission bits like `+x`, then redraw their lines
+    pub async fn action_change_mode<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let targets = if self.has_selection() {
+            self.selected_paths()
+        } else {
+            let idx = CursorContext::try_from(&ctx)?.idx;
+            vec![self.file_items[idx].path.clone()]
+        };
+        // the mode of a single item is offered for editing
+        let current = match targets.as_slice() {
+            [path] => chmod::mode(path)
+                .map(|mode| format!("{:o}", mode))
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let prompt = if targets.len() == 1 {
+            String::from("New mode: ")
+        } else {
+            format!("New mode of {} items: ", targets.len())
+        };
+        let spec = match nvim_call::call_function(
+            nvim,
+            "tree#util#input",
+            vec![
+                Value::from(prompt),
+                Value::from(current.as_str()),
+                Value::from(""),
+            ],
+        )
+        .await?
+        {
+            Value::String(v) => String::from_utf8_lossy(v.as_bytes()).trim().to_owned(),
+            _ => return Err(Box::new(ArgError::new("Wrong return type"))),
+        };
+        if spec.is_empty() || spec == current {
+            return Ok(());
+        }
+        // rejected before any file is changed
+        chmod::apply_spec(&spec, 0)?;
+        let mut rows = Vec::new();
+        for path in &targets {
+            let mode = chmod::mode(path).map_err(path_error(path))?;
+            let new_mode = chmod::apply_spec(&spec, mode)?;
+            info!("change_mode: {:?} {:o} -> {:o}", path, mode, new_mode);
+            chmod::set_mode(path, new_mode).map_err(path_error(path))?;
+            // the mtime of the directory stays, the cached listing would be stale
+            if let Some(parent) = path.parent() {
+                invalidate_listings(parent);
+            }
+            if let Some(idx) = self.file_items.iter().position(|fi| &fi.path == path) {
+                self.restat_item(idx)?;
+                rows.push(idx);
+            }
+        }
+        self.clear_selection();
+        // the rows changed, from the ancestors losing their partial selection mark on
+        let mut sl = match rows.iter().min() {
+            Some(first) => *first,
+            None => return Ok(()),
+        };
+        let el = rows.iter().max().map_or(sl, |last| last + 1);
+        for &idx in &rows {
+            let mut parent = self.file_items[idx].parent.as_ref();
+            while let Some(p) = parent {
+                sl = sl.min(p.id);
+                parent = p.parent.as_ref();
+            }
+        }
+        self.update_cells(sl, el);
+        self.redraw_lines_diffed(nvim, sl, el).await?;
+        Ok(())
+    }
+
+    /// Read the metadata of the item at `idx` again, after it was changed in place
+    fn restat_item(&mut self, idx: usize) -> Result<(), TreeError> {
+        let old = self.file_items[idx].clone();
+        // the entries are listed without following the links, unlike the root
+        let metadata = if idx == 0 {
+            std::fs::metadata(&old.path)
+        } else {
+            std::fs::symlink_metadata(&old.path)
+        }
+        .map_err(path_error(&old.path))?;
+        let mut fileitem = FileItem::new(old.path.clone(), metadata, old.id);
+        fileitem.level = old.level;
+        fileitem.parent = old.parent.clone();
+        fileitem.last = old.last;
+        self.file_items[idx] = Arc::new(fileitem);
+        Ok(())
+    }
+
+    pub async fn action_purge_marked<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // items may have been removed by others in the meantime
+        self.marked_for_deletion.retain(|p| p.exists());
+        if self.marked_for_deletion.is_empty() {
+            self.notify(nvim, NotifyLevel::WARN, "Nothing marked for deletion")
+                .await?;
+            return Ok(());
+        }
+        let mut targets: Vec<PathBuf> = self.marked_for_deletion.iter().cloned().collect();
+        targets.sort();
+        let message = format!(
+            "Are you sure you want to delete {} marked files?\n{}",
+            targets.len(),
+            targets
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+        if !Self::confirm(nvim, message).await? {
+            info!("Purge cancelled");
+            return Ok(());
+        }
+        // a failure doesn't stop the others, the failed items stay marked
+        let mut removed = Vec::new();
+        let mut errors = Vec::new();
+        for target in &targets {
+            // the parent of the target might have been purged already. A link to a
+            // directory is removed, not what's in the directory
+            let is_dir = match std::fs::symlink_metadata(target) {
+                Ok(m) => m.is_dir(),
+                Err(_) => {
+                    self.marked_for_deletion.remove(target);
+                    removed.push(target.clone());
+                    continue;
+                }
+            };
+            let res = if is_dir {
+                std::fs::remove_dir_all(target)
             } else {
-                std::fs::remove_file(&target.path)?;
+                std::fs::remove_file(target)
+            };
+            match res {
+                Ok(()) => {
+                    self.marked_for_deletion.remove(target);
+                    removed.push(target.clone());
+                }
+                Err(e) => errors.push(TreeError::Io(Some(target.clone()), e)),
             }
         }
-        // redraw the entire tree
         self.redraw_subtree(nvim, 0, true).await?;
-
+        Self::close_removed_buffers(nvim, &removed).await?;
+        if !errors.is_empty() {
+            let msg = format!(
+                "{} of {} marked files not deleted:\n{}",
+                errors.len(),
+                targets.len(),
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            );
+            self.notify(nvim, NotifyLevel::ERROR, &msg).await?;
+        }
         Ok(())
     }
+
     pub async fn action_toggle_select<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
         _arg: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let idx = (ctx.cursor - 1) as usize;
-        if self.selected_items.contains(&idx) {
-            self.selected_items.remove(&idx);
-        } else {
-            self.selected_items.insert(idx);
+        let cursor = CursorContext::try_from(&ctx)?;
+        // [count] items starting from the cursor
+        let idx = cursor.idx;
+        let end = std::cmp::min(idx + cursor.count, self.file_items.len());
+        for i in idx..end {
+            self.toggle_item_selection(i);
+        }
+        if self.config.select_recursive {
+            // ancestors and descendants change as well
+            self.redraw_subtree(nvim, 0, false).await?;
+            return Ok(());
         }
 
-        // soft redraw a single line
-        self.update_cells(idx, idx + 1);
-        let ret = vec![self.makeline(idx)];
-        self.buf_set_lines(nvim, idx as i64, idx as i64 + 1, true, ret)
-            .await?;
-        self.hl_lines(&nvim, idx, idx + 1).await?;
+        // soft redraw the toggled lines
+        self.update_cells(idx, end);
+        self.redraw_lines_diffed(nvim, idx, end).await?;
 
         Ok(())
     }
@@ -656,11 +4901,38 @@ impl Tree {
         _arg: Value,
         _ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.selected_items.clear();
+        self.clear_selection();
         self.redraw_subtree(nvim, 0, false).await?;
         Ok(())
     }
 
+    /// List the selected paths, including the ones in collapsed directories or out of the
+    /// current root
+    pub async fn action_selection_list<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.has_selection() {
+            self.notify(nvim, NotifyLevel::WARN, "Nothing selected")
+                .await?;
+            return Ok(());
+        }
+        let root = self.file_items[0].path.clone();
+        let lines: Vec<Value> = self
+            .selected_paths()
+            .iter()
+            .map(|p| {
+                let shown = p.strip_prefix(&root).unwrap_or(p);
+                Value::from(shown.to_string_lossy().into_owned())
+            })
+            .collect();
+        let args = vec![Value::from("[selection]"), Value::Array(lines)];
+        nvim_call::execute_lua(nvim, "tree.show_output(...)", args).await?;
+        Ok(())
+    }
+
     pub async fn action_toggle_select_all<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
@@ -683,12 +4955,13 @@ impl Tree {
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
         info!("{:?}", _arg);
-        let idx = ctx.cursor as usize - 1;
+        let idx = CursorContext::try_from(&ctx)?.idx;
         let cur = &self.file_items[idx];
-        let old_path = cur.path.to_str().unwrap();
-        let cwd = self.file_items[0].path.to_str().unwrap();
+        let old_path = cur.path.to_string_lossy();
+        let cwd = &self.file_items[0].path;
         let msg = format!("New name: {} -> ", old_path);
-        let new_filename = Self::cwd_input(nvim, cwd, &msg, old_path, "file").await?;
+        let new_filename =
+            Self::cwd_input(nvim, cwd, &msg, &old_path, self.path_completion()).await?;
         if new_filename.is_empty() {
             return Ok(());
         }
@@ -700,15 +4973,16 @@ impl Tree {
         info!("New path: {:?}", new_path);
 
         if new_path.exists() {
-            let message = Value::from(format!("{} already exists", new_path.to_str().unwrap()));
-            nvim.execute_lua("tree.print_message(...)", vec![message])
-                .await?;
-            return Err(Box::new(ArgError::new("File exists!")));
+            return Err(Box::new(TreeError::Conflict(new_path)));
         }
-        std::fs::rename(&cur.path, new_path)?;
+        let src_path = cur.path.clone();
+        self.rename_path(nvim, &src_path, &new_path).await?;
+        let selected = self.rebase_path_state(&src_path, &new_path).await;
         // TODO: no need to redraw the entire tree, we can redraw the parent and the target's
         // parent
         self.redraw_subtree(nvim, 0, true).await?;
+        self.reselect(nvim, &selected).await?;
+        Self::rename_buffers(nvim, &src_path, &new_path).await?;
 
         Ok(())
     }
@@ -719,47 +4993,113 @@ impl Tree {
         _arg: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let idx = ctx.cursor as usize - 1;
+        let idx = CursorContext::try_from(&ctx)?.idx;
         let cur = &self.file_items[idx];
-        let cur_path_str = cur.path.to_str().unwrap();
         let idx_to_redraw;
         // idx == 0 => is_root
-        let cwd = if self.is_item_opened(cur_path_str) || idx == 0 {
+        let cwd = if self.is_item_opened(&cur.path) || idx == 0 {
             idx_to_redraw = idx;
-            cur_path_str
+            cur.path.clone()
         } else if let Some(p) = cur.parent.as_ref() {
             idx_to_redraw = p.id;
-            p.path.to_str().unwrap()
+            p.path.clone()
         } else {
             return Err(Box::new(ArgError::new(
                 "can't find correct position to create new file",
             )));
         };
-        let new_filename =
-            Self::cwd_input(nvim, &cwd, "Please input a new filename: ", "", "file").await?;
-        let is_dir = new_filename.ends_with('/');
-        let mut filename = std::path::PathBuf::from(cwd);
+        let new_filename = Self::cwd_input(
+            nvim,
+            &cwd,
+            "Please input a new filename: ",
+            "",
+            self.path_completion(),
+        )
+        .await?;
+        let is_dir = new_filename.ends_with('/') || new_filename.ends_with(MAIN_SEPARATOR);
+        let mut filename = cwd;
         filename.push(new_filename);
         info!("New file name: {:?}", filename);
-        let message = Value::from(format!("{} already exists", filename.to_str().unwrap()));
-        if filename.exists() {
-            nvim.execute_lua("tree.print_message(...)", vec![message])
-                .await?;
-            return Err(Box::new(ArgError::new("File exists!")));
-        }
+        // only an empty file can replace an existing one
+        let can_overwrite = !is_dir && filename.is_file();
+        let filename = match Self::resolve_name_conflict(nvim, &filename, can_overwrite).await? {
+            Some(f) => f,
+            None => return Ok(()),
+        };
         if is_dir {
-            std::fs::create_dir(filename)?;
+            std::fs::create_dir(&filename).map_err(path_error(&filename))?;
         } else {
             let mut parent = filename.clone();
             parent.pop();
-            std::fs::create_dir_all(parent)?;
-            std::fs::File::create(filename)?;
+            std::fs::create_dir_all(&parent).map_err(path_error(&parent))?;
+            std::fs::File::create(&filename).map_err(path_error(&filename))?;
         }
 
         self.redraw_subtree(nvim, idx_to_redraw, true).await?;
 
         Ok(())
     }
+
+    /// Copy the item under the cursor next to it, under a name asked for
+    pub async fn action_duplicate<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let idx = CursorContext::try_from(&ctx)?.idx;
+        if idx == 0 {
+            return Err(Box::new(ArgError::new("the root can't be duplicated")));
+        }
+        let cur = self.file_items[idx].clone();
+        let dir = match cur.path.parent() {
+            Some(d) => d.to_owned(),
+            None => return Ok(()),
+        };
+        let suggested = available_name(&cur.path);
+        let new_name = Self::cwd_input(
+            nvim,
+            &dir,
+            &format!("Duplicate {} as: ", cur.path.display()),
+            &suggested.file_name().unwrap_or_default().to_string_lossy(),
+            self.path_completion(),
+        )
+        .await?;
+        if new_name.is_empty() {
+            return Ok(());
+        }
+        let dest = dir.join(new_name);
+        if dest == cur.path {
+            return Ok(());
+        }
+        let is_dir = cur.metadata.is_dir();
+        let can_overwrite = !is_dir && dest.is_file();
+        let dest = match Self::resolve_name_conflict(nvim, &dest, can_overwrite).await? {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        if is_dir {
+            let mut options = fs_extra::dir::CopyOptions::new();
+            options.copy_inside = true;
+            fs_extra::dir::copy(&cur.path, &dest, &options)?;
+        } else if dest.is_file() {
+            let preserve = self.config.preserve_permissions;
+            overwrite_file(&cur.path, &dest, preserve, self.config.fs_retries)
+                .await
+                .map_err(copy_error(&cur.path, &dest))?;
+        } else {
+            let retries = self.config.fs_retries;
+            fs_retry::retry_async("copy", &cur.path, retries, || {
+                std::fs::copy(&cur.path, &dest)
+            })
+            .await
+            .map_err(copy_error(&cur.path, &dest))?;
+        }
+        let idx_to_redraw = cur.parent.as_ref().map_or(0, |p| p.id);
+        self.redraw_subtree(nvim, idx_to_redraw, true).await?;
+        self.reveal_pasted(nvim, &dest).await
+    }
+
     pub async fn action_call<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
@@ -769,84 +5109,599 @@ impl Tree {
         let args = match arg {
             Value::Array(v) => v,
             _ => {
-                Err(ArgError::new("Invalid arg type"))?;
-                return Ok(());
+                Err(ArgError::new("Invalid arg type"))?;
+                return Ok(());
+            }
+        };
+        let func = if let Some(Value::String(v)) = args.get(0) {
+            v.as_str().unwrap()
+        } else {
+            return Err(Box::new(ArgError::new("func not defined")));
+        };
+        let cur = &self.file_items[CursorContext::try_from(&ctx)?.idx];
+
+        let ctx = Value::Map(vec![(
+            Value::from("targets"),
+            Value::Array(vec![path_value(&cur.path)]),
+        )]);
+        nvim_call::call_function(nvim, func, vec![ctx]).await?;
+        Ok(())
+    }
+
+    pub async fn action_cd<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_cursor(&ctx);
+        let args = match arg {
+            Value::Array(v) => v,
+            _ => {
+                Err(ArgError::new("Invalid arg type"))?;
+                return Ok(());
+            }
+        };
+        if args.is_empty() {
+            return self.cd_interactive(nvim).await;
+        }
+        let dir = if let Some(d) = args[0].as_str() {
+            d
+        } else {
+            Err(ArgError::new("Dir should be of type String"))?;
+            return Ok(());
+        };
+        if dir == ".." {
+            match parent_dir(&self.file_items[0].path.clone()) {
+                Some(p) => {
+                    self.push_root_history();
+                    self.change_root(p, nvim).await?
+                }
+                None => {}
+            }
+        } else if dir == "." {
+            let cur_idx = CursorContext::try_from(&ctx)?.idx;
+            let cur = match self.file_items.get(cur_idx) {
+                Some(i) => i,
+                None => {
+                    Err(ArgError::new("invalid cursor pos"))?;
+                    return Ok(());
+                }
+            };
+            let cmd = if self.is_item_opened(&cur.path) {
+                format!("cd {}", cur.path.display())
+            } else {
+                format!("cd {}", dir)
+            };
+            nvim_call::command(nvim, &cmd).await?
+        } else if dir == "-" {
+            // back to the previous root, like the shell
+            match self.root_history.pop() {
+                Some(prev) => self.change_root(&prev, nvim).await?,
+                None => {
+                    self.notify(nvim, NotifyLevel::WARN, "No previous root")
+                        .await?
+                }
+            }
+        } else {
+            self.push_root_history();
+            self.change_root(dir, nvim).await?;
+        }
+        Ok(())
+    }
+
+    /// Prompt for the new root with directory completion, relative to the current one
+    async fn cd_interactive<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let root = self.file_items[0].path.clone();
+        let input = Self::cwd_input(nvim, &root, "New root: ", "", self.dir_completion()).await?;
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(());
+        }
+        let target = root.join(expand_path(input));
+        if !target.is_dir() {
+            let msg = format!("{} is not a directory", target.display());
+            self.notify(nvim, NotifyLevel::ERROR, &msg).await?;
+            return Ok(());
+        }
+        let target = absolute_path(&target)?;
+        self.push_root_history();
+        self.change_root(&target, nvim).await
+    }
+    /// Open like :drop
+    pub async fn action_update_git_map<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _args: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.git_statuses_shown() {
+            self.update_git_map();
+            self.redraw_subtree(nvim, 0, false).await?;
+        }
+        Ok(())
+    }
+
+    /// `filter <pattern>` filters the tree, without argument the filter is typed in a
+    /// prompt and applied as it's typed
+    pub async fn action_filter<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pattern = match &arg {
+            Value::Array(args) => args.get(0).and_then(|v| v.as_str()).map(str::to_owned),
+            _ => None,
+        };
+        match pattern {
+            Some(pattern) => self.set_filter(nvim, pattern).await,
+            None => {
+                // the prompt sends the pattern back as it changes, the tree must not be
+                // locked by this action meanwhile
+                let nvim = nvim.clone();
+                let args = vec![Value::from(self.config.search.as_str())];
+                self.tasks.spawn(async move {
+                    if let Err(e) =
+                        nvim_call::execute_lua(&nvim, "tree.filter_prompt(...)", args).await
+                    {
+                        error!("filter prompt error: {}", e);
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// The pattern given as argument, or typed in a prompt, empty when cancelled
+    async fn pattern_arg<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        nvim: &Neovim<W>,
+        arg: &Value,
+        prompt: &str,
+        text: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(pattern) = match arg {
+            Value::Array(args) => args.get(0).and_then(|v| v.as_str()),
+            _ => None,
+        } {
+            return Ok(pattern.to_owned());
+        }
+        let typed =
+            nvim_call::call_function(nvim, "input", vec![Value::from(prompt), Value::from(text)])
+                .await?;
+        Ok(typed.as_str().unwrap_or_default().to_owned())
+    }
+
+    /// `search [pattern]` puts the cursor on the next item whose name matches, wrapping
+    /// around. Without argument the pattern is typed, the last one offered
+    pub async fn action_search<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let idx = CursorContext::try_from(&ctx)?.idx;
+        let pattern = Self::pattern_arg(nvim, &arg, "Search: ", &self.last_search).await?;
+        if pattern.is_empty() {
+            return Ok(());
+        }
+        let matcher = self.matcher(&pattern);
+        self.last_search = pattern;
+        let count = self.file_items.len();
+        let found = (1..=count)
+            .map(|n| (idx + n) % count)
+            .find(|i| *i != 0 && matcher.matches_path(&self.file_items[*i].path));
+        match found {
+            Some(i) => {
+                nvim_call::call_function(nvim, "cursor", vec![Value::from(i + 1), Value::from(1)])
+                    .await?;
+            }
+            None => {
+                let msg = format!("No match for {}", self.last_search);
+                self.notify(nvim, NotifyLevel::WARN, &msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `goto_file [name]` looks for the files whose name matches under the root in the
+    /// background, and reveals the one chosen. `{reveal = path}` is the choice coming back
+    pub async fn action_goto_file<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Value::Map(opts) = &arg {
+            if let Some((_, path)) = opts.iter().find(|(k, _)| k.as_str() == Some("reveal")) {
+                let path = value_to_path(path)
+                    .ok_or_else(|| ArgError::new("goto_file: reveal needs a path"))?;
+                return self.reveal_path(nvim, &path).await;
+            }
+        }
+        let pattern = Self::pattern_arg(nvim, &arg, "Go to file: ", "").await?;
+        if pattern.is_empty() {
+            return Ok(());
+        }
+        let root = self.file_items[0].path.clone();
+        let matcher = self.matcher(&pattern);
+        let filters = self.entry_filters();
+        // git lists the ignored directories without what's inside
+        let ignored_dirs: HashSet<PathBuf> = self
+            .git_map
+            .iter()
+            .filter(|(_, status)| status.contains(Status::IGNORED))
+            .map(|(path, _)| PathBuf::from(path.trim_end_matches('/')))
+            .collect();
+        let nvim = nvim.clone();
+        let bufnr = self.bufnr.clone();
+        let notify_level = self.config.notify_level;
+        let retries = self.config.fs_retries;
+        self.tasks.spawn(async move {
+            let found = {
+                let root = root.clone();
+                async_std::task::spawn_blocking(move || {
+                    find_files(&root, &matcher, &filters, &ignored_dirs, retries)
+                })
+                .await
+            };
+            info!("goto_file {}: {} found", pattern, found.len());
+            if found.is_empty() {
+                if NotifyLevel::WARN >= notify_level {
+                    let msg = format!("No file matches {} under {}", pattern, root.display());
+                    let args = vec![Value::from(NotifyLevel::WARN.as_str()), Value::from(msg)];
+                    if let Err(e) = nvim_call::execute_lua(&nvim, "tree.notify(...)", args).await {
+                        error!("goto_file: {}", e);
+                    }
+                }
+                return;
+            }
+            let labels = found
+                .iter()
+                .map(|path| {
+                    let rel = path.strip_prefix(&root).unwrap_or(path);
+                    Value::from(rel.to_string_lossy().into_owned())
+                })
+                .collect();
+            let paths = found.iter().map(|path| path_value(path)).collect();
+            let args = vec![
+                bufnr,
+                Value::from(format!("Go to {}:", pattern)),
+                Value::Array(labels),
+                Value::Array(paths),
+            ];
+            if let Err(e) = nvim_call::execute_lua(&nvim, "tree.goto_file_found(...)", args).await {
+                error!("goto_file: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// `select_pattern [pattern]` adds the items whose name matches to the selection
+    pub async fn action_select_pattern<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pattern = Self::pattern_arg(nvim, &arg, "Select: ", "").await?;
+        if pattern.is_empty() {
+            return Ok(());
+        }
+        let matcher = self.matcher(&pattern);
+        let matching: Vec<usize> = (1..self.file_items.len())
+            .filter(|i| matcher.matches_path(&self.file_items[*i].path))
+            .collect();
+        let msg = format!("{} items match {}", matching.len(), pattern);
+        self.selected_items.extend(matching);
+        self.redraw_subtree(nvim, 0, false).await?;
+        self.notify(nvim, NotifyLevel::INFO, &msg).await?;
+        Ok(())
+    }
+
+    pub async fn action_clear_filter<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.set_filter(nvim, String::new()).await
+    }
+
+    async fn set_filter<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        pattern: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if pattern == self.config.search {
+            return Ok(());
+        }
+        info!("filter: {:?}", pattern);
+        let narrower = !self.config.search.is_empty()
+            && self
+                .matcher(&pattern)
+                .narrows(&self.matcher(&self.config.search));
+        self.config.search = pattern;
+        // typing on only drops items, the listed ones are filtered again
+        if narrower {
+            self.clear_selection();
+            if self.refilter().await? {
+                return self.redraw_listing(nvim).await;
+            }
+        }
+        self.relist(nvim).await
+    }
+
+    /// The listed items a filter narrower than the one they were listed with keeps, and
+    /// the directories leading to them, without listing the tree again. False when that
+    /// takes a listing: a directory listed a chunk at a time, or a match in a directory
+    /// not expanded
+    async fn refilter(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.flat_view || self.hidden_entries.lock().map_or(true, |h| !h.is_empty()) {
+            return Ok(false);
+        }
+        let matcher = self.matcher(&self.config.search);
+        let filters = self.entry_filters();
+        let retries = self.config.fs_retries;
+        let len = self.file_items.len();
+        let parent = |i: usize| self.file_items[i].parent.as_ref().map(|p| p.id);
+        // the items matching or inside a match, and the directories with listed children
+        let mut keep = vec![false; len];
+        let mut listed = vec![false; len];
+        for i in 1..len {
+            let inside = parent(i).filter(|p| *p != 0).map_or(false, |p| keep[p]);
+            keep[i] = inside || matcher.matches_path(&self.file_items[i].path);
+            if let Some(p) = parent(i) {
+                listed[p] = true;
+            }
+        }
+        for i in 1..len {
+            let fi = &self.file_items[i];
+            if !keep[i]
+                && !listed[i]
+                && fi.metadata.is_dir()
+                && dir_contains_match(&fi.path, &matcher, &filters, retries).await
+            {
+                return Ok(false);
+            }
+        }
+        keep[0] = true;
+        for i in (1..len).rev() {
+            if let (true, Some(p)) = (keep[i], parent(i)) {
+                keep[p] = true;
+            }
+        }
+        let mut last_child = vec![0; len];
+        for i in (1..len).filter(|i| keep[*i]) {
+            if let Some(p) = parent(i) {
+                last_child[p] = i;
+            }
+        }
+        // the ids and the last flags change, the parents are the new items
+        let mut new_ids = vec![0; len];
+        let mut items: Vec<FileItemPtr> = Vec::new();
+        for i in (0..len).filter(|i| keep[*i]) {
+            let fi = &self.file_items[i];
+            new_ids[i] = items.len();
+            let item = FileItem {
+                path: fi.path.clone(),
+                metadata: fi.metadata.clone(),
+                level: fi.level,
+                parent: parent(i).map(|p| items[new_ids[p]].clone()),
+                last: parent(i).map_or(fi.last, |p| last_child[p] == i),
+                id: items.len(),
+                locked: fi.locked,
+                link_target: fi.link_target.clone(),
+                broken_link: fi.broken_link,
+            };
+            items.push(Arc::new(item));
+        }
+        info!("refilter: {} of {} items kept", items.len(), len);
+        let root = self.file_items[0].path.clone();
+        self.restore_root(root, items)?;
+        Ok(true)
+    }
+
+    /// List the tree again after the filters changed, through the listing cache as the
+    /// filter changes with every keystroke
+    async fn relist<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.clear_selection();
+        let root = self.file_items[0].path.clone();
+        self.list_root(root).await?;
+        self.redraw_listing(nvim).await
+    }
+
+    /// Draw all the items, listed anew
+    async fn redraw_listing<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let lines = self.lines();
+        self.buf_set_lines(nvim, 0, -1, true, lines).await?;
+        self.hl_lines(nvim, 0, self.file_items.len()).await?;
+        self.auto_fit_width(nvim).await
+    }
+
+    /// `filter_extension rs,toml` only lists the files with these extensions, without
+    /// argument they're typed in a prompt, empty clears the filter
+    pub async fn action_filter_extension<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let spec = match &arg {
+            Value::Array(args) if !args.is_empty() => args
+                .get(0)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_owned(),
+            _ => {
+                let current = self.extension_filter.join(",");
+                let root = self.file_items[0].path.clone();
+                Self::cwd_input(nvim, &root, "Extensions: ", &current, "").await?
             }
         };
-        let func = if let Some(Value::String(v)) = args.get(0) {
-            v.as_str().unwrap()
-        } else {
-            return Err(Box::new(ArgError::new("func not defined")));
-        };
-        let cur = &self.file_items[ctx.cursor as usize - 1];
+        let exts: Vec<String> = spec
+            .split(',')
+            .map(|x| x.trim().trim_start_matches('.').to_owned())
+            .filter(|x| !x.is_empty())
+            .collect();
+        if exts == self.extension_filter {
+            return Ok(());
+        }
+        info!("extension filter: {:?}", exts);
+        self.extension_filter = exts;
+        self.relist(nvim).await
+    }
 
-        let ctx = Value::Map(vec![(
-            Value::from("targets"),
-            Value::Array(vec![Value::from(cur.path.to_str().unwrap())]),
-        )]);
-        nvim.call_function(func, vec![ctx]).await?;
-        Ok(())
+    /// Hide the binary files, or show them again
+    pub async fn action_toggle_binary_files<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.hide_binary = !self.hide_binary;
+        info!("hide binary files: {}", self.hide_binary);
+        self.relist(nvim).await
     }
 
-    pub async fn action_cd<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// Delete the swap files of the selected items (or the cursor item) left behind by a
+    /// vim that is no longer running
+    pub async fn action_delete_swap<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
-        arg: Value,
+        _arg: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.save_cursor(&ctx);
-        let args = match arg {
-            Value::Array(v) => v,
-            _ => {
-                Err(ArgError::new("Invalid arg type"))?;
-                return Ok(());
+        let targets: Vec<PathBuf> = if self.has_selection() {
+            self.selected_paths()
+        } else {
+            match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+                Some(fi) => vec![fi.path.clone()],
+                None => vec![],
             }
         };
-        if !args.is_empty() {
-            let dir = if let Some(d) = args[0].as_str() {
-                d
-            } else {
-                Err(ArgError::new("Dir should be of type String"))?;
-                return Ok(());
+        let mut deleted = 0;
+        for path in targets {
+            if path.is_dir() {
+                continue;
+            }
+            let swap = match self.swap_of_path(&path) {
+                Some(s) => s,
+                None => continue,
             };
-            if dir == ".." {
-                match self.file_items[0].path.clone().parent() {
-                    Some(p) => self.change_root(p.to_str().unwrap(), nvim).await?,
-                    None => {}
-                }
-            } else if dir == "." {
-                let cur_idx = ctx.cursor as usize - 1;
-                let cur = match self.file_items.get(cur_idx) {
-                    Some(i) => i,
-                    None => {
-                        Err(ArgError::new("invalid cursor pos"))?;
-                        return Ok(());
-                    }
-                };
-                let cur_path_str = cur.path.to_str().unwrap();
-                let cmd = if self.is_item_opened(cur_path_str) {
-                    format!("cd {}", cur_path_str)
-                } else {
-                    format!("cd {}", dir)
-                };
-                nvim.command(&cmd).await?
-            } else {
-                self.change_root(dir, nvim).await?;
+            if swap::is_in_use(&swap) {
+                let msg = format!("{} is in use, not deleted", swap.display());
+                self.notify(nvim, NotifyLevel::WARN, &msg).await?;
+                continue;
             }
+            std::fs::remove_file(&swap)?;
+            info!("deleted the swap file {:?}", swap);
+            deleted += 1;
         }
-        Ok(())
+        if deleted == 0 {
+            return Ok(());
+        }
+        let msg = format!("{} stale swap file(s) deleted", deleted);
+        self.notify(nvim, NotifyLevel::INFO, &msg).await?;
+        self.redraw_subtree(nvim, 0, true).await
     }
-    /// Open like :drop
-    pub async fn action_update_git_map<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+
+    /// Toggle the flat view of the files git reports as changed under the root
+    pub async fn action_git_changed<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
-        _args: Value,
+        _arg: Value,
         _ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        if self.config.columns.contains(&ColumnType::GIT) {
+        self.flat_view = !self.flat_view;
+        if self.flat_view {
             self.update_git_map();
-            self.redraw_subtree(nvim, 0, false).await?;
         }
+        self.clear_selection();
+        self.redraw_subtree(nvim, 0, true).await
+    }
+
+    /// Checkout the tracked files deleted from the cursor directory (or the cursor file's
+    /// directory) from HEAD
+    pub async fn action_git_restore<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _args: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let idx = CursorContext::try_from(&ctx)?.idx;
+        let cur = match self.file_items.get(idx) {
+            Some(fi) => fi.clone(),
+            None => {
+                return Err(Box::new(ArgError::new(
+                    "git_restore: invalid cursor position",
+                )))
+            }
+        };
+        let (dir, idx_to_redraw) =
+            if cur.metadata.is_dir() && (idx == 0 || self.is_item_opened(&cur.path)) {
+                (cur.path.clone(), idx)
+            } else {
+                match cur.parent.as_ref() {
+                    Some(p) => (p.path.clone(), p.id),
+                    None => (cur.path.clone(), idx),
+                }
+            };
+        if self.git_repo.is_none() {
+            self.notify(nvim, NotifyLevel::WARN, "Not in a git repository")
+                .await?;
+            return Ok(());
+        }
+        self.update_git_map();
+        let mut deleted: Vec<PathBuf> = self
+            .git_map
+            .iter()
+            .filter(|(_, status)| status.contains(Status::WT_DELETED))
+            .map(|(path, _)| PathBuf::from(path))
+            .filter(|path| path.starts_with(&dir))
+            .collect();
+        if deleted.is_empty() {
+            self.notify(nvim, NotifyLevel::WARN, "No deleted files to restore")
+                .await?;
+            return Ok(());
+        }
+        deleted.sort();
+        let message = format!(
+            "Restore {} deleted file(s) from HEAD?\n{}",
+            deleted.len(),
+            deleted
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+        if !Self::confirm(nvim, message).await? {
+            return Ok(());
+        }
+        if let Some(ref mutex) = self.git_repo {
+            let repo = mutex.lock().await;
+            let work_dir = match repo.workdir() {
+                Some(d) => d.to_path_buf(),
+                None => return Err(Box::new(ArgError::new("git_restore: bare repository"))),
+            };
+            let mut checkout = git2::build::CheckoutBuilder::new();
+            checkout.force();
+            for path in &deleted {
+                checkout.path(path.strip_prefix(&work_dir)?);
+            }
+            repo.checkout_head(Some(&mut checkout))?;
+        }
+        self.update_git_map();
+        self.redraw_subtree(nvim, idx_to_redraw, true).await?;
         Ok(())
     }
 
@@ -857,10 +5712,10 @@ impl Tree {
         args: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let info: String;
+        let info: PathBuf;
         let should_change_root;
-        if let Some(cur) = self.file_items.get(ctx.cursor as usize - 1) {
-            info = cur.path.to_str().unwrap().to_owned();
+        if let Some(cur) = self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            info = cur.path.clone();
             if cur.metadata.is_dir() {
                 should_change_root = true;
             } else {
@@ -872,8 +5727,54 @@ impl Tree {
         if should_change_root {
             self.change_root(&info, nvim).await?;
         } else {
-            nvim.execute_lua("tree.drop(...)", vec![args, Value::from(info)])
-                .await?;
+            let (args, pos) = split_position(&args)?;
+            nvim_call::execute_lua(
+                nvim,
+                "tree.drop(...)",
+                vec![Value::Array(args), path_value(&info), pos],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// `open split|vsplit|tabnew|edit [+line[:col]]` opens the file under the cursor that
+    /// way, in the previous window for split and edit, a directory becomes the root like
+    /// with drop
+    pub async fn action_open<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        args: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (args, pos) = split_position(&args)?;
+        let mode = match args.get(0) {
+            Some(m) => match m.as_str() {
+                Some(m) => m.to_owned(),
+                None => return Err(Box::new(ArgError::new("open: mode should be a string"))),
+            },
+            None => "edit".to_owned(),
+        };
+        if !["split", "vsplit", "tabnew", "edit"].contains(&mode.as_str()) {
+            return Err(Box::new(ArgError::from_string(format!(
+                "open: unknown mode {}, expected split, vsplit, tabnew or edit",
+                mode
+            ))));
+        }
+        let cur = match self.file_items.get(CursorContext::try_from(&ctx)?.idx) {
+            Some(cur) => cur,
+            None => return Err(Box::new(ArgError::new("open: invalid cursor position"))),
+        };
+        let path = cur.path.clone();
+        if cur.metadata.is_dir() {
+            self.change_root(&path, nvim).await?;
+        } else {
+            nvim_call::execute_lua(
+                nvim,
+                "tree.open_file(...)",
+                vec![Value::from(mode), path_value(&path), pos],
+            )
+            .await?;
         }
         Ok(())
     }
@@ -899,18 +5800,9 @@ impl Tree {
             }
         }
         .clone();
-        let path_str = match target.path.to_str() {
-            Some(path) => path,
-            None => {
-                return Err(Box::new(ArgError::new("filename error")));
-            }
-        };
-        let is_opened = match self.expand_store.get(path_str) {
-            Some(v) => *v,
-            None => false,
-        };
+        let is_opened = self.is_item_opened(&target.path);
         if target.metadata.is_dir() && is_opened {
-            self.expand_store.remove(path_str);
+            self.unset_expanded(&target.path);
             let start = idx + 1;
             let base_level = target.level;
             let mut end = start;
@@ -926,6 +5818,7 @@ impl Tree {
             self.buf_set_lines(nvim, idx as i64, end as i64, true, ret)
                 .await?;
             self.hl_lines(&nvim, idx, idx + 1).await?;
+            self.auto_fit_width(nvim).await?;
         }
 
         Ok(())
@@ -950,21 +5843,23 @@ impl Tree {
             }
         }
         .clone();
-        let path_str = match cur.path.to_str() {
-            Some(path) => path,
-            None => {
-                return Err(Box::new(ArgError::new("filename error")));
-            }
-        };
-        let is_opened = match self.expand_store.get(path_str) {
-            Some(v) => *v,
-            None => false,
-        };
+        let is_opened = self.is_item_opened(&cur.path);
 
-        if cur.metadata.is_dir() && !is_opened {
+        // the children are listed already, by an expand that raced with this one
+        let children_listed = self
+            .file_items
+            .get(idx + 1)
+            .map_or(false, |next| next.level > cur.level);
+        if cur.metadata.is_dir() && !is_opened && children_listed {
+            warn!("{:?} is expanded already", cur.path);
+            self.set_expanded(&cur.path);
+        } else if cur.metadata.is_dir() && !is_opened {
+            // the listing precomputed as the cursor went by is likely still being read
+            scan_finished(&cur.path).await;
             let mut child_fileitem = Vec::new();
-            self.entry_info_recursively_sync(cur.clone(), &mut child_fileitem, idx + 1)?;
-            self.expand_store.insert(path_str.to_owned(), true);
+            self.entry_info_recursively(cur.clone(), &mut child_fileitem, idx + 1)
+                .await?;
+            self.set_expanded(&cur.path);
             // icon should be open
             self.update_cells(idx, idx + 1);
             let child_item_size = child_fileitem.len();
@@ -975,6 +5870,7 @@ impl Tree {
             self.buf_set_lines(nvim, idx as i64, (idx + 1) as i64, true, ret)
                 .await?;
             self.hl_lines(&nvim, idx, idx + 1 + child_item_size).await?;
+            self.auto_fit_width(nvim).await?;
         }
         Ok(())
     }
@@ -984,7 +5880,7 @@ impl Tree {
         _args: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let idx = ctx.cursor as usize - 1;
+        let idx = CursorContext::try_from(&ctx)?.idx;
         let target = match self.file_items.get(idx) {
             Some(fi) => fi,
             None => {
@@ -994,7 +5890,7 @@ impl Tree {
                 ))));
             }
         };
-        if target.metadata.is_dir() && self.is_item_opened(target.path.to_str().unwrap()) {
+        if target.metadata.is_dir() && self.is_item_opened(&target.path) {
             self.close_tree(nvim, idx).await
         } else if let Some(p) = target.parent.clone() {
             self.close_tree(nvim, p.id).await?;
@@ -1017,7 +5913,7 @@ impl Tree {
         _args: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let idx = ctx.cursor as usize - 1;
+        let idx = CursorContext::try_from(&ctx)?.idx;
         let target = match self.file_items.get(idx) {
             Some(fi) => fi,
             None => {
@@ -1028,7 +5924,7 @@ impl Tree {
             }
         };
 
-        if target.metadata.is_dir() && self.is_item_opened(target.path.to_str().unwrap()) {
+        if target.metadata.is_dir() && self.is_item_opened(&target.path) {
             self.close_tree(nvim, idx).await?;
         } else {
             self.open_tree(nvim, idx).await?;
@@ -1036,13 +5932,48 @@ impl Tree {
         Ok(())
     }
 
+    /// Toggle the clicked directory, or drop the clicked file
+    pub async fn action_click<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        args: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mouse = MouseContext::try_from(&ctx)?;
+        let target = match self.file_items.get(mouse.idx) {
+            Some(fi) => fi.clone(),
+            None => return Ok(()),
+        };
+        // clicks on the indentation don't count
+        let indent_end = self
+            .col_map
+            .get(&ColumnType::INDENT)
+            .and_then(|cells| cells.get(mouse.idx))
+            .map(|cell| cell.col_end)
+            .unwrap_or(0);
+        if mouse.column <= indent_end {
+            return Ok(());
+        }
+        if target.metadata.is_dir() {
+            if self.is_item_opened(&target.path) {
+                self.close_tree(nvim, mouse.idx).await
+            } else {
+                self.open_tree(nvim, mouse.idx).await
+            }
+        } else {
+            nvim_call::execute_lua(nvim, "tree.drop(...)", vec![args, path_value(&target.path)])
+                .await?;
+            Ok(())
+        }
+    }
+
     pub async fn action_open_directory<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
         nvim: &Neovim<W>,
         _args: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let idx = ctx.cursor as usize - 1;
+        let idx = CursorContext::try_from(&ctx)?.idx;
         let target = match self.file_items.get(idx) {
             Some(fi) => fi,
             None => {
@@ -1053,7 +5984,7 @@ impl Tree {
             }
         };
         if target.metadata.is_dir() && idx != 0 {
-            let target_path = target.path.to_str().unwrap().to_owned();
+            let target_path = target.path.clone();
             self.change_root(&target_path, nvim).await?;
         }
         Ok(())
@@ -1065,7 +5996,61 @@ impl Tree {
         _args: Value,
         ctx: Context,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let idx = ctx.cursor as usize - 1;
+        let idx = CursorContext::try_from(&ctx)?.idx;
+        self.open_tree(nvim, idx).await
+    }
+
+    /// Expand the cursor directory, and below it the single-child chains and the
+    /// directories with no more than smart_expand_threshold entries
+    pub async fn action_smart_expand<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _args: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let idx = CursorContext::try_from(&ctx)?.idx;
+        let target = match self.file_items.get(idx) {
+            Some(fi) if idx != 0 && fi.metadata.is_dir() => fi.clone(),
+            _ => return Ok(()),
+        };
+        let threshold = self.config.smart_expand_threshold.max(1);
+        let mut expanded = 0;
+        let mut queue = std::collections::VecDeque::new();
+        queue.push_back(target.path.clone());
+        let filters = self.entry_filters();
+        let retries = self.config.fs_retries;
+        let listed = |dir: PathBuf| {
+            let filters = &filters;
+            async move {
+                read_listing_cached(&dir, retries).await.map(|l| {
+                    l.into_iter()
+                        .filter(|(path, metadata)| passes(filters, path, metadata))
+                        .collect::<Vec<_>>()
+                })
+            }
+        };
+        while let Some(dir) = queue.pop_front() {
+            let listing = listed(dir).await?;
+            for (path, metadata) in listing.iter() {
+                if !metadata.is_dir() || expanded >= SMART_EXPAND_MAX_DIRS {
+                    continue;
+                }
+                let entries = match listed(path.clone()).await {
+                    Ok(l) => l.len(),
+                    Err(_) => continue,
+                };
+                if listing.len() == 1 || entries <= threshold {
+                    self.set_expanded(path);
+                    expanded += 1;
+                    queue.push_back(path.clone());
+                }
+            }
+        }
+        info!("smart_expand {:?}: {} directories", target.path, expanded);
+        // reopen so the newly expanded descendants are listed
+        if self.is_item_opened(&target.path) {
+            self.close_tree(nvim, idx).await?;
+        }
         self.open_tree(nvim, idx).await
     }
 
@@ -1091,59 +6076,233 @@ impl Tree {
             ),
             (
                 Value::from("is_opened_tree"),
-                Value::from(self.is_item_opened(ft.path.to_str().unwrap())),
+                Value::from(self.is_item_opened(&ft.path)),
             ),
             (Value::from("level"), Value::from(ft.level)),
         ])
     }
 
-    pub async fn change_root<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    /// The root of the root-relative paths, while the flat view is on
+    pub fn flat_root(&self) -> Option<&Path> {
+        if self.flat_view {
+            self.file_items.get(0).map(|fi| fi.path.as_path())
+        } else {
+            None
+        }
+    }
+
+    /// The items below `item`: the git changed files for the root of the flat view,
+    /// the directory listing otherwise
+    async fn list_children(
+        &self,
+        item: FileItemPtr,
+        fileitem_lst: &mut Vec<FileItemPtr>,
+        start_id: usize,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        if !self.flat_view || item.parent.is_some() {
+            return self
+                .entry_info_recursively(item, fileitem_lst, start_id)
+                .await;
+        }
+        let mut paths: Vec<&String> = self
+            .git_map
+            .iter()
+            .filter(|(path, status)| {
+                !status.contains(Status::IGNORED) && Path::new(path).starts_with(&item.path)
+            })
+            .map(|(path, _)| path)
+            .collect();
+        paths.sort();
+        let mut items = Vec::new();
+        for path in paths {
+            // deleted files have nothing to show
+            let metadata = match std::fs::metadata(path) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            let path = PathBuf::from(path.trim_end_matches('/'));
+            let mut fileitem = FileItem::new(path, metadata, start_id + items.len());
+            fileitem.level = item.level + 1;
+            fileitem.parent = Some(item.clone());
+            items.push(fileitem);
+        }
+        if let Some(last) = items.last_mut() {
+            last.last = true;
+        }
+        let id = start_id + items.len();
+        fileitem_lst.extend(items.into_iter().map(Arc::new));
+        Ok(id)
+    }
+
+    /// `list_root` for the callers off the executor, the headless mode and the tests
+    pub fn load_root(&mut self, root_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        async_std::task::block_on(self.list_root(root_path))
+    }
+
+    /// Rebuild the items and cells from the root, the part of `change_root` that
+    /// doesn't touch the buffer
+    #[instrument(skip_all)]
+    async fn list_root(&mut self, root_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        self.set_expanded(&root_path);
+
+        self.stash_selection();
+        self.targets.clear();
+        self.col_map.clear();
+        self.file_items.clear();
+        self.refresh_swap_files();
+
+        let filemeta = std::fs::metadata(&root_path)?;
+        let mut fileitems = vec![Arc::new(FileItem::new(root_path, filemeta, 0))];
+
+        // recursively what the directory and build up the tree
+        self.list_children(fileitems[0].clone(), &mut fileitems, 1)
+            .await?;
+
+        self.insert_items_and_cells(0, fileitems)?;
+        Ok(())
+    }
+
+    /// The items listed at the root, without reading the directories again
+    #[instrument(skip_all)]
+    fn restore_root(
+        &mut self,
+        root_path: PathBuf,
+        items: Vec<FileItemPtr>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.set_expanded(&root_path);
+
+        self.stash_selection();
+        self.targets.clear();
+        self.col_map.clear();
+        self.file_items.clear();
+        self.refresh_swap_files();
+
+        self.insert_items_and_cells(0, items)?;
+        Ok(())
+    }
+
+    /// The options the listings depend on, a snapshot taken with other ones isn't restored
+    fn listing_key(&self) -> String {
+        format!(
+            "{}|{}|{}|{:?}|{}",
+            self.config.sort,
+            self.config.show_ignored_files,
+            self.config.ignored_files,
+            self.extension_filter,
+            self.hide_binary
+        )
+    }
+
+    /// Keep the items of the current root when `new_root` is below it, and drop the
+    /// snapshots of the roots that aren't above `new_root` anymore
+    fn snapshot_root(&mut self, new_root: &Path) {
+        self.root_snapshots
+            .retain(|root, _| new_root.starts_with(root) && new_root != root);
+        let root = match self.file_items.get(0) {
+            Some(fi) => fi.path.clone(),
+            None => return,
+        };
+        // the filtered and flat listings aren't the plain directory contents
+        if !new_root.starts_with(&root)
+            || new_root == root
+            || !self.config.search.is_empty()
+            || self.flat_view
+        {
+            return;
+        }
+        let mtimes = self
+            .file_items
+            .iter()
+            .enumerate()
+            .filter(|(i, fi)| *i == 0 || (fi.metadata.is_dir() && self.is_item_opened(&fi.path)))
+            .map(|(_, fi)| {
+                let mtime = std::fs::metadata(&fi.path).and_then(|m| m.modified()).ok();
+                (fi.path.clone(), mtime)
+            })
+            .collect();
+        let snapshot = RootSnapshot {
+            items: self.file_items.clone(),
+            mtimes,
+            key: self.listing_key(),
+            child: new_root.to_owned(),
+            child_expanded: self.is_item_opened(new_root),
+        };
+        self.root_snapshots.insert(root, snapshot);
+    }
+
+    /// None of the listed directories changed, and the same ones are still expanded
+    fn snapshot_valid(&self, snapshot: &RootSnapshot) -> bool {
+        if snapshot.key != self.listing_key() || !self.config.search.is_empty() || self.flat_view {
+            return false;
+        }
+        let expanded_same = snapshot
+            .items
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, fi)| fi.metadata.is_dir() && fi.path != snapshot.child)
+            .all(|(i, fi)| {
+                let listed = snapshot
+                    .items
+                    .get(i + 1)
+                    .map_or(false, |next| next.level > fi.level);
+                listed == self.is_item_opened(&fi.path)
+            });
+        expanded_same
+            && snapshot.mtimes.iter().all(|(path, mtime)| {
+                mtime.is_some() && std::fs::metadata(path).and_then(|m| m.modified()).ok() == *mtime
+            })
+    }
+
+    /// The rendered lines of all the items
+    #[instrument(skip_all)]
+    pub fn lines(&self) -> Vec<String> {
+        (0..self.file_items.len())
+            .map(|i| self.makeline(i))
+            .collect()
+    }
+
+    pub async fn change_root<P: AsRef<Path>, W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &mut self,
-        path_str: &str,
+        path: P,
         nvim: &Neovim<W>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = std::path::Path::new(path_str);
+        let path = path.as_ref();
         if !path.is_dir() {
             return Ok(());
         }
         let root_path = absolute_path(path)?;
+        self.detect_network_fs(&root_path);
         // if we have loaded git repo previously, we need to update
         // it. Otherwise we won't do a hard reload in the future
         if self.git_repo.is_some() {
             self.init_git_repo(&root_path);
             // self.update_git_map();
         }
-        let root_path_str = if let Some(p) = root_path.to_str() {
-            p
-        } else {
-            return Err(Box::new(ArgError::from_string(format!(
-                "Invalid path {:?}",
-                root_path
-            ))));
-        };
-        let last_cursor = match self.cursor_history.get(root_path_str) {
+        let last_cursor = match self.cursor_history.get(&root_path) {
             Some(v) => Some(*v),
             None => None,
         };
-        self.expand_store.insert(root_path_str.to_owned(), true);
-
-        self.targets.clear();
-        self.col_map.clear();
-        self.file_items.clear();
-
-        let filemeta = std::fs::metadata(root_path_str)?;
-        let mut fileitems = vec![Arc::new(FileItem::new(root_path, filemeta, 0))];
-
-        // recursively what the directory and build up the tree
-        self.entry_info_recursively_sync(fileitems[0].clone(), &mut fileitems, 1)?;
-
-        self.insert_items_and_cells(0, fileitems)?;
+        let snapshot = self.root_snapshots.remove(&root_path);
+        self.snapshot_root(&root_path);
+        match snapshot {
+            Some(snapshot) if self.snapshot_valid(&snapshot) => {
+                info!("{:?} restored from its snapshot", root_path);
+                if !snapshot.child_expanded {
+                    self.unset_expanded(&snapshot.child);
+                }
+                self.restore_root(root_path.clone(), snapshot.items)?;
+            }
+            _ => self.list_root(root_path.clone()).await?,
+        }
 
-        let ret = (0..self.file_items.len())
-            .map(|i| self.makeline(i))
-            .collect();
+        let ret = self.lines();
         self.buf_set_lines(nvim, 0, -1, true, ret).await?;
         self.hl_lines(&nvim, 0, self.file_items.len()).await?;
+        self.auto_fit_width(nvim).await?;
+        if let Err(e) = self.refresh_custom_columns(nvim).await {
+            error!("custom columns: {:?}", e);
+        }
         if let Some(v) = last_cursor {
             let win = Window::new(Value::from(0), nvim.clone());
             let cursor_pos = if v as usize >= self.file_items.len() {
@@ -1156,9 +6315,37 @@ impl Tree {
                 Err(e) => warn!("Fail to set cursor position {}: {:?}", cursor_pos, e),
             };
         }
+        if self.config.breadcrumbs {
+            self.update_breadcrumbs(nvim).await?;
+        }
         Ok(())
     }
 
+    /// Scan the collapsed directory under the cursor in the background, so that opening it
+    /// later doesn't hit the disk
+    pub fn precompute_listing(&self, cursor: usize) {
+        if self.network_fs {
+            return;
+        }
+        let fi = match cursor
+            .checked_sub(1)
+            .and_then(|idx| self.file_items.get(idx))
+        {
+            Some(fi) => fi,
+            None => return,
+        };
+        if !fi.metadata.is_dir() || self.is_item_opened(&fi.path) {
+            return;
+        }
+        let path = fi.path.clone();
+        let retries = self.config.fs_retries;
+        async_std::task::spawn_blocking(move || {
+            if let Err(e) = async_std::task::block_on(read_listing_cached(&path, retries)) {
+                debug!("precompute {:?} failed: {:?}", path, e);
+            }
+        });
+    }
+
     fn make_cells(
         &self,
         items: &[FileItemPtr],
@@ -1168,6 +6355,13 @@ impl Tree {
         for col in &self.config.columns {
             r.push((col.clone(), Vec::new()))
         }
+        // the ids, sizes and cells may have changed since the last draw
+        if let Ok(mut m) = self.sibling_max_size.lock() {
+            m.clear();
+        }
+        if let Ok(mut w) = self.custom_widths.lock() {
+            w.clear();
+        }
         let mut is_first = true;
         for fileitem in items {
             let mut start = 0;
@@ -1176,6 +6370,9 @@ impl Tree {
             for i in 0..self.config.columns.len() {
                 let col = &self.config.columns[i];
                 let mut cell = ColumnCell::new(self, fileitem, col.clone(), is_root);
+                if *col == ColumnType::FILENAME && !is_root && self.config.truncate_filename {
+                    cell.truncate(KSTOP.saturating_sub(start));
+                }
                 cell.byte_start = byte_start;
                 cell.byte_end = byte_start + cell.text.len();
                 cell.col_start = start;
@@ -1213,10 +6410,13 @@ impl Tree {
         for (_, val) in self.col_map.iter_mut() {
             val.splice(start..end, vec![]);
         }
-        self.file_items.splice(start..end, vec![]);
         for i in start..end {
-            self.selected_items.remove(&i);
+            if self.selected_items.remove(&i) {
+                self.selected_offscreen
+                    .insert(self.file_items[i].path.clone());
+            }
         }
+        self.file_items.splice(start..end, vec![]);
 
         // items after the deleted
         if start < self.file_items.len() {
@@ -1266,6 +6466,16 @@ impl Tree {
             }
         }
 
+        // the items selected before they were collapsed away
+        if !self.selected_offscreen.is_empty() {
+            for (i, item) in items.iter().enumerate() {
+                if self.selected_offscreen.remove(&item.path) {
+                    self.selected_items.insert(pos + i);
+                }
+            }
+        }
+
+        let widened = self.render_columns(&items);
         // make cells
         let cells = self.make_cells(&items, is_first_item_root);
         // insert the cells
@@ -1275,10 +6485,17 @@ impl Tree {
             }
             self.col_map.get_mut(&col).unwrap().splice(pos..pos, cells);
         }
+        // the new cells have the new width already
+        if widened {
+            let len = self.file_items.len();
+            self.update_cells(0, pos);
+            self.update_cells(pos + size_to_insert, len);
+        }
         Ok(())
     }
 
     // set the content of the buffer
+    #[instrument(skip_all)]
     async fn buf_set_lines<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &self,
         nvim: &Neovim<W>,
@@ -1288,69 +6505,80 @@ impl Tree {
         replacement: Vec<String>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let buf = Buffer::new(self.bufnr.clone(), nvim.clone());
+        let drawn_lines: Vec<_> = replacement.iter().map(|x| (x.clone(), 0)).collect();
         buf.set_option("modifiable", Value::from(true)).await?;
         buf.set_lines(start, end, strict, replacement).await?;
         buf.set_option("modifiable", Value::from(false)).await?;
+        if let Ok(mut drawn) = self.drawn.lock() {
+            // negative indices count from the end, -1 is past the last line
+            let len = drawn.len() as i64;
+            let index = |i: i64| (if i < 0 { len + 1 + i } else { i }).max(0).min(len) as usize;
+            let (start, end) = (index(start), index(end));
+            drawn.splice(start..end.max(start), drawn_lines);
+        }
+        // the lines under the footer may have been replaced
+        self.update_footer(nvim).await?;
+        self.update_more_marks(nvim).await?;
         Ok(())
     }
 
-    // NOTE: tests show that the sync version is much faster than the async version
-    // using tokio::fs
-    fn entry_info_recursively_sync<'a>(
-        &'a self,
-        item: Arc<FileItem>,
-        fileitem_lst: &'a mut Vec<FileItemPtr>,
-        mut start_id: usize,
-    ) -> Result<usize, Box<dyn std::error::Error>> {
-        let mut entries: Vec<_> = std::fs::read_dir(&item.path)?
-            .map(|x| x.unwrap())
-            .filter(|x| {
-                self.config.show_ignored_files
-                    || !(x.file_name().to_str().unwrap().starts_with('.'))
-            })
-            .map(|x| {
-                let meta = x.metadata().unwrap();
-                (x, meta)
-            })
-            .collect();
-        entries.sort_by(|l, r| {
-            if l.1.is_dir() && !r.1.is_dir() {
-                Ordering::Less
-            } else if !l.1.is_dir() && r.1.is_dir() {
-                Ordering::Greater
-            } else {
-                l.0.file_name().cmp(&r.0.file_name())
+    /// The filters every listed entry goes through
+    fn entry_filters(&self) -> Vec<EntryFilter> {
+        let mut filters = Vec::new();
+        if !self.config.show_ignored_files {
+            filters.push(EntryFilter::HIDDEN);
+            if !self.config.ignored_patterns.is_empty() {
+                filters.push(EntryFilter::IGNORED(self.config.ignored_patterns.clone()));
             }
-        });
-        let level = item.level + 1;
-        let mut i = 0;
-        let count = entries.len();
-        for entry in entries {
-            let mut fileitem = FileItem::new(absolute_path(entry.0.path())?, entry.1, start_id);
-            start_id += 1;
-            fileitem.level = level;
-            fileitem.parent = Some(item.clone());
-            if i == count - 1 {
-                fileitem.last = true;
-            }
-            i += 1;
-            if let Some(expand) = self.expand_store.get(fileitem.path.to_str().unwrap()) {
-                if *expand {
-                    let ft_ptr = Arc::new(fileitem);
-                    fileitem_lst.push(ft_ptr.clone());
-                    start_id =
-                        self.entry_info_recursively_sync(ft_ptr.clone(), fileitem_lst, start_id)?
-                } else {
-                    fileitem_lst.push(Arc::new(fileitem));
-                }
-            } else {
-                fileitem_lst.push(Arc::new(fileitem));
+        }
+        if !self.extension_filter.is_empty() {
+            filters.push(EntryFilter::EXTENSIONS(self.extension_filter.clone()));
+        }
+        if self.hide_binary {
+            filters.push(EntryFilter::TEXT);
+        }
+        filters
+    }
+
+    /// The active filters, for the root line
+    pub fn filter_summary(&self) -> Vec<String> {
+        let mut summary = Vec::new();
+        if !self.config.search.is_empty() {
+            summary.push(format!("filter: {}", self.config.search));
+        }
+        if !self.extension_filter.is_empty() {
+            summary.push(format!("ext: {}", self.extension_filter.join(",")));
+        }
+        if self.hide_binary {
+            summary.push("text only".to_owned());
+        }
+        summary
+    }
+
+    /// The matcher of what was typed for filter, search or select_pattern
+    fn matcher(&self, pattern: &str) -> Matcher {
+        Matcher::new(pattern, self.config.smart_case)
+    }
+
+    /// Whether `item` or one of its ancestors below the root matches the filter
+    fn inside_filter_match(&self, item: &FileItem) -> bool {
+        let matcher = self.matcher(&self.config.search);
+        let mut cur = Some(item);
+        while let Some(fi) = cur {
+            // the root itself doesn't count
+            if fi.parent.is_none() {
+                return false;
             }
+            if matcher.matches_path(&fi.path) {
+                return true;
+            }
+            cur = fi.parent.as_deref();
         }
-        Ok(start_id)
+        false
     }
 
-    /*
+    /// List the entries of `item` into `fileitem_lst` and the expanded directories below
+    /// it, the ids start at `start_id`. The id after the last one listed
     fn entry_info_recursively<'a>(
         &'a self,
         item: Arc<FileItem>,
@@ -1358,46 +6586,71 @@ impl Tree {
         mut start_id: usize,
     ) -> Pin<Box<dyn Future<Output = Result<usize, Box<dyn std::error::Error>>> + 'a + Send>> {
         Box::pin(async move {
-            let mut read_dir = tokio::fs::read_dir(&item.path).await?;
-            let mut dir_entries = Vec::new();
-            // filter: dirs, files, no dot and dot dot
-            while let Some(entry) = read_dir.next_entry().await? {
-                // skip hidden file or dot or dot dot
-                if fs_utils::is_dot_or_dotdot(&entry)
-                    || (!self.config.show_ignored_files && fs_utils::is_hidden(&entry))
-                {
-                    continue;
+            let retries = self.config.fs_retries;
+            let listing = read_listing_cached(&item.path, retries).await?;
+            let filters = self.entry_filters();
+            let mut entries: Vec<_> = listing
+                .into_iter()
+                .filter(|(path, metadata)| passes(&filters, path, metadata))
+                .collect();
+            // the directories leading to matches, shown expanded while the filter is on
+            let mut match_dirs = HashSet::new();
+            // the contents of a matching directory are listed as is
+            if !self.config.search.is_empty() && !self.inside_filter_match(&item) {
+                let matcher = self.matcher(&self.config.search);
+                let mut kept = Vec::with_capacity(entries.len());
+                for (path, metadata) in entries {
+                    if matcher.matches_path(&path) {
+                        kept.push((path, metadata));
+                    } else if metadata.is_dir()
+                        && dir_contains_match(&path, &matcher, &filters, retries).await
+                    {
+                        match_dirs.insert(path.clone());
+                        kept.push((path, metadata));
+                    }
                 }
-                dir_entries.push(entry);
-                // entries.push((entry, metadata));
+                entries = kept;
             }
-            if dir_entries.len() <= 0 {
-                return Ok(start_id);
-            }
-            let metadata = futures::future::join_all(dir_entries.iter().map(|x| x.metadata()))
-                .await
-                .into_iter()
-                .map(|x| x.unwrap());
-            let mut entries: Vec<_> = dir_entries.into_iter().zip(metadata.into_iter()).collect();
-            // directory first, name order
+            // directory first, then the sort keys chained, the file name breaks the ties
+            let sort_keys = &self.config.sort_keys;
             entries.sort_by(|l, r| {
                 if l.1.is_dir() && !r.1.is_dir() {
                     Ordering::Less
                 } else if !l.1.is_dir() && r.1.is_dir() {
                     Ordering::Greater
                 } else {
-                    l.0.file_name().cmp(&r.0.file_name())
+                    sort_keys
+                        .iter()
+                        .map(|(key, reverse)| {
+                            let ord = key.compare(l, r);
+                            if *reverse {
+                                ord.reverse()
+                            } else {
+                                ord
+                            }
+                        })
+                        .find(|ord| *ord != Ordering::Equal)
+                        .unwrap_or_else(|| l.0.file_name().cmp(&r.0.file_name()))
                 }
             });
+            // huge directories are listed a chunk at a time
+            let limit = match self.dir_limits.get(&item.path) {
+                Some(limit) => *limit,
+                None => self.config.dir_chunk_size,
+            };
+            if let Ok(mut hidden) = self.hidden_entries.lock() {
+                if limit > 0 && entries.len() > limit {
+                    hidden.insert(item.path.clone(), entries.len() - limit);
+                    entries.truncate(limit);
+                } else {
+                    hidden.remove(&item.path);
+                }
+            }
             let level = item.level + 1;
             let mut i = 0;
             let count = entries.len();
             for entry in entries {
-                let mut fileitem = FileItem::new(
-                    tokio::fs::canonicalize(entry.0.path()).await?,
-                    entry.1,
-                    start_id,
-                );
+                let mut fileitem = FileItem::new(absolute_path(&entry.0)?, entry.1, start_id);
                 start_id += 1;
                 fileitem.level = level;
                 fileitem.parent = Some(item.clone());
@@ -1405,28 +6658,46 @@ impl Tree {
                     fileitem.last = true;
                 }
                 i += 1;
-                if let Some(expand) = self.expand_store.get(fileitem.path.to_str().unwrap()) {
-                    if *expand {
-                        let ft_ptr = Arc::new(fileitem);
-                        fileitem_lst.push(ft_ptr.clone());
-                        start_id = self.entry_info_recursively_sync(
-                            ft_ptr.clone(),
-                            fileitem_lst,
-                            start_id,
-                        )?;
-                    } else {
-                        fileitem_lst.push(Arc::new(fileitem));
-                    }
-                } else {
-                    fileitem_lst.push(Arc::new(fileitem));
+                let expand = match_dirs.contains(&entry.0)
+                    || self.expand_store.get(&fileitem.path).map_or(false, |e| *e);
+                let ft_ptr = Arc::new(fileitem);
+                fileitem_lst.push(ft_ptr.clone());
+                if expand {
+                    start_id = self
+                        .entry_info_recursively(ft_ptr, fileitem_lst, start_id)
+                        .await?;
                 }
             }
             Ok(start_id)
         })
     }
-    */
 
+    /// The line as displayed, scrolled horizontally
     fn makeline(&self, pos: usize) -> String {
+        let line = self.makeline_full(pos);
+        if self.hscroll == 0 {
+            return line;
+        }
+        let (cut, padding) = hscroll_split(&line, self.hscroll);
+        let mut scrolled = " ".repeat(padding);
+        scrolled.push_str(&line[cut..]);
+        scrolled
+    }
+
+    /// Byte range of a cell in the displayed line, None when it's scrolled out
+    fn hl_range(&self, pos: usize, byte_start: usize, byte_end: usize) -> Option<(i64, i64)> {
+        if self.hscroll == 0 {
+            return Some((byte_start as i64, byte_end as i64));
+        }
+        let (cut, padding) = hscroll_split(&self.makeline_full(pos), self.hscroll);
+        if byte_end <= cut {
+            return None;
+        }
+        let shift = |b: usize| (b.saturating_sub(cut) + padding) as i64;
+        Some((shift(byte_start.max(cut)), shift(byte_end)))
+    }
+
+    fn makeline_full(&self, pos: usize) -> String {
         let mut start = 0;
         let mut line = String::new();
         for col in &self.config.columns {
@@ -1446,47 +6717,216 @@ impl Tree {
         line
     }
 
+    /// Switch between the regular and the dimmed highlight groups when the tree window
+    /// gains or loses focus
+    pub async fn set_focused<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        focused: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.focused == focused {
+            return Ok(());
+        }
+        self.focused = focused;
+        let buf = Buffer::new(self.bufnr.clone(), nvim.clone());
+        buf.clear_namespace(self.icon_ns_id, 0, -1).await?;
+        if !focused {
+            buf.clear_namespace(self.cursor_ns_id, 0, -1).await?;
+        }
+        self.hl_lines(nvim, 0, self.file_items.len()).await
+    }
+
+    /// Highlight the filename of the item under the cursor (1-based line), the previous
+    /// cursor highlight is cleared by the lua side
+    pub async fn hl_cursor_item<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+        cursor: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.config.highlight_cursor_item || !self.focused || cursor == 0 {
+            return Ok(());
+        }
+        let idx = cursor - 1;
+        let cell = match self
+            .col_map
+            .get(&ColumnType::FILENAME)
+            .and_then(|cells| cells.get(idx))
+        {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let (start, end) =
+            match self.hl_range(idx, cell.byte_start, cell.byte_start + cell.text.len()) {
+                Some(r) => r,
+                None => return Ok(()),
+            };
+        nvim_call::execute_lua(
+            nvim,
+            "tree.hl_cursor_item(...)",
+            vec![
+                self.bufnr.clone(),
+                Value::from(self.cursor_ns_id),
+                Value::from(idx),
+                Value::from(start),
+                Value::from(end),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// (hl_group, start, end) of the line, as displayed
+    fn line_highlights(&self, i: usize) -> Vec<(String, i64, i64)> {
+        let mut highlights = Vec::new();
+        let hl_name = |hl_group: &str| {
+            if self.focused {
+                hl_group.to_string()
+            } else {
+                dim_hl_group(hl_group)
+            }
+        };
+        for col in &self.config.columns {
+            let cell = &self.col_map.get(col).unwrap()[i];
+            for (seg_start, seg_end, hl_group) in &cell.hl_segments {
+                if let Some((start, end)) =
+                    self.hl_range(i, cell.byte_start + seg_start, cell.byte_start + seg_end)
+                {
+                    highlights.push((hl_name(hl_group), start, end));
+                }
+            }
+            if !cell.hl_segments.is_empty() {
+                continue;
+            }
+            if let Some(hl_group) = cell.hl_group.as_deref() {
+                if let Some((start, end)) =
+                    self.hl_range(i, cell.byte_start, cell.byte_start + cell.text.len())
+                {
+                    highlights.push((hl_name(hl_group), start, end));
+                }
+            }
+        }
+        highlights
+    }
+
     // [sl, el)
+    #[instrument(skip_all)]
     async fn hl_lines<W: AsyncWrite + Send + Sync + Unpin + 'static>(
         &self,
         nvim: &Neovim<W>,
         sl: usize,
         el: usize,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut hl_args = Vec::<Value>::new();
-        let icon_ns_id = self.icon_ns_id;
+        // the lines [sl, el) are cleared first, and the highlights are applied before the
+        // next draw can change the lines again
+        let mut calls = vec![clear_hl_call(&self.bufnr, self.icon_ns_id, sl, el)];
+        let mut hashes = Vec::new();
         for i in sl..el {
-            for col in &self.config.columns {
-                let cell = &self.col_map.get(col).unwrap()[i];
-                if let Some(hl_group) = cell.hl_group.clone() {
-                    // let buf = Buffer::new(self.bufnr.clone(), nvim.clone());
-                    let start = cell.byte_start as i64;
-                    let end = (cell.byte_start + cell.text.len()) as i64;
-                    hl_args.push(Value::from(hl_group));
-                    hl_args.push(Value::from(start));
-                    hl_args.push(Value::from(end));
-                    hl_args.push(Value::from(i));
-                    // async_std::task::spawn(async move {
-                    //     let hl_group = hl_group;
-                    //     buf.add_highlight(icon_ns_id, &hl_group, i as i64, start, end)
-                    //         .await
-                    //         .unwrap();
-                    // });
+            let highlights = self.line_highlights(i);
+            hashes.push(hl_hash(&highlights));
+            push_hl_calls(&mut calls, &self.bufnr, self.icon_ns_id, highlights, i);
+        }
+        nvim_call::call_atomic(nvim, calls).await?;
+        if let Ok(mut drawn) = self.drawn.lock() {
+            for (i, hash) in (sl..el).zip(hashes) {
+                if let Some(line) = drawn.get_mut(i) {
+                    line.1 = hash;
                 }
             }
         }
-        let args = vec![
-            self.bufnr.clone(),
-            Value::from(icon_ns_id),
-            Value::from(hl_args),
-        ];
-        let nvim_c = nvim.clone();
-        async_std::task::spawn(async move {
-            nvim_c
-                .execute_lua("tree.hl_lines(...)", args)
-                .await
-                .unwrap();
-        });
+        Ok(())
+    }
+
+    /// Draw the lines [sl, el) again, the number of items unchanged, sending only the runs
+    /// of lines whose text or highlights differ from what was drawn last
+    #[instrument(skip_all)]
+    async fn redraw_lines_diffed<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+        sl: usize,
+        el: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let in_sync = self
+            .drawn
+            .lock()
+            .map_or(false, |drawn| drawn.len() == self.file_items.len());
+        if !in_sync {
+            let lines = (sl..el).map(|i| self.makeline(i)).collect();
+            self.buf_set_lines(nvim, sl as i64, el as i64, true, lines)
+                .await?;
+            return self.hl_lines(nvim, sl, el).await;
+        }
+        // (first line, lines, hashes, highlight calls) of every run of changed lines
+        let mut runs: Vec<(usize, Vec<String>, Vec<u64>, Vec<Value>)> = Vec::new();
+        {
+            let drawn = self.drawn.lock().unwrap();
+            for i in sl..el {
+                let line = self.makeline(i);
+                let highlights = self.line_highlights(i);
+                let hash = hl_hash(&highlights);
+                if drawn[i].0 == line && drawn[i].1 == hash {
+                    continue;
+                }
+                let extends = runs
+                    .last()
+                    .map_or(false, |(first, lines, _, _)| first + lines.len() == i);
+                if !extends {
+                    runs.push((i, Vec::new(), Vec::new(), Vec::new()));
+                }
+                let run = runs.last_mut().unwrap();
+                run.1.push(line);
+                run.2.push(hash);
+                push_hl_calls(&mut run.3, &self.bufnr, self.icon_ns_id, highlights, i);
+            }
+        }
+        let changed: usize = runs.iter().map(|run| run.1.len()).sum();
+        debug!("redraw [{}, {}): {} lines changed", sl, el, changed);
+        if runs.is_empty() {
+            return Ok(());
+        }
+        // every run is replaced, then highlighted, in a single request
+        let set_modifiable = |on: bool| {
+            nvim_call::atomic_call(
+                "nvim_buf_set_option",
+                vec![
+                    self.bufnr.clone(),
+                    Value::from("modifiable"),
+                    Value::from(on),
+                ],
+            )
+        };
+        let mut calls = vec![set_modifiable(true)];
+        for (first, lines, _, _) in &runs {
+            calls.push(nvim_call::atomic_call(
+                "nvim_buf_set_lines",
+                vec![
+                    self.bufnr.clone(),
+                    Value::from(*first),
+                    Value::from(first + lines.len()),
+                    Value::from(true),
+                    Value::Array(lines.iter().map(|x| Value::from(x.as_str())).collect()),
+                ],
+            ));
+        }
+        calls.push(set_modifiable(false));
+        for (first, lines, _, hl_calls) in &mut runs {
+            calls.push(clear_hl_call(
+                &self.bufnr,
+                self.icon_ns_id,
+                *first,
+                *first + lines.len(),
+            ));
+            calls.append(hl_calls);
+        }
+        nvim_call::call_atomic(nvim, calls).await?;
+        if let Ok(mut drawn) = self.drawn.lock() {
+            for (first, lines, hashes, _) in runs {
+                for (i, (line, hash)) in lines.into_iter().zip(hashes).enumerate() {
+                    drawn[first + i] = (line, hash);
+                }
+            }
+        }
+        self.update_footer(nvim).await?;
+        self.update_more_marks(nvim).await?;
         Ok(())
     }
 
@@ -1500,11 +6940,8 @@ impl Tree {
             (*CLIPBOARD_MODE.write().await) = ClipboardMode::COPY;
         }
         self.copy_or_move(ctx).await?;
-        nvim.execute_lua(
-            "tree.print_message(...)",
-            vec![Value::from("Copy to clipboard")],
-        )
-        .await?;
+        self.notify(nvim, NotifyLevel::INFO, "Copy to clipboard")
+            .await?;
         Ok(())
     }
 
@@ -1518,25 +6955,22 @@ impl Tree {
             (*CLIPBOARD_MODE.write().await) = ClipboardMode::MOVE;
         }
         self.copy_or_move(ctx).await?;
-        nvim.execute_lua(
-            "tree.print_message(...)",
-            vec![Value::from("Move to clipboard")],
-        )
-        .await?;
+        self.notify(nvim, NotifyLevel::INFO, "Move to clipboard")
+            .await?;
         Ok(())
     }
 
     pub async fn copy_or_move(&self, ctx: Context) -> Result<(), Box<dyn std::error::Error>> {
         let mut clipboard = CLIPBOARD.write().await;
         clipboard.clear();
-        if self.selected_items.is_empty() {
-            clipboard.push(self.file_items[ctx.cursor as usize - 1].path.clone());
+        if !self.has_selection() {
+            clipboard.push(
+                self.file_items[CursorContext::try_from(&ctx)?.idx]
+                    .path
+                    .clone(),
+            );
         } else {
-            clipboard.extend(
-                self.selected_items
-                    .iter()
-                    .map(|x| self.file_items[*x].path.clone()),
-            )
+            clipboard.extend(self.selected_paths())
         }
 
         Ok(())
@@ -1549,23 +6983,26 @@ impl Tree {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let clipboard_empty = { CLIPBOARD.read().await.is_empty() };
         if clipboard_empty {
-            nvim.execute_lua(
-                "tree.print_message(...)",
-                vec![Value::from("Nothing in clipboard")],
-            )
-            .await?;
+            self.notify(nvim, NotifyLevel::WARN, "Nothing in clipboard")
+                .await?;
             return Ok(());
         }
+        let cursor = CursorContext::try_from(&ctx)?;
         let items: Vec<_> = { CLIPBOARD.read().await.iter().map(|x| x.clone()).collect() };
         for item in items {
             if !item.exists() {
                 continue;
             }
-            let cur = self.file_items[ctx.cursor as usize - 1].as_ref();
-            let dest_fname = item.file_name().unwrap().to_str().unwrap().to_owned();
-            let cur_dir = cur.path.parent().unwrap().to_path_buf();
+            let cur = self.file_items[cursor.idx].as_ref();
+            let dest_fname = item.file_name().unwrap().to_owned();
+            // into the cursor directory, next to the cursor file
+            let cur_dir = if cur.metadata.is_dir() {
+                cur.path.clone()
+            } else {
+                cur.path.parent().unwrap().to_path_buf()
+            };
             let mut dest_file = cur_dir.clone();
-            dest_file.push(PathBuf::from(dest_fname).as_path());
+            dest_file.push(dest_fname);
             info!("dest_file: {:?}", dest_file);
             if dest_file.exists() {
                 let dest_meta = std::fs::metadata(&dest_file)?;
@@ -1580,11 +7017,13 @@ impl Tree {
                                 .as_secs(),
                         ),
                     ),
+                    (Value::from("path"), path_value(&dest_file)),
+                    (Value::from("size"), Value::from(dest_meta.len())),
+                    // offered in place of the name taken
                     (
-                        Value::from("path"),
-                        Value::from(dest_file.as_os_str().to_str().unwrap()),
+                        Value::from("suffixed"),
+                        path_value(&available_name(&dest_file)),
                     ),
-                    (Value::from("size"), Value::from(dest_meta.len())),
                 ]);
                 let src = Value::from(vec![
                     (
@@ -1596,29 +7035,22 @@ impl Tree {
                                 .as_secs(),
                         ),
                     ),
-                    (
-                        Value::from("path"),
-                        Value::from(item.as_os_str().to_str().unwrap()),
-                    ),
+                    (Value::from("path"), path_value(&item)),
                     (Value::from("size"), Value::from(src_meta.len())),
                 ]);
-                nvim.execute_lua(
+                nvim_call::execute_lua(
+                    nvim,
                     "tree.pre_paste(...)",
                     vec![
-                        Value::from(vec![self.bufnr.clone(), Value::from(ctx.cursor as u64 - 1)]),
+                        Value::from(vec![self.bufnr.clone(), Value::from(cursor.idx)]),
                         src,
                         dest,
                     ],
                 )
                 .await?;
             } else {
-                self.func_paste(
-                    nvim,
-                    ctx.cursor - 1,
-                    item.as_os_str().to_str().unwrap(),
-                    dest_file.as_os_str().to_str().unwrap(),
-                )
-                .await?;
+                self.func_paste(nvim, cursor.idx as u64, &item, &dest_file)
+                    .await?;
             }
         }
 
@@ -1629,8 +7061,8 @@ impl Tree {
         &mut self,
         nvim: &Neovim<W>,
         idx: u64,
-        src: &str,
-        dest: &str,
+        src: &Path,
+        dest: &Path,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mode;
         {
@@ -1644,15 +7076,28 @@ impl Tree {
                 }
             }
         }
-        let from_path = Path::new(src);
-        let to_path = Path::new(dest);
+        let from_path = src;
+        let to_path = dest;
         let is_dir = std::fs::metadata(from_path).unwrap().is_dir();
         match mode {
             ClipboardMode::COPY => {
                 if is_dir {
-                    fs_extra::dir::copy(&from_path, &to_path, &fs_extra::dir::CopyOptions::new())?;
+                    // drawn once the copy is over, see finish_copy
+                    self.spawn_dir_copy(nvim, from_path, to_path);
+                    return Ok(());
+                } else if to_path.is_file() {
+                    // confirmed by the user in pre_paste
+                    let preserve = self.config.preserve_permissions;
+                    overwrite_file(from_path, to_path, preserve, self.config.fs_retries)
+                        .await
+                        .map_err(copy_error(from_path, to_path))?;
                 } else {
-                    std::fs::copy(from_path, to_path)?;
+                    let retries = self.config.fs_retries;
+                    fs_retry::retry_async("copy", from_path, retries, || {
+                        std::fs::copy(from_path, to_path)
+                    })
+                    .await
+                    .map_err(copy_error(from_path, to_path))?;
                 }
                 let idx_to_redraw =
                     if let Some(parent) = self.file_items[idx as usize].parent.as_ref() {
@@ -1663,10 +7108,359 @@ impl Tree {
                 self.redraw_subtree(nvim, idx_to_redraw, true).await?;
             }
             ClipboardMode::MOVE => {
-                std::fs::rename(from_path, to_path)?;
+                self.rename_path(nvim, from_path, to_path).await?;
+                let selected = self.rebase_path_state(from_path, to_path).await;
                 self.redraw_subtree(nvim, 0, true).await?;
+                self.reselect(nvim, &selected).await?;
+                Self::rename_buffers(nvim, from_path, to_path).await?;
+            }
+        }
+        self.reveal_pasted(nvim, to_path).await
+    }
+
+    /// Copy the directory in the background, the progress shows on the message line
+    fn spawn_dir_copy<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &self,
+        nvim: &Neovim<W>,
+        from: &Path,
+        to: &Path,
+    ) {
+        let (from, to) = (from.to_owned(), to.to_owned());
+        let nvim = nvim.clone();
+        let bufnr = self.bufnr.clone();
+        self.tasks.spawn(async move {
+            let (tx, mut rx) = futures::channel::mpsc::unbounded();
+            let copy = {
+                let (from, to) = (from.clone(), to.clone());
+                async_std::task::spawn_blocking(move || copy_job::copy_dir(&from, &to, tx))
+            };
+            let name = from.file_name().unwrap_or_default().to_string_lossy();
+            // the sender is dropped when the copy is over
+            while let Some(p) = futures::StreamExt::next(&mut rx).await {
+                let msg = format!("Copying {}: {}% {}", name, p.percent(), p.file);
+                if let Err(e) =
+                    nvim_call::execute_lua(&nvim, "tree.copy_progress(...)", vec![Value::from(msg)])
+                        .await
+                {
+                    warn!("copy progress: {}", e);
+                }
+            }
+            let (level, msg) = match copy.await {
+                Ok(bytes) => (
+                    NotifyLevel::INFO,
+                    format!("Copied {} ({})", to.display(), short_size(bytes)),
+                ),
+                Err(e) => (
+                    NotifyLevel::ERROR,
+                    format!("Copy to {} stopped: {}", to.display(), e),
+                ),
+            };
+            info!("{}", msg);
+            let args = vec![
+                bufnr,
+                path_value(&to),
+                Value::from(level.as_str()),
+                Value::from(msg),
+            ];
+            if let Err(e) = nvim_call::execute_lua(&nvim, "tree.copy_done(...)", args).await {
+                error!("copy done: {}", e);
+            }
+        });
+    }
+
+    /// Draw the directory a background copy went to, and put the cursor on it
+    pub async fn finish_copy<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        dest: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let parent_idx = dest
+            .parent()
+            .and_then(|dir| self.file_items.iter().position(|fi| fi.path == dir))
+            .unwrap_or(0);
+        self.redraw_subtree(nvim, parent_idx, true).await?;
+        self.reveal_pasted(nvim, dest).await
+    }
+
+    /// `load_more` lists the next dir_chunk_size entries of the directory under the cursor,
+    /// or of the one the item under the cursor is in
+    pub async fn action_load_more<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _args: Value,
+        ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let idx = CursorContext::try_from(&ctx)?.idx;
+        let cur = match self.file_items.get(idx) {
+            Some(fi) => fi.clone(),
+            None => return Err(Box::new(ArgError::new("invalid cursor pos"))),
+        };
+        let hidden = match self.hidden_entries.lock() {
+            Ok(hidden) => hidden.clone(),
+            Err(_) => return Ok(()),
+        };
+        let opened = idx == 0 || self.is_item_opened(&cur.path);
+        let dir = if opened && hidden.contains_key(&cur.path) {
+            cur
+        } else {
+            match cur.parent.clone() {
+                Some(p) if hidden.contains_key(&p.path) => p,
+                _ => {
+                    self.notify(nvim, NotifyLevel::INFO, "Everything is listed already")
+                        .await?;
+                    return Ok(());
+                }
+            }
+        };
+        // a chunk size turned to 0 lists the rest
+        let chunk = self.config.dir_chunk_size;
+        let limit = match self.dir_limits.get(&dir.path) {
+            Some(limit) if chunk > 0 => limit + chunk,
+            None if chunk > 0 => chunk * 2,
+            _ => 0,
+        };
+        self.dir_limits.insert(dir.path.clone(), limit);
+        // listed again from the cached listing, unlike a redraw
+        let start = dir.id + 1;
+        let end = self.file_items[start..]
+            .iter()
+            .position(|fi| fi.level <= dir.level)
+            .map_or(self.file_items.len(), |n| start + n);
+        self.remove_items_and_cells(start, end)?;
+        let mut child_items = Vec::new();
+        self.list_children(dir.clone(), &mut child_items, start)
+            .await?;
+        let new_end = start + child_items.len();
+        self.insert_items_and_cells(start, child_items)?;
+        let ret = (start..new_end).map(|i| self.makeline(i)).collect();
+        self.buf_set_lines(nvim, start as i64, end as i64, true, ret)
+            .await?;
+        self.hl_lines(&nvim, start, new_end).await?;
+        self.auto_fit_width(nvim).await?;
+        Ok(())
+    }
+
+    /// `cancel_copy` stops the directory copies running in the background
+    pub async fn action_cancel_copy<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        _arg: Value,
+        _ctx: Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let msg = match copy_job::cancel_all() {
+            0 => "No copy running".to_owned(),
+            n => format!("Cancelling {} copies", n),
+        };
+        self.notify(nvim, NotifyLevel::INFO, &msg).await?;
+        Ok(())
+    }
+
+    /// Open the directories from the root down to `path`, and put the cursor on it
+    async fn reveal_path<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut dir = self.file_items[0].path.clone();
+        let rel = match path.strip_prefix(&dir).ok().and_then(Path::parent) {
+            Some(rel) => rel.to_path_buf(),
+            None => return Ok(()),
+        };
+        for component in rel.components() {
+            dir.push(component);
+            // the indices change as the directories open, look them up every time
+            let idx = match self.file_items.iter().position(|fi| fi.path == dir) {
+                Some(idx) => idx,
+                None => break,
+            };
+            if !self.is_item_opened(&dir) {
+                self.open_tree(nvim, idx).await?;
+            }
+        }
+        match self.file_items.iter().position(|fi| fi.path == path) {
+            Some(idx) => {
+                let win = Window::new(Value::from(0), nvim.clone());
+                if let Err(e) = win.set_cursor((idx as i64 + 1, 0)).await {
+                    warn!("Fail to set cursor position {}: {:?}", idx + 1, e);
+                }
+            }
+            None => {
+                let msg = format!("{} isn't shown by the tree", path.display());
+                self.notify(nvim, NotifyLevel::WARN, &msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand the directory pasted into if it's collapsed, and put the cursor on the
+    /// pasted item
+    async fn reveal_pasted<W: AsyncWrite + Send + Sync + Unpin + 'static>(
+        &mut self,
+        nvim: &Neovim<W>,
+        dest: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let dest_dir = match dest.parent() {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        // the indices changed with the redraw, look the items up again
+        if let Some(dir_idx) = self.file_items.iter().position(|fi| fi.path == dest_dir) {
+            if !self.is_item_opened(&dest_dir) {
+                self.open_tree(nvim, dir_idx).await?;
+            }
+        }
+        if let Some(idx) = self.file_items.iter().position(|fi| fi.path == dest) {
+            let win = Window::new(Value::from(0), nvim.clone());
+            if let Err(e) = win.set_cursor((idx as i64 + 1, 0)).await {
+                warn!("Fail to set cursor position {}: {:?}", idx + 1, e);
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbatim_prefixes() {
+        assert_eq!(
+            strip_verbatim(r"\\?\C:\Users\me"),
+            Some(String::from(r"C:\Users\me"))
+        );
+        assert_eq!(
+            strip_verbatim(r"\\?\UNC\server\share\dir"),
+            Some(String::from(r"\\server\share\dir"))
+        );
+        assert_eq!(strip_verbatim(r"\\?\Volume{1234}\dir"), None);
+        assert_eq!(strip_verbatim(r"C:\Users"), None);
+        assert_eq!(strip_verbatim("/home/me"), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn drive_roots() {
+        assert_eq!(
+            normalize_path(PathBuf::from(r"\\?\c:\Users/me")),
+            PathBuf::from(r"C:\Users\me")
+        );
+        assert_eq!(parent_dir(Path::new(r"C:\")), None);
+        assert_eq!(parent_dir(Path::new(r"C:\Users")), Some(Path::new(r"C:\")));
+        assert_eq!(parent_dir(Path::new(r"\\server\share\")), None);
+        assert_eq!(
+            parent_dir(Path::new(r"\\server\share\dir")),
+            Some(Path::new(r"\\server\share\"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_root() {
+        assert_eq!(parent_dir(Path::new("/")), None);
+        assert_eq!(parent_dir(Path::new("/home")), Some(Path::new("/")));
+    }
+
+    /// Switching roots back and forth, filter on, leaves nothing of the previous root
+    /// behind: the items, the cells, the highlights and what the filter let through are the
+    /// current root's
+    #[test]
+    fn alternating_roots() {
+        let base = std::env::temp_dir().join(format!("tree_nvim_roots_{}", std::process::id()));
+        let roots = [base.join("a"), base.join("b")];
+        for (root, names) in roots
+            .iter()
+            .zip(&[["one.rs", "two.txt"], ["three.rs", "four.md"]])
+        {
+            std::fs::create_dir_all(root).unwrap();
+            for name in names {
+                std::fs::write(root.join(name), "").unwrap();
+            }
+        }
+        let listed: Vec<Tree> = roots
+            .iter()
+            .map(|root| {
+                let mut config = Config::default();
+                config.search = String::from(".rs");
+                let mut tree = Tree::headless(config);
+                tree.load_root(root.clone()).unwrap();
+                tree
+            })
+            .collect();
+        let mut config = Config::default();
+        config.search = String::from(".rs");
+        let mut tree = Tree::headless(config);
+        for round in 0..20 {
+            let root = &roots[round % 2];
+            tree.load_root(root.clone()).unwrap();
+            assert_eq!(tree.file_items[0].path, *root);
+            let names: Vec<String> = tree.file_items[1..]
+                .iter()
+                .map(|fi| fi.path.strip_prefix(root).unwrap().display().to_string())
+                .collect();
+            let expected = if round % 2 == 0 { "one.rs" } else { "three.rs" };
+            assert_eq!(names, vec![expected]);
+            for cells in tree.col_map.values() {
+                assert_eq!(cells.len(), tree.file_items.len());
+            }
+            // the rows highlighted as in a tree that only ever had this root
+            for i in 0..tree.file_items.len() {
+                let line = tree.makeline(i);
+                let highlights = tree.line_highlights(i);
+                for (_, start, end) in &highlights {
+                    assert!(0 <= *start && start <= end && *end as usize <= line.len());
+                }
+                assert_eq!(highlights, listed[round % 2].line_highlights(i));
+            }
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    /// Typing on filters the listed items again, which leaves the same tree as listing it
+    /// with the narrower filter
+    #[test]
+    fn narrowed_filter() {
+        let base = std::env::temp_dir().join(format!("tree_nvim_refilter_{}", std::process::id()));
+        for name in &[
+            "lib/set.rs",
+            "lib/main.rs",
+            "docs/sheet.md",
+            "docs/a.md",
+            "bet.txt",
+            "bean.txt",
+        ] {
+            let path = base.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let names = |tree: &Tree| -> Vec<(String, bool)> {
+            tree.file_items[1..]
+                .iter()
+                .map(|fi| {
+                    let name = fi.path.strip_prefix(&base).unwrap().display().to_string();
+                    (name, fi.last)
+                })
+                .collect()
+        };
+        let mut config = Config::default();
+        config.search = String::from("e");
+        let mut tree = Tree::headless(config);
+        tree.load_root(base.clone()).unwrap();
+        assert_eq!(names(&tree).len(), 6);
+
+        tree.config.search = String::from("et");
+        assert!(async_std::task::block_on(tree.refilter()).unwrap());
+        let mut config = Config::default();
+        config.search = String::from("et");
+        let mut listed = Tree::headless(config);
+        listed.load_root(base.clone()).unwrap();
+        assert_eq!(names(&tree), names(&listed));
+        for (i, fi) in tree.file_items.iter().enumerate() {
+            assert_eq!(fi.id, i);
+        }
+        for cells in tree.col_map.values() {
+            assert_eq!(cells.len(), tree.file_items.len());
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
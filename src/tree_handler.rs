@@ -1,4 +1,16 @@
+use crate::column::highlight_commands;
 use crate::errors::ArgError;
+use crate::logging;
+use crate::nvim_call;
+use crate::protocol::{ClientProtocol, CAP_HIGHLIGHT_LINKS, CAP_SCOPED_TREES};
+use crate::session::Session;
+use crate::swap::swap_dirs;
+use crate::tasks::TaskSet;
+use crate::tree::clipboard_cut_items;
+use crate::tree::complete_path;
+use crate::tree::options_value;
+use crate::tree::value_to_path;
+use crate::tree::Config;
 use crate::tree::Context;
 use crate::tree::Tree;
 use async_std::sync::Arc;
@@ -10,31 +22,77 @@ use nvim_rs::{exttypes::Buffer, Handler, Neovim, Value};
 use std::borrow::BorrowMut;
 use std::collections::HashMap;
 use std::convert::From;
+use tracing::{info_span, Instrument};
 
-fn bufnr_val_to_tuple(val: &Value) -> Option<(i8, Vec<u8>)> {
+/// Decode the msgpack integer neovim packs into the data of a Buffer ext value
+fn decode_ext_int(data: &[u8]) -> Option<i64> {
+    let be = |bytes: &[u8]| bytes.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+    let (&marker, rest) = data.split_first()?;
+    let width = match marker {
+        0x00..=0x7f => return Some(i64::from(marker)),
+        0xe0..=0xff => return Some(i64::from(marker as i8)),
+        0xcc | 0xd0 => 1,
+        0xcd | 0xd1 => 2,
+        0xce | 0xd2 => 4,
+        0xcf | 0xd3 => 8,
+        _ => return None,
+    };
+    if rest.len() < width {
+        return None;
+    }
+    let raw = be(&rest[..width]);
+    Some(match marker {
+        0xd0 => i64::from(raw as u8 as i8),
+        0xd1 => i64::from(raw as u16 as i16),
+        0xd2 => i64::from(raw as u32 as i32),
+        _ => raw as i64,
+    })
+}
+
+/// The buffer number of a bufnr sent by lua (integer) or a Buffer handle (ext)
+fn bufnr_val_to_key(val: &Value) -> Option<i64> {
     match val {
-        Value::Integer(v) => Some((0, vec![v.as_u64().unwrap() as u8])),
-        Value::Ext(v1, v2) => Some((*v1, v2.clone())),
+        Value::Integer(v) => v.as_i64(),
+        Value::Ext(0, data) => decode_ext_int(data),
         _ => None,
     }
 }
 
-// fn tuple_to_bufnr_val(v: &(i8, Vec<u8>)) -> Value {
-//     Value::Ext(v.0.clone(), v.1.clone())
-// }
-
 #[derive(Default, Debug)]
 pub struct TreeHandlerData {
     // cfg_map: HashMap<String, Value>,
-    bufnr_to_tree: HashMap<(i8, Vec<u8>), Tree>,
+    bufnr_to_tree: HashMap<i64, Tree>,
     tree_bufs: Vec<Value>, // recently used order
     // buffer: Option<Buffer<<TreeHandler as Handler>::Writer>>,
     buf_count: u32,
     prev_bufnr: Option<Value>,
+    // "tab:<tabpage>" or "win:<winid>" => bufnr, for trees not in the global scope
+    scope_to_bufnr: HashMap<String, Value>,
+    client_protocol: ClientProtocol,
+    // highlight groups are registered with the first tree, not on connect
+    highlights_registered: bool,
+    watcher_started: bool,
+    // the tasks not bound to a tree, the watcher
+    tasks: TaskSet,
+}
+
+/// The key of the tab/window the tree is bound to, None for global trees
+fn scope_key(cfg_map: &HashMap<String, Value>) -> Option<String> {
+    let scope = cfg_map
+        .get("scope")
+        .and_then(|v| v.as_str())
+        .unwrap_or("global");
+    match scope {
+        "tab" => cfg_map.get("tabpage").map(|v| format!("tab:{}", v)),
+        "window" => cfg_map.get("winid").map(|v| format!("win:{}", v)),
+        _ => None,
+    }
 }
 
 type TreeHandlerDataPtr = Arc<RwLock<TreeHandlerData>>;
 
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(1000);
+
 /// Handling requests and notifications from neovim
 pub struct TreeHandler<W: AsyncWrite + Send + Sync + Unpin + 'static> {
     _phantom: std::marker::PhantomData<W>, // ugly, but otherwise the compiler will complain, need to workout a more elegant way
@@ -60,52 +118,267 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> Default for TreeHandler<W> {
 }
 
 impl<W: AsyncWrite + Send + Sync + Unpin + 'static> TreeHandler<W> {
+    /// A namespace of its own for every tree, clearing one tree's highlights leaves the
+    /// others alone
     async fn create_namespace(
         nvim: &Neovim<<Self as Handler>::Writer>,
+        name: &str,
+        bufnr: &Value,
     ) -> Result<i64, Box<dyn std::error::Error>> {
-        let ns_id = nvim.create_namespace("tree_icon").await?;
+        let key = bufnr_val_to_key(bufnr).unwrap_or_default();
+        let ns_id = nvim.create_namespace(&format!("{}_{}", name, key)).await?;
         Ok(ns_id)
     }
 
+    /// Cancel the background work of every tree, neovim is gone
+    pub async fn shutdown(&self) {
+        let d = self.data.read().await;
+        d.tasks.cancel_all().await;
+        for tree in d.bufnr_to_tree.values() {
+            tree.tasks.cancel_all().await;
+        }
+        info!("shut down {} trees", d.bufnr_to_tree.len());
+    }
+
+    /// Define the highlight groups, then the user links so they win over the defaults,
+    /// all in a single request
+    async fn register_highlights(
+        data: &mut TreeHandlerData,
+        nvim: &Neovim<<Self as Handler>::Writer>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let commands = highlight_commands()
+            .into_iter()
+            .map(Value::from)
+            .collect::<Vec<Value>>();
+        let code = if data.client_protocol.supports(CAP_HIGHLIGHT_LINKS) {
+            "local t = require('tree') t.run_commands_batch(...) t.apply_highlight_links()"
+        } else {
+            "require('tree').run_commands_batch(...)"
+        };
+        nvim_call::execute_lua(nvim, code, vec![Value::from(commands)]).await?;
+        data.highlights_registered = true;
+        Ok(())
+    }
+
+    /// Poll the displayed directories of the trees and the git metadata of the ones showing
+    /// the GIT column, and refresh them after files were added, removed, committed or
+    /// staged outside the tree
+    fn spawn_watcher(
+        tasks: &TaskSet,
+        data: TreeHandlerDataPtr,
+        nvim: Neovim<<Self as Handler>::Writer>,
+    ) {
+        tasks.spawn(async move {
+            loop {
+                async_std::task::sleep(POLL_INTERVAL).await;
+                // the write lock holds the actions up, only taken when there's work, and the
+                // files are stated before on a blocking thread
+                let targets: Vec<_> = {
+                    let d = data.read().await;
+                    d.bufnr_to_tree
+                        .iter()
+                        .filter(|(_, tree)| tree.needs_polling())
+                        .map(|(key, tree)| (*key, tree.poll_targets()))
+                        .collect()
+                };
+                if targets.is_empty() {
+                    continue;
+                }
+                let stamps = async_std::task::spawn_blocking(move || {
+                    targets
+                        .into_iter()
+                        .map(|(key, targets)| (key, targets.stat()))
+                        .collect::<Vec<_>>()
+                })
+                .await;
+                let mut d = data.write().await;
+                for (key, stamps) in stamps {
+                    let tree = match d.bufnr_to_tree.get_mut(&key) {
+                        Some(tree) => tree,
+                        None => continue,
+                    };
+                    let dirs = tree.poll_dir_stamps(&stamps);
+                    if !dirs.is_empty() {
+                        if let Err(e) = tree.refresh_changed_dirs(&nvim, &dirs).await {
+                            error!("directory refresh error: {:?}", e);
+                        }
+                    }
+                    if tree.take_computed_sizes() {
+                        if let Err(e) = tree.redraw_subtree(&nvim, 0, false).await {
+                            error!("size refresh error: {:?}", e);
+                        }
+                    }
+                    if !tree.poll_git_stamp(&stamps) {
+                        continue;
+                    }
+                    info!("git metadata changed, refreshing {:?}", tree.bufnr);
+                    tree.update_git_map();
+                    if let Err(e) = tree.redraw_subtree(&nvim, 0, false).await {
+                        error!("git refresh error: {:?}", e);
+                    }
+                }
+            }
+        });
+    }
+
     async fn create_tree(
         data: &mut TreeHandlerData,
         nvim: &Neovim<<Self as Handler>::Writer>,
         bufnr: Value,
         path: &str,
         cfg_map: HashMap<String, Value>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Self::attach_tree(data, nvim, bufnr.clone(), path, cfg_map, None).await?;
+        nvim_call::execute_lua(nvim, "tree.resume(...)", vec![bufnr])
+            .instrument(info_span!("resume"))
+            .await?;
+        Ok(())
+    }
+
+    /// Set up a tree in the buffer `bufnr`, rooted at `path` or with the state of
+    /// `session`, given when a restarted backend takes over a tree of its predecessor
+    async fn attach_tree(
+        data: &mut TreeHandlerData,
+        nvim: &Neovim<<Self as Handler>::Writer>,
+        bufnr: Value,
+        path: &str,
+        cfg_map: HashMap<String, Value>,
+        session: Option<Session>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let buf = Buffer::new(bufnr.clone(), nvim.clone());
         // new namespace and new buffer for the new tree
-        let ns_id = Self::create_namespace(nvim).await?;
+        let ns_id = Self::create_namespace(nvim, "tree_icon", &bufnr).await?;
 
         let mut tree = Tree::new(bufnr.clone(), &buf, &nvim, ns_id).await?;
+        match nvim.get_option("directory").await {
+            Ok(Value::String(dirs)) => {
+                tree.swap_dirs = swap_dirs(dirs.as_str().unwrap_or_default())
+            }
+            Ok(_) => {}
+            Err(e) => warn!("can't get 'directory': {:?}", e),
+        }
+        tree.cursor_ns_id = Self::create_namespace(nvim, "tree_cursor", &bufnr).await?;
+        tree.footer_ns_id = Self::create_namespace(nvim, "tree_footer", &bufnr).await?;
+        tree.more_ns_id = Self::create_namespace(nvim, "tree_more", &bufnr).await?;
+        tree.flash_ns_id = Self::create_namespace(nvim, "tree_flash", &bufnr).await?;
         {
             tree.config.update(&cfg_map)?;
         }
+        tree.provision_buffer(nvim).await?;
+        tree.options = cfg_map;
+        tree.register_icon_highlights(nvim).await?;
+        let path = match session {
+            Some(session) => {
+                tree.apply_session(&session);
+                session.root
+            }
+            None => tree
+                .restore_session(path)
+                .unwrap_or_else(|| path.to_owned()),
+        };
+        let path = path.as_str();
+        if tree.config.initial_expand_level > 0 {
+            tree.expand_initial_levels(path).await;
+        }
 
-        let start = std::time::Instant::now();
-        tree.change_root(path, &nvim).await?;
-        info!("change root took: {} secs", start.elapsed().as_secs_f64());
-
-        buf.set_option("buflisted", Value::from(tree.config.listed))
+        tree.change_root(path, &nvim)
+            .instrument(info_span!("change_root", path))
             .await?;
 
         data.bufnr_to_tree
-            .insert(bufnr_val_to_tuple(&bufnr).unwrap(), tree);
+            .insert(bufnr_val_to_key(&bufnr).unwrap(), tree);
         data.tree_bufs.push(bufnr.clone());
-        data.prev_bufnr = Some(bufnr.clone());
-
-        // let start = std::time::Instant::now();
-        // let nvim = nvim.clone();
-        // async_std::task::spawn(async move {
-        nvim.execute_lua("tree.resume(...)", vec![bufnr])
-            .await
-            .unwrap();
-        // });
-        // info!("resume took: {} secs", start.elapsed().as_secs_f64());
+        data.prev_bufnr = Some(bufnr);
         Ok(())
     }
 
+    /// Take over a tree of a backend that exited, from the state `_tree_serialize` gave
+    /// the frontend: {bufnr, session, options}
+    async fn restore_tree(
+        data: &mut TreeHandlerData,
+        nvim: &Neovim<<Self as Handler>::Writer>,
+        state: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let get = |key: &str| {
+            state
+                .as_map()
+                .and_then(|m| m.iter().find(|(k, _)| k.as_str() == Some(key)))
+                .map(|(_, v)| v)
+        };
+        let bufnr = match get("bufnr") {
+            Some(v) => v.clone(),
+            None => return Err(Box::new(ArgError::new("restore: bufnr is required"))),
+        };
+        let mut session = match get("session") {
+            Some(v) => Session::from_value(v)?,
+            None => return Err(Box::new(ArgError::new("restore: session is required"))),
+        };
+        // the windows showing the tree kept their cursor
+        let root = session.root.clone();
+        session.cursor_history.retain(|(path, _)| path != &root);
+        let mut cfg_map = HashMap::new();
+        if let Some(Value::Map(options)) = get("options") {
+            for (k, v) in options {
+                if let Some(key) = k.as_str() {
+                    cfg_map.insert(key.to_owned(), v.clone());
+                }
+            }
+        }
+        info!("restoring the tree of {:?} at {}", bufnr, root);
+        if let Some(key) = scope_key(&cfg_map) {
+            data.scope_to_bufnr.insert(key, bufnr.clone());
+        }
+        Self::attach_tree(data, nvim, bufnr, &root, cfg_map, Some(session)).await
+    }
+
+    /// The state of every tree, least recently used first, for the frontend to replay with
+    /// `_tree_restore` should the backend exit
+    fn serialize_trees(data: &TreeHandlerData) -> Value {
+        let states = data
+            .tree_bufs
+            .iter()
+            .filter_map(|bufnr| data.bufnr_to_tree.get(&bufnr_val_to_key(bufnr)?))
+            .map(|tree| {
+                let options = tree
+                    .options
+                    .iter()
+                    .map(|(k, v)| (Value::from(k.as_str()), v.clone()))
+                    .collect();
+                Value::Map(vec![
+                    (Value::from("bufnr"), tree.bufnr.clone()),
+                    (Value::from("session"), tree.session().to_value()),
+                    (Value::from("options"), Value::Map(options)),
+                ])
+            })
+            .collect();
+        Value::Array(states)
+    }
+
+    /// Hand the state of every tree to the frontend, kept there for `_tree_restore`
+    async fn push_states(data: &TreeHandlerData, nvim: &Neovim<<Self as Handler>::Writer>) {
+        let states = Self::serialize_trees(data);
+        if let Err(e) = nvim_call::execute_lua(nvim, "tree.store_states(...)", vec![states]).await {
+            error!("can't store the tree states: {}", e);
+        }
+    }
+
+    /// Let every tree know about the clipboard, so that the items to be moved are
+    /// rendered consistently across trees
+    async fn broadcast_clipboard(
+        data: &mut TreeHandlerData,
+        nvim: &Neovim<<Self as Handler>::Writer>,
+    ) {
+        let cut_items = clipboard_cut_items().await;
+        for tree in data.bufnr_to_tree.values_mut() {
+            if let Err(e) = tree.update_cut_items(nvim, cut_items.clone()).await {
+                error!(
+                    "Fail to update clipboard state of {:?}: {:?}",
+                    tree.bufnr, e
+                );
+            }
+        }
+    }
+
     /// starts the tree, either create a new one or using the existing one
     async fn start_tree(
         data: &mut TreeHandlerData,
@@ -113,14 +386,57 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> TreeHandler<W> {
         path: String,
         cfg_map: HashMap<String, Value>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let bufnr = cfg_map.get("bufnr");
+        if !data.highlights_registered {
+            Self::register_highlights(data, nvim)
+                .instrument(info_span!("register_highlights"))
+                .await?;
+        }
+        let bufnr = cfg_map.get("bufnr").cloned();
+        // older frontends can't create the buffers of scoped trees
+        let scope_key = if data.client_protocol.supports(CAP_SCOPED_TREES) {
+            scope_key(&cfg_map)
+        } else {
+            None
+        };
+        let scoped_bufnr = match &scope_key {
+            Some(key) => match data.scope_to_bufnr.get(key) {
+                Some(nr)
+                    if data
+                        .bufnr_to_tree
+                        .contains_key(&bufnr_val_to_key(nr).unwrap()) =>
+                {
+                    Some(nr.clone())
+                }
+                _ => None,
+            },
+            None => None,
+        };
 
         if let Some(bufnr) = bufnr {
             info!("creating new tree at {}", bufnr);
-            // let start = std::time::Instant::now();
-            Self::create_tree(data, nvim, bufnr.clone(), &path, cfg_map).await?;
-        // info!("Create tree took {} secs", start.elapsed().as_secs_f64());
+            if let Some(key) = scope_key {
+                data.scope_to_bufnr.insert(key, bufnr.clone());
+            }
+            Self::create_tree(data, nvim, bufnr, &path, cfg_map)
+                .instrument(info_span!("create_tree"))
+                .await?;
+        } else if let (Some(key), None) = (&scope_key, &scoped_bufnr) {
+            // first tree of the tab/window, let the lua side set up the buffer
+            let ctx = Value::Map(
+                cfg_map
+                    .iter()
+                    .map(|(k, v)| (Value::from(k.as_str()), v.clone()))
+                    .collect(),
+            );
+            let bufnr =
+                nvim_call::execute_lua(nvim, "return tree.new_buffer(...)", vec![ctx]).await?;
+            info!("creating new tree for {} at {}", key, bufnr);
+            data.scope_to_bufnr.insert(key.clone(), bufnr.clone());
+            Self::create_tree(data, nvim, bufnr, &path, cfg_map).await?;
         } else {
+            if let Some(nr) = scoped_bufnr {
+                data.prev_bufnr = Some(nr);
+            }
             let bufnr_vals;
             {
                 // only a few items, wouldn't be a problem
@@ -131,18 +447,44 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> TreeHandler<W> {
                 .clone();
                 let tree = match data
                     .bufnr_to_tree
-                    .get_mut(&bufnr_val_to_tuple(&prev_bufnr).unwrap())
+                    .get_mut(&bufnr_val_to_key(&prev_bufnr).unwrap())
                 {
                     Some(t) => t,
                     None => return Err(Box::new(ArgError::new("unknown tree"))),
                 };
+                let prev_sort = tree.config.sort_keys.clone();
+                let prev_icons = tree.config.icons.clone();
+                let prev_footer = tree.config.summary_footer.clone();
+                let prev_breadcrumbs = tree.config.breadcrumbs;
                 tree.config.update(&cfg_map)?;
+                // the tree stays in the scope it was created in
+                for (k, v) in cfg_map {
+                    if !["bufnr", "scope", "tabpage", "winid"].contains(&k.as_str()) {
+                        tree.options.insert(k, v);
+                    }
+                }
+                if tree.config.icons != prev_icons {
+                    tree.register_icon_highlights(nvim).await?;
+                }
+                // the listings are sorted as they're read, a new order needs a full redraw
+                if tree.config.sort_keys != prev_sort {
+                    tree.redraw_subtree(nvim, 0, true).await?;
+                } else if tree.config.icons != prev_icons {
+                    tree.redraw_subtree(nvim, 0, false).await?;
+                }
+                if tree.config.summary_footer != prev_footer {
+                    let buf = Buffer::new(prev_bufnr.clone(), nvim.clone());
+                    buf.clear_namespace(tree.footer_ns_id, 0, -1).await?;
+                    tree.update_footer(nvim).await?;
+                }
+                if tree.config.breadcrumbs != prev_breadcrumbs {
+                    tree.update_breadcrumbs(nvim).await?;
+                }
                 data.tree_bufs.retain(|v| v != &prev_bufnr);
                 data.tree_bufs.push(prev_bufnr);
                 bufnr_vals = Value::Array(data.tree_bufs.iter().rev().cloned().collect());
             }
-            nvim.execute_lua("tree.resume(...)", vec![bufnr_vals])
-                .await?;
+            nvim_call::execute_lua(nvim, "tree.resume(...)", vec![bufnr_vals]).await?;
         }
         Ok(())
     }
@@ -198,22 +540,136 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> Handler for TreeHandler<W> {
                 });
                 Ok(Value::Nil)
                 */
+                // took_ms of the reply, lock wait included
                 let start = std::time::Instant::now();
                 {
-                    let mut d = self.data.write().await;
-                    info!("Wait for lock took {} secs", start.elapsed().as_secs_f64());
-                    match Self::start_tree(d.borrow_mut(), &nvim, path, cfg_map).await {
+                    let mut d = self.data.write().instrument(info_span!("wait_lock")).await;
+                    if !d.watcher_started {
+                        d.watcher_started = true;
+                        Self::spawn_watcher(&d.tasks, self.data.clone(), nvim.clone());
+                    }
+                    let started = Self::start_tree(d.borrow_mut(), &nvim, path, cfg_map)
+                        .instrument(info_span!("start_tree"))
+                        .await;
+                    match started {
                         Err(e) => Err(Value::from(format!("Error: {:?}", e))),
                         _ => {
-                            info!(
-                                "Start tree took {} secs, at bufnr {:?}",
-                                start.elapsed().as_secs_f64(),
-                                d.prev_bufnr
-                            );
-                            Ok(Value::Nil)
+                            info!("Tree started at bufnr {:?}", d.prev_bufnr);
+                            // the tree that was created or resumed
+                            let tree = d
+                                .prev_bufnr
+                                .as_ref()
+                                .and_then(bufnr_val_to_key)
+                                .and_then(|key| d.bufnr_to_tree.get(&key));
+                            match tree {
+                                Some(tree) => {
+                                    Ok(tree.start_reply(start.elapsed().as_millis() as u64))
+                                }
+                                None => Ok(Value::Nil),
+                            }
+                        }
+                    }
+                }
+            }
+            "_tree_hello" => {
+                let client = match args
+                    .get(0)
+                    .and_then(|v| v.as_array())
+                    .and_then(|v| v.get(0))
+                {
+                    Some(v) => match ClientProtocol::from_value(v) {
+                        Ok(c) => c,
+                        Err(e) => return Err(Value::from(format!("Error: {}", e))),
+                    },
+                    None => return Err(Value::from("Error: protocol info is required")),
+                };
+                info!("client protocol: {:?}", client);
+                let mut d = self.data.write().await;
+                d.client_protocol = client;
+                Ok(client.to_reply())
+            }
+            "_tree_serialize" => {
+                let d = self.data.read().await;
+                Ok(Self::serialize_trees(&d))
+            }
+            // the window options of a tree the backend hasn't provisioned yet
+            "_tree_default_window_options" => Ok(options_value(&Config::default().window_options)),
+            "_tree_restore" => {
+                // [states], as `_tree_serialize` of the previous backend replied
+                let states = match args
+                    .get(0)
+                    .and_then(|v| v.as_array())
+                    .and_then(|v| v.get(0))
+                    .and_then(|v| v.as_array())
+                {
+                    Some(states) => states.clone(),
+                    None => return Err(Value::from("Error: states are required")),
+                };
+                let mut d = self.data.write().await;
+                if !d.highlights_registered {
+                    if let Err(e) = Self::register_highlights(&mut d, &nvim).await {
+                        return Err(Value::from(format!("Error: {:?}", e)));
+                    }
+                }
+                if !d.watcher_started {
+                    d.watcher_started = true;
+                    Self::spawn_watcher(&d.tasks, self.data.clone(), nvim.clone());
+                }
+                let mut restored = 0;
+                for state in &states {
+                    match Self::restore_tree(&mut d, &nvim, state).await {
+                        Ok(_) => restored += 1,
+                        Err(e) => error!("can't restore {:?}: {:?}", state, e),
+                    }
+                }
+                Ok(Value::from(restored))
+            }
+            "_tree_export" => {
+                // [bufnr or nil for the last used tree, {selection_only}]
+                let vl = match args.get(0) {
+                    Some(Value::Array(v)) => v,
+                    _ => return Err(Value::from("Error: invalid arg type")),
+                };
+                let mut selection_only = false;
+                if let Some(Value::Map(opts)) = vl.get(1) {
+                    for (k, v) in opts {
+                        if k.as_str() == Some("selection_only") {
+                            selection_only = v.as_bool().unwrap_or(false);
                         }
                     }
                 }
+                let d = self.data.read().await;
+                let bufnr = match vl.get(0) {
+                    Some(Value::Nil) | None => d.prev_bufnr.clone(),
+                    Some(v) => Some(v.clone()),
+                };
+                match bufnr
+                    .as_ref()
+                    .and_then(bufnr_val_to_key)
+                    .and_then(|key| d.bufnr_to_tree.get(&key))
+                {
+                    Some(tree) => Ok(Value::from(tree.export_json(selection_only))),
+                    None => Err(Value::from("Can't find view")),
+                }
+            }
+            "_tree_set_log" => {
+                // [level or nil, file or nil], an empty file stops logging
+                let vl = match args.get(0) {
+                    Some(Value::Array(v)) => v,
+                    _ => return Err(Value::from("Error: invalid arg type")),
+                };
+                let level = vl.get(0).and_then(|v| v.as_str());
+                let file = vl.get(1).and_then(|v| v.as_str());
+                match logging::set(level, file) {
+                    Ok((level, file)) => {
+                        info!("log level {}, file {:?}", level, file);
+                        Ok(Value::Map(vec![
+                            (Value::from("level"), Value::from(level)),
+                            (Value::from("file"), file.map_or(Value::Nil, Value::from)),
+                        ]))
+                    }
+                    Err(e) => Err(Value::from(format!("Error: {}", e))),
+                }
             }
             "_tree_get_candidate" => {
                 let buf = match nvim.get_current_buf().await {
@@ -222,22 +678,25 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> Handler for TreeHandler<W> {
                         return Err(Value::from(format!("Can't get current buffer: {:?}", e)));
                     }
                 };
-                let bufnr = match buf.get_value() {
-                    Value::Ext(v0, v1) => (*v0, v1.clone()),
-                    _ => {
+                let bufnr = match bufnr_val_to_key(buf.get_value()) {
+                    Some(key) => key,
+                    None => {
                         return Err(Value::from(format!("Type for current buffer error")));
                     }
                 };
-                let cursor = match nvim.call_function("line", vec![Value::from(".")]).await {
+                let cursor = match nvim_call::query_function(&nvim, "line", vec![Value::from(".")])
+                    .await
+                {
                     Ok(Value::Integer(v)) => match v.as_u64() {
                         Some(i) => i as usize,
                         None => {
                             return Err(Value::from(format!("Type for current line error")));
                         }
                     },
-                    _ => {
+                    Ok(_) => {
                         return Err(Value::from(format!("Type for current line error")));
                     }
+                    Err(e) => return Err(Value::from(format!("Can't get current line: {}", e))),
                 };
                 info!("bufnr: {:?}, cursor {}", bufnr, cursor);
                 let d = self.data.read().await;
@@ -247,6 +706,41 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> Handler for TreeHandler<W> {
                     Err(Value::from("Can't find view"))
                 }
             }
+            "_tree_complete_path" => {
+                // NOTE: this is requested from within the prompts of running actions, which
+                // hold the data lock, so everything needed is carried by the arguments
+                let vl = match args.get(0) {
+                    Some(Value::Array(v)) => v,
+                    _ => return Err(Value::from("Error: invalid arg type")),
+                };
+                let partial = match vl.get(0).and_then(|v| v.as_str()) {
+                    Some(v) => v,
+                    None => return Err(Value::from("Error: partial path should be string")),
+                };
+                let mut cwd = String::from(".");
+                let mut show_ignored_files = false;
+                let mut dirs_only = false;
+                if let Some(Value::Map(context)) = vl.get(1) {
+                    for (k, v) in context {
+                        match k.as_str() {
+                            Some("cwd") => {
+                                if let Some(s) = v.as_str() {
+                                    cwd = s.to_owned();
+                                }
+                            }
+                            Some("show_ignored_files") => {
+                                show_ignored_files = v.as_bool().unwrap_or(false)
+                            }
+                            Some("dirs_only") => dirs_only = v.as_bool().unwrap_or(false),
+                            _ => {}
+                        }
+                    }
+                }
+                let candidates = complete_path(partial, &cwd, show_ignored_files, dirs_only);
+                Ok(Value::Array(
+                    candidates.into_iter().map(Value::from).collect(),
+                ))
+            }
             _ => Err(Value::from(format!("Unknown method: {}", name))),
         }
     }
@@ -272,28 +766,15 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> Handler for TreeHandler<W> {
                 error!("Arg num should be 3 but got {}", vl.len());
             }
 
-            let mut ctx = Context::default();
-
             // 3rd update context
-            match vl.pop().unwrap() {
-                Value::Map(context_val) => {
-                    for (k, v) in context_val {
-                        let key = match k {
-                            Value::String(v) => match v.into_str() {
-                                Some(vv) => vv,
-                                None => {
-                                    error!("Can't convert to str");
-                                    continue;
-                                }
-                            },
-                            _ => {
-                                error!("Key should be of type string");
-                                continue;
-                            }
-                        };
-                        ctx.update(&key, v);
+            let ctx = match vl.pop().unwrap() {
+                Value::Map(context_val) => match Context::from_map(context_val) {
+                    Ok(ctx) => ctx,
+                    Err(e) => {
+                        error!("Invalid context: {}", e);
+                        return;
                     }
-                }
+                },
                 _ => {
                     error!("Context must be of map");
                     return;
@@ -313,53 +794,302 @@ impl<W: AsyncWrite + Send + Sync + Unpin + 'static> Handler for TreeHandler<W> {
             info!("async action: {}", action);
 
             {
-                let start = std::time::Instant::now();
-                let mut d = self.data.write().await;
-                info!(
-                    "Waited took {} secs for lock",
-                    start.elapsed().as_secs_f64()
-                );
-                if let Some(bufnr) = d.prev_bufnr.clone() {
-                    if let Some(tree) = d
-                        .bufnr_to_tree
-                        .get_mut(&bufnr_val_to_tuple(&bufnr).unwrap())
+                let mut d = self.data.write().instrument(info_span!("wait_lock")).await;
+                // the tree the action targets or is issued from, falling back to the most
+                // recently used one
+                let candidates = vec![
+                    ctx.bufnr.clone(),
+                    ctx.prev_bufnr.clone(),
+                    d.prev_bufnr.clone(),
+                    d.tree_bufs.last().cloned(),
+                ];
+                let bufnr_val = candidates.into_iter().flatten().find(|v| {
+                    bufnr_val_to_key(v).map_or(false, |key| d.bufnr_to_tree.contains_key(&key))
+                });
+                let bufnr_val = match bufnr_val {
+                    Some(v) => v,
+                    None => {
+                        error!("No tree for action {}, context: {:?}", action, ctx);
+                        let msg = format!("{}: no tree buffer found", action);
+                        if let Err(e) = nvim_call::execute_lua(
+                            &neovim,
+                            "require('tree').notify(...)",
+                            vec![Value::from("error"), Value::from(msg)],
+                        )
+                        .await
+                        {
+                            error!("can't report the error: {}", e);
+                        }
+                        return;
+                    }
+                };
+                d.prev_bufnr = Some(bufnr_val.clone());
+                let mut state_changed = false;
+                if let Some(bufnr) = bufnr_val_to_key(&bufnr_val) {
+                    if let Some(tree) = d.bufnr_to_tree.get_mut(&bufnr) {
+                        let before = tree.session();
+                        tree.action(&neovim, &action, act_args, ctx)
+                            .instrument(info_span!("action", name = action.as_str()))
+                            .await;
+                        state_changed = tree.session() != before;
+                    }
+                }
+                // expanded, collapsed or moved to another root, the frontend keeps the
+                // states to replay into a restarted backend
+                if state_changed {
+                    Self::push_states(&d, &neovim).await;
+                }
+                if action == "copy" || action == "move" || action == "paste" {
+                    Self::broadcast_clipboard(d.borrow_mut(), &neovim).await;
+                }
+            }
+        }
+
+        if name == "_tree_push_states" {
+            let d = self.data.read().await;
+            Self::push_states(&d, &neovim).await;
+        }
+
+        if name == "_tree_colorscheme_changed" {
+            let mut d = self.data.write().await;
+            // nothing to reload before the first tree
+            if !d.highlights_registered {
+                return;
+            }
+            if let Err(e) = Self::register_highlights(&mut d, &neovim).await {
+                error!("Fail to reload highlights: {:?}", e);
+            }
+            for tree in d.bufnr_to_tree.values() {
+                if let Err(e) = tree.register_icon_highlights(&neovim).await {
+                    error!("Fail to reload the icon highlights: {:?}", e);
+                }
+            }
+        }
+
+        if name == "_tree_tab_enter" {
+            // make the tree of the tab the one `Tree` resumes, and show it
+            if let Some(tabpage) = vl.get(0) {
+                let key = format!("tab:{}", tabpage);
+                let mut resumed = None;
+                {
+                    let mut d = self.data.write().await;
+                    if let Some(bufnr) = d.scope_to_bufnr.get(&key).cloned() {
+                        if d.bufnr_to_tree
+                            .contains_key(&bufnr_val_to_key(&bufnr).unwrap())
+                        {
+                            d.tree_bufs.retain(|v| v != &bufnr);
+                            d.tree_bufs.push(bufnr.clone());
+                            d.prev_bufnr = Some(bufnr.clone());
+                            resumed = Some(bufnr);
+                        } else {
+                            d.scope_to_bufnr.remove(&key);
+                        }
+                    }
+                }
+                if let Some(bufnr) = resumed {
+                    if let Err(e) =
+                        nvim_call::execute_lua(&neovim, "tree.tab_resume(...)", vec![bufnr]).await
                     {
-                        let start = std::time::Instant::now();
-                        tree.action(&neovim, &action, act_args, ctx).await;
-                        info!(
-                            "Action {} took {} secs",
-                            action,
-                            start.elapsed().as_secs_f64()
-                        );
+                        error!("tab resume error: {}", e);
                     }
                 }
             }
         }
 
+        if name == "_tree_cursor_hold" {
+            // [bufnr, line]
+            let key = match vl.get(0).and_then(bufnr_val_to_key) {
+                Some(k) => k,
+                None => {
+                    error!("bufnr is required for _tree_cursor_hold");
+                    return;
+                }
+            };
+            let cursor = match vl.get(1).and_then(|v| v.as_u64()) {
+                Some(v) => v as usize,
+                None => {
+                    error!("cursor should be of integer type");
+                    return;
+                }
+            };
+            let d = self.data.read().await;
+            if let Some(tree) = d.bufnr_to_tree.get(&key) {
+                tree.precompute_listing(cursor);
+            }
+        }
+
+        if name == "_tree_cursor_moved" {
+            // [bufnr, line]
+            let bufnr = match vl.get(0) {
+                Some(v) => v.clone(),
+                None => {
+                    error!("bufnr is required for _tree_cursor_moved");
+                    return;
+                }
+            };
+            let cursor = match vl.get(1).and_then(|v| v.as_u64()) {
+                Some(v) => v as usize,
+                None => {
+                    error!("cursor should be of integer type");
+                    return;
+                }
+            };
+            let key = match bufnr_val_to_key(&bufnr) {
+                Some(k) => k,
+                None => return,
+            };
+            let d = self.data.read().await;
+            if let Some(tree) = d.bufnr_to_tree.get(&key) {
+                tree.follow_preview(&neovim, cursor);
+                if let Err(e) = tree.hl_cursor_item(&neovim, cursor).await {
+                    error!("hl_cursor_item error: {:?}", e);
+                }
+            }
+        }
+
+        if name == "_tree_buf_detach" {
+            // [bufnr], the buffer of the tree was unloaded or wiped out
+            let bufnr = match vl.get(0) {
+                Some(v) => v.clone(),
+                None => return,
+            };
+            let key = match bufnr_val_to_key(&bufnr) {
+                Some(k) => k,
+                None => return,
+            };
+            let mut d = self.data.write().await;
+            if let Some(tree) = d.bufnr_to_tree.remove(&key) {
+                info!("tree {:?} destroyed", bufnr);
+                tree.tasks.cancel_all().await;
+            }
+            d.tree_bufs.retain(|v| v != &bufnr);
+            d.scope_to_bufnr.retain(|_, v| v != &bufnr);
+            if d.prev_bufnr.as_ref() == Some(&bufnr) {
+                d.prev_bufnr = d.tree_bufs.last().cloned();
+            }
+        }
+
+        if name == "_tree_shown_changed" {
+            // [bufnr, shown]
+            let key = match vl.get(0).and_then(bufnr_val_to_key) {
+                Some(k) => k,
+                None => {
+                    error!("bufnr is required for _tree_shown_changed");
+                    return;
+                }
+            };
+            let shown = vl.get(1).and_then(|v| v.as_bool()).unwrap_or(true);
+            let mut d = self.data.write().await;
+            if let Some(tree) = d.bufnr_to_tree.get_mut(&key) {
+                tree.shown = shown;
+            }
+        }
+
+        if name == "_tree_focus_changed" {
+            // [bufnr, focused]
+            let bufnr = match vl.get(0) {
+                Some(v) => v.clone(),
+                None => {
+                    error!("bufnr is required for _tree_focus_changed");
+                    return;
+                }
+            };
+            let focused = vl.get(1).and_then(|v| v.as_bool()).unwrap_or(true);
+            let key = match bufnr_val_to_key(&bufnr) {
+                Some(k) => k,
+                None => return,
+            };
+            let mut d = self.data.write().await;
+            if let Some(tree) = d.bufnr_to_tree.get_mut(&key) {
+                if let Err(e) = tree.set_focused(&neovim, focused).await {
+                    error!("set_focused error: {:?}", e);
+                }
+            }
+        }
+
+        if name == "_tree_copy_done" {
+            // [bufnr, dest], a background copy of the tree is over
+            let (bufnr, dest) = match (vl.get(0), vl.get(1).and_then(value_to_path)) {
+                (Some(b), Some(d)) => (b.clone(), d),
+                _ => {
+                    error!("bufnr and dest are required for _tree_copy_done");
+                    return;
+                }
+            };
+            let key = match bufnr_val_to_key(&bufnr) {
+                Some(k) => k,
+                None => return,
+            };
+            let mut d = self.data.write().await;
+            if let Some(tree) = d.bufnr_to_tree.get_mut(&key) {
+                if let Err(e) = tree.finish_copy(&neovim, &dest).await {
+                    error!("finish_copy error: {:?}", e);
+                }
+            }
+        }
+
         if name == "_tree_async_func" {
             let func_name = args[0].as_str().unwrap();
             if func_name == "paste" {
                 let fargs = args[1].as_array().unwrap();
                 let pos = fargs[0].as_array().unwrap();
-                let src = fargs[1].as_str().unwrap();
-                let dest = fargs[2].as_str().unwrap();
+                let src = value_to_path(&fargs[1]).unwrap();
+                let dest = value_to_path(&fargs[2]).unwrap();
                 let buf = pos[0].as_u64().unwrap();
                 let line = pos[1].as_u64().unwrap();
                 {
                     let mut d = self.data.write().await;
                     if let Some(tree) = d
                         .bufnr_to_tree
-                        .get_mut(&bufnr_val_to_tuple(&Value::from(buf)).unwrap())
+                        .get_mut(&bufnr_val_to_key(&Value::from(buf)).unwrap())
                     {
-                        match tree.func_paste(&neovim, line, src, dest).await {
+                        match tree.func_paste(&neovim, line, &src, &dest).await {
                             Ok(_) => {}
                             Err(e) => {
                                 error!("paste error: {:?}", e);
                             }
                         }
                     }
+                    Self::broadcast_clipboard(d.borrow_mut(), &neovim).await;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ext_ints() {
+        assert_eq!(decode_ext_int(&[0x05]), Some(5));
+        assert_eq!(decode_ext_int(&[0xff]), Some(-1));
+        assert_eq!(decode_ext_int(&[0xcc, 0xc8]), Some(200));
+        assert_eq!(decode_ext_int(&[0xcd, 0x01, 0x00]), Some(256));
+        assert_eq!(decode_ext_int(&[0xd0, 0x80]), Some(-128));
+        assert_eq!(decode_ext_int(&[0xd2, 0xff, 0xff, 0xff, 0xfe]), Some(-2));
+        assert_eq!(
+            decode_ext_int(&[0xcf, 0, 0, 0, 1, 0, 0, 0, 0]),
+            Some(1 << 32)
+        );
+        // truncated, not an integer, empty
+        assert_eq!(decode_ext_int(&[0xcd, 0x01]), None);
+        assert_eq!(decode_ext_int(&[0xc0]), None);
+        assert_eq!(decode_ext_int(&[]), None);
+    }
+
+    #[test]
+    fn bufnr_keys() {
+        assert_eq!(bufnr_val_to_key(&Value::from(3)), Some(3));
+        assert_eq!(bufnr_val_to_key(&Value::Ext(0, vec![0x03])), Some(3));
+        assert_eq!(
+            bufnr_val_to_key(&Value::Ext(0, vec![0xcd, 0x01, 0x2c])),
+            Some(300)
+        );
+        // windows and tabpages are other ext types
+        assert_eq!(bufnr_val_to_key(&Value::Ext(1, vec![0x03])), None);
+        assert_eq!(bufnr_val_to_key(&Value::from("3")), None);
+        assert_eq!(bufnr_val_to_key(&Value::Nil), None);
+    }
+}
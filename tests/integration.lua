@@ -0,0 +1,85 @@
+-- Driven by tests/integration.rs: runs the tree server cargo built ($TREE_BIN) against
+-- the fixture directory ($TREE_FIXTURE) and checks the lines of the tree buffer.
+-- The failures go to stderr and make nvim exit with an error code.
+local a = vim.api
+local tree = require('tree')
+tree.bin = os.getenv('TREE_BIN')
+local root = os.getenv('TREE_FIXTURE')
+
+local failures = {}
+
+local function fail(msg) table.insert(failures, msg) end
+
+local function lines() return a.nvim_buf_get_lines(0, 0, -1, false) end
+
+-- 1-based line of the first item named `name`, nil when it isn't shown. The root line
+-- is skipped, the fixture path could contain anything
+local function line_of(name)
+    for i, line in ipairs(lines()) do
+        if i > 1 and line:find(name, 1, true) then return i end
+    end
+    return nil
+end
+
+local function count_of(name)
+    return #vim.tbl_filter(function(line) return line:find(name, 1, true) ~= nil end,
+                           {unpack(lines(), 2)})
+end
+
+local function wait_for(what, pred)
+    if not vim.wait(5000, pred, 20) then
+        fail(what .. ', buffer:\n  ' .. table.concat(lines(), '\n  '))
+        return false
+    end
+    return true
+end
+
+local function shown(name) return function() return line_of(name) ~= nil end end
+local function hidden(name) return function() return line_of(name) == nil end end
+
+local function on(name, action, args)
+    local lnum = line_of(name)
+    if lnum == nil then
+        fail(string.format('%s: %s is not shown', action, name))
+        return
+    end
+    a.nvim_win_set_cursor(0, {lnum, 0})
+    call_async_action(action, args or {})
+end
+
+local function exists(path) return vim.loop.fs_stat(root .. '/' .. path) ~= nil end
+
+local function run()
+    vim.cmd('Tree ' .. root)
+    if not wait_for('_tree_start draws the root', shown('b.txt')) then return end
+    for _, name in ipairs({'dir/', 'b.txt', 'c.txt'}) do
+        if not line_of(name) then fail('start: ' .. name .. ' is not shown') end
+    end
+    if line_of('inner.txt') then fail('start: dir/ is expanded') end
+
+    on('dir/', 'open_tree')
+    wait_for('open_tree shows inner.txt', shown('inner.txt'))
+
+    on('dir/', 'close_tree')
+    wait_for('close_tree hides inner.txt', hidden('inner.txt'))
+
+    on('c.txt', 'remove', {'true'})
+    wait_for('remove hides c.txt', hidden('c.txt'))
+    if exists('c.txt') then fail('remove: c.txt is still on disk') end
+
+    on('b.txt', 'copy')
+    on('dir/', 'paste')
+    wait_for('paste copies b.txt into dir/', function() return exists('dir/b.txt') end)
+    -- the directory pasted into is expanded to show the pasted file
+    wait_for('paste shows the pasted file',
+             function() return count_of('b.txt') == 2 end)
+end
+
+local ok, err = pcall(run)
+if not ok then fail('error: ' .. tostring(err)) end
+if #failures > 0 then
+    io.stderr:write(table.concat(failures, '\n') .. '\n')
+    vim.cmd('cquit 1')
+else
+    vim.cmd('qall!')
+end
@@ -0,0 +1,219 @@
+//! End to end. The server cargo built talks msgpack-rpc to a mock peer standing in for
+//! neovim. Then, with `nvim` on the PATH and `cargo test -- --ignored`, a headless neovim
+//! loads the plugin of this checkout and tests/integration.lua drives the tree over a
+//! fixture directory.
+use rmpv::Value;
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+fn nvim_available() -> bool {
+    Command::new("nvim")
+        .arg("--version")
+        .output()
+        .map_or(false, |out| out.status.success())
+}
+
+/// dir/inner.txt, b.txt and c.txt in a directory of its own, removed when dropped
+struct Fixture(PathBuf);
+
+impl Fixture {
+    fn new() -> std::io::Result<Self> {
+        let root = std::env::temp_dir().join(format!("tree-nvim-rs-it-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dir"))?;
+        std::fs::write(root.join("dir").join("inner.txt"), "inner\n")?;
+        std::fs::write(root.join("b.txt"), "b\n")?;
+        std::fs::write(root.join("c.txt"), "c\n")?;
+        Ok(Fixture(root))
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+#[ignore]
+fn start_open_close_remove_paste() {
+    assert!(nvim_available(), "nvim not found on the PATH");
+    let repo = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let fixture = Fixture::new().expect("fixture");
+    let output = Command::new("nvim")
+        .args(&["--clean", "--headless", "-n", "--cmd"])
+        .arg(format!("set rtp^={}", repo.display()))
+        .arg("-c")
+        .arg(format!(
+            "luafile {}",
+            repo.join("tests").join("integration.lua").display()
+        ))
+        .env("TREE_BIN", env!("CARGO_BIN_EXE_tree-nvim-rs"))
+        .env("TREE_FIXTURE", &fixture.0)
+        .output()
+        .expect("nvim");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// The neovim end of the channel, the requests of the server are answered with nil except
+/// the ones sent on startup
+struct MockPeer {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+}
+
+impl MockPeer {
+    fn accept(listener: &TcpListener, server: &mut Child) -> Self {
+        listener.set_nonblocking(true).expect("nonblocking");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if let Ok(Some(status)) = server.try_wait() {
+                        panic!("the server exited with {} before connecting", status);
+                    }
+                    assert!(Instant::now() < deadline, "the server didn't connect");
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                Err(e) => panic!("accept: {}", e),
+            }
+        };
+        stream.set_nonblocking(false).expect("blocking");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .expect("read timeout");
+        Self {
+            reader: BufReader::new(stream.try_clone().expect("clone")),
+            writer: stream,
+            next_id: 1,
+        }
+    }
+
+    fn send(&mut self, msg: Value) {
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &msg).expect("encode");
+        self.writer.write_all(&buf).expect("write");
+    }
+
+    fn answer(&mut self, id: Value, method: &str) {
+        let result = match method {
+            // [channel id, api metadata]
+            "nvim_get_api_info" => Value::Array(vec![Value::from(1), Value::Map(vec![])]),
+            _ => Value::Nil,
+        };
+        self.send(Value::Array(vec![Value::from(1), id, Value::Nil, result]));
+    }
+
+    /// Send the request and answer the server until the response comes, Err(error) when
+    /// the server failed it
+    fn request(&mut self, method: &str, params: Vec<Value>) -> Result<Value, Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(Value::Array(vec![
+            Value::from(0),
+            Value::from(id),
+            Value::from(method),
+            Value::Array(params),
+        ]));
+        loop {
+            let msg = rmpv::decode::read_value(&mut self.reader).expect("read");
+            let msg = match msg {
+                Value::Array(v) => v,
+                other => panic!("not a message: {}", other),
+            };
+            match msg[0].as_u64() {
+                Some(0) => {
+                    let method = msg[2].as_str().unwrap_or_default().to_owned();
+                    self.answer(msg[1].clone(), &method);
+                }
+                Some(1) if msg[1].as_u64() == Some(id) => {
+                    return if msg[2].is_nil() {
+                        Ok(msg[3].clone())
+                    } else {
+                        Err(msg[2].clone())
+                    };
+                }
+                // notifications and stray responses
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The version the lua frontend of this checkout speaks
+fn frontend_protocol_version() -> u64 {
+    let lua = std::fs::read_to_string(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("lua")
+            .join("tree.lua"),
+    )
+    .expect("lua/tree.lua");
+    lua.lines()
+        .find_map(|l| l.strip_prefix("M.PROTOCOL_VERSION = "))
+        .and_then(|v| v.trim().parse().ok())
+        .expect("M.PROTOCOL_VERSION")
+}
+
+fn hello(version: u64) -> Vec<Value> {
+    vec![Value::Array(vec![Value::Map(vec![
+        (Value::from("version"), Value::from(version)),
+        (Value::from("capabilities"), Value::from(0)),
+    ])])]
+}
+
+fn map_get<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v)
+}
+
+#[test]
+fn handshake_with_mock_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let address = listener.local_addr().expect("address").to_string();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_tree-nvim-rs"))
+        .arg(&address)
+        .spawn()
+        .expect("server");
+    let mut peer = MockPeer::accept(&listener, &mut server);
+
+    let version = frontend_protocol_version();
+    let reply = peer
+        .request("_tree_hello", hello(version))
+        .expect("_tree_hello");
+    assert_eq!(
+        map_get(&reply, "version").and_then(|v| v.as_u64()),
+        Some(version)
+    );
+    assert!(peer.request("_tree_hello", hello(version + 1)).is_err());
+
+    let options = peer
+        .request("_tree_default_window_options", vec![])
+        .expect("_tree_default_window_options");
+    assert!(options.is_map(), "{}", options);
+
+    // neovim is gone, the server quits
+    drop(peer);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = server.try_wait().expect("wait") {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = server.kill();
+            panic!("the server is still running without its peer");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert!(status.success(), "{}", status);
+}